@@ -27,6 +27,11 @@ fn main() {
     compile_and_copy_files(shaders_dir, target_dir);
 }
 
+/// Storage formats of layer textures in documents deeper than 8 bits, see
+/// `BitDepth::storage_format`. Shaders that read or write layers get a variant for each,
+/// named `<shader>.<format>.spv`.
+const LAYER_FORMATS: [&str; 3] = ["rgba16unorm", "rgba16float", "rgba32float"];
+
 fn compile_and_copy_files(from: &Path, to: &Path) {
     let read_dir = std::fs::read_dir(from).unwrap();
     for entry in read_dir {
@@ -38,39 +43,47 @@ fn compile_and_copy_files(from: &Path, to: &Path) {
             std::fs::create_dir(&new_dir).unwrap();
             compile_and_copy_files(&path, &new_dir);
         } else {
-            let file_name = path.file_name().unwrap();
+            let file_stem = path.file_stem().unwrap().to_str().unwrap();
 
             let wgsl_code = std::fs::read_to_string(&path).unwrap();
-            let module = wgsl::parse_str(&wgsl_code).unwrap();
+            compile(&wgsl_code, &to.join(format!("{}.spv", file_stem)));
 
-            let mut validator = Validator::new(ValidationFlags::all(), Capabilities::all());
-            let module_info = validator.validate(&module).unwrap();
+            if wgsl_code.contains("rgba8unorm") {
+                for format in LAYER_FORMATS {
+                    let variant = wgsl_code.replace("rgba8unorm", format);
+                    compile(&variant, &to.join(format!("{}.{}.spv", file_stem, format)));
+                }
+            }
+        }
+    }
+}
 
-            let options = spv::Options::default();
+fn compile(wgsl_code: &str, new_file: &Path) {
+    let module = wgsl::parse_str(wgsl_code).unwrap();
 
-            let mut spv_writer = spv::Writer::new(&options).unwrap();
-            let mut spv_words = Vec::<u32>::new();
-            spv_writer
-                .write(&module, &module_info, None, &None, &mut spv_words)
-                .unwrap();
+    let mut validator = Validator::new(ValidationFlags::all(), Capabilities::all());
+    let module_info = validator.validate(&module).unwrap();
 
-            let spv_bytes = spv_words
-                .iter()
-                .flat_map(|word| word.to_le_bytes().to_vec())
-                .collect::<Vec<u8>>();
+    let options = spv::Options::default();
 
-            let new_file = to.join(file_name);
-            let new_file = new_file.with_extension("spv");
+    let mut spv_writer = spv::Writer::new(&options).unwrap();
+    let mut spv_words = Vec::<u32>::new();
+    spv_writer
+        .write(&module, &module_info, None, &None, &mut spv_words)
+        .unwrap();
 
-            std::fs::write(&new_file, &spv_bytes).unwrap();
+    let spv_bytes = spv_words
+        .iter()
+        .flat_map(|word| word.to_le_bytes().to_vec())
+        .collect::<Vec<u8>>();
 
-            // ignore errors since spirv-tools might not be installed
-            _ = Command::new("spirv-opt")
-                .arg("-O")
-                .arg(&new_file)
-                .arg("-o")
-                .arg(&new_file)
-                .output();
-        }
-    }
+    std::fs::write(new_file, &spv_bytes).unwrap();
+
+    // ignore errors since spirv-tools might not be installed
+    _ = Command::new("spirv-opt")
+        .arg("-O")
+        .arg(new_file)
+        .arg("-o")
+        .arg(new_file)
+        .output();
 }
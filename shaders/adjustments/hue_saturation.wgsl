@@ -0,0 +1,64 @@
+// Shared by every adjustment: params and lut are filled from `Adjustment` in
+// src/workspace/adjustments.rs. The alpha of the input is passed through unchanged.
+@group(0) @binding(0)
+var in_image : texture_storage_2d<rgba8unorm, read>;
+@group(0) @binding(1)
+var out_image : texture_storage_2d<rgba8unorm, write>;
+@group(0) @binding(2)
+var<uniform> params : array<vec4<f32>, 2>;
+@group(0) @binding(3)
+var<storage, read> lut : array<f32, 256>;
+
+// params[0] is hue rotation in degrees, saturation and lightness in -1..1
+fn rgb_to_hsl(color : vec3<f32>) -> vec3<f32> {
+    let high = max(max(color.r, color.g), color.b);
+    let low = min(min(color.r, color.g), color.b);
+    let lightness = (high + low) / 2.0;
+    let delta = high - low;
+    if (delta <= 0.0) {
+        return vec3<f32>(0.0, 0.0, lightness);
+    }
+
+    let saturation = delta / (1.0 - abs(2.0 * lightness - 1.0));
+    var hue = 0.0;
+    if (high == color.r) {
+        hue = (color.g - color.b) / delta;
+    } else if (high == color.g) {
+        hue = (color.b - color.r) / delta + 2.0;
+    } else {
+        hue = (color.r - color.g) / delta + 4.0;
+    }
+    return vec3<f32>(fract(hue / 6.0 + 1.0), saturation, lightness);
+}
+
+fn hsl_to_rgb(hsl : vec3<f32>) -> vec3<f32> {
+    let chroma = (1.0 - abs(2.0 * hsl.z - 1.0)) * hsl.y;
+    let rgb = clamp(
+        abs(fract(vec3<f32>(hsl.x) + vec3<f32>(0.0, 2.0 / 3.0, 1.0 / 3.0)) * 6.0 - 3.0) - 1.0,
+        vec3<f32>(0.0),
+        vec3<f32>(1.0),
+    );
+    return (rgb - 0.5) * chroma + hsl.z;
+}
+
+fn adjust(color : vec3<f32>) -> vec3<f32> {
+    var hsl = rgb_to_hsl(color);
+    hsl.x = fract(hsl.x + params[0].x / 360.0 + 1.0);
+    hsl.y = clamp(hsl.y * (1.0 + params[0].y), 0.0, 1.0);
+
+    let rgb = hsl_to_rgb(hsl);
+    let lightness = params[0].z;
+    if (lightness > 0.0) {
+        return mix(rgb, vec3<f32>(1.0), lightness);
+    }
+    return rgb * (1.0 + lightness);
+}
+
+@compute @workgroup_size(16, 16, 1)
+fn main(@builtin(global_invocation_id) GlobalInvocationID : vec3<u32>) {
+    let pixelCoord = vec2<i32>(GlobalInvocationID.xy);
+
+    let pixel = textureLoad(in_image, pixelCoord);
+    let color = clamp(adjust(pixel.rgb), vec3<f32>(0.0), vec3<f32>(1.0));
+    textureStore(out_image, pixelCoord, vec4<f32>(color, pixel.a));
+}
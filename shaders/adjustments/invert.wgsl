@@ -0,0 +1,23 @@
+// Shared by every adjustment: params and lut are filled from `Adjustment` in
+// src/workspace/adjustments.rs. The alpha of the input is passed through unchanged.
+@group(0) @binding(0)
+var in_image : texture_storage_2d<rgba8unorm, read>;
+@group(0) @binding(1)
+var out_image : texture_storage_2d<rgba8unorm, write>;
+@group(0) @binding(2)
+var<uniform> params : array<vec4<f32>, 2>;
+@group(0) @binding(3)
+var<storage, read> lut : array<f32, 256>;
+
+fn adjust(color : vec3<f32>) -> vec3<f32> {
+    return vec3<f32>(1.0) - color;
+}
+
+@compute @workgroup_size(16, 16, 1)
+fn main(@builtin(global_invocation_id) GlobalInvocationID : vec3<u32>) {
+    let pixelCoord = vec2<i32>(GlobalInvocationID.xy);
+
+    let pixel = textureLoad(in_image, pixelCoord);
+    let color = clamp(adjust(pixel.rgb), vec3<f32>(0.0), vec3<f32>(1.0));
+    textureStore(out_image, pixelCoord, vec4<f32>(color, pixel.a));
+}
@@ -0,0 +1,22 @@
+// Takes the colors of an adjustment blended onto the layers below, with the alpha of the
+// layers below. Uses the same bindings as the blend modes.
+@group(0) @binding(0)
+var in_image : texture_storage_2d<rgba8unorm, read>; // the adjustment blended onto running_total
+@group(0) @binding(1)
+var running_total : texture_storage_2d<rgba8unorm, read>; // the layers below the adjustment
+@group(0) @binding(2)
+var out_image : texture_storage_2d<rgba8unorm, write>;
+@group(0) @binding(3)
+var mask : texture_storage_2d<r8unorm, read>;
+@group(0) @binding(4)
+var<uniform> opacity : f32;
+
+@compute @workgroup_size(16, 16, 1)
+fn main(@builtin(global_invocation_id) GlobalInvocationID : vec3<u32>) {
+    let pixelCoord = vec2<i32>(GlobalInvocationID.xy);
+
+    let blended = textureLoad(in_image, pixelCoord);
+    let below = textureLoad(running_total, pixelCoord);
+
+    textureStore(out_image, pixelCoord, vec4<f32>(blended.rgb, below.a));
+}
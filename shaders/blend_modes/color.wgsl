@@ -0,0 +1,58 @@
+@group(0) @binding(0)
+var in_image : texture_storage_2d<rgba8unorm, read>;
+@group(0) @binding(1)
+var running_total : texture_storage_2d<rgba8unorm, read>;
+@group(0) @binding(2)
+var out_image : texture_storage_2d<rgba8unorm, write>;
+@group(0) @binding(3)
+var mask : texture_storage_2d<r8unorm, read>;
+@group(0) @binding(4)
+var<uniform> opacity : f32;
+
+// non-separable helpers from the W3C compositing spec
+fn lum(color : vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.3, 0.59, 0.11));
+}
+
+fn clip_color(color : vec3<f32>) -> vec3<f32> {
+    let l = lum(color);
+    let n = min(min(color.r, color.g), color.b);
+    let x = max(max(color.r, color.g), color.b);
+    var result = color;
+    if (n < 0.0) {
+        result = vec3<f32>(l) + (result - vec3<f32>(l)) * l / max(l - n, 0.0001);
+    }
+    if (x > 1.0) {
+        result = vec3<f32>(l) + (result - vec3<f32>(l)) * (1.0 - l) / max(x - l, 0.0001);
+    }
+    return result;
+}
+
+fn set_lum(color : vec3<f32>, l : f32) -> vec3<f32> {
+    return clip_color(color + vec3<f32>(l - lum(color)));
+}
+
+fn blend(cb : vec3<f32>, cs : vec3<f32>) -> vec3<f32> {
+    return set_lum(cs, lum(cb));
+}
+
+@compute @workgroup_size(16, 16, 1)
+fn main(@builtin(global_invocation_id) GlobalInvocationID : vec3<u32>) {
+    let pixelCoord = vec2<i32>(GlobalInvocationID.xy);
+
+    let pixel = textureLoad(in_image, pixelCoord);
+    let cur = textureLoad(running_total, pixelCoord);
+    let alpha = pixel.a * opacity * textureLoad(mask, pixelCoord).r;
+
+    // the blended color shows where the layers below are opaque, the layer's own elsewhere
+    let color = mix(pixel.rgb, blend(cur.rgb, pixel.rgb), cur.a);
+
+    // straight alpha "over" operator
+    let out_alpha = alpha + cur.a * (1.0 - alpha);
+    var out_color = vec3<f32>(0.0);
+    if (out_alpha > 0.0) {
+        out_color = (color * alpha + cur.rgb * cur.a * (1.0 - alpha)) / out_alpha;
+    }
+
+    textureStore(out_image, pixelCoord, vec4<f32>(out_color, out_alpha));
+}
@@ -9,32 +9,37 @@ var mask : texture_storage_2d<r8unorm, read>;
 @group(0) @binding(4)
 var<uniform> opacity : f32;
 
-@compute @workgroup_size(16, 16, 1)
-fn main(@builtin(global_invocation_id) GlobalInvocationID : vec3<u32>) {
-    let pixelCoord : vec2<u32> = GlobalInvocationID.xy;
-
-    var pixel = vec4<f32>(textureLoad(in_image, vec2<i32>(pixelCoord)));
-    pixel.a *= opacity;
-    let alpha = pixel.a;
-
-    let cur = vec4<f32>(textureLoad(running_total, vec2<i32>(pixelCoord)));
-    let cur_alpha = cur.a;
+fn burn(cb : f32, cs : f32) -> f32 {
+    if (cb == 1.0) {
+        return 1.0;
+    }
+    if (cs <= 0.0) {
+        return 0.0;
+    }
+    return 1.0 - min(1.0, (1.0 - cb) / cs);
+}
 
-    let burn_result = vec3<f32>(
-        1.0 - (1.0 - cur.r) / max(pixel.r, 0.001),
-        1.0 - (1.0 - cur.g) / max(pixel.g, 0.001),
-        1.0 - (1.0 - cur.b) / max(pixel.b, 0.001)
-    );
+fn blend(cb : vec3<f32>, cs : vec3<f32>) -> vec3<f32> {
+    return vec3<f32>(burn(cb.r, cs.r), burn(cb.g, cs.g), burn(cb.b, cs.b));
+}
 
-    let blended_color = burn_result * alpha + cur.rgb * (1.0 - alpha);
-    let out_alpha = alpha + cur_alpha * (1.0 - alpha);
+@compute @workgroup_size(16, 16, 1)
+fn main(@builtin(global_invocation_id) GlobalInvocationID : vec3<u32>) {
+    let pixelCoord = vec2<i32>(GlobalInvocationID.xy);
 
-    var sum = vec4<f32>(blended_color, out_alpha);
+    let pixel = textureLoad(in_image, pixelCoord);
+    let cur = textureLoad(running_total, pixelCoord);
+    let alpha = pixel.a * opacity * textureLoad(mask, pixelCoord).r;
 
-    let mask_texture_dimensions = textureDimensions(mask);
+    // the blended color shows where the layers below are opaque, the layer's own elsewhere
+    let color = mix(pixel.rgb, blend(cur.rgb, pixel.rgb), cur.a);
 
-    let mask_value = textureLoad(mask, vec2<i32>(pixelCoord)).r;
-    sum = sum * mask_value + cur * (1.0 - mask_value);
+    // straight alpha "over" operator
+    let out_alpha = alpha + cur.a * (1.0 - alpha);
+    var out_color = vec3<f32>(0.0);
+    if (out_alpha > 0.0) {
+        out_color = (color * alpha + cur.rgb * cur.a * (1.0 - alpha)) / out_alpha;
+    }
 
-    textureStore(out_image, vec2<i32>(pixelCoord), sum);
+    textureStore(out_image, pixelCoord, vec4<f32>(out_color, out_alpha));
 }
@@ -0,0 +1,45 @@
+@group(0) @binding(0)
+var in_image : texture_storage_2d<rgba8unorm, read>;
+@group(0) @binding(1)
+var running_total : texture_storage_2d<rgba8unorm, read>;
+@group(0) @binding(2)
+var out_image : texture_storage_2d<rgba8unorm, write>;
+@group(0) @binding(3)
+var mask : texture_storage_2d<r8unorm, read>;
+@group(0) @binding(4)
+var<uniform> opacity : f32;
+
+fn dodge(cb : f32, cs : f32) -> f32 {
+    if (cb == 0.0) {
+        return 0.0;
+    }
+    if (cs >= 1.0) {
+        return 1.0;
+    }
+    return min(1.0, cb / (1.0 - cs));
+}
+
+fn blend(cb : vec3<f32>, cs : vec3<f32>) -> vec3<f32> {
+    return vec3<f32>(dodge(cb.r, cs.r), dodge(cb.g, cs.g), dodge(cb.b, cs.b));
+}
+
+@compute @workgroup_size(16, 16, 1)
+fn main(@builtin(global_invocation_id) GlobalInvocationID : vec3<u32>) {
+    let pixelCoord = vec2<i32>(GlobalInvocationID.xy);
+
+    let pixel = textureLoad(in_image, pixelCoord);
+    let cur = textureLoad(running_total, pixelCoord);
+    let alpha = pixel.a * opacity * textureLoad(mask, pixelCoord).r;
+
+    // the blended color shows where the layers below are opaque, the layer's own elsewhere
+    let color = mix(pixel.rgb, blend(cur.rgb, pixel.rgb), cur.a);
+
+    // straight alpha "over" operator
+    let out_alpha = alpha + cur.a * (1.0 - alpha);
+    var out_color = vec3<f32>(0.0);
+    if (out_alpha > 0.0) {
+        out_color = (color * alpha + cur.rgb * cur.a * (1.0 - alpha)) / out_alpha;
+    }
+
+    textureStore(out_image, pixelCoord, vec4<f32>(out_color, out_alpha));
+}
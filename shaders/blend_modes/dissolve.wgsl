@@ -9,44 +9,30 @@ var mask : texture_storage_2d<r8unorm, read>;
 @group(0) @binding(4)
 var<uniform> opacity : f32;
 
-fn rand(p : vec2<u32>) -> f32 {
+// xxHash32 of the pixel position, scaled to 0 up to 1
+fn noise(p : vec2<u32>) -> f32 {
     let p2 = 2246822519u; let p3 = 3266489917u;
     let p4 = 668265263u; let p5 = 374761393u;
     var h32 = p.y + p5 + p.x * p3;
     h32 = p4 * ((h32 << 17) | (h32 >> (32 - 17)));
     h32 = p2 * (h32^(h32 >> 15));
     h32 = p3 * (h32^(h32 >> 13));
-    let h = sin(f32(h32^(h32 >> 16)));
-    // convert to evenly distributed random number
-    let r = fract(h * 43758.5453123);
-
-    return r;
+    h32 = h32^(h32 >> 16);
+    // the top 24 bits convert to f32 exactly, so the pattern is the same everywhere
+    return f32(h32 >> 8u) / 16777216.0;
 }
 
 @compute @workgroup_size(16, 16, 1)
 fn main(@builtin(global_invocation_id) GlobalInvocationID : vec3<u32>) {
-    let pixelCoord : vec2<u32> = GlobalInvocationID.xy;
-
-    var pixel = vec4<f32>(textureLoad(in_image, vec2<i32>(pixelCoord)));
-    pixel.a *= opacity;
-
-    let mask_texture_dimensions = textureDimensions(mask);
-
-    let mask_value = textureLoad(mask, vec2<i32>(pixelCoord)).r;
-    pixel.a *= mask_value;
+    let pixelCoord = vec2<i32>(GlobalInvocationID.xy);
 
-    let alpha = pixel.a;
+    let pixel = textureLoad(in_image, pixelCoord);
+    let alpha = pixel.a * opacity * textureLoad(mask, pixelCoord).r;
 
-    let cur = vec4<f32>(textureLoad(running_total, vec2<i32>(pixelCoord)));
-
-    let random_value = rand(pixelCoord);
-
-    var blended_color: vec4<f32>;
-    if (random_value < alpha) {
-        blended_color = pixel;
+    // a pixel is drawn fully opaque with a chance of its alpha, or not at all
+    if (noise(GlobalInvocationID.xy) < alpha) {
+        textureStore(out_image, pixelCoord, vec4<f32>(pixel.rgb, 1.0));
     } else {
-        blended_color = cur;
-    };
-
-    textureStore(out_image, vec2<i32>(pixelCoord), blended_color);
+        textureStore(out_image, pixelCoord, textureLoad(running_total, pixelCoord));
+    }
 }
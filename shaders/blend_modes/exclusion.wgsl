@@ -9,23 +9,27 @@ var mask : texture_storage_2d<r8unorm, read>;
 @group(0) @binding(4)
 var<uniform> opacity : f32;
 
-@compute @workgroup_size(16, 16, 1)
-fn main(@builtin(global_invocation_id) GlobalInvocationID: vec3<u32>) {
-    let pixelCoord: vec2<u32> = GlobalInvocationID.xy;
-
-    var pixel = vec4<f32>(textureLoad(in_image, vec2<i32>(pixelCoord)));
-    pixel.a *= opacity;
-    let cur = vec4<f32>(textureLoad(running_total, vec2<i32>(pixelCoord)));
+fn blend(cb : vec3<f32>, cs : vec3<f32>) -> vec3<f32> {
+    return cb + cs - 2.0 * cb * cs;
+}
 
-    let blended_color = cur.rgb + pixel.rgb - 2.0 * cur.rgb * pixel.rgb;
-    let out_alpha = pixel.a + cur.a * (1.0 - pixel.a);
+@compute @workgroup_size(16, 16, 1)
+fn main(@builtin(global_invocation_id) GlobalInvocationID : vec3<u32>) {
+    let pixelCoord = vec2<i32>(GlobalInvocationID.xy);
 
-    var sum = vec4<f32>(blended_color, out_alpha);
+    let pixel = textureLoad(in_image, pixelCoord);
+    let cur = textureLoad(running_total, pixelCoord);
+    let alpha = pixel.a * opacity * textureLoad(mask, pixelCoord).r;
 
-    let mask_texture_dimensions = textureDimensions(mask);
+    // the blended color shows where the layers below are opaque, the layer's own elsewhere
+    let color = mix(pixel.rgb, blend(cur.rgb, pixel.rgb), cur.a);
 
-    let mask_value = textureLoad(mask, vec2<i32>(pixelCoord)).r;
-    sum = sum * mask_value + cur * (1.0 - mask_value);
+    // straight alpha "over" operator
+    let out_alpha = alpha + cur.a * (1.0 - alpha);
+    var out_color = vec3<f32>(0.0);
+    if (out_alpha > 0.0) {
+        out_color = (color * alpha + cur.rgb * cur.a * (1.0 - alpha)) / out_alpha;
+    }
 
-    textureStore(out_image, vec2<i32>(pixelCoord), sum);
+    textureStore(out_image, pixelCoord, vec4<f32>(out_color, out_alpha));
 }
@@ -9,24 +9,29 @@ var mask : texture_storage_2d<r8unorm, read>;
 @group(0) @binding(4)
 var<uniform> opacity : f32;
 
+fn blend(cb : vec3<f32>, cs : vec3<f32>) -> vec3<f32> {
+    return cs;
+}
+
 @compute @workgroup_size(16, 16, 1)
 fn main(@builtin(global_invocation_id) GlobalInvocationID : vec3<u32>) {
-	let pixelCoord : vec2<u32> = GlobalInvocationID.xy;
-
-    var pixel = vec4<f32>(textureLoad(in_image, vec2<i32>(pixelCoord)));
-    pixel.a *= opacity;
-    let mask_texture_dimensions = textureDimensions(mask);
-
-    let mask_value = textureLoad(mask, vec2<i32>(pixelCoord)).r;
-    pixel.a *= mask_value;
+    let pixelCoord = vec2<i32>(GlobalInvocationID.xy);
 
-    let alpha = pixel.a;
+    let pixel = textureLoad(in_image, pixelCoord);
+    let cur = textureLoad(running_total, pixelCoord);
+    let alpha = pixel.a * opacity * textureLoad(mask, pixelCoord).r;
 
-    let cur = vec4<f32>(textureLoad(running_total, vec2<i32>(pixelCoord)));
-    let cur_alpha = cur.a;
-    let alpha_inv = 1.0 - alpha;
+    // the blended color shows where the layers below are opaque, the layer's own elsewhere
+    let color = mix(pixel.rgb, blend(cur.rgb, pixel.rgb), cur.a);
 
-    let sum = (pixel * alpha + cur * alpha_inv * cur_alpha);
+    // straight alpha "over" operator. Committing a brush stroke stores the result as the
+    // layer's pixels, so weighting by alpha twice would darken colors on transparent layers
+    // and square the coverage of every stroke.
+    let out_alpha = alpha + cur.a * (1.0 - alpha);
+    var out_color = vec3<f32>(0.0);
+    if (out_alpha > 0.0) {
+        out_color = (color * alpha + cur.rgb * cur.a * (1.0 - alpha)) / out_alpha;
+    }
 
-	textureStore(out_image, vec2<i32>(pixelCoord), vec4<f32>(sum));
+    textureStore(out_image, pixelCoord, vec4<f32>(out_color, out_alpha));
 }
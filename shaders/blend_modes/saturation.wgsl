@@ -9,39 +9,39 @@ var mask : texture_storage_2d<r8unorm, read>;
 @group(0) @binding(4)
 var<uniform> opacity : f32;
 
-fn rgb_to_hsv(rgb: vec3<f32>) -> vec3<f32> {
+// hue in degrees from 0 up to 360, saturation and value from 0 to 1
+fn rgb_to_hsv(rgb : vec3<f32>) -> vec3<f32> {
     let c_max = max(max(rgb.r, rgb.g), rgb.b);
     let c_min = min(min(rgb.r, rgb.g), rgb.b);
     let delta = c_max - c_min;
 
     var h = 0.0;
     if (delta == 0.0) {
-        h = 0.0; // Undefined
+        h = 0.0; // undefined
     } else if (c_max == rgb.r) {
-        h = ((rgb.g - rgb.b) / delta) % 6.0;
+        h = (rgb.g - rgb.b) / delta;
+        if (h < 0.0) {
+            h += 6.0;
+        }
     } else if (c_max == rgb.g) {
         h = (rgb.b - rgb.r) / delta + 2.0;
     } else {
         h = (rgb.r - rgb.g) / delta + 4.0;
     }
 
-    let v = c_max;
-    var s : f32;
-    if c_max == 0.0 {
-        s = 0.0;
-    } else {
+    var s = 0.0;
+    if (c_max > 0.0) {
         s = delta / c_max;
     }
-    return vec3<f32>(h * 60.0, s, v);
+    return vec3<f32>(h * 60.0, s, c_max);
 }
 
-fn hsv_to_rgb(hsv: vec3<f32>) -> vec3<f32> {
+fn hsv_to_rgb(hsv : vec3<f32>) -> vec3<f32> {
     let c = hsv.z * hsv.y;
     let x = c * (1.0 - abs(((hsv.x / 60.0) % 2.0) - 1.0));
     let m = hsv.z - c;
 
-    var rgb = vec3<f32>(0.0, 0.0, 0.0);
-
+    var rgb = vec3<f32>(0.0);
     if (hsv.x < 60.0) {
         rgb = vec3<f32>(c, x, 0.0);
     } else if (hsv.x < 120.0) {
@@ -56,29 +56,31 @@ fn hsv_to_rgb(hsv: vec3<f32>) -> vec3<f32> {
         rgb = vec3<f32>(c, 0.0, x);
     }
 
-    return rgb + vec3<f32>(m, m, m);
+    return rgb + vec3<f32>(m);
 }
 
-@compute @workgroup_size(16, 16, 1)
-fn main(@builtin(global_invocation_id) GlobalInvocationID: vec3<u32>) {
-    let pixelCoord: vec2<u32> = GlobalInvocationID.xy;
-
-    var pixel = vec4<f32>(textureLoad(in_image, vec2<i32>(pixelCoord)));
-    pixel.a *= opacity;
-    let cur = vec4<f32>(textureLoad(running_total, vec2<i32>(pixelCoord)));
+fn blend(cb : vec3<f32>, cs : vec3<f32>) -> vec3<f32> {
+    let below = rgb_to_hsv(cb);
+    return hsv_to_rgb(vec3<f32>(below.x, rgb_to_hsv(cs).y, below.z));
+}
 
-    let cur_hsv = rgb_to_hsv(cur.rgb);
-    let pixel_hsv = rgb_to_hsv(pixel.rgb);
+@compute @workgroup_size(16, 16, 1)
+fn main(@builtin(global_invocation_id) GlobalInvocationID : vec3<u32>) {
+    let pixelCoord = vec2<i32>(GlobalInvocationID.xy);
 
-    let blended_hue = vec3<f32>(cur_hsv.x, pixel_hsv.y, cur_hsv.z);
-    let blended_color = hsv_to_rgb(blended_hue);
-    let out_alpha = pixel.a + cur.a * (1.0 - pixel.a);
+    let pixel = textureLoad(in_image, pixelCoord);
+    let cur = textureLoad(running_total, pixelCoord);
+    let alpha = pixel.a * opacity * textureLoad(mask, pixelCoord).r;
 
-    var sum = vec4<f32>(blended_color, out_alpha);    
-    let mask_texture_dimensions = textureDimensions(mask);
+    // the blended color shows where the layers below are opaque, the layer's own elsewhere
+    let color = mix(pixel.rgb, blend(cur.rgb, pixel.rgb), cur.a);
 
-    let mask_value = textureLoad(mask, vec2<i32>(pixelCoord)).r;
-    sum = sum * mask_value + cur * (1.0 - mask_value);
+    // straight alpha "over" operator
+    let out_alpha = alpha + cur.a * (1.0 - alpha);
+    var out_color = vec3<f32>(0.0);
+    if (out_alpha > 0.0) {
+        out_color = (color * alpha + cur.rgb * cur.a * (1.0 - alpha)) / out_alpha;
+    }
 
-    textureStore(out_image, vec2<i32>(pixelCoord), sum);
+    textureStore(out_image, pixelCoord, vec4<f32>(out_color, out_alpha));
 }
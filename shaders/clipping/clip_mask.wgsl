@@ -0,0 +1,19 @@
+// Combines the mask of a clipped layer with the coverage of its base layer
+@group(0) @binding(0)
+var base_image : texture_storage_2d<rgba8unorm, read>;
+@group(0) @binding(1)
+var base_mask : texture_storage_2d<r8unorm, read>;
+@group(0) @binding(2)
+var layer_mask : texture_storage_2d<r8unorm, read>;
+@group(0) @binding(3)
+var out_mask : texture_storage_2d<r8unorm, write>;
+
+@compute @workgroup_size(16, 16, 1)
+fn main(@builtin(global_invocation_id) GlobalInvocationID : vec3<u32>) {
+    let pixelCoord = vec2<i32>(GlobalInvocationID.xy);
+
+    let coverage = textureLoad(base_image, pixelCoord).a * textureLoad(base_mask, pixelCoord).r;
+    let mask = textureLoad(layer_mask, pixelCoord).r * coverage;
+
+    textureStore(out_mask, pixelCoord, vec4<f32>(mask, 0.0, 0.0, 0.0));
+}
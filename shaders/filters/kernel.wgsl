@@ -32,5 +32,6 @@ fn main(@builtin(global_invocation_id) GlobalInvocationID : vec3<u32>) {
 		}
 	}
 
-	textureStore(outImage, vec2<i32>(pixelCoord), vec4<f32>(sum));
+	// float layers don't clamp on their own like unorm ones do
+	textureStore(outImage, vec2<i32>(pixelCoord), clamp(sum, vec4<f32>(0.0), vec4<f32>(1.0)));
 }
\ No newline at end of file
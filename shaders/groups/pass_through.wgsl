@@ -0,0 +1,30 @@
+// Fades from the layers below a pass-through group to the result of blending its contents
+// onto them. Uses the same bindings as the blend modes.
+@group(0) @binding(0)
+var in_image : texture_storage_2d<rgba8unorm, read>; // the group's contents blended onto running_total
+@group(0) @binding(1)
+var running_total : texture_storage_2d<rgba8unorm, read>; // the layers below the group
+@group(0) @binding(2)
+var out_image : texture_storage_2d<rgba8unorm, write>;
+@group(0) @binding(3)
+var mask : texture_storage_2d<r8unorm, read>;
+@group(0) @binding(4)
+var<uniform> opacity : f32;
+
+@compute @workgroup_size(16, 16, 1)
+fn main(@builtin(global_invocation_id) GlobalInvocationID : vec3<u32>) {
+    let pixelCoord = vec2<i32>(GlobalInvocationID.xy);
+
+    let contents = textureLoad(in_image, pixelCoord);
+    let below = textureLoad(running_total, pixelCoord);
+    let amount = opacity * textureLoad(mask, pixelCoord).r;
+
+    // fades premultiplied, so transparent pixels don't bleed their color
+    let alpha = mix(below.a, contents.a, amount);
+    var color = vec3<f32>(0.0);
+    if (alpha > 0.0) {
+        color = mix(below.rgb * below.a, contents.rgb * contents.a, amount) / alpha;
+    }
+
+    textureStore(out_image, pixelCoord, vec4<f32>(color, alpha));
+}
@@ -0,0 +1,16 @@
+// Draws the top running total of a document deeper than 8 bits into the 8-bit output
+// texture, which is what gets shown and exported. Storing into the target clamps and rounds.
+@group(0) @binding(0)
+var total : texture_2d<f32>;
+
+// a single triangle covering the whole target
+@vertex
+fn vs_main(@builtin(vertex_index) index : u32) -> @builtin(position) vec4<f32> {
+    let corner = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    return vec4<f32>(corner * 2.0 - 1.0, 0.0, 1.0);
+}
+
+@fragment
+fn fs_main(@builtin(position) position : vec4<f32>) -> @location(0) vec4<f32> {
+    return textureLoad(total, vec2<i32>(position.xy), 0);
+}
@@ -0,0 +1,21 @@
+@group(0) @binding(0)
+var original : texture_storage_2d<rgba8unorm, read>;
+@group(0) @binding(1)
+var filtered : texture_storage_2d<rgba8unorm, read>;
+@group(0) @binding(2)
+var selection : texture_storage_2d<r8unorm, read>;
+@group(0) @binding(3)
+var out_image : texture_storage_2d<rgba8unorm, write>;
+
+@compute @workgroup_size(16, 16, 1)
+fn main(@builtin(global_invocation_id) GlobalInvocationId: vec3<u32>) {
+    let pixel = vec2<i32>(GlobalInvocationId.xy);
+    let dimensions = textureDimensions(out_image);
+    if (u32(pixel.x) >= dimensions.x || u32(pixel.y) >= dimensions.y) {
+        return;
+    }
+
+    let coverage = textureLoad(selection, pixel).r;
+    let result = mix(textureLoad(original, pixel), textureLoad(filtered, pixel), coverage);
+    textureStore(out_image, pixel, result);
+}
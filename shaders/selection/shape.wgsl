@@ -0,0 +1,120 @@
+struct Params {
+    kind : u32, // 0 rectangle, 1 ellipse, 2 lasso, 3 everything, 4 mask
+    op : u32, // 0 replace, 1 add, 2 subtract, 3 intersect, 4 invert
+    feather : f32, // width of the soft edge in pixels, 0 for a one pixel antialiased edge
+    point_count : u32,
+    min : vec2<f32>, // bounds of rectangles and ellipses
+    max : vec2<f32>,
+}
+
+@group(0) @binding(0)
+var selection : texture_storage_2d<r8unorm, read_write>;
+@group(0) @binding(1)
+var<uniform> params : Params;
+@group(0) @binding(2)
+var<storage> points : array<vec2<f32>>; // lasso polygon, implicitly closed
+@group(0) @binding(3)
+var mask : texture_2d<f32>; // coverage computed on the cpu, e.g. by the magic wand
+
+@compute @workgroup_size(16, 16, 1)
+fn main(@builtin(global_invocation_id) GlobalInvocationId: vec3<u32>) {
+    let pixel = GlobalInvocationId.xy;
+    let dimensions = textureDimensions(selection);
+    if (pixel.x >= dimensions.x || pixel.y >= dimensions.y) {
+        return;
+    }
+    let cur = textureLoad(selection, vec2<i32>(pixel)).r;
+
+    if (params.op == 4u) {
+        textureStore(selection, vec2<i32>(pixel), vec4<f32>(1.0 - cur));
+        return;
+    }
+
+    let point = vec2<f32>(pixel) + vec2<f32>(0.5);
+    var distance : f32;
+    switch params.kind {
+        case 0u: {
+            distance = rectangle_distance(point);
+        }
+        case 1u: {
+            distance = ellipse_distance(point);
+        }
+        case 2u: {
+            distance = lasso_distance(point);
+        }
+        default: {
+            distance = -1e9;
+        }
+    }
+
+    var coverage : f32;
+    if (params.kind == 4u) {
+        coverage = textureLoad(mask, vec2<i32>(pixel), 0).r;
+    } else if (params.feather > 0.0) {
+        coverage = 1.0 - smoothstep(-params.feather, params.feather, distance);
+    } else {
+        coverage = clamp(0.5 - distance, 0.0, 1.0);
+    }
+
+    var result : f32;
+    switch params.op {
+        case 1u: {
+            result = max(cur, coverage);
+        }
+        case 2u: {
+            result = min(cur, 1.0 - coverage);
+        }
+        case 3u: {
+            result = min(cur, coverage);
+        }
+        default: {
+            result = coverage;
+        }
+    }
+
+    textureStore(selection, vec2<i32>(pixel), vec4<f32>(result));
+}
+
+// signed distances are negative inside the shape
+
+fn rectangle_distance(point : vec2<f32>) -> f32 {
+    let center = (params.min + params.max) * 0.5;
+    let half_size = (params.max - params.min) * 0.5;
+    let q = abs(point - center) - half_size;
+    return length(max(q, vec2<f32>(0.0))) + min(max(q.x, q.y), 0.0);
+}
+
+fn ellipse_distance(point : vec2<f32>) -> f32 {
+    let center = (params.min + params.max) * 0.5;
+    let radius = max((params.max - params.min) * 0.5, vec2<f32>(0.001));
+    // not exact away from the edge, but close enough for antialiasing and feathering
+    return (length((point - center) / radius) - 1.0) * min(radius.x, radius.y);
+}
+
+fn lasso_distance(point : vec2<f32>) -> f32 {
+    var distance = 1e9;
+    var inside = false;
+    var j = params.point_count - 1u;
+    for (var i = 0u; i < params.point_count; i = i + 1u) {
+        let a = points[j];
+        let b = points[i];
+
+        let a_to_b = b - a;
+        let a_to_point = point - a;
+        let length_squared = dot(a_to_b, a_to_b);
+        var t = 0.0;
+        if (length_squared > 0.0) {
+            t = clamp(dot(a_to_point, a_to_b) / length_squared, 0.0, 1.0);
+        }
+        distance = min(distance, length(a_to_point - a_to_b * t));
+
+        // even-odd rule
+        if ((b.y > point.y) != (a.y > point.y)
+            && point.x < (a.x - b.x) * (point.y - b.y) / (a.y - b.y) + b.x) {
+            inside = !inside;
+        }
+        j = i;
+    }
+
+    return select(distance, -distance, inside);
+}
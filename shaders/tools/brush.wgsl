@@ -1,5 +1,7 @@
 @group(0) @binding(0)
 var mask_texture : texture_storage_2d<r8unorm, read_write>;
+@group(0) @binding(1)
+var selection : texture_2d<f32>; // strokes are clipped to the workspace selection
 @group(1) @binding(0)
 var<uniform> opacity : f32;
 @group(1) @binding(1)
@@ -29,7 +31,8 @@ fn main(@builtin(global_invocation_id) GlobalInvocationId: vec3<u32>) {
         } else {
             distance = distance(brush_center, vec2<f32>(pixel));
         }
-        let alpha = opacity * apply_hardness_circle_brush(1.0 - distance / brush_size, brush_hardness);
+        let alpha = opacity * apply_hardness_circle_brush(1.0 - distance / brush_size, brush_hardness)
+            * textureLoad(selection, vec2<i32>(pixel), 0).r;
         let cur = textureLoad(mask_texture, vec2<i32>(pixel));
 
         if (alpha > cur.r) {
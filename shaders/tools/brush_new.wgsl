@@ -8,36 +8,75 @@ var<uniform> brush_size : f32;
 var<uniform> brush_hardness : f32;
 @group(0) @binding(4)
 var<uniform> brush_rotation : f32; // in radians
+@group(0) @binding(5)
+var selection : texture_2d<f32>; // strokes are clipped to the workspace selection
+// size, opacity and hardness are multipliers of the uniforms above, from pen pressure
+struct PathPoint {
+    position : vec2<f32>,
+    size : f32,
+    opacity : f32,
+    hardness : f32,
+}
+
 @group(1) @binding(0)
-var<storage> path : array<vec2<f32>>;
+var<storage> path : array<PathPoint>;
 @group(1) @binding(1)
 var<uniform> path_length : u32;
+@group(1) @binding(2)
+var<uniform> origin : vec2<u32>; // top left corner of the dispatched area
 
 @compute @workgroup_size(8, 8, 1)
 fn main(@builtin(global_invocation_id) GlobalInvocationId: vec3<u32>) {
-    let pixel = vec2<u32>(GlobalInvocationId.xy);
-
-    for (var i = 0u; i < path_length + 1u; i = i + 1u) {
-        let start = path[i];
-        let end = path[(i + 1u) % path_length];
-        let start_to_pixel = vec2<f32>(pixel) - start;
-        let start_to_end = end - start;
-        let start_to_end_length = length(start_to_end);
-        let start_to_end_normalized = start_to_end / start_to_end_length;
-        let start_to_end_normal = vec2<f32>(-start_to_end_normalized.y, start_to_end_normalized.x);
-        let start_to_end_normal_scaled = start_to_end_normal * brush_size;
-        let start_to_pixel_length = dot(start_to_pixel, start_to_end_normal_scaled) / start_to_end_length;
-        let start_to_pixel_normal = start_to_pixel - start_to_pixel_length * start_to_end_normalized;
-        let distance = length(start_to_pixel_normal);
-        let alpha = opacity * apply_hardness_circle_brush(1.0 - distance / brush_size, brush_hardness);
-        let cur = textureLoad(mask_texture, vec2<i32>(pixel));
-
-        if (alpha > cur.r) {
-            textureStore(mask_texture, vec2<i32>(pixel), vec4<f32>(alpha));
+    let pixel = vec2<u32>(GlobalInvocationId.xy) + origin;
+    let dimensions = textureDimensions(mask_texture);
+    if (pixel.x >= dimensions.x || pixel.y >= dimensions.y) {
+        return;
+    }
+    let point = vec2<f32>(pixel);
+
+    // coverage of the closest capsule, a single point is a capsule of length zero
+    var alpha = coverage(length(point - path[0].position), path[0]);
+    for (var i = 1u; i < path_length; i = i + 1u) {
+        let start = path[i - 1u];
+        let end = path[i];
+        let start_to_end = end.position - start.position;
+        let start_to_pixel = point - start.position;
+
+        let segment_length_squared = dot(start_to_end, start_to_end);
+        var t = 0.0;
+        if (segment_length_squared > 0.0) {
+            t = clamp(dot(start_to_pixel, start_to_end) / segment_length_squared, 0.0, 1.0);
         }
+
+        let interpolated = PathPoint(
+            vec2<f32>(0.0),
+            mix(start.size, end.size, t),
+            mix(start.opacity, end.opacity, t),
+            mix(start.hardness, end.hardness, t),
+        );
+        alpha = max(alpha, coverage(length(start_to_pixel - start_to_end * t), interpolated));
+    }
+
+    alpha = alpha * textureLoad(selection, vec2<i32>(pixel), 0).r;
+    let cur = textureLoad(mask_texture, vec2<i32>(pixel));
+
+    if (alpha > cur.r) {
+        textureStore(mask_texture, vec2<i32>(pixel), vec4<f32>(alpha));
     }
 }
 
+fn coverage(distance : f32, point : PathPoint) -> f32 {
+    let size = brush_size * point.size;
+    var dist = distance;
+    if (size < 1.0) {
+        // round to nearest pixel so that it works to draw a single pixel
+        dist = select(1.0, 0.0, dist < 0.5);
+    }
+
+    let hardness = clamp(brush_hardness * point.hardness, 0.0, 1.0);
+    return opacity * point.opacity * apply_hardness_circle_brush(1.0 - dist / max(size, 1.0), hardness);
+}
+
 fn apply_hardness_circle_brush(input: f32, hardness: f32) -> f32 {
     return smoothstep(0.0, 1.0 - hardness, input);
-}
\ No newline at end of file
+}
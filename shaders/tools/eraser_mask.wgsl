@@ -0,0 +1,87 @@
+@group(0) @binding(0)
+var coverage_texture : texture_storage_2d<r8unorm, read_write>; // how much of each pixel has been erased this stroke
+@group(0) @binding(1)
+var<uniform> opacity : f32;
+@group(0) @binding(2)
+var<uniform> brush_size : f32;
+@group(0) @binding(3)
+var<uniform> brush_hardness : f32;
+@group(0) @binding(4)
+var<uniform> brush_rotation : f32; // in radians
+@group(0) @binding(5)
+var source_texture : texture_2d<f32>; // the layer as it was before the stroke
+@group(0) @binding(6)
+var target_texture : texture_storage_2d<r8unorm, write>;
+@group(0) @binding(7)
+var selection : texture_2d<f32>; // strokes are clipped to the workspace selection
+
+// size, opacity and hardness are multipliers of the uniforms above, from pen pressure
+struct PathPoint {
+    position : vec2<f32>,
+    size : f32,
+    opacity : f32,
+    hardness : f32,
+}
+
+@group(1) @binding(0)
+var<storage> path : array<PathPoint>;
+@group(1) @binding(1)
+var<uniform> path_length : u32;
+@group(1) @binding(2)
+var<uniform> origin : vec2<u32>; // top left corner of the dispatched area
+
+@compute @workgroup_size(8, 8, 1)
+fn main(@builtin(global_invocation_id) GlobalInvocationId: vec3<u32>) {
+    let pixel = vec2<u32>(GlobalInvocationId.xy) + origin;
+    let dimensions = textureDimensions(coverage_texture);
+    if (pixel.x >= dimensions.x || pixel.y >= dimensions.y) {
+        return;
+    }
+    let point = vec2<f32>(pixel);
+
+    // coverage of the closest capsule, a single point is a capsule of length zero
+    var alpha = coverage(length(point - path[0].position), path[0]);
+    for (var i = 1u; i < path_length; i = i + 1u) {
+        let start = path[i - 1u];
+        let end = path[i];
+        let start_to_end = end.position - start.position;
+        let start_to_pixel = point - start.position;
+
+        let segment_length_squared = dot(start_to_end, start_to_end);
+        var t = 0.0;
+        if (segment_length_squared > 0.0) {
+            t = clamp(dot(start_to_pixel, start_to_end) / segment_length_squared, 0.0, 1.0);
+        }
+
+        let interpolated = PathPoint(
+            vec2<f32>(0.0),
+            mix(start.size, end.size, t),
+            mix(start.opacity, end.opacity, t),
+            mix(start.hardness, end.hardness, t),
+        );
+        alpha = max(alpha, coverage(length(start_to_pixel - start_to_end * t), interpolated));
+    }
+
+    alpha = alpha * textureLoad(selection, vec2<i32>(pixel), 0).r;
+    let erased = max(alpha, textureLoad(coverage_texture, vec2<i32>(pixel)).r);
+    textureStore(coverage_texture, vec2<i32>(pixel), vec4<f32>(erased));
+
+    let source = textureLoad(source_texture, vec2<i32>(pixel), 0);
+    textureStore(target_texture, vec2<i32>(pixel), vec4<f32>(source.r * (1.0 - erased)));
+}
+
+fn coverage(distance : f32, point : PathPoint) -> f32 {
+    let size = brush_size * point.size;
+    var dist = distance;
+    if (size < 1.0) {
+        // round to nearest pixel so that it works to draw a single pixel
+        dist = select(1.0, 0.0, dist < 0.5);
+    }
+
+    let hardness = clamp(brush_hardness * point.hardness, 0.0, 1.0);
+    return opacity * point.opacity * apply_hardness_circle_brush(1.0 - dist / max(size, 1.0), hardness);
+}
+
+fn apply_hardness_circle_brush(input: f32, hardness: f32) -> f32 {
+    return smoothstep(0.0, 1.0 - hardness, input);
+}
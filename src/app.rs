@@ -1,78 +1,1209 @@
+use crate::batch;
 use crate::device::GpuDevice;
-use crate::workspace::{tools::ActionOrigin, Workspace};
-use egui::{Image, Pos2, Rect, Sense, TextureId, Vec2};
-use std::sync::Arc;
+use crate::file_browser::{FileBrowser, FileBrowserMode, FileBrowserResult};
+use crate::filters::kernel::Kernel;
+use crate::recent_files::RecentFiles;
+use crate::workspace::{
+    adjustments::Adjustment,
+    bit_depth::BitDepth,
+    export::{ExportFormat, ExportOptions, PngCompression},
+    import::{read_image, IMPORT_EXTENSIONS},
+    layer_info::{BlendMode, GroupMode, LayerInfo, LayerKind},
+    openraster::is_openraster,
+    psd::is_psd,
+    selection::{selection_outline, ColorRange, SampleSource, Segment, SelectionOp},
+    tools::{
+        brush::BrushToolSettings,
+        brush_new::BrushToolNew,
+        eraser::{EraserMode, EraserTool},
+        magic_wand::MagicWandTool,
+        ActionOrigin, PointerInput, SelectTool, Tool,
+    },
+    Workspace,
+};
+use egui::{Color32, Image, Pos2, Rect, Sense, Shape, Stroke, TextureId, Vec2};
+use egui_wgpu::RenderState;
+use image::{ImageBuffer, Rgba};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::runtime::Runtime;
 use wgpu::*;
 
+struct ExportDialog {
+    path: String,
+    format: ExportFormat,
+    options: ExportOptions,
+    /// Why the last attempt failed
+    error: Option<String>,
+}
+
+impl Default for ExportDialog {
+    fn default() -> Self {
+        let format = ExportFormat::default();
+        Self {
+            path: format!("export.{}", format.extension()),
+            format,
+            options: ExportOptions::default(),
+            error: None,
+        }
+    }
+}
+
+/// Extensions File > Open lists
+fn open_extensions() -> Vec<&'static str> {
+    ["jc", "ora", "psd", "psb"]
+        .into_iter()
+        .chain(IMPORT_EXTENSIONS)
+        .collect()
+}
+
+/// Replaces or closes the document, which asks first when there are unsaved changes
+#[derive(Clone, Debug, PartialEq)]
+enum DocumentAction {
+    New {
+        size: (u32, u32),
+        background: [u8; 4],
+        bit_depth: BitDepth,
+    },
+    Open(PathBuf),
+    Close,
+}
+
+/// What the open file browser is picking a file for
+enum BrowsePurpose {
+    Open,
+    /// Runs the action once the document has been saved
+    SaveAs(Option<DocumentAction>),
+    Export,
+    PlaceImage,
+}
+
+/// An image waiting to be placed as a layer, and where its top left corner goes
+struct PlaceImageDialog {
+    path: PathBuf,
+    image: ImageBuffer<Rgba<u8>, Vec<u8>>,
+    offset: (i32, i32),
+}
+
+struct NewDocumentDialog {
+    width: u32,
+    height: u32,
+    transparent: bool,
+    bit_depth: BitDepth,
+}
+
+impl Default for NewDocumentDialog {
+    fn default() -> Self {
+        Self {
+            width: 1024,
+            height: 768,
+            transparent: false,
+            bit_depth: BitDepth::Eight,
+        }
+    }
+}
+
 pub struct App {
     gpu: GpuDevice,
+    /// What egui draws with, the output texture is registered with its renderer
+    render_state: RenderState,
     runtime: Arc<Runtime>,
     output_tex: TextureId,
     workspace: Workspace,
+    /// Every tool in the toolbar. The selected tool is lent to the workspace, leaving `None`
+    /// in its slot.
+    tools: Vec<Option<Box<dyn Tool>>>,
+    selected_tool: usize,
+    /// Selection edges in workspace pixels, along with the selection generation they were
+    /// read back from
+    selection_outline: Option<(u64, Vec<Segment>)>,
+    /// Settings of the open "Select Color Range" window
+    color_range: Option<(ColorRange, SelectionOp)>,
+    /// The selected adjustment layer and its parameters from before the current edit
+    adjustment_edit: Option<(usize, Adjustment)>,
+    /// Settings of the open "Export" window
+    export: Option<ExportDialog>,
+    /// Settings of the open "New" window
+    new_document: Option<NewDocumentDialog>,
+    place_image: Option<PlaceImageDialog>,
+    file_browser: Option<(FileBrowser, BrowsePurpose)>,
+    /// Waiting for the user to decide what happens to unsaved changes
+    pending_action: Option<DocumentAction>,
+    /// The `.jc` or `.ora` file the document was loaded from or last saved to
+    path: Option<PathBuf>,
+    recent_files: RecentFiles,
+    /// Shown in a window until dismissed
+    error: Option<String>,
+    /// What could not be read from the last opened file, shown in a window until dismissed
+    warnings: Vec<String>,
+    /// Set once closing has been confirmed, so the close request is let through
+    closing: bool,
+    title: String,
     prev_mouse_pos: Pos2,
     sec_mouse_down: bool,
     prim_mouse_down: bool,
+    /// Pressure of the pen that is touching, `None` for the mouse and when nothing touches
+    pen_pressure: Option<f32>,
+    /// The touch ended this frame. Its pressure is kept until the frame's events are handled,
+    /// so the button release that ends the stroke still has it.
+    pen_lifted: bool,
     central_panel_center: Pos2,
 }
 
 impl App {
     pub fn new(
         gpu: GpuDevice,
+        render_state: RenderState,
         runtime: Arc<Runtime>,
         output_tex: TextureId,
-        workspace: Workspace,
+        mut workspace: Workspace,
+        opened_file: Option<PathBuf>,
     ) -> App {
+        let mut recent_files = RecentFiles::load();
+        if let Some(path) = &opened_file {
+            recent_files.add(path);
+        }
+
+        if workspace.selected_tool.is_none() {
+            let brush = BrushToolNew::new(
+                BrushToolSettings {
+                    color: Some([0, 0, 0, 255]),
+                    ..Default::default()
+                },
+                &gpu,
+            );
+            workspace.set_tool(Box::new(brush));
+        }
+        let eraser = EraserTool::new(
+            BrushToolSettings {
+                size: 50.0,
+                ..Default::default()
+            },
+            EraserMode::Alpha,
+            &gpu,
+        );
+
         Self {
             gpu,
+            render_state,
             runtime,
             output_tex,
             workspace,
+            tools: vec![
+                None,
+                Some(Box::new(eraser)),
+                Some(Box::new(SelectTool::new())),
+                Some(Box::new(MagicWandTool::default())),
+            ],
+            selected_tool: 0,
+            selection_outline: None,
+            color_range: None,
+            adjustment_edit: None,
+            export: None,
+            new_document: None,
+            place_image: None,
+            file_browser: None,
+            pending_action: None,
+            path: opened_file.filter(|path| is_workspace_file(path)),
+            recent_files,
+            error: None,
+            warnings: Vec::new(),
+            closing: false,
+            title: String::new(),
             prev_mouse_pos: Pos2::new(0.0, 0.0),
             sec_mouse_down: false,
             prim_mouse_down: false,
+            pen_pressure: None,
+            pen_lifted: false,
             central_panel_center: Pos2::new(0.0, 0.0),
         }
     }
+
+    fn select_tool(&mut self, index: usize) {
+        if index == self.selected_tool || self.prim_mouse_down {
+            return;
+        }
+
+        self.tools[self.selected_tool] = self.workspace.selected_tool.take();
+        self.workspace.selected_tool = self.tools[index].take();
+        self.selected_tool = index;
+    }
+
+    /// The selected layer, or the top layer when none is selected
+    fn target_layer(&self) -> Option<usize> {
+        let index = self
+            .workspace
+            .selected_layer
+            .or(self.workspace.layers.len().checked_sub(1))?;
+        let info = &self.workspace.layers[index];
+        (!info.is_tool_layer && info.kind.is_pixel()).then_some(index)
+    }
+
+    fn gaussian_blur(&mut self) {
+        if let Some(index) = self.target_layer() {
+            let kernel = Kernel::gaussian_kernel::<5, 5>(&self.gpu);
+            self.runtime
+                .block_on(kernel.apply_to_layer(&mut self.workspace, index, &self.gpu));
+        }
+    }
+
+    /// Parameters of the selected adjustment layer. Edits preview live and are recorded as a
+    /// single change once the pointer is released.
+    fn adjustment_ui(&mut self, ui: &mut egui::Ui) {
+        if !ui.input(|input| input.pointer.any_down()) {
+            if let Some((index, original)) = self.adjustment_edit.take() {
+                if let LayerKind::Adjustment(edited) = self.workspace.layers[index].kind.clone() {
+                    self.workspace
+                        .preview_adjustment(index, original, &self.gpu);
+                    self.workspace.set_adjustment(index, edited, &self.gpu);
+                }
+            }
+        }
+
+        let Some(index) = self.workspace.selected_layer else {
+            return;
+        };
+        let LayerKind::Adjustment(adjustment) = &self.workspace.layers[index].kind else {
+            return;
+        };
+
+        let mut edited = adjustment.clone();
+        ui.separator();
+        ui.heading(edited.name());
+        edited.ui(ui);
+
+        if &edited != adjustment {
+            if self.adjustment_edit.is_none() {
+                self.adjustment_edit = Some((index, adjustment.clone()));
+            }
+            self.workspace.preview_adjustment(index, edited, &self.gpu);
+        }
+    }
+
+    /// Runs `action` right away, or asks what to do with unsaved changes first
+    fn request(&mut self, action: DocumentAction) {
+        if self.workspace.history.is_modified() {
+            self.pending_action = Some(action);
+        } else {
+            self.perform(action);
+        }
+    }
+
+    fn perform(&mut self, action: DocumentAction) {
+        match action {
+            DocumentAction::New {
+                size,
+                background,
+                bit_depth,
+            } => match Workspace::new_document(size, background, bit_depth, &self.gpu) {
+                Ok(workspace) => self.replace_workspace(workspace, None),
+                Err(e) => self.error = Some(format!("Failed to create the document: {}", e)),
+            },
+            DocumentAction::Open(path) => {
+                let opened =
+                    batch::unicode(&path).and_then(|name| Ok(Workspace::open(name, &self.gpu)?));
+                match opened {
+                    Ok((workspace, warnings)) => {
+                        self.warnings = warnings;
+                        self.recent_files.add(&path);
+                        let path = Some(path).filter(|path| is_workspace_file(path));
+                        self.replace_workspace(workspace, path);
+                    }
+                    Err(e) => {
+                        if !path.exists() {
+                            self.recent_files.remove(&path);
+                        }
+                        self.error = Some(format!("Failed to open {}: {}", path.display(), e));
+                    }
+                }
+            }
+            DocumentAction::Close => self.closing = true,
+        }
+    }
+
+    /// Shows `workspace` instead of the current one, keeping the selected tool
+    fn replace_workspace(&mut self, mut workspace: Workspace, path: Option<PathBuf>) {
+        workspace.selected_tool = self.workspace.selected_tool.take();
+        self.workspace = workspace;
+        self.register_output_texture();
+        self.path = path;
+        self.selection_outline = None;
+        self.adjustment_edit = None;
+    }
+
+    /// Shows the workspace's output texture after it was replaced
+    fn register_output_texture(&mut self) {
+        self.render_state
+            .renderer
+            .write()
+            .free_texture(&self.output_tex);
+        self.output_tex = self.workspace.register_output_texture(&self.render_state);
+    }
+
+    /// Converts the document, which clears its history
+    fn set_bit_depth(&mut self, depth: BitDepth) {
+        let converted = self
+            .runtime
+            .block_on(self.workspace.set_bit_depth(depth, &self.gpu));
+        match converted {
+            Ok(()) => self.register_output_texture(),
+            Err(e) => self.error = Some(format!("Failed to convert the document: {}", e)),
+        }
+    }
+
+    /// Saves to the file the document came from, or asks for one. Returns whether the
+    /// document was saved right away.
+    fn save(&mut self, then: Option<DocumentAction>) -> bool {
+        match self.path.clone() {
+            Some(path) => {
+                let saved = self.save_to(path);
+                if saved {
+                    if let Some(action) = then {
+                        self.perform(action);
+                    }
+                }
+                saved
+            }
+            None => {
+                self.save_as(then);
+                false
+            }
+        }
+    }
+
+    fn save_as(&mut self, then: Option<DocumentAction>) {
+        let path = self
+            .path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("untitled.jc"));
+        let browser = FileBrowser::new("Save As", FileBrowserMode::Save, &["jc", "ora", "psd"])
+            .with_path(&path);
+        self.file_browser = Some((browser, BrowsePurpose::SaveAs(then)));
+    }
+
+    /// Writes the document to `path`. Only a .jc file keeps everything, so saving as .ora or
+    /// .psd is an export that leaves the document modified and Save still asking for a .jc.
+    fn save_to(&mut self, path: PathBuf) -> bool {
+        let result = batch::unicode(&path).and_then(|name| {
+            let saved = if is_openraster(&path) {
+                self.runtime
+                    .block_on(self.workspace.save_ora(name, &self.gpu))
+            } else if is_psd(&path) {
+                self.runtime
+                    .block_on(self.workspace.save_psd(name, &self.gpu))
+            } else {
+                self.runtime.block_on(self.workspace.save(name, &self.gpu))
+            };
+            Ok(saved?)
+        });
+        match result {
+            Ok(()) => {
+                self.recent_files.add(&path);
+                if is_workspace_file(&path) {
+                    self.workspace.history.mark_saved();
+                    self.path = Some(path);
+                }
+                true
+            }
+            Err(e) => {
+                self.error = Some(format!("Failed to save {}: {}", path.display(), e));
+                false
+            }
+        }
+    }
+
+    fn browse_open(&mut self) {
+        let browser = FileBrowser::new("Open", FileBrowserMode::Open, &open_extensions());
+        self.file_browser = Some((browser, BrowsePurpose::Open));
+    }
+
+    fn document_name(&self) -> String {
+        self.path
+            .as_ref()
+            .and_then(|path| path.file_name())
+            .map_or("Untitled".to_string(), |name| {
+                name.to_string_lossy().into_owned()
+            })
+    }
+
+    fn file_menu(&mut self, ui: &mut egui::Ui) {
+        if ui.button("New...").clicked() {
+            self.new_document = Some(Default::default());
+            ui.close_menu();
+        }
+        if ui.button("Open...").clicked() {
+            self.browse_open();
+            ui.close_menu();
+        }
+        ui.add_enabled_ui(!self.recent_files.paths().is_empty(), |ui| {
+            ui.menu_button("Recent Files", |ui| {
+                let mut open = None;
+                for path in self.recent_files.paths() {
+                    if ui.button(path.display().to_string()).clicked() {
+                        open = Some(path.clone());
+                    }
+                }
+                ui.separator();
+                if ui.button("Clear").clicked() {
+                    self.recent_files.clear();
+                    ui.close_menu();
+                }
+                if let Some(path) = open {
+                    self.request(DocumentAction::Open(path));
+                    ui.close_menu();
+                }
+            });
+        });
+        if ui.button("Place Image as Layer...").clicked() {
+            let browser = FileBrowser::new(
+                "Place Image as Layer",
+                FileBrowserMode::Open,
+                &IMPORT_EXTENSIONS,
+            );
+            self.file_browser = Some((browser, BrowsePurpose::PlaceImage));
+            ui.close_menu();
+        }
+        ui.separator();
+        if ui.button("Save").clicked() {
+            self.save(None);
+            ui.close_menu();
+        }
+        if ui.button("Save As...").clicked() {
+            self.save_as(None);
+            ui.close_menu();
+        }
+        if ui.button("Export...").clicked() {
+            self.export = Some(Default::default());
+            ui.close_menu();
+        }
+    }
+
+    fn new_document_window(&mut self, ctx: &egui::Context) {
+        let Some(dialog) = &mut self.new_document else {
+            return;
+        };
+
+        let max_size = self.gpu.device.limits().max_texture_dimension_2d;
+        let supported = supported_bit_depths(&self.gpu);
+        let mut open = true;
+        let mut create = false;
+        egui::Window::new("New")
+            .open(&mut open)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                ui.add(
+                    egui::DragValue::new(&mut dialog.width)
+                        .range(1..=max_size)
+                        .prefix("Width: "),
+                );
+                ui.add(
+                    egui::DragValue::new(&mut dialog.height)
+                        .range(1..=max_size)
+                        .prefix("Height: "),
+                );
+                ui.checkbox(&mut dialog.transparent, "Transparent background");
+                egui::ComboBox::from_label("Bit depth")
+                    .selected_text(dialog.bit_depth.name())
+                    .show_ui(ui, |ui| {
+                        for depth in BitDepth::ALL {
+                            ui.add_enabled_ui(supported.contains(&depth), |ui| {
+                                ui.selectable_value(&mut dialog.bit_depth, depth, depth.name());
+                            });
+                        }
+                    });
+                create = ui.button("Create").clicked();
+            });
+
+        if create {
+            let action = DocumentAction::New {
+                size: (dialog.width, dialog.height),
+                background: if dialog.transparent {
+                    [255, 255, 255, 0]
+                } else {
+                    [255, 255, 255, 255]
+                },
+                bit_depth: dialog.bit_depth,
+            };
+            self.new_document = None;
+            self.request(action);
+        } else if !open {
+            self.new_document = None;
+        }
+    }
+
+    fn file_browser_window(&mut self, ctx: &egui::Context) {
+        let Some((browser, _)) = &mut self.file_browser else {
+            return;
+        };
+
+        let path = match browser.show(ctx) {
+            FileBrowserResult::Pending => return,
+            FileBrowserResult::Cancelled => {
+                self.file_browser = None;
+                return;
+            }
+            FileBrowserResult::Picked(path) => path,
+        };
+
+        let (_, purpose) = self.file_browser.take().unwrap();
+        match purpose {
+            BrowsePurpose::Open => self.request(DocumentAction::Open(path)),
+            BrowsePurpose::SaveAs(then) => {
+                if self.save_to(path) {
+                    if let Some(action) = then {
+                        self.perform(action);
+                    }
+                }
+            }
+            BrowsePurpose::PlaceImage => {
+                match read_image(&path, self.gpu.device.limits().max_texture_dimension_2d) {
+                    Ok(image) => {
+                        // centered to start with
+                        let offset = (
+                            (self.workspace.size.0 as i32 - image.width() as i32) / 2,
+                            (self.workspace.size.1 as i32 - image.height() as i32) / 2,
+                        );
+                        self.place_image = Some(PlaceImageDialog {
+                            path,
+                            image,
+                            offset,
+                        });
+                    }
+                    Err(e) => {
+                        self.error = Some(format!("Failed to read {}: {}", path.display(), e))
+                    }
+                }
+            }
+            BrowsePurpose::Export => {
+                if let Some(dialog) = &mut self.export {
+                    if let Some(format) = ExportFormat::from_path(&path) {
+                        dialog.format = format;
+                    }
+                    dialog.path = path.display().to_string();
+                }
+            }
+        }
+    }
+
+    fn place_image_window(&mut self, ctx: &egui::Context) {
+        let Some(dialog) = &mut self.place_image else {
+            return;
+        };
+
+        let size = self.workspace.size;
+        let mut open = true;
+        let mut place = false;
+        egui::Window::new("Place Image as Layer")
+            .open(&mut open)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "{} ({}x{})",
+                    dialog.path.display(),
+                    dialog.image.width(),
+                    dialog.image.height()
+                ));
+                ui.horizontal(|ui| {
+                    ui.add(egui::DragValue::new(&mut dialog.offset.0).prefix("X: "));
+                    ui.add(egui::DragValue::new(&mut dialog.offset.1).prefix("Y: "));
+                });
+                ui.horizontal(|ui| {
+                    if ui.button("Top Left").clicked() {
+                        dialog.offset = (0, 0);
+                    }
+                    if ui.button("Center").clicked() {
+                        dialog.offset = (
+                            (size.0 as i32 - dialog.image.width() as i32) / 2,
+                            (size.1 as i32 - dialog.image.height() as i32) / 2,
+                        );
+                    }
+                });
+                place = ui.button("Place").clicked();
+            });
+
+        if place {
+            let dialog = self.place_image.take().unwrap();
+            let name = dialog.path.file_stem().map_or("Image".to_string(), |stem| {
+                stem.to_string_lossy().into_owned()
+            });
+            let index = self.workspace.selected_layer.map(|i| i + 1);
+            let placed =
+                self.workspace
+                    .place_image(&dialog.image, name, dialog.offset, index, &self.gpu);
+            if let Err(e) = placed {
+                self.error = Some(format!("Failed to place {}: {}", dialog.path.display(), e));
+            }
+        } else if !open {
+            self.place_image = None;
+        }
+    }
+
+    /// Asks whether to save before a pending action throws away unsaved changes
+    fn unsaved_changes_window(&mut self, ctx: &egui::Context) {
+        let Some(action) = &self.pending_action else {
+            return;
+        };
+
+        let verb = match action {
+            DocumentAction::New { .. } | DocumentAction::Open(_) => "closing it",
+            DocumentAction::Close => "quitting",
+        };
+        let mut choice = None;
+        egui::Window::new("Unsaved Changes")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "Save the changes to {} before {}?",
+                    self.document_name(),
+                    verb
+                ));
+                ui.horizontal(|ui| {
+                    if ui.button("Save").clicked() {
+                        choice = Some(Some(true));
+                    }
+                    if ui.button("Don't Save").clicked() {
+                        choice = Some(Some(false));
+                    }
+                    if ui.button("Cancel").clicked() {
+                        choice = Some(None);
+                    }
+                });
+            });
+
+        let Some(choice) = choice else {
+            return;
+        };
+        let action = self.pending_action.take().unwrap();
+        match choice {
+            Some(true) => {
+                self.save(Some(action));
+            }
+            Some(false) => self.perform(action),
+            None => (),
+        }
+    }
+
+    fn error_window(&mut self, ctx: &egui::Context) {
+        let Some(error) = &self.error else {
+            return;
+        };
+
+        let mut dismissed = false;
+        egui::Window::new("Error")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label(error);
+                dismissed = ui.button("OK").clicked();
+            });
+        if dismissed {
+            self.error = None;
+        }
+    }
+
+    fn warnings_window(&mut self, ctx: &egui::Context) {
+        if self.warnings.is_empty() {
+            return;
+        }
+
+        let mut dismissed = false;
+        egui::Window::new("Opened with Warnings")
+            .collapsible(false)
+            .show(ctx, |ui| {
+                egui::ScrollArea::vertical()
+                    .max_height(240.0)
+                    .show(ui, |ui| {
+                        for warning in &self.warnings {
+                            ui.label(warning);
+                        }
+                    });
+                dismissed = ui.button("OK").clicked();
+            });
+        if dismissed {
+            self.warnings.clear();
+        }
+    }
+
+    fn color_range_window(&mut self, ctx: &egui::Context) {
+        let Some((range, op)) = &mut self.color_range else {
+            return;
+        };
+
+        let mut open = true;
+        let mut apply = false;
+        egui::Window::new("Select Color Range")
+            .open(&mut open)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Color");
+                    ui.color_edit_button_srgba_unmultiplied(&mut range.color);
+                });
+                ui.add(egui::Slider::new(&mut range.fuzziness, 0.0..=255.0).text("Fuzziness"));
+                ui.checkbox(&mut range.antialias, "Anti-alias");
+                ui.horizontal(|ui| {
+                    ui.selectable_value(&mut range.source, SampleSource::Layer, "Layer");
+                    ui.selectable_value(&mut range.source, SampleSource::Composite, "Composite");
+                });
+                ui.horizontal(|ui| {
+                    for candidate in SelectionOp::ALL {
+                        ui.selectable_value(op, candidate, candidate.name());
+                    }
+                });
+                apply = ui.button("Select").clicked();
+            });
+
+        if apply {
+            let (range, op) = (*range, *op);
+            self.runtime
+                .block_on(self.workspace.select_color_range(&range, op, &self.gpu));
+            self.color_range = None;
+        } else if !open {
+            self.color_range = None;
+        }
+    }
+
+    fn export_window(&mut self, ctx: &egui::Context) {
+        let Some(dialog) = &mut self.export else {
+            return;
+        };
+
+        let mut open = true;
+        let mut export = false;
+        let mut browse = false;
+        egui::Window::new("Export")
+            .open(&mut open)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("File");
+                    if ui.text_edit_singleline(&mut dialog.path).changed() {
+                        if let Some(format) = ExportFormat::from_path(&dialog.path) {
+                            dialog.format = format;
+                        }
+                    }
+                    browse = ui.button("Browse...").clicked();
+                });
+                ui.horizontal(|ui| {
+                    for format in ExportFormat::ALL {
+                        if ui
+                            .selectable_value(&mut dialog.format, format, format.name())
+                            .clicked()
+                        {
+                            dialog.path = Path::new(&dialog.path)
+                                .with_extension(format.extension())
+                                .to_string_lossy()
+                                .into_owned();
+                        }
+                    }
+                });
+
+                match dialog.format {
+                    ExportFormat::Png => {
+                        ui.horizontal(|ui| {
+                            ui.label("Compression");
+                            for compression in PngCompression::ALL {
+                                ui.selectable_value(
+                                    &mut dialog.options.png_compression,
+                                    compression,
+                                    compression.name(),
+                                );
+                            }
+                        });
+                        ui.checkbox(&mut dialog.options.keep_alpha, "Keep transparency");
+                    }
+                    ExportFormat::Jpeg => {
+                        ui.add(
+                            egui::Slider::new(&mut dialog.options.jpeg_quality, 1..=100)
+                                .text("Quality"),
+                        );
+                        ui.label("Transparent areas become white");
+                    }
+                }
+
+                if let Some(error) = &dialog.error {
+                    ui.colored_label(Color32::RED, error);
+                }
+                export = ui.button("Export").clicked();
+            });
+
+        if browse {
+            let extensions: Vec<&str> = ExportFormat::ALL
+                .iter()
+                .map(|format| format.extension())
+                .collect();
+            let browser = FileBrowser::new("Export", FileBrowserMode::Save, &extensions)
+                .with_path(Path::new(&dialog.path));
+            self.file_browser = Some((browser, BrowsePurpose::Export));
+        }
+
+        if export {
+            let result = self.runtime.block_on(self.workspace.export(
+                &dialog.path,
+                dialog.format,
+                &dialog.options,
+                &self.gpu,
+            ));
+            match result {
+                Ok(()) => self.export = None,
+                Err(e) => dialog.error = Some(e.to_string()),
+            }
+        } else if !open {
+            self.export = None;
+        }
+    }
+
+    /// Draws marching ants along the edges of the selection
+    fn paint_selection(&mut self, painter: &egui::Painter, to_screen: &dyn Fn((f32, f32)) -> Pos2) {
+        let selection = self.workspace.selection();
+        if !selection.is_active() {
+            return;
+        }
+
+        let generation = selection.generation();
+        if self.selection_outline.as_ref().map(|(g, _)| *g) != Some(generation) {
+            let coverage = self.runtime.block_on(
+                self.gpu
+                    .texture_to_luma_image(selection.texture(), self.workspace.size.0),
+            );
+            self.selection_outline = Some((generation, selection_outline(&coverage)));
+        }
+        let Some((_, outline)) = &self.selection_outline else {
+            return;
+        };
+
+        let time = painter.ctx().input(|i| i.time) as f32;
+        let clip = painter.clip_rect();
+        let mut shapes = Vec::new();
+        for &(start, end) in outline {
+            let segment = [to_screen(start), to_screen(end)];
+            if !clip.intersects(Rect::from_two_pos(segment[0], segment[1])) {
+                continue;
+            }
+
+            shapes.push(Shape::line_segment(
+                segment,
+                Stroke {
+                    width: 1.0,
+                    color: Color32::WHITE,
+                },
+            ));
+            // offset by position so that the dashes line up across segments
+            let offset = (time * 8.0 + segment[0].x + segment[0].y).rem_euclid(8.0);
+            Shape::dashed_line_many_with_offset(
+                &segment,
+                Stroke {
+                    width: 1.0,
+                    color: Color32::BLACK,
+                },
+                &[4.0],
+                &[4.0],
+                offset,
+                &mut shapes,
+            );
+        }
+        painter.extend(shapes);
+        painter
+            .ctx()
+            .request_repaint_after(Duration::from_millis(50));
+    }
+
+    /// Converts a screen position into workspace pixels along with the rest of the pen state
+    fn pointer_input(&self, pos: Pos2, modifiers: egui::Modifiers, timestamp: f64) -> PointerInput {
+        let mouse_loc = (
+            (pos.x - self.central_panel_center.x) / self.workspace.zoom,
+            (pos.y - self.central_panel_center.y) / self.workspace.zoom,
+        );
+
+        PointerInput {
+            pos: (
+                mouse_loc.0 + self.workspace.pixel_at_center.0,
+                mouse_loc.1 + self.workspace.pixel_at_center.1,
+            ),
+            // devices that don't report pressure press fully
+            pressure: self.pen_pressure.unwrap_or(1.0),
+            // egui does not report tilt yet
+            tilt: (0.0, 0.0),
+            modifiers,
+            timestamp,
+        }
+    }
 }
 
 impl eframe::App for App {
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        if ctx.input(|i| i.viewport().close_requested())
+            && !self.closing
+            && self.workspace.history.is_modified()
+        {
+            ctx.send_viewport_cmd(egui::ViewportCommand::CancelClose);
+            self.pending_action = Some(DocumentAction::Close);
+        }
+
+        let title = format!(
+            "{}{} - Joyful Create",
+            self.document_name(),
+            if self.workspace.history.is_modified() {
+                "*"
+            } else {
+                ""
+            }
+        );
+        if title != self.title {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Title(title.clone()));
+            self.title = title;
+        }
+
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             ui.horizontal(|ui| {
                 ui.label("Joyful Create v0.0.5");
+                ui.separator();
+
+                let mut clicked = None;
+                for (i, tool) in self.tools.iter().enumerate() {
+                    let name = match tool {
+                        Some(tool) => tool.name(),
+                        None => self
+                            .workspace
+                            .selected_tool
+                            .as_ref()
+                            .map_or("", |tool| tool.name()),
+                    };
+                    if ui.selectable_label(i == self.selected_tool, name).clicked() {
+                        clicked = Some(i);
+                    }
+                }
+                if let Some(i) = clicked {
+                    self.select_tool(i);
+                }
+                ui.separator();
+
+                ui.menu_button("File", |ui| self.file_menu(ui));
+                ui.menu_button("Image", |ui| {
+                    ui.menu_button("Bit Depth", |ui| {
+                        let supported = supported_bit_depths(&self.gpu);
+                        for depth in BitDepth::ALL {
+                            let selected = self.workspace.bit_depth == depth;
+                            let label = egui::SelectableLabel::new(selected, depth.name());
+                            if ui.add_enabled(supported.contains(&depth), label).clicked() {
+                                if !selected {
+                                    self.set_bit_depth(depth);
+                                }
+                                ui.close_menu();
+                            }
+                        }
+                    });
+                });
+                ui.menu_button("Layer", |ui| {
+                    ui.menu_button("New Adjustment Layer", |ui| {
+                        for adjustment in Adjustment::presets() {
+                            if ui.button(adjustment.name()).clicked() {
+                                let index = self.workspace.selected_layer.map(|i| i + 1);
+                                self.workspace
+                                    .create_adjustment_layer(adjustment, index, &self.gpu);
+                                ui.close_menu();
+                            }
+                        }
+                    });
+                });
+                ui.menu_button("Select", |ui| {
+                    if ui.button("All").clicked() {
+                        self.workspace.select_all(&self.gpu);
+                        ui.close_menu();
+                    }
+                    if ui.button("Deselect").clicked() {
+                        self.workspace.select_none(&self.gpu);
+                        ui.close_menu();
+                    }
+                    if ui.button("Inverse").clicked() {
+                        self.workspace.invert_selection(&self.gpu);
+                        ui.close_menu();
+                    }
+                    if ui.button("Color Range...").clicked() {
+                        self.color_range = Some(Default::default());
+                        ui.close_menu();
+                    }
+                });
+                ui.menu_button("Filter", |ui| {
+                    if ui.button("Gaussian Blur").clicked() {
+                        self.gaussian_blur();
+                        ui.close_menu();
+                    }
+                });
             });
         });
 
+        self.color_range_window(ctx);
+        self.export_window(ctx);
+        self.new_document_window(ctx);
+        self.place_image_window(ctx);
+        self.file_browser_window(ctx);
+        self.unsaved_changes_window(ctx);
+        self.error_window(ctx);
+        self.warnings_window(ctx);
+        if self.closing {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+        }
+
         egui::SidePanel::left("left_panel").show(ctx, |ui| {
-            ui.heading("Left Panel");
-            ui.label("This is a simple egui app.");
+            if let Some(tool) = self.workspace.selected_tool.as_mut() {
+                ui.heading(tool.name().to_string());
+                tool.ui(ui);
+            }
+            self.adjustment_ui(ui);
 
             let max_rect = ui.max_rect();
 
             egui::TopBottomPanel::bottom("bottom_panel")
                 .min_height(max_rect.height() / 2.)
                 .show_inside(ui, |ui| {
-                    ui.heading("Layers");
+                    let mut new_group = false;
+                    ui.horizontal(|ui| {
+                        ui.heading("Layers");
+                        new_group = ui.button("New Group").clicked();
+                    });
+                    if new_group {
+                        let around = self.target_layer().or(self.workspace.selected_layer);
+                        self.workspace.create_group(
+                            "Group".to_string(),
+                            GroupMode::default(),
+                            around,
+                            &self.gpu,
+                        );
+                    }
 
-                    let len = self.workspace.layers.len();
-                    for i in 0..len {
-                        let layer_info = &mut self.workspace.layers[len - i - 1];
+                    // rows are listed top down, so group contents follow their header
+                    let mut depth = 0;
+                    let mut i = self.workspace.layers.len();
+                    while i > 0 {
+                        i -= 1;
+                        let layer_info = &self.workspace.layers[i];
                         if layer_info.is_tool_layer {
                             continue;
                         }
+                        if layer_info.kind == LayerKind::GroupEnd {
+                            depth -= 1;
+                            continue;
+                        }
 
+                        let mut visible = layer_info.visible;
                         let mut click_flag = false;
+                        let mut selected = false;
+                        let mut collapse = None;
+                        let mut new_mode = None;
+                        let mut toggle_clip = false;
+                        let mut new_blend_mode = None;
+                        // clipped layers are indented one step further than their base
+                        let clipped = self.workspace.clip_base(i).is_some();
                         ui.horizontal(|ui| {
-                            ui.label(&layer_info.name);
+                            ui.add_space((depth + clipped as usize) as f32 * 16.0);
+                            if let LayerKind::Group { mode, collapsed } = layer_info.kind {
+                                if ui.small_button(if collapsed { "▶" } else { "▼" }).clicked()
+                                {
+                                    collapse = Some(!collapsed);
+                                }
+
+                                let mut selected_mode = mode;
+                                egui::ComboBox::from_id_salt(("group_mode", i))
+                                    .selected_text(group_mode_name(mode))
+                                    .show_ui(ui, |ui| {
+                                        for option in [GroupMode::PassThrough, GroupMode::Isolated]
+                                        {
+                                            ui.selectable_value(
+                                                &mut selected_mode,
+                                                option,
+                                                group_mode_name(option),
+                                            );
+                                        }
+                                    });
+                                if selected_mode != mode {
+                                    new_mode = Some(selected_mode);
+                                }
+                            }
+
+                            selected = ui
+                                .selectable_label(
+                                    self.workspace.selected_layer == Some(i),
+                                    &layer_info.name,
+                                )
+                                .clicked();
                             click_flag = ui
-                                .add(egui::Checkbox::new(&mut layer_info.visible, ""))
+                                .add(egui::Checkbox::new(&mut visible, ""))
                                 .interact(Sense::click())
                                 .clicked();
+                            // pass-through groups don't blend their contents as a whole
+                            let blends = !matches!(
+                                layer_info.kind,
+                                LayerKind::Group {
+                                    mode: GroupMode::PassThrough,
+                                    ..
+                                }
+                            );
+                            if blends {
+                                let mut blend_mode = layer_info.blend_mode;
+                                egui::ComboBox::from_id_salt(("blend_mode", i))
+                                    .selected_text(blend_mode.label())
+                                    .show_ui(ui, |ui| {
+                                        for option in BlendMode::ALL {
+                                            ui.selectable_value(
+                                                &mut blend_mode,
+                                                option,
+                                                option.label(),
+                                            );
+                                        }
+                                    });
+                                if blend_mode != layer_info.blend_mode {
+                                    new_blend_mode = Some(blend_mode);
+                                }
+                            }
+                            if layer_info.kind.is_pixel() || layer_info.kind.is_adjustment() {
+                                toggle_clip = ui
+                                    .selectable_label(layer_info.clipped, "Clip")
+                                    .on_hover_text("Clip to the layer below")
+                                    .clicked();
+                            }
                         });
+
+                        if selected {
+                            self.workspace.selected_layer = Some(i);
+                        }
                         if click_flag {
-                            self.workspace
-                                .recalculate_output_texture(&self.gpu, len - i - 1);
+                            self.workspace.set_layer_visibility(i, visible, &self.gpu);
+                        }
+                        if let Some(blend_mode) = new_blend_mode {
+                            let info = LayerInfo {
+                                blend_mode,
+                                ..self.workspace.layers[i].clone()
+                            };
+                            self.workspace.set_layer_info(i, info, &self.gpu);
+                        }
+                        if toggle_clip {
+                            let mut info = self.workspace.layers[i].clone();
+                            info.clipped = !info.clipped;
+                            self.workspace.set_layer_info(i, info, &self.gpu);
+                        }
+                        if let Some(collapsed) = collapse {
+                            self.workspace.set_group_collapsed(i, collapsed);
+                        }
+                        if let Some(mode) = new_mode {
+                            let mut info = self.workspace.layers[i].clone();
+                            if let LayerKind::Group { collapsed, .. } = info.kind {
+                                info.kind = LayerKind::Group { mode, collapsed };
+                            }
+                            self.workspace.set_layer_info(i, info, &self.gpu);
+                        }
+
+                        // the group end closes the depth again unless it's skipped over
+                        if let LayerKind::Group { collapsed, .. } = self.workspace.layers[i].kind {
+                            if collapsed {
+                                i = self.workspace.group_end(i);
+                            } else {
+                                depth += 1;
+                            }
                         }
                     }
                 });
@@ -94,21 +1225,20 @@ impl eframe::App for App {
                             }
                             self.prev_mouse_pos = *pos;
 
-                            let mouse_loc = (
-                                (pos.x - self.central_panel_center.x) / self.workspace.zoom,
-                                (pos.y - self.central_panel_center.y) / self.workspace.zoom,
-                            );
-
-                            let mouse_loc = (
-                                mouse_loc.0 + self.workspace.pixel_at_center.0,
-                                mouse_loc.1 + self.workspace.pixel_at_center.1,
-                            );
-
                             if self.prim_mouse_down {
+                                let input = self.pointer_input(*pos, reader.modifiers, reader.time);
                                 self.workspace
-                                    .perform_action(&self.gpu, ActionOrigin::MouseMove(mouse_loc));
+                                    .perform_action(&self.gpu, ActionOrigin::MouseMove(input));
                             }
                         }
+                        egui::Event::Touch { phase, force, .. } => match phase {
+                            egui::TouchPhase::Start | egui::TouchPhase::Move => {
+                                self.pen_pressure = Some(force.unwrap_or(1.0));
+                            }
+                            egui::TouchPhase::End | egui::TouchPhase::Cancel => {
+                                self.pen_lifted = true;
+                            }
+                        },
                         egui::Event::MouseWheel {
                             unit,
                             delta,
@@ -152,26 +1282,14 @@ impl eframe::App for App {
                             egui::PointerButton::Primary => {
                                 self.prim_mouse_down = *pressed;
 
-                                let mouse_loc = (
-                                    (pos.x - self.central_panel_center.x) / self.workspace.zoom,
-                                    (pos.y - self.central_panel_center.y) / self.workspace.zoom,
-                                );
-
-                                let mouse_loc = (
-                                    mouse_loc.0 + self.workspace.pixel_at_center.0,
-                                    mouse_loc.1 + self.workspace.pixel_at_center.1,
-                                );
+                                let input = self.pointer_input(*pos, *modifiers, reader.time);
 
                                 if *pressed {
-                                    self.workspace.perform_action(
-                                        &self.gpu,
-                                        ActionOrigin::MouseDown(mouse_loc),
-                                    );
+                                    self.workspace
+                                        .perform_action(&self.gpu, ActionOrigin::MouseDown(input));
                                 } else {
-                                    self.workspace.perform_action(
-                                        &self.gpu,
-                                        ActionOrigin::MouseUp(mouse_loc),
-                                    );
+                                    self.workspace
+                                        .perform_action(&self.gpu, ActionOrigin::MouseUp(input));
                                 }
                             }
                             egui::PointerButton::Secondary => {
@@ -186,12 +1304,36 @@ impl eframe::App for App {
                             repeat,
                             modifiers,
                         } => match key {
-                            egui::Key::F5 => {
-                                if !*pressed {
+                            egui::Key::N if modifiers.command && *pressed => {
+                                self.new_document = Some(Default::default());
+                            }
+                            egui::Key::O if modifiers.command && *pressed => {
+                                self.browse_open();
+                            }
+                            egui::Key::S if modifiers.command && modifiers.shift && *pressed => {
+                                self.save_as(None);
+                            }
+                            egui::Key::S if modifiers.command && *pressed => {
+                                self.save(None);
+                            }
+                            egui::Key::A if modifiers.command && *pressed => {
+                                self.workspace.select_all(&self.gpu);
+                            }
+                            egui::Key::D if modifiers.command && *pressed => {
+                                self.workspace.select_none(&self.gpu);
+                            }
+                            egui::Key::I if modifiers.command && modifiers.shift && *pressed => {
+                                self.workspace.invert_selection(&self.gpu);
+                            }
+                            egui::Key::Z if modifiers.command => {
+                                if !*pressed || self.prim_mouse_down {
                                     continue;
                                 }
-                                self.runtime
-                                    .block_on(self.workspace.save("saved.jc", &self.gpu));
+                                if modifiers.shift {
+                                    self.workspace.redo(&self.gpu);
+                                } else {
+                                    self.workspace.undo(&self.gpu);
+                                }
                             }
                             _ => {}
                         },
@@ -199,6 +1341,12 @@ impl eframe::App for App {
                     }
                 }
             });
+            // the mouse doesn't report pressure, so a stroke after the pen is lifted must not
+            // keep the pen's last pressure
+            if self.pen_lifted {
+                self.pen_pressure = None;
+                self.pen_lifted = false;
+            }
             let size: (u32, u32) = self.workspace.size;
             let zoom: f32 = self.workspace.zoom;
             let size: Vec2 = Vec2::new(size.0 as f32 * zoom, size.1 as f32 * zoom);
@@ -221,6 +1369,36 @@ impl eframe::App for App {
 
             let image = image.sense(Sense::click());
             ui.put(image_rect, image);
+
+            let painter = ui.painter().with_clip_rect(ui.clip_rect());
+            let to_screen = |pos: (f32, f32)| top_left + Vec2::new(pos.0, pos.1) * zoom;
+            self.paint_selection(&painter, &to_screen);
+            if let Some(tool) = &self.workspace.selected_tool {
+                tool.paint(&painter, &to_screen);
+            }
         });
     }
 }
+
+/// Whether `path` is a native document, the only format that keeps everything and so the
+/// only one Save writes back to
+fn is_workspace_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("jc"))
+}
+
+/// The bit depths offered for new documents and conversions, the others are greyed out
+fn supported_bit_depths(gpu: &GpuDevice) -> Vec<BitDepth> {
+    BitDepth::ALL
+        .into_iter()
+        .filter(|depth| depth.is_supported(gpu))
+        .collect()
+}
+
+fn group_mode_name(mode: GroupMode) -> &'static str {
+    match mode {
+        GroupMode::PassThrough => "Pass Through",
+        GroupMode::Isolated => "Isolated",
+    }
+}
@@ -0,0 +1,638 @@
+//! Headless processing of many files from the command line, for example
+//! `joyful_create convert "scans/*.jc" out/ --format png`
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use image::imageops::FilterType;
+
+use crate::device::GpuDevice;
+use crate::filters::kernel::{Kernel, KernelFile, KernelLoadError};
+use crate::workspace::{
+    export::{ExportFormat, ExportOptions, PngCompression},
+    openraster::is_openraster,
+    psd::is_psd,
+    Workspace, WorkspaceLoadError,
+};
+
+pub const USAGE: &str = "\
+Usage: joyful_create [file]
+       joyful_create <command> [options] <input>... <output>
+
+Commands:
+  convert        Saves each input in the format of the output
+  flatten        Merges all layers of each input into one
+  resize         Scales each input, keeping the aspect ratio if only one side is given
+                   --width <pixels>  --height <pixels>
+                   --filter <nearest|linear|cubic|gaussian|lanczos>  (default lanczos)
+  apply-kernel   Convolves pixel layers with a kernel read from a .json file
+                   --kernel <file>   {\"width\": 3, \"height\": 3, \"weights\": [...], \"normalize\": true}
+                   --layer <index>   only filter this layer instead of every pixel layer
+
+Options for every command:
+  --quality <1-100>                 JPEG quality (default 90)
+  --compression <fast|default|best> PNG compression (default default)
+  --alpha <keep|drop>               drop flattens PNGs onto white (default keep)
+
+Inputs can be .jc, .ora or .psd files or images, and may be glob patterns such as \"photos/*.png\".
+With more than one input the output is a directory, and --format <extension> picks the
+type of the files written into it (by default the same as each input).
+
+Exit codes: 0 on success, 1 if any input failed, 2 for bad arguments, 3 if no usable GPU
+was found";
+
+/// Every input was processed
+pub const EXIT_SUCCESS: i32 = 0;
+/// At least one input could not be processed
+pub const EXIT_FAILURE: i32 = 1;
+/// The arguments could not be understood
+pub const EXIT_USAGE: i32 = 2;
+/// No adapter can run the shaders
+pub const EXIT_NO_GPU: i32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subcommand {
+    Convert,
+    Flatten,
+    Resize,
+    ApplyKernel,
+}
+
+impl Subcommand {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "convert" => Some(Subcommand::Convert),
+            "flatten" => Some(Subcommand::Flatten),
+            "resize" => Some(Subcommand::Resize),
+            "apply-kernel" => Some(Subcommand::ApplyKernel),
+            _ => None,
+        }
+    }
+
+    /// Options the subcommand accepts besides the ones in [`COMMON_OPTIONS`], all of which
+    /// take a value
+    fn options(self) -> &'static [&'static str] {
+        match self {
+            Subcommand::Convert | Subcommand::Flatten => &[],
+            Subcommand::Resize => &["--width", "--height", "--filter"],
+            Subcommand::ApplyKernel => &["--kernel", "--layer"],
+        }
+    }
+}
+
+/// Options every subcommand accepts
+const COMMON_OPTIONS: [&str; 4] = ["--format", "--quality", "--compression", "--alpha"];
+
+enum Operation {
+    Convert,
+    Flatten,
+    Resize {
+        width: Option<u32>,
+        height: Option<u32>,
+        filter: FilterType,
+    },
+    ApplyKernel {
+        kernel: KernelFile,
+        layer: Option<usize>,
+    },
+}
+
+struct Job {
+    command: Subcommand,
+    operation: Operation,
+    /// Paths or glob patterns
+    inputs: Vec<String>,
+    output: PathBuf,
+    /// Extension of the files written when the output is a directory
+    format: Option<String>,
+    export: ExportOptions,
+}
+
+#[derive(Debug)]
+pub enum BatchError {
+    NoMatches(String),
+    Load(WorkspaceLoadError),
+    Image(image::ImageError),
+    Io(std::io::Error),
+    NotAPixelLayer(usize),
+    UnsupportedFormat(PathBuf),
+    /// The path is not valid UTF-8, which the workspace file functions need
+    NotUnicode(PathBuf),
+    /// Another input already writes the output, like `a.png` and `a.jpg` converted into the
+    /// same directory
+    SameOutput {
+        output: PathBuf,
+        first: PathBuf,
+    },
+}
+
+impl std::fmt::Display for BatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoMatches(pattern) => write!(f, "no files match {}", pattern),
+            Self::Load(e) => write!(f, "{}", e),
+            Self::Image(e) => write!(f, "{}", e),
+            Self::Io(e) => write!(f, "{}", e),
+            Self::NotAPixelLayer(index) => write!(f, "layer {} is not a pixel layer", index),
+            Self::UnsupportedFormat(path) => write!(f, "can't write {}", path.display()),
+            Self::NotUnicode(path) => write!(f, "{} is not valid Unicode", path.display()),
+            Self::SameOutput { output, first } => write!(
+                f,
+                "{} is already written from {}",
+                output.display(),
+                first.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BatchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Load(e) => Some(e),
+            Self::Image(e) => Some(e),
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<WorkspaceLoadError> for BatchError {
+    fn from(e: WorkspaceLoadError) -> Self {
+        Self::Load(e)
+    }
+}
+
+impl From<image::ImageError> for BatchError {
+    fn from(e: image::ImageError) -> Self {
+        Self::Image(e)
+    }
+}
+
+impl From<std::io::Error> for BatchError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl Job {
+    /// Whether each input gets its own file in the output directory, checking that the output
+    /// makes sense for `input_count` inputs
+    fn writes_to_directory(&self, input_count: usize) -> Result<bool, String> {
+        let output = &self.output;
+        let names_directory = output.is_dir()
+            || output
+                .to_str()
+                .is_some_and(|output| output.ends_with(std::path::is_separator));
+        if input_count == 1 && !names_directory {
+            if self.format.is_some() {
+                return Err("--format only applies when writing into a directory".to_string());
+            }
+            if output.extension().is_none() {
+                return Err(format!(
+                    "Can't tell what format to write {} in",
+                    output.display()
+                ));
+            }
+            return Ok(false);
+        }
+
+        if output.is_file() || (!output.exists() && output.extension().is_some()) {
+            return Err(format!(
+                "There are {} inputs, so the output has to be a directory and not {}",
+                input_count,
+                output.display()
+            ));
+        }
+        if self.command == Subcommand::Convert && self.format.is_none() {
+            return Err("convert needs --format when writing into a directory".to_string());
+        }
+
+        Ok(true)
+    }
+
+    /// Where each input is written. Inputs whose output an earlier input already writes fail
+    /// instead of overwriting it.
+    fn outputs<'a>(
+        &self,
+        inputs: &'a [PathBuf],
+        to_directory: bool,
+    ) -> Vec<Result<PathBuf, BatchError>> {
+        let mut written_from: HashMap<PathBuf, &'a PathBuf> = HashMap::new();
+        inputs
+            .iter()
+            .map(|input| {
+                if !to_directory {
+                    return Ok(self.output.clone());
+                }
+
+                let extension = self
+                    .format
+                    .as_deref()
+                    .or(input.extension().and_then(|extension| extension.to_str()))
+                    .unwrap_or("jc");
+                let output = self
+                    .output
+                    .join(input.file_stem().unwrap_or_default())
+                    .with_extension(extension);
+                match written_from.get(&output) {
+                    Some(first) => Err(BatchError::SameOutput {
+                        output,
+                        first: first.to_path_buf(),
+                    }),
+                    None => {
+                        written_from.insert(output.clone(), input);
+                        Ok(output)
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+/// Runs `command` with the arguments that follow it, returning the process exit code
+pub fn run(command: Subcommand, args: &[String]) -> i32 {
+    if args.iter().any(|arg| arg == "--help") {
+        println!("{}", USAGE);
+        return EXIT_SUCCESS;
+    }
+
+    let job = match parse(command, args) {
+        Ok(job) => job,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+
+    let mut unmatched = 0;
+    let mut inputs = Vec::new();
+    for pattern in &job.inputs {
+        match expand(pattern) {
+            Ok(paths) => inputs.extend(paths),
+            Err(e) => {
+                eprintln!("{}", e);
+                unmatched += 1;
+            }
+        }
+    }
+    if inputs.is_empty() {
+        return EXIT_FAILURE;
+    }
+
+    let to_directory = match job.writes_to_directory(inputs.len()) {
+        Ok(to_directory) => to_directory,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let Some(gpu) = runtime.block_on(GpuDevice::headless()) else {
+        eprintln!("No GPU adapter that supports the required features was found");
+        return EXIT_NO_GPU;
+    };
+
+    if to_directory {
+        if let Err(e) = std::fs::create_dir_all(&job.output) {
+            eprintln!("Could not create {}: {}", job.output.display(), e);
+            return EXIT_FAILURE;
+        }
+    }
+
+    let kernel = match &job.operation {
+        Operation::ApplyKernel { kernel, .. } => Some(Kernel::from_file(kernel, &gpu)),
+        _ => None,
+    };
+
+    let mut failed = 0;
+    for (input, output) in inputs.iter().zip(job.outputs(&inputs, to_directory)) {
+        let result = output.and_then(|output| {
+            runtime.block_on(process(
+                &job.operation,
+                &job.export,
+                kernel.as_ref(),
+                input,
+                &output,
+                &gpu,
+            ))?;
+            Ok(output)
+        });
+        match result {
+            Ok(output) => println!("{} -> {}", input.display(), output.display()),
+            Err(e) => {
+                eprintln!("{}: {}", input.display(), e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed + unmatched > 0 {
+        eprintln!(
+            "{} of {} inputs failed",
+            failed + unmatched,
+            inputs.len() + unmatched
+        );
+        EXIT_FAILURE
+    } else {
+        EXIT_SUCCESS
+    }
+}
+
+fn parse(command: Subcommand, args: &[String]) -> Result<Job, String> {
+    let mut options = Vec::new();
+    let mut positional = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg.starts_with("--") {
+            if !COMMON_OPTIONS.contains(&arg.as_str()) && !command.options().contains(&arg.as_str())
+            {
+                return Err(format!("Unknown option {}", arg));
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value", arg))?;
+            options.push((arg.as_str(), value.as_str()));
+        } else {
+            positional.push(arg.clone());
+        }
+    }
+
+    let option = |name: &str| {
+        options
+            .iter()
+            .rev()
+            .find(|(option, _)| *option == name)
+            .map(|(_, value)| *value)
+    };
+    let number = |name: &str| -> Result<Option<u32>, String> {
+        option(name)
+            .map(|value| match value.parse::<u32>() {
+                Ok(number) if number > 0 => Ok(number),
+                _ => Err(format!("{} must be a positive whole number", name)),
+            })
+            .transpose()
+    };
+
+    let operation = match command {
+        Subcommand::Convert => Operation::Convert,
+        Subcommand::Flatten => Operation::Flatten,
+        Subcommand::Resize => {
+            let width = number("--width")?;
+            let height = number("--height")?;
+            if width.is_none() && height.is_none() {
+                return Err("resize needs --width, --height or both".to_string());
+            }
+
+            let filter = match option("--filter").unwrap_or("lanczos") {
+                "nearest" => FilterType::Nearest,
+                "linear" => FilterType::Triangle,
+                "cubic" => FilterType::CatmullRom,
+                "gaussian" => FilterType::Gaussian,
+                "lanczos" => FilterType::Lanczos3,
+                filter => return Err(format!("Unknown filter {}", filter)),
+            };
+
+            Operation::Resize {
+                width,
+                height,
+                filter,
+            }
+        }
+        Subcommand::ApplyKernel => {
+            let path = option("--kernel").ok_or("apply-kernel needs --kernel")?;
+            let kernel = KernelFile::load(path).map_err(|e| format!("{}: {}", path, e))?;
+            let layer = option("--layer")
+                .map(|layer| {
+                    layer
+                        .parse::<usize>()
+                        .map_err(|_| "--layer must be a layer index".to_string())
+                })
+                .transpose()?;
+
+            Operation::ApplyKernel { kernel, layer }
+        }
+    };
+
+    let format = option("--format").map(|format| format.trim_start_matches('.').to_lowercase());
+    if let Some(format) = &format {
+        check_writable(format)?;
+    }
+
+    let mut export = ExportOptions::default();
+    if let Some(quality) = option("--quality") {
+        export.jpeg_quality = match quality.parse::<u8>() {
+            Ok(quality @ 1..=100) => quality,
+            _ => return Err("--quality must be between 1 and 100".to_string()),
+        };
+    }
+    if let Some(compression) = option("--compression") {
+        export.png_compression = PngCompression::ALL
+            .into_iter()
+            .find(|candidate| candidate.name().eq_ignore_ascii_case(compression))
+            .ok_or_else(|| format!("Unknown compression {}", compression))?;
+    }
+    export.keep_alpha = match option("--alpha").unwrap_or("keep") {
+        "keep" => true,
+        "drop" => false,
+        alpha => return Err(format!("--alpha must be keep or drop, not {}", alpha)),
+    };
+
+    let Some(output) = positional.pop() else {
+        return Err("Missing input and output".to_string());
+    };
+    if positional.is_empty() {
+        return Err("Missing output".to_string());
+    }
+    let output = PathBuf::from(output);
+    if let Some(extension) = output.extension().and_then(|extension| extension.to_str()) {
+        check_writable(&extension.to_lowercase())?;
+    }
+
+    Ok(Job {
+        command,
+        operation,
+        inputs: positional,
+        output,
+        format,
+        export,
+    })
+}
+
+fn check_writable(extension: &str) -> Result<(), String> {
+    let layered = ["jc", "ora", "psd"].contains(&extension);
+    if layered || ExportFormat::from_extension(extension).is_some() {
+        Ok(())
+    } else {
+        Err(format!("Can't write .{} files", extension))
+    }
+}
+
+/// The files matching `pattern`, or just `pattern` when it has no wildcards. Shells usually
+/// expand patterns already, but not on every platform or when they are quoted.
+fn expand(pattern: &str) -> Result<Vec<PathBuf>, BatchError> {
+    if !pattern.contains(['*', '?', '[']) {
+        return Ok(vec![PathBuf::from(pattern)]);
+    }
+
+    let paths: Vec<PathBuf> = glob::glob(pattern)
+        .map_err(|_| BatchError::NoMatches(pattern.to_string()))?
+        .filter_map(Result::ok)
+        .filter(|path| path.is_file())
+        .collect();
+
+    if paths.is_empty() {
+        Err(BatchError::NoMatches(pattern.to_string()))
+    } else {
+        Ok(paths)
+    }
+}
+
+async fn process(
+    operation: &Operation,
+    export: &ExportOptions,
+    kernel: Option<&Kernel>,
+    input: &Path,
+    output: &Path,
+    gpu: &GpuDevice,
+) -> Result<(), BatchError> {
+    let mut workspace = open(input, gpu)?;
+
+    match operation {
+        Operation::Convert => {}
+        Operation::Flatten => workspace.flatten(gpu).await,
+        Operation::Resize {
+            width,
+            height,
+            filter,
+        } => {
+            let (old_width, old_height) = workspace.size;
+            let size = match (*width, *height) {
+                (Some(width), Some(height)) => (width, height),
+                (Some(width), None) => (width, scale_side(old_height, width, old_width)),
+                (None, Some(height)) => (scale_side(old_width, height, old_height), height),
+                (None, None) => workspace.size,
+            };
+            workspace.resize(size, *filter, gpu).await;
+        }
+        Operation::ApplyKernel { layer, .. } => {
+            let kernel = kernel.unwrap();
+            let layers = match *layer {
+                Some(index) => match workspace.layers.get(index) {
+                    Some(info) if info.kind.is_pixel() => vec![index],
+                    _ => return Err(BatchError::NotAPixelLayer(index)),
+                },
+                None => (0..workspace.layers.len())
+                    .filter(|&index| workspace.layers[index].kind.is_pixel())
+                    .collect(),
+            };
+
+            for index in layers {
+                kernel.apply_to_layer(&mut workspace, index, gpu).await;
+            }
+        }
+    }
+
+    save(&workspace, output, export, gpu).await
+}
+
+/// `side` scaled by `new / old`, at least one pixel
+fn scale_side(side: u32, new: u32, old: u32) -> u32 {
+    ((side as u64 * new as u64 + old as u64 / 2) / old as u64).max(1) as u32
+}
+
+/// The path as the workspace file functions take it
+pub fn unicode(path: &Path) -> Result<&str, BatchError> {
+    path.to_str()
+        .ok_or_else(|| BatchError::NotUnicode(path.to_path_buf()))
+}
+
+fn open(path: &Path, gpu: &GpuDevice) -> Result<Workspace, BatchError> {
+    let (workspace, warnings) = Workspace::open(unicode(path)?, gpu)?;
+    for warning in warnings {
+        eprintln!("{}: {}", path.display(), warning);
+    }
+    Ok(workspace)
+}
+
+async fn save(
+    workspace: &Workspace,
+    path: &Path,
+    options: &ExportOptions,
+    gpu: &GpuDevice,
+) -> Result<(), BatchError> {
+    let path_str = unicode(path)?;
+    if is_workspace(path) {
+        return Ok(workspace.save(path_str, gpu).await?);
+    }
+    if is_openraster(path) {
+        return Ok(workspace.save_ora(path_str, gpu).await?);
+    }
+    if is_psd(path) {
+        return Ok(workspace.save_psd(path_str, gpu).await?);
+    }
+
+    let format = ExportFormat::from_path(path)
+        .ok_or_else(|| BatchError::UnsupportedFormat(path.to_path_buf()))?;
+    workspace.export(path_str, format, options, gpu).await?;
+
+    Ok(())
+}
+
+fn is_workspace(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("jc"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(args: &[&str]) -> Job {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        parse(Subcommand::Convert, &args).unwrap()
+    }
+
+    #[test]
+    fn inputs_with_the_same_stem_fail_instead_of_overwriting() {
+        let job = job(&["a.png", "a.jpg", "b.png", "out/", "--format", "png"]);
+        let inputs = ["a.png", "a.jpg", "b.png"].map(PathBuf::from);
+        let outputs = job.outputs(&inputs, true);
+
+        assert_eq!(
+            outputs[0].as_ref().unwrap(),
+            &Path::new("out").join("a.png")
+        );
+        match &outputs[1] {
+            Err(BatchError::SameOutput { output, first }) => {
+                assert_eq!(output, &Path::new("out").join("a.png"));
+                assert_eq!(first, Path::new("a.png"));
+            }
+            other => panic!("expected a collision, got {:?}", other),
+        }
+        assert_eq!(
+            outputs[2].as_ref().unwrap(),
+            &Path::new("out").join("b.png")
+        );
+    }
+
+    #[test]
+    fn one_input_writes_the_output() {
+        let job = job(&["a.png", "b.jpg"]);
+        let outputs = job.outputs(&[PathBuf::from("a.png")], false);
+        assert_eq!(outputs[0].as_ref().unwrap(), Path::new("b.jpg"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn paths_that_are_not_unicode_are_errors() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"scan\xff.png"));
+        assert!(matches!(unicode(path), Err(BatchError::NotUnicode(_))));
+    }
+}
@@ -0,0 +1,803 @@
+//! A CPU reference for compositing, for machines without a GPU and as the ground truth the
+//! shaders are tested against.
+//!
+//! Blend modes follow the W3C Compositing and Blending spec
+//! (<https://www.w3.org/TR/compositing-1/>): the blend function of the mode mixes the colors of
+//! the layer and the layers below, that replaces the layer's color in proportion to the alpha
+//! below, and the result goes on top with the straight alpha "over" operator. Modes the spec
+//! doesn't define use the usual formulas from Photoshop and GIMP, and hue, saturation and value
+//! work in HSV like GIMP's modes of the same names. Dissolve draws a pixel fully opaque with a
+//! chance of its alpha.
+//!
+//! Adjustments recolor the layers below and blend that onto them as a layer with their alpha,
+//! keeping their alpha. Pass-through groups fade between the layers below and the result of
+//! their contents, premultiplied.
+//!
+//! Images are stored as 8 bit between steps the same way the GPU stores the running totals of
+//! 8-bit documents in `Rgba8Unorm` textures. Deeper documents round less and differ slightly.
+
+use image::{GrayImage, ImageBuffer, Luma, Rgba, RgbaImage};
+
+use crate::{
+    workspace::{
+        adjustments::Adjustment,
+        clipping::clip_base,
+        groups::{group_end, group_header},
+        layer_info::{BlendMode, GroupMode, LayerInfo, LayerKind},
+        Workspace,
+    },
+    GpuDevice,
+};
+
+type Color = [f32; 4];
+
+fn load(pixel: &Rgba<u8>) -> Color {
+    pixel.0.map(|channel| channel as f32 / 255.0)
+}
+
+/// Converts the way storing into a unorm texture does
+fn store(color: Color) -> Rgba<u8> {
+    Rgba(color.map(|channel| (channel.clamp(0.0, 1.0) * 255.0).round() as u8))
+}
+
+fn rgb(color: Color) -> [f32; 3] {
+    [color[0], color[1], color[2]]
+}
+
+fn with_alpha([r, g, b]: [f32; 3], alpha: f32) -> Color {
+    [r, g, b, alpha]
+}
+
+fn separable(below: [f32; 3], layer: [f32; 3], f: impl Fn(f32, f32) -> f32) -> [f32; 3] {
+    [0, 1, 2].map(|i| f(below[i], layer[i]))
+}
+
+/// The blend function B(Cb, Cs) of `mode`, the color a layer of color `layer` gives on top of
+/// opaque `below`. Dissolve is Normal here, it only differs in alpha.
+pub fn blend_colors(mode: BlendMode, below: [f32; 3], layer: [f32; 3]) -> [f32; 3] {
+    match mode {
+        BlendMode::Normal | BlendMode::Dissolve => layer,
+        BlendMode::Multiply => separable(below, layer, |cb, cs| cb * cs),
+        BlendMode::Screen => separable(below, layer, screen),
+        BlendMode::Overlay => separable(below, layer, |cb, cs| hard_light(cs, cb)),
+        BlendMode::HardLight => separable(below, layer, hard_light),
+        BlendMode::SoftLight => separable(below, layer, |cb, cs| {
+            if cs <= 0.5 {
+                return cb - (1.0 - 2.0 * cs) * cb * (1.0 - cb);
+            }
+            let d = if cb <= 0.25 {
+                ((16.0 * cb - 12.0) * cb + 4.0) * cb
+            } else {
+                cb.sqrt()
+            };
+            cb + (2.0 * cs - 1.0) * (d - cb)
+        }),
+        BlendMode::DarkenOnly => separable(below, layer, f32::min),
+        BlendMode::LightenOnly => separable(below, layer, f32::max),
+        BlendMode::Difference => separable(below, layer, |cb, cs| (cb - cs).abs()),
+        BlendMode::Exclusion => separable(below, layer, |cb, cs| cb + cs - 2.0 * cb * cs),
+        BlendMode::ColorDodge => separable(below, layer, dodge),
+        BlendMode::ColorBurn => separable(below, layer, burn),
+        BlendMode::LinearDodge => separable(below, layer, |cb, cs| (cb + cs).min(1.0)),
+        BlendMode::LinearBurn => separable(below, layer, |cb, cs| (cb + cs - 1.0).max(0.0)),
+        BlendMode::VividLight => separable(below, layer, |cb, cs| {
+            if cs <= 0.5 {
+                burn(cb, 2.0 * cs)
+            } else {
+                dodge(cb, 2.0 * cs - 1.0)
+            }
+        }),
+        BlendMode::LinearLight => {
+            separable(below, layer, |cb, cs| (cb + 2.0 * cs - 1.0).clamp(0.0, 1.0))
+        }
+        BlendMode::PinLight => separable(below, layer, |cb, cs| {
+            if cs <= 0.5 {
+                cb.min(2.0 * cs)
+            } else {
+                cb.max(2.0 * cs - 1.0)
+            }
+        }),
+        BlendMode::HardMix => separable(
+            below,
+            layer,
+            |cb, cs| if cb + cs >= 1.0 { 1.0 } else { 0.0 },
+        ),
+        BlendMode::Divide => separable(below, layer, |cb, cs| {
+            if cs <= 0.0 {
+                // anything but black divided by nothing is as bright as it gets
+                if cb > 0.0 {
+                    1.0
+                } else {
+                    0.0
+                }
+            } else {
+                (cb / cs).min(1.0)
+            }
+        }),
+        BlendMode::Subtract => separable(below, layer, |cb, cs| (cb - cs).max(0.0)),
+        BlendMode::GrainExtract => {
+            separable(below, layer, |cb, cs| (cb - cs + 0.5).clamp(0.0, 1.0))
+        }
+        BlendMode::GrainMerge => separable(below, layer, |cb, cs| (cb + cs - 0.5).clamp(0.0, 1.0)),
+        BlendMode::Hue => {
+            let [h, s, _] = rgb_to_hsv(layer);
+            // gray has no hue to give
+            if s == 0.0 {
+                return below;
+            }
+            let [_, below_s, below_v] = rgb_to_hsv(below);
+            hsv_to_rgb([h, below_s, below_v])
+        }
+        BlendMode::Saturation => {
+            let [h, _, v] = rgb_to_hsv(below);
+            hsv_to_rgb([h, rgb_to_hsv(layer)[1], v])
+        }
+        BlendMode::Value => {
+            let [h, s, _] = rgb_to_hsv(below);
+            hsv_to_rgb([h, s, rgb_to_hsv(layer)[2]])
+        }
+        BlendMode::Color => set_lum(layer, lum(below)),
+        BlendMode::Luminosity => set_lum(below, lum(layer)),
+    }
+}
+
+fn screen(cb: f32, cs: f32) -> f32 {
+    cb + cs - cb * cs
+}
+
+fn hard_light(cb: f32, cs: f32) -> f32 {
+    if cs <= 0.5 {
+        cb * 2.0 * cs
+    } else {
+        screen(cb, 2.0 * cs - 1.0)
+    }
+}
+
+fn dodge(cb: f32, cs: f32) -> f32 {
+    if cb == 0.0 {
+        0.0
+    } else if cs >= 1.0 {
+        1.0
+    } else {
+        (cb / (1.0 - cs)).min(1.0)
+    }
+}
+
+fn burn(cb: f32, cs: f32) -> f32 {
+    if cb == 1.0 {
+        1.0
+    } else if cs <= 0.0 {
+        0.0
+    } else {
+        1.0 - ((1.0 - cb) / cs).min(1.0)
+    }
+}
+
+/// The straight alpha "over" operator
+fn over(color: [f32; 3], alpha: f32, below: Color) -> Color {
+    let out_alpha = alpha + below[3] * (1.0 - alpha);
+    let color = if out_alpha > 0.0 {
+        [0, 1, 2].map(|i| (color[i] * alpha + below[i] * below[3] * (1.0 - alpha)) / out_alpha)
+    } else {
+        [0.0; 3]
+    };
+    with_alpha(color, out_alpha)
+}
+
+/// Blends a single pixel of a layer onto the pixel below it. `position` seeds the noise of
+/// dissolve.
+pub fn blend_pixel(
+    mode: BlendMode,
+    layer: Color,
+    below: Color,
+    opacity: f32,
+    mask: f32,
+    position: (u32, u32),
+) -> Color {
+    let mut alpha = layer[3] * opacity * mask;
+    if mode == BlendMode::Dissolve {
+        alpha = if dissolve_noise(position) < alpha {
+            1.0
+        } else {
+            0.0
+        };
+    }
+
+    let blended = blend_colors(mode, rgb(below), rgb(layer));
+    let color = [0, 1, 2].map(|i| layer[i] * (1.0 - below[3]) + blended[i] * below[3]);
+    over(color, alpha, below)
+}
+
+/// xxHash32 of the position, scaled to `0.0..1.0`
+fn dissolve_noise((x, y): (u32, u32)) -> f32 {
+    const P2: u32 = 2246822519;
+    const P3: u32 = 3266489917;
+    const P4: u32 = 668265263;
+    const P5: u32 = 374761393;
+
+    let mut h32 = y.wrapping_add(P5).wrapping_add(x.wrapping_mul(P3));
+    h32 = P4.wrapping_mul(h32.rotate_left(17));
+    h32 = P2.wrapping_mul(h32 ^ (h32 >> 15));
+    h32 = P3.wrapping_mul(h32 ^ (h32 >> 13));
+    h32 ^= h32 >> 16;
+
+    (h32 >> 8) as f32 / 16777216.0
+}
+
+/// Hue in degrees from 0 up to 360, saturation and value from 0 to 1
+fn rgb_to_hsv([r, g, b]: [f32; 3]) -> [f32; 3] {
+    let c_max = r.max(g).max(b);
+    let c_min = r.min(g).min(b);
+    let delta = c_max - c_min;
+
+    let h = if delta == 0.0 {
+        0.0
+    } else if c_max == r {
+        let h = (g - b) / delta;
+        if h < 0.0 {
+            h + 6.0
+        } else {
+            h
+        }
+    } else if c_max == g {
+        (b - r) / delta + 2.0
+    } else {
+        (r - g) / delta + 4.0
+    };
+    let s = if c_max > 0.0 { delta / c_max } else { 0.0 };
+
+    [h * 60.0, s, c_max]
+}
+
+fn hsv_to_rgb([h, s, v]: [f32; 3]) -> [f32; 3] {
+    let c = v * s;
+    let x = c * (1.0 - (((h / 60.0) % 2.0) - 1.0).abs());
+    let m = v - c;
+
+    let [r, g, b] = if h < 60.0 {
+        [c, x, 0.0]
+    } else if h < 120.0 {
+        [x, c, 0.0]
+    } else if h < 180.0 {
+        [0.0, c, x]
+    } else if h < 240.0 {
+        [0.0, x, c]
+    } else if h < 300.0 {
+        [x, 0.0, c]
+    } else {
+        [c, 0.0, x]
+    };
+
+    [r + m, g + m, b + m]
+}
+
+fn lum([r, g, b]: [f32; 3]) -> f32 {
+    r * 0.3 + g * 0.59 + b * 0.11
+}
+
+fn clip_color(color: [f32; 3]) -> [f32; 3] {
+    let l = lum(color);
+    let n = color[0].min(color[1]).min(color[2]);
+    let x = color[0].max(color[1]).max(color[2]);
+    let mut result = color;
+    if n < 0.0 {
+        result = result.map(|c| l + (c - l) * l / (l - n).max(0.0001));
+    }
+    if x > 1.0 {
+        result = result.map(|c| l + (c - l) * (1.0 - l) / (x - l).max(0.0001));
+    }
+    result
+}
+
+fn set_lum(color: [f32; 3], l: f32) -> [f32; 3] {
+    let d = l - lum(color);
+    clip_color(color.map(|c| c + d))
+}
+
+/// Blends a whole layer onto the layers below it
+pub fn blend_images(
+    mode: BlendMode,
+    opacity: f32,
+    layer: &RgbaImage,
+    mask: &GrayImage,
+    below: &RgbaImage,
+) -> RgbaImage {
+    ImageBuffer::from_fn(below.width(), below.height(), |x, y| {
+        let mask = mask.get_pixel(x, y).0[0] as f32 / 255.0;
+        store(blend_pixel(
+            mode,
+            load(layer.get_pixel(x, y)),
+            load(below.get_pixel(x, y)),
+            opacity,
+            mask,
+            (x, y),
+        ))
+    })
+}
+
+/// Recolors a pixel as described by the parameters of `adjustment`, `lut` is `Adjustment::lut`
+pub fn adjust_pixel(adjustment: &Adjustment, lut: &[f32], color: [f32; 3]) -> [f32; 3] {
+    let result = match *adjustment {
+        Adjustment::Levels {
+            input_black,
+            input_white,
+            gamma,
+            output_black,
+            output_white,
+        } => {
+            let range = (input_white - input_black).max(0.0001);
+            color.map(|c| {
+                let normalized = ((c - input_black) / range).clamp(0.0, 1.0);
+                let corrected = normalized.powf(1.0 / gamma);
+                output_black * (1.0 - corrected) + output_white * corrected
+            })
+        }
+        Adjustment::Curves(_) => color.map(|c| {
+            let position = c.clamp(0.0, 1.0) * (lut.len() - 1) as f32;
+            let low = position.floor() as usize;
+            let high = (low + 1).min(lut.len() - 1);
+            let t = position.fract();
+            lut[low] * (1.0 - t) + lut[high] * t
+        }),
+        Adjustment::HueSaturation {
+            hue,
+            saturation,
+            lightness,
+        } => {
+            let [h, s, l] = rgb_to_hsl(color);
+            let h = (h + hue / 360.0 + 1.0).fract();
+            let s = (s * (1.0 + saturation)).clamp(0.0, 1.0);
+            let rgb = hsl_to_rgb([h, s, l]);
+            if lightness > 0.0 {
+                rgb.map(|c| c * (1.0 - lightness) + lightness)
+            } else {
+                rgb.map(|c| c * (1.0 + lightness))
+            }
+        }
+        Adjustment::BrightnessContrast {
+            brightness,
+            contrast,
+        } => {
+            let slope = ((contrast.clamp(-1.0, 0.99) + 1.0) * std::f32::consts::FRAC_PI_4).tan();
+            color.map(|c| (c + brightness - 0.5) * slope + 0.5)
+        }
+        Adjustment::Invert => color.map(|c| 1.0 - c),
+        Adjustment::Threshold { level } => {
+            let luma = color[0] * 0.299 + color[1] * 0.587 + color[2] * 0.114;
+            [if luma >= level { 1.0 } else { 0.0 }; 3]
+        }
+    };
+
+    result.map(|c| c.clamp(0.0, 1.0))
+}
+
+fn rgb_to_hsl([r, g, b]: [f32; 3]) -> [f32; 3] {
+    let high = r.max(g).max(b);
+    let low = r.min(g).min(b);
+    let lightness = (high + low) / 2.0;
+    let delta = high - low;
+    if delta <= 0.0 {
+        return [0.0, 0.0, lightness];
+    }
+
+    let saturation = delta / (1.0 - (2.0 * lightness - 1.0).abs());
+    let hue = if high == r {
+        (g - b) / delta
+    } else if high == g {
+        (b - r) / delta + 2.0
+    } else {
+        (r - g) / delta + 4.0
+    };
+    [(hue / 6.0 + 1.0).fract(), saturation, lightness]
+}
+
+fn hsl_to_rgb([h, s, l]: [f32; 3]) -> [f32; 3] {
+    let chroma = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    [0.0, 2.0 / 3.0, 1.0 / 3.0].map(|offset| {
+        let channel = (((h + offset).fract() * 6.0 - 3.0).abs() - 1.0).clamp(0.0, 1.0);
+        (channel - 0.5) * chroma + l
+    })
+}
+
+pub fn adjust_image(adjustment: &Adjustment, image: &RgbaImage) -> RgbaImage {
+    let lut = adjustment.lut();
+    ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+        let pixel = load(image.get_pixel(x, y));
+        store(with_alpha(
+            adjust_pixel(adjustment, &lut, rgb(pixel)),
+            pixel[3],
+        ))
+    })
+}
+
+/// Fades from `from` to `to` by `amount`, premultiplied so transparent pixels don't bleed
+/// their color
+fn fade(from: Color, to: Color, amount: f32) -> Color {
+    let alpha = from[3] * (1.0 - amount) + to[3] * amount;
+    if alpha <= 0.0 {
+        return [0.0; 4];
+    }
+    let color =
+        [0, 1, 2].map(|i| (from[i] * from[3] * (1.0 - amount) + to[i] * to[3] * amount) / alpha);
+    with_alpha(color, alpha)
+}
+
+/// Composites a layer stack like `Workspace::recalculate_output_texture`. `pixels` and
+/// `masks` are parallel to `layers`, and every image has the size of the workspace.
+pub fn composite(
+    layers: &[LayerInfo],
+    pixels: &[RgbaImage],
+    masks: &[GrayImage],
+    size: (u32, u32),
+) -> RgbaImage {
+    let blank = RgbaImage::new(size.0, size.1);
+    let mut running_totals: Vec<RgbaImage> = Vec::with_capacity(layers.len());
+
+    for (i, info) in layers.iter().enumerate() {
+        let below = match i {
+            0 => &blank,
+            _ => &running_totals[i - 1],
+        };
+
+        let base = clip_base(layers, i);
+        let visible = info.visible && base.is_none_or(|base| layers[base].visible);
+        let clip_mask;
+        let mask = match base {
+            Some(base) if visible => {
+                clip_mask = ImageBuffer::from_fn(size.0, size.1, |x, y| {
+                    let coverage = pixels[base].get_pixel(x, y).0[3] as f32 / 255.0
+                        * (masks[base].get_pixel(x, y).0[0] as f32 / 255.0);
+                    let mask = masks[i].get_pixel(x, y).0[0] as f32 * coverage;
+                    Luma([mask.round() as u8])
+                });
+                &clip_mask
+            }
+            _ => &masks[i],
+        };
+
+        let total = match info.kind {
+            LayerKind::Pixel if visible => {
+                blend_images(info.blend_mode, info.opacity, &pixels[i], mask, below)
+            }
+            LayerKind::Adjustment(ref adjustment) if visible => {
+                let adjusted = adjust_image(adjustment, below);
+                let blended = blend_images(info.blend_mode, info.opacity, &adjusted, mask, below);
+                ImageBuffer::from_fn(size.0, size.1, |x, y| {
+                    let mut pixel = *blended.get_pixel(x, y);
+                    pixel.0[3] = below.get_pixel(x, y).0[3];
+                    pixel
+                })
+            }
+            LayerKind::Pixel | LayerKind::Adjustment(_) => below.clone(),
+            LayerKind::GroupEnd => match layers[group_header(layers, i)].kind {
+                LayerKind::Group {
+                    mode: GroupMode::PassThrough,
+                    ..
+                } => below.clone(),
+                _ => blank.clone(),
+            },
+            LayerKind::Group { mode, .. } => {
+                let end = group_end(layers, i);
+                let base = match end {
+                    0 => &blank,
+                    _ => &running_totals[end - 1],
+                };
+                let contents = below;
+
+                match mode {
+                    _ if !info.visible => base.clone(),
+                    GroupMode::Isolated => {
+                        blend_images(info.blend_mode, info.opacity, contents, &masks[i], base)
+                    }
+                    GroupMode::PassThrough => ImageBuffer::from_fn(size.0, size.1, |x, y| {
+                        let amount = info.opacity * masks[i].get_pixel(x, y).0[0] as f32 / 255.0;
+                        store(fade(
+                            load(base.get_pixel(x, y)),
+                            load(contents.get_pixel(x, y)),
+                            amount,
+                        ))
+                    }),
+                }
+            }
+        };
+        running_totals.push(total);
+    }
+
+    running_totals.pop().unwrap_or(blank)
+}
+
+/// How far apart two images are
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Comparison {
+    /// Largest difference of any channel
+    pub max_difference: u8,
+    /// Number of pixels where any channel differs by more than the tolerance
+    pub differing_pixels: usize,
+    pub total_pixels: usize,
+}
+
+pub fn compare(a: &RgbaImage, b: &RgbaImage, tolerance: u8) -> Comparison {
+    assert_eq!(a.dimensions(), b.dimensions(), "images differ in size");
+
+    let mut comparison = Comparison {
+        total_pixels: (a.width() * a.height()) as usize,
+        ..Default::default()
+    };
+    for (a, b) in a.pixels().zip(b.pixels()) {
+        let difference = (0..4).map(|i| a.0[i].abs_diff(b.0[i])).max().unwrap();
+        comparison.max_difference = comparison.max_difference.max(difference);
+        if difference > tolerance {
+            comparison.differing_pixels += 1;
+        }
+    }
+    comparison
+}
+
+/// Composites the workspace on the CPU and compares the result with its output texture
+pub async fn compare_with_gpu(workspace: &Workspace, gpu: &GpuDevice, tolerance: u8) -> Comparison {
+    let width = workspace.size.0;
+    let mut pixels = Vec::with_capacity(workspace.layers.len());
+    let mut masks = Vec::with_capacity(workspace.layers.len());
+    for layer in &workspace.layer_data {
+        pixels.push(gpu.texture_to_image(layer.texture(), width).await);
+        masks.push(gpu.texture_to_luma_image(layer.mask(), width).await);
+    }
+
+    let expected = composite(&workspace.layers, &pixels, &masks, workspace.size);
+    let actual = gpu
+        .texture_to_image(workspace.output_texture.as_ref().unwrap(), width)
+        .await;
+
+    compare(&expected, &actual, tolerance)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::workspace::{layer_info::LayerCreationInfo, tools::response_curve::ResponseCurve};
+
+    const SIZE: (u32, u32) = (32, 32);
+
+    /// Varied colors and alphas. The colors are even so no two add up to exactly 255, where
+    /// hard mix flips and a rounding difference would be all or nothing. The left half is
+    /// opaque.
+    fn pattern(seed: u32) -> RgbaImage {
+        ImageBuffer::from_fn(SIZE.0, SIZE.1, |x, y| {
+            let even = |value: u32| (value % 128 * 2) as u8;
+            let alpha = match x < SIZE.0 / 2 {
+                true => 255,
+                false => (128 + (x * y + seed * 29) % 128) as u8,
+            };
+            Rgba([
+                even(x * 8 + seed * 37),
+                even(y * 8 + seed * 11),
+                even((x + y) * 4 + seed * 53),
+                alpha,
+            ])
+        })
+    }
+
+    fn gradient_mask() -> GrayImage {
+        ImageBuffer::from_fn(SIZE.0, SIZE.1, |x, y| Luma([(x * 4 + y * 4) as u8]))
+    }
+
+    fn layer(image: RgbaImage, blend_mode: BlendMode) -> LayerCreationInfo {
+        LayerCreationInfo {
+            init_image: Some(image),
+            init_mask_image: Some(gradient_mask()),
+            opacity: 0.8,
+            blend_mode,
+            ..Default::default()
+        }
+    }
+
+    fn marker(kind: LayerKind) -> LayerCreationInfo {
+        LayerCreationInfo {
+            kind,
+            init_mask_image: Some(gradient_mask()),
+            opacity: 0.7,
+            ..Default::default()
+        }
+    }
+
+    /// A workspace with `layers` stacked bottom up on a background of `pattern(0)`
+    fn workspace(layers: Vec<LayerCreationInfo>, gpu: &GpuDevice) -> Workspace {
+        let mut workspace = Workspace::from_image(pattern(0), gpu);
+        for layer in layers {
+            workspace.create_layer(layer, gpu, None);
+        }
+        workspace.recalculate_output_texture(gpu, 0);
+        workspace
+    }
+
+    fn assert_matches(workspace: &Workspace, gpu: &GpuDevice, what: &str) {
+        let comparison = block_on(compare_with_gpu(workspace, gpu, 2));
+        assert_eq!(comparison.differing_pixels, 0, "{}: {:?}", what, comparison);
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32], what: &str) {
+        let close = actual
+            .iter()
+            .zip(expected)
+            .all(|(a, e)| (a - e).abs() < 1e-5);
+        assert!(close, "{}: got {:?}, expected {:?}", what, actual, expected);
+    }
+
+    #[test]
+    fn blend_functions_follow_their_formulas() {
+        let below = [0.25, 0.5, 0.75];
+        let layer = [0.75, 0.25, 0.5];
+        let cases = [
+            (BlendMode::LinearDodge, [1.0, 0.75, 1.0]),
+            (BlendMode::ColorDodge, [1.0, 2.0 / 3.0, 1.0]),
+            (BlendMode::VividLight, [0.5, 0.0, 0.75]),
+            (BlendMode::LinearLight, [0.75, 0.0, 0.75]),
+            (BlendMode::PinLight, [0.5, 0.5, 0.75]),
+            (BlendMode::HardMix, [1.0, 0.0, 1.0]),
+            (BlendMode::Divide, [1.0 / 3.0, 1.0, 1.0]),
+            (BlendMode::Subtract, [0.0, 0.25, 0.25]),
+            (BlendMode::GrainExtract, [0.0, 0.75, 0.75]),
+            (BlendMode::GrainMerge, [0.5, 0.25, 0.75]),
+            // luma 0.4525 below and 0.4275 in the layer
+            (BlendMode::Color, [0.775, 0.275, 0.525]),
+            (BlendMode::Luminosity, [0.225, 0.475, 0.725]),
+        ];
+        for (mode, expected) in cases {
+            assert_close(&blend_colors(mode, below, layer), &expected, mode.label());
+        }
+    }
+
+    #[test]
+    fn blend_functions_handle_the_extremes() {
+        let dodge = blend_colors(BlendMode::ColorDodge, [0.0, 0.5, 1.0], [1.0, 1.0, 0.0]);
+        assert_close(&dodge, &[0.0, 1.0, 1.0], "color dodge");
+        let burn = blend_colors(BlendMode::ColorBurn, [1.0, 0.5, 0.0], [0.0, 0.0, 1.0]);
+        assert_close(&burn, &[1.0, 0.0, 0.0], "color burn");
+        let divide = blend_colors(BlendMode::Divide, [0.5, 0.0, 0.0], [0.0, 0.0, 1.0]);
+        assert_close(&divide, &[1.0, 0.0, 0.0], "divide");
+        // black, white and gray have no hue to give
+        let hue = blend_colors(BlendMode::Hue, [0.8, 0.4, 0.2], [0.5, 0.5, 0.5]);
+        assert_close(&hue, &[0.8, 0.4, 0.2], "hue");
+    }
+
+    #[test]
+    fn blending_composites_with_straight_alpha() {
+        // half the layer's color is replaced by the blend of [0.6, 0.4, 0.2], and the result
+        // goes on top with alpha 0.5 + 0.5 * 0.5
+        let subtract = blend_pixel(
+            BlendMode::Subtract,
+            [0.2, 0.2, 0.2, 0.5],
+            [0.8, 0.6, 0.4, 0.5],
+            1.0,
+            1.0,
+            (0, 0),
+        );
+        assert_close(
+            &subtract,
+            &[0.4 / 0.75, 0.3 / 0.75, 0.2 / 0.75, 0.75],
+            "subtract",
+        );
+
+        // opacity and mask fade from the opaque pixel below to the blend of [0.8, 0.2, 0.4]
+        let grain_merge = blend_pixel(
+            BlendMode::GrainMerge,
+            [0.9, 0.3, 0.5, 1.0],
+            [0.4, 0.4, 0.4, 1.0],
+            0.5,
+            0.5,
+            (0, 0),
+        );
+        assert_close(&grain_merge, &[0.5, 0.35, 0.4, 1.0], "grain merge");
+
+        // nothing below to blend with
+        let hard_mix = blend_pixel(
+            BlendMode::HardMix,
+            [0.2, 0.4, 0.6, 0.8],
+            [1.0, 1.0, 1.0, 0.0],
+            1.0,
+            1.0,
+            (0, 0),
+        );
+        assert_close(&hard_mix, &[0.2, 0.4, 0.6, 0.8], "hard mix");
+    }
+
+    #[test]
+    #[ignore = "needs a GPU"]
+    fn every_blend_mode_matches_the_gpu() {
+        let gpu = GpuDevice::for_tests();
+
+        for mode in BlendMode::ALL {
+            let workspace = workspace(vec![layer(pattern(1), mode)], &gpu);
+            assert_matches(&workspace, &gpu, mode.label());
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a GPU"]
+    fn groups_match_the_gpu() {
+        let gpu = GpuDevice::for_tests();
+
+        for mode in [GroupMode::Isolated, GroupMode::PassThrough] {
+            let workspace = workspace(
+                vec![
+                    marker(LayerKind::GroupEnd),
+                    layer(pattern(1), BlendMode::Multiply),
+                    layer(pattern(2), BlendMode::Screen),
+                    LayerCreationInfo {
+                        blend_mode: BlendMode::Overlay,
+                        ..marker(LayerKind::Group {
+                            mode,
+                            collapsed: false,
+                        })
+                    },
+                    layer(pattern(3), BlendMode::Normal),
+                ],
+                &gpu,
+            );
+            assert_matches(&workspace, &gpu, &format!("{:?} group", mode));
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a GPU"]
+    fn clipping_matches_the_gpu() {
+        let gpu = GpuDevice::for_tests();
+
+        let workspace = workspace(
+            vec![
+                layer(pattern(1), BlendMode::Normal),
+                LayerCreationInfo {
+                    clipped: true,
+                    ..layer(pattern(2), BlendMode::HardLight)
+                },
+                LayerCreationInfo {
+                    clipped: true,
+                    ..layer(pattern(3), BlendMode::Normal)
+                },
+            ],
+            &gpu,
+        );
+        assert_matches(&workspace, &gpu, "clipping");
+    }
+
+    #[test]
+    #[ignore = "needs a GPU"]
+    fn adjustments_match_the_gpu() {
+        let gpu = GpuDevice::for_tests();
+
+        let adjustments = [
+            Adjustment::Levels {
+                input_black: 0.1,
+                input_white: 0.9,
+                gamma: 1.6,
+                output_black: 0.05,
+                output_white: 0.95,
+            },
+            Adjustment::Curves(ResponseCurve::new(vec![(0.0, 0.1), (0.4, 0.7), (1.0, 0.9)])),
+            Adjustment::HueSaturation {
+                hue: 75.0,
+                saturation: 0.4,
+                lightness: -0.2,
+            },
+            Adjustment::BrightnessContrast {
+                brightness: 0.1,
+                contrast: 0.3,
+            },
+            Adjustment::Invert,
+            Adjustment::Threshold { level: 0.45 },
+        ];
+        for adjustment in adjustments {
+            let name = adjustment.name();
+            let workspace = workspace(
+                vec![LayerCreationInfo {
+                    blend_mode: BlendMode::Color,
+                    ..marker(LayerKind::Adjustment(adjustment))
+                }],
+                &gpu,
+            );
+            assert_matches(&workspace, &gpu, name);
+        }
+    }
+}
@@ -1,11 +1,14 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
-use egui_wgpu::RenderState;
-use image::{GenericImageView, ImageBuffer, Rgba};
+use image::{ImageBuffer, Luma, Rgba};
 use wgpu::*;
 
+use crate::workspace::bit_depth::{BitDepth, UnsupportedBitDepth};
+
 pub struct GpuDevice {
-    pub render_state: RenderState,
+    pub adapter: Arc<Adapter>,
+    pub device: Arc<Device>,
+    pub queue: Arc<Queue>,
     pub shaders: HashMap<String, ShaderModule>,
 }
 
@@ -31,42 +34,60 @@ fn gather_all_files(root: PathBuf) -> Vec<PathBuf> {
     files
 }
 
+/// The build script puts the compiled shaders next to the executable. Test and example
+/// binaries live one directory further down, in `deps/` or `examples/`.
+fn find_shaders_dir() -> PathBuf {
+    let exe = std::env::current_exe().expect("Can't find path to executable");
+    let exe_dir = exe.parent().unwrap();
+
+    exe_dir
+        .ancestors()
+        .take(2)
+        .map(|dir| dir.join("joyful_create_shaders"))
+        .find(|dir| dir.is_dir())
+        .unwrap_or_else(|| exe_dir.join("joyful_create_shaders"))
+}
+
 impl GpuDevice {
-    pub async fn new(render_state: RenderState) -> Option<Self> {
-        let mut shaders = HashMap::new();
+    /// Features the shaders rely on, such as storage access to `R8Unorm` masks
+    pub const REQUIRED_FEATURES: Features = Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES;
 
-        let shaders_dir = std::env::current_exe().expect("Can't find path to executable");
-        let shaders_dir = format!(
-            "{}/joyful_create_shaders",
-            shaders_dir.parent().unwrap().display()
-        );
-        let files = gather_all_files(PathBuf::from(&shaders_dir));
+    /// Features that are requested when the adapter has them, such as `Rgba16Unorm` layers
+    pub const OPTIONAL_FEATURES: Features = Features::TEXTURE_FORMAT_16BIT_NORM;
 
-        for file in files {
-            let file_extension = file.extension().unwrap().to_str().unwrap().to_string();
+    /// The features to create a device on `adapter` with
+    pub fn features(adapter: &Adapter) -> Features {
+        Self::REQUIRED_FEATURES | (adapter.features() & Self::OPTIONAL_FEATURES)
+    }
 
-            let shader = match file_extension.as_str() {
-                "wgsl" => render_state
-                    .device
-                    .create_shader_module(ShaderModuleDescriptor {
-                        label: None,
-                        source: ShaderSource::Wgsl(
-                            std::fs::read_to_string(file.clone()).unwrap().into(),
-                        ),
-                    }),
-                "spv" => {
-                    let shader_data: Vec<u8> = std::fs::read(file.clone()).unwrap();
-                    let source = wgpu::util::make_spirv(&shader_data);
+    /// Whether the adapter has everything the shaders need. Some backends, like GL, report the
+    /// features but still can't write to `R8Unorm` storage textures.
+    pub fn is_supported(adapter: &Adapter) -> bool {
+        adapter.features().contains(Self::REQUIRED_FEATURES)
+            && adapter
+                .get_texture_format_features(TextureFormat::R8Unorm)
+                .allowed_usages
+                .contains(TextureUsages::STORAGE_BINDING)
+    }
 
-                    render_state
-                        .device
-                        .create_shader_module(ShaderModuleDescriptor {
-                            label: None,
-                            source,
-                        })
-                }
-                _ => continue,
-            };
+    /// Loads the shaders onto a device, which may be the one egui renders with
+    pub async fn new(
+        adapter: Arc<Adapter>,
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+    ) -> Option<Self> {
+        let mut gpu = Self {
+            adapter,
+            device,
+            queue,
+            shaders: HashMap::new(),
+        };
+
+        let shaders_dir = find_shaders_dir();
+        let files = gather_all_files(shaders_dir.clone());
+
+        for file in files {
+            let file_extension = file.extension().unwrap().to_str().unwrap().to_string();
 
             let relative_file = file
                 .strip_prefix(&shaders_dir)
@@ -81,44 +102,169 @@ impl GpuDevice {
             // replace `\\` with `/` for windows`
             let relative_file = relative_file.replace("\\", "/");
 
+            // variants for bit depths the device can't store fail to validate
+            let unsupported = BitDepth::ALL.into_iter().any(|depth| {
+                relative_file.ends_with(&format!(".{}", depth.storage_format()))
+                    && !depth.is_supported(&gpu)
+            });
+            if unsupported {
+                continue;
+            }
+
+            let shader = match file_extension.as_str() {
+                "wgsl" => gpu.device.create_shader_module(ShaderModuleDescriptor {
+                    label: None,
+                    source: ShaderSource::Wgsl(
+                        std::fs::read_to_string(file.clone()).unwrap().into(),
+                    ),
+                }),
+                "spv" => {
+                    let shader_data: Vec<u8> = std::fs::read(file.clone()).unwrap();
+                    let source = wgpu::util::make_spirv(&shader_data);
+
+                    gpu.device.create_shader_module(ShaderModuleDescriptor {
+                        label: None,
+                        source,
+                    })
+                }
+                _ => continue,
+            };
+
             #[cfg(debug_assertions)]
             print!("Loaded shader: {}\n", relative_file);
-            shaders.insert(relative_file, shader);
+            gpu.shaders.insert(relative_file, shader);
         }
 
-        Some(Self {
-            render_state,
-            shaders,
-        })
+        Some(gpu)
     }
 
+    /// Creates a device without a window, for batch processing. Uses a GPU when there is one
+    /// and falls back to a software adapter otherwise.
+    pub async fn headless() -> Option<Self> {
+        let instance = Instance::new(InstanceDescriptor::default());
+
+        // prefer real GPUs, but take whatever can run the shaders
+        let mut adapters = instance.enumerate_adapters(Backends::all());
+        adapters.retain(Self::is_supported);
+        adapters.sort_by_key(|adapter| match adapter.get_info().device_type {
+            DeviceType::DiscreteGpu => 0,
+            DeviceType::IntegratedGpu => 1,
+            DeviceType::VirtualGpu => 2,
+            DeviceType::Cpu => 3,
+            DeviceType::Other => 4,
+        });
+        let adapter = match adapters.into_iter().next() {
+            Some(adapter) => adapter,
+            None => instance
+                .request_adapter(&RequestAdapterOptions {
+                    power_preference: PowerPreference::HighPerformance,
+                    force_fallback_adapter: true,
+                    compatible_surface: None,
+                })
+                .await
+                .filter(Self::is_supported)?,
+        };
+
+        #[cfg(debug_assertions)]
+        println!("Using adapter {:?}", adapter.get_info());
+
+        let (device, queue) = adapter
+            .request_device(
+                &DeviceDescriptor {
+                    label: None,
+                    required_features: Self::features(&adapter),
+                    required_limits: adapter.limits(),
+                    memory_hints: Default::default(),
+                },
+                None,
+            )
+            .await
+            .ok()?;
+
+        Self::new(Arc::new(adapter), Arc::new(device), Arc::new(queue)).await
+    }
+
+    /// The headless device for tests that need the GPU. Those tests are ignored by default, run
+    /// them with `cargo test -- --ignored` on a machine with an adapter that can run the shaders.
+    pub fn for_tests() -> Self {
+        futures::executor::block_on(Self::headless()).expect("no adapter can run the shaders")
+    }
+
+    /// The variant of a shader that reads and writes layers of `depth`. Only the variants of
+    /// supported depths are loaded, see [`BitDepth::is_supported`].
+    pub fn layer_shader(
+        &self,
+        shader: &str,
+        depth: BitDepth,
+    ) -> Result<&ShaderModule, UnsupportedBitDepth> {
+        self.shaders
+            .get(&depth.shader(shader))
+            .ok_or(UnsupportedBitDepth(depth))
+    }
+
+    /// Reads back a texture in any of the layer formats, rounding it to 8 bits per channel
     pub async fn texture_to_image(
         &self,
         texture: &Texture,
         width: u32,
     ) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
-        let size = texture.size();
         #[cfg(debug_assertions)]
         print!(
             "Converting texture to image with size {}x{}...\n",
-            width, size.height
+            width,
+            texture.height()
         );
-        let buffer_size = (size.width * size.height * 4) as u64;
+        let depth = BitDepth::of(texture);
+        let data = self
+            .read_texture(texture, depth.bytes_per_pixel(), width)
+            .await;
+
+        ImageBuffer::from_raw(width, texture.height(), depth.decode_u8(data)).unwrap()
+    }
+
+    /// Reads back a texture in any of the layer formats as it is stored, see
+    /// [`BitDepth::decode`]
+    pub async fn texture_data(&self, texture: &Texture, width: u32) -> Vec<u8> {
+        let depth = BitDepth::of(texture);
+        self.read_texture(texture, depth.bytes_per_pixel(), width)
+            .await
+    }
+
+    pub async fn texture_to_luma_image(
+        &self,
+        texture: &Texture,
+        width: u32,
+    ) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+        #[cfg(debug_assertions)]
+        println!(
+            "Converting mask texture to image with size {}x{}...",
+            width,
+            texture.height()
+        );
+        let data = self.read_texture(texture, 1, width).await;
+
+        ImageBuffer::from_raw(width, texture.height(), data).unwrap()
+    }
+
+    /// Reads back the first `width` columns of a texture into a tightly packed buffer
+    async fn read_texture(&self, texture: &Texture, bytes_per_pixel: u32, width: u32) -> Vec<u8> {
+        let size = texture.size();
+        let padded_bytes_per_row = pad_to_multiple_of_256(bytes_per_pixel * size.width);
+        let buffer_size = (padded_bytes_per_row * size.height) as u64;
         let buffer_desc = BufferDescriptor {
             label: None,
             size: buffer_size,
             usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
             mapped_at_creation: false,
         };
-        let buffer = self.render_state.device.create_buffer(&buffer_desc);
+        let buffer = self.device.create_buffer(&buffer_desc);
 
         let mut encoder = self
-            .render_state
             .device
             .create_command_encoder(&CommandEncoderDescriptor { label: None });
         encoder.copy_texture_to_buffer(
             ImageCopyTexture {
-                texture: &texture,
+                texture,
                 mip_level: 0,
                 origin: Origin3d::ZERO,
                 aspect: TextureAspect::All,
@@ -127,28 +273,33 @@ impl GpuDevice {
                 buffer: &buffer,
                 layout: ImageDataLayout {
                     offset: 0,
-                    bytes_per_row: Some(pad_to_multiple_of_256(4 * size.width)),
+                    bytes_per_row: Some(padded_bytes_per_row),
                     rows_per_image: Some(size.height),
                 },
             },
             size,
         );
 
-        self.render_state.queue.submit(Some(encoder.finish()));
+        self.queue.submit(Some(encoder.finish()));
         let buffer_slice = buffer.slice(..);
 
         buffer_slice.map_async(MapMode::Read, |result| {
             if let Err(e) = result {
                 eprintln!("Failed to map buffer: {:?}", e);
-                return;
             }
         });
-        self.render_state.device.poll(Maintain::Wait);
+        self.device.poll(Maintain::Wait);
 
-        let data = buffer_slice.get_mapped_range();
-
-        let image = ImageBuffer::from_raw(size.width, size.height, data.to_vec()).unwrap();
         //crop off the padding
-        image.view(0, 0, width, size.height).to_image()
+        let row_len = (bytes_per_pixel * width) as usize;
+        let data = buffer_slice
+            .get_mapped_range()
+            .chunks(padded_bytes_per_row as usize)
+            .flat_map(|row| &row[..row_len])
+            .copied()
+            .collect::<Vec<u8>>();
+        buffer.unmap();
+
+        data
     }
 }
@@ -0,0 +1,233 @@
+//! A file picker drawn with egui, so opening and saving looks the same on every platform
+
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileBrowserMode {
+    Open,
+    Save,
+}
+
+/// What happened in the browser this frame
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FileBrowserResult {
+    Pending,
+    Picked(PathBuf),
+    Cancelled,
+}
+
+struct Entry {
+    path: PathBuf,
+    name: String,
+    is_dir: bool,
+}
+
+pub struct FileBrowser {
+    title: String,
+    mode: FileBrowserMode,
+    directory: PathBuf,
+    /// Text of the directory field, which may not be a valid path while it is being typed
+    directory_text: String,
+    file_name: String,
+    /// Lowercase extensions of the files to list, every file when empty
+    extensions: Vec<String>,
+    entries: Vec<Entry>,
+    error: Option<String>,
+}
+
+impl FileBrowser {
+    /// Browser starting in the working directory that lists files with one of `extensions`
+    pub fn new(title: &str, mode: FileBrowserMode, extensions: &[&str]) -> Self {
+        let directory = std::env::current_dir().unwrap_or_default();
+        let mut browser = Self {
+            title: title.to_string(),
+            mode,
+            directory_text: directory.display().to_string(),
+            directory,
+            file_name: String::new(),
+            extensions: extensions
+                .iter()
+                .map(|extension| extension.to_lowercase())
+                .collect(),
+            entries: Vec::new(),
+            error: None,
+        };
+        browser.refresh();
+        browser
+    }
+
+    /// Starts in the directory of `path` with its file name filled in
+    pub fn with_path(mut self, path: &Path) -> Self {
+        if let Some(directory) = path.parent().filter(|parent| parent.is_dir()) {
+            self.set_directory(directory.to_path_buf());
+        }
+        if let Some(name) = path.file_name() {
+            self.file_name = name.to_string_lossy().into_owned();
+        }
+        self
+    }
+
+    fn set_directory(&mut self, directory: PathBuf) {
+        self.directory_text = directory.display().to_string();
+        self.directory = directory;
+        self.refresh();
+    }
+
+    /// Lists the current directory, folders first
+    fn refresh(&mut self) {
+        self.entries.clear();
+        self.error = None;
+
+        let read_dir = match std::fs::read_dir(&self.directory) {
+            Ok(read_dir) => read_dir,
+            Err(e) => {
+                self.error = Some(format!("Can't list {}: {}", self.directory.display(), e));
+                return;
+            }
+        };
+
+        for entry in read_dir.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                continue;
+            }
+
+            let path = entry.path();
+            let is_dir = path.is_dir();
+            if !is_dir && !self.accepts(&path) {
+                continue;
+            }
+
+            self.entries.push(Entry { path, name, is_dir });
+        }
+
+        self.entries
+            .sort_by_cached_key(|entry| (!entry.is_dir, entry.name.to_lowercase()));
+    }
+
+    fn accepts(&self, path: &Path) -> bool {
+        if self.extensions.is_empty() {
+            return true;
+        }
+
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| self.extensions.contains(&extension.to_lowercase()))
+    }
+
+    /// The file the name field points at, with the first extension added when saving without
+    /// one
+    fn chosen_path(&self) -> Option<PathBuf> {
+        let name = self.file_name.trim();
+        if name.is_empty() {
+            return None;
+        }
+
+        let mut path = self.directory.join(name);
+        if self.mode == FileBrowserMode::Save && !self.accepts(&path) {
+            if let Some(extension) = self.extensions.first() {
+                path.set_extension(extension);
+            }
+        }
+        Some(path)
+    }
+
+    pub fn show(&mut self, ctx: &egui::Context) -> FileBrowserResult {
+        let mut open = true;
+        let mut result = FileBrowserResult::Pending;
+        let mut navigate = None;
+
+        egui::Window::new(&self.title)
+            .open(&mut open)
+            .collapsible(false)
+            .default_size([480.0, 360.0])
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    if ui.button("Up").clicked() {
+                        navigate = self.directory.parent().map(Path::to_path_buf);
+                    }
+                    let response = ui.add(
+                        egui::TextEdit::singleline(&mut self.directory_text)
+                            .desired_width(f32::INFINITY),
+                    );
+                    if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                        navigate = Some(PathBuf::from(&self.directory_text));
+                    }
+                });
+                ui.separator();
+
+                egui::ScrollArea::vertical()
+                    .max_height(240.0)
+                    .auto_shrink([false, false])
+                    .show(ui, |ui| {
+                        for entry in &self.entries {
+                            let label = if entry.is_dir {
+                                format!("\u{1F4C1} {}", entry.name)
+                            } else {
+                                entry.name.clone()
+                            };
+                            let selected = !entry.is_dir && entry.name == self.file_name;
+                            let response = ui.selectable_label(selected, label);
+
+                            if entry.is_dir {
+                                if response.double_clicked() {
+                                    navigate = Some(entry.path.clone());
+                                }
+                            } else if response.double_clicked() {
+                                result = FileBrowserResult::Picked(entry.path.clone());
+                            } else if response.clicked() {
+                                self.file_name = entry.name.clone();
+                            }
+                        }
+                    });
+                ui.separator();
+
+                if let Some(error) = &self.error {
+                    ui.colored_label(egui::Color32::RED, error);
+                }
+
+                let chosen = self.chosen_path();
+                ui.horizontal(|ui| {
+                    ui.label("Name");
+                    ui.text_edit_singleline(&mut self.file_name);
+                });
+                ui.horizontal(|ui| {
+                    let action = match self.mode {
+                        FileBrowserMode::Open => "Open",
+                        FileBrowserMode::Save => "Save",
+                    };
+                    let valid = match (&chosen, self.mode) {
+                        (Some(path), FileBrowserMode::Open) => path.is_file(),
+                        (Some(path), FileBrowserMode::Save) => !path.is_dir(),
+                        (None, _) => false,
+                    };
+                    if ui.add_enabled(valid, egui::Button::new(action)).clicked() {
+                        result = FileBrowserResult::Picked(chosen.clone().unwrap());
+                    }
+                    if ui.button("Cancel").clicked() {
+                        result = FileBrowserResult::Cancelled;
+                    }
+
+                    if self.mode == FileBrowserMode::Save
+                        && chosen.as_ref().is_some_and(|path| path.is_file())
+                    {
+                        ui.label("Replaces the existing file");
+                    }
+                });
+            });
+
+        if let Some(directory) = navigate {
+            if directory.is_dir() {
+                self.set_directory(directory);
+            } else {
+                self.error = Some(format!("{} is not a folder", directory.display()));
+            }
+        }
+
+        if !open {
+            FileBrowserResult::Cancelled
+        } else {
+            result
+        }
+    }
+}
@@ -1,17 +1,19 @@
 use crate::device::{pad_to_multiple_of_256, GpuDevice};
+use crate::workspace::{
+    bit_depth::{BitDepth, DEPTH_CHECKED},
+    Workspace,
+};
 
 use image::{ImageBuffer, Rgba};
+use serde::Deserialize;
 use wgpu::*;
 
 impl GpuDevice {
     pub async fn compile_kernel_shader(&self) -> std::io::Result<ShaderModule> {
-        let kernel_shader = self
-            .render_state
-            .device
-            .create_shader_module(ShaderModuleDescriptor {
-                label: None,
-                source: ShaderSource::Wgsl(std::fs::read_to_string("filters/kernel")?.into()),
-            });
+        let kernel_shader = self.device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: ShaderSource::Wgsl(std::fs::read_to_string("filters/kernel")?.into()),
+        });
 
         Ok(kernel_shader)
     }
@@ -19,6 +21,121 @@ impl GpuDevice {
 
 pub struct Kernel(Texture);
 
+/// A kernel as stored in a `.json` file. `weights` holds `height` rows of `width` entries, each
+/// either a single weight for every channel or `[r, g, b, a]`.
+#[derive(Deserialize, Debug)]
+pub struct KernelFile {
+    pub width: u32,
+    pub height: u32,
+    pub weights: Vec<KernelWeight>,
+    /// Divides the weights of each channel by their sum so the image keeps its brightness
+    #[serde(default)]
+    pub normalize: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(untagged)]
+pub enum KernelWeight {
+    Uniform(f32),
+    Channels([f32; 4]),
+}
+
+impl KernelWeight {
+    fn channels(self) -> [f32; 4] {
+        match self {
+            KernelWeight::Uniform(weight) => [weight; 4],
+            KernelWeight::Channels(channels) => channels,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum KernelLoadError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    EmptyKernel,
+    WrongWeightCount { expected: usize, found: usize },
+}
+
+impl std::fmt::Display for KernelLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "could not read kernel: {}", e),
+            Self::Json(e) => write!(f, "kernel is not valid: {}", e),
+            Self::EmptyKernel => write!(f, "kernel width and height must be at least 1"),
+            Self::WrongWeightCount { expected, found } => write!(
+                f,
+                "kernel has {} weights but its size needs {}",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for KernelLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for KernelLoadError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for KernelLoadError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
+
+impl KernelFile {
+    pub fn load(path: &str) -> Result<Self, KernelLoadError> {
+        let file: Self = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+
+        if file.width == 0 || file.height == 0 {
+            return Err(KernelLoadError::EmptyKernel);
+        }
+        let expected = (file.width * file.height) as usize;
+        if file.weights.len() != expected {
+            return Err(KernelLoadError::WrongWeightCount {
+                expected,
+                found: file.weights.len(),
+            });
+        }
+
+        Ok(file)
+    }
+
+    /// The weights laid out the way [`Kernel::new`] expects them
+    pub fn data(&self) -> Vec<f32> {
+        let mut data: Vec<f32> = self
+            .weights
+            .iter()
+            .flat_map(|weight| weight.channels())
+            .collect();
+
+        if self.normalize {
+            for channel in 0..4 {
+                let sum: f32 = data.iter().skip(channel).step_by(4).sum();
+                if sum != 0.0 {
+                    data.iter_mut()
+                        .skip(channel)
+                        .step_by(4)
+                        .for_each(|weight| *weight /= sum);
+                }
+            }
+        }
+
+        data
+    }
+}
+
 impl Kernel {
     pub fn new(data: &[f32], i: u32, j: u32, gpu: &GpuDevice) -> Self {
         #[cfg(debug_assertions)]
@@ -27,7 +144,7 @@ impl Kernel {
         #[cfg(debug_assertions)]
         print!("Creating kernel with size {}x{}...\n", i, j);
 
-        let texture = gpu.render_state.device.create_texture(&TextureDescriptor {
+        let texture = gpu.device.create_texture(&TextureDescriptor {
             label: None,
             size: Extent3d {
                 width: i,
@@ -45,7 +162,7 @@ impl Kernel {
             view_formats: &[TextureFormat::Rgba32Float],
         });
 
-        gpu.render_state.queue.write_texture(
+        gpu.queue.write_texture(
             ImageCopyTexture {
                 texture: &texture,
                 mip_level: 0,
@@ -68,6 +185,10 @@ impl Kernel {
         Self(texture)
     }
 
+    pub fn from_file(file: &KernelFile, gpu: &GpuDevice) -> Self {
+        Self::new(&file.data(), file.width, file.height, gpu)
+    }
+
     pub async fn apply_to_image(
         &self,
         image: ImageBuffer<Rgba<u8>, Vec<u8>>,
@@ -78,7 +199,7 @@ impl Kernel {
 
         #[cfg(debug_assertions)]
         print!("Creating input texture...\n");
-        let input_texture = gpu.render_state.device.create_texture(&TextureDescriptor {
+        let input_texture = gpu.device.create_texture(&TextureDescriptor {
             label: None,
             size: Extent3d {
                 width: width,
@@ -98,7 +219,7 @@ impl Kernel {
 
         #[cfg(debug_assertions)]
         print!("Writing input texture...\n");
-        gpu.render_state.queue.write_texture(
+        gpu.queue.write_texture(
             ImageCopyTexture {
                 texture: &input_texture,
                 mip_level: 0,
@@ -120,7 +241,7 @@ impl Kernel {
 
         #[cfg(debug_assertions)]
         print!("Creating output texture...\n");
-        let output_texture = gpu.render_state.device.create_texture(&TextureDescriptor {
+        let output_texture = gpu.device.create_texture(&TextureDescriptor {
             label: None,
             size: Extent3d {
                 width: pad_to_multiple_of_256(width),
@@ -146,6 +267,20 @@ impl Kernel {
         gpu.texture_to_image(&output_texture, width).await
     }
 
+    /// Filters a layer in place, only inside the workspace selection
+    pub async fn apply_to_layer(&self, workspace: &mut Workspace, index: usize, gpu: &GpuDevice) {
+        let target = workspace.begin_filter(index, gpu);
+        self.apply(
+            &target.input,
+            &target.output,
+            workspace.size.0,
+            workspace.size.1,
+            gpu,
+        )
+        .await;
+        workspace.finish_filter(target, gpu);
+    }
+
     pub async fn apply(
         &self,
         input_texture: &Texture,
@@ -156,101 +291,94 @@ impl Kernel {
     ) {
         #[cfg(debug_assertions)]
         print!("Applying kernel...\n");
-        let bind_group_layout =
-            gpu.render_state
-                .device
-                .create_bind_group_layout(&BindGroupLayoutDescriptor {
-                    label: None,
-                    entries: &[
-                        BindGroupLayoutEntry {
-                            binding: 0,
-                            visibility: ShaderStages::COMPUTE,
-                            ty: BindingType::StorageTexture {
-                                access: StorageTextureAccess::ReadOnly,
-                                format: TextureFormat::Rgba8Unorm,
-                                view_dimension: TextureViewDimension::D2,
-                            },
-                            count: None,
-                        },
-                        BindGroupLayoutEntry {
-                            binding: 1,
-                            visibility: ShaderStages::COMPUTE,
-                            ty: BindingType::StorageTexture {
-                                access: StorageTextureAccess::ReadOnly,
-                                format: TextureFormat::Rgba32Float,
-                                view_dimension: TextureViewDimension::D2,
-                            },
-                            count: None,
-                        },
-                        BindGroupLayoutEntry {
-                            binding: 2,
-                            visibility: ShaderStages::COMPUTE,
-                            ty: BindingType::StorageTexture {
-                                access: StorageTextureAccess::WriteOnly,
-                                format: TextureFormat::Rgba8Unorm,
-                                view_dimension: TextureViewDimension::D2,
-                            },
-                            count: None,
-                        },
-                    ],
-                });
-
-        let pipeline_layout =
-            gpu.render_state
-                .device
-                .create_pipeline_layout(&PipelineLayoutDescriptor {
-                    label: None,
-                    bind_group_layouts: &[&bind_group_layout],
-                    push_constant_ranges: &[],
-                });
-
-        #[cfg(debug_assertions)]
-        assert!(gpu.shaders.contains_key("filters/kernel"));
-
-        let kernel_shader = gpu.shaders.get("filters/kernel").unwrap();
-
-        let pipeline =
-            gpu.render_state
-                .device
-                .create_compute_pipeline(&ComputePipelineDescriptor {
-                    label: None,
-                    layout: Some(&pipeline_layout),
-                    module: kernel_shader,
-                    entry_point: "main",
-                    compilation_options: Default::default(),
-                    cache: None,
-                });
-
-        let bind_group = gpu
-            .render_state
+        let depth = BitDepth::of(output_texture);
+        let bind_group_layout = gpu
             .device
-            .create_bind_group(&BindGroupDescriptor {
+            .create_bind_group_layout(&BindGroupLayoutDescriptor {
                 label: None,
-                layout: &bind_group_layout,
                 entries: &[
-                    BindGroupEntry {
+                    BindGroupLayoutEntry {
                         binding: 0,
-                        resource: BindingResource::TextureView(
-                            &input_texture.create_view(&TextureViewDescriptor::default()),
-                        ),
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::StorageTexture {
+                            access: StorageTextureAccess::ReadOnly,
+                            format: depth.texture_format(),
+                            view_dimension: TextureViewDimension::D2,
+                        },
+                        count: None,
                     },
-                    BindGroupEntry {
+                    BindGroupLayoutEntry {
                         binding: 1,
-                        resource: BindingResource::TextureView(
-                            &self.0.create_view(&TextureViewDescriptor::default()),
-                        ),
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::StorageTexture {
+                            access: StorageTextureAccess::ReadOnly,
+                            format: TextureFormat::Rgba32Float,
+                            view_dimension: TextureViewDimension::D2,
+                        },
+                        count: None,
                     },
-                    BindGroupEntry {
+                    BindGroupLayoutEntry {
                         binding: 2,
-                        resource: BindingResource::TextureView(
-                            &output_texture.create_view(&TextureViewDescriptor::default()),
-                        ),
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::StorageTexture {
+                            access: StorageTextureAccess::WriteOnly,
+                            format: depth.texture_format(),
+                            view_dimension: TextureViewDimension::D2,
+                        },
+                        count: None,
                     },
                 ],
             });
 
+        let pipeline_layout = gpu
+            .device
+            .create_pipeline_layout(&PipelineLayoutDescriptor {
+                label: None,
+                bind_group_layouts: &[&bind_group_layout],
+                push_constant_ranges: &[],
+            });
+
+        let kernel_shader = gpu
+            .layer_shader("filters/kernel", depth)
+            .expect(DEPTH_CHECKED);
+
+        let pipeline = gpu
+            .device
+            .create_compute_pipeline(&ComputePipelineDescriptor {
+                label: None,
+                layout: Some(&pipeline_layout),
+                module: kernel_shader,
+                entry_point: "main",
+                compilation_options: Default::default(),
+                cache: None,
+            });
+
+        let bind_group = gpu.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &bind_group_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(
+                        &input_texture.create_view(&TextureViewDescriptor::default()),
+                    ),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(
+                        &self.0.create_view(&TextureViewDescriptor::default()),
+                    ),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::TextureView(
+                        &output_texture.create_view(&TextureViewDescriptor::default()),
+                    ),
+                },
+            ],
+        });
+
         let mut encoder = gpu
-            .render_state
             .device
             .create_command_encoder(&CommandEncoderDescriptor { label: None });
 
@@ -273,9 +401,7 @@ impl Kernel {
 
         #[cfg(debug_assertions)]
         print!("Submitting work...\n");
-        gpu.render_state
-            .queue
-            .submit(std::iter::once(encoder.finish()));
+        gpu.queue.submit(std::iter::once(encoder.finish()));
     }
 
     pub fn gaussian_kernel<const I: usize, const J: usize>(gpu: &GpuDevice) -> Self
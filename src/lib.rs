@@ -0,0 +1,16 @@
+#![allow(incomplete_features)]
+#![allow(unused)] // TODO: remove when done
+#![feature(generic_const_exprs)]
+#![feature(iter_next_chunk)]
+#![feature(iter_intersperse)]
+
+pub mod app;
+pub mod batch;
+pub mod compositor;
+pub mod device;
+pub mod file_browser;
+pub mod filters;
+pub mod recent_files;
+pub mod workspace;
+
+pub use device::GpuDevice;
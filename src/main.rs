@@ -1,49 +1,34 @@
-#![allow(incomplete_features)]
-#![allow(unused)] // TODO: remove when done
-#![feature(generic_const_exprs)]
-#![feature(iter_next_chunk)]
-#![feature(iter_intersperse)]
-#![feature(vec_into_raw_parts)]
-
-pub mod app;
-pub mod device;
-pub mod filters;
-
-use app::*;
-use device::*;
-use wgpu::*;
-mod workspace;
-
-use std::{
-    collections::HashMap,
-    path::PathBuf,
-    sync::{Arc, Mutex},
-    time::Instant,
-    vec,
-};
+use std::{path::PathBuf, sync::Arc};
 
 use eframe::egui;
-use egui::{
-    load::SizedTexture, panel, Image, Layout, Pos2, Rect, Sense, TextureId, TopBottomPanel, Vec2,
-};
-use egui_wgpu::{RenderState, WgpuConfiguration};
-use tokio::runtime::Runtime;
-use workspace::{
-    layer_info::LayerCreationInfo,
-    tools::{
-        brush::{BrushTool, BrushToolSettings},
-        ActionOrigin,
+use egui_wgpu::WgpuConfiguration;
+use joyful_create::{
+    app::App,
+    batch,
+    workspace::{
+        bit_depth::BitDepth,
+        layer_info::BlendMode,
+        tools::{brush::BrushToolSettings, brush_new::BrushToolNew},
+        Workspace,
     },
-    Workspace,
+    GpuDevice,
 };
+use wgpu::DeviceDescriptor;
 
 fn main() -> eframe::Result {
     let args = std::env::args().collect::<Vec<String>>();
+    if let Some(command) = args
+        .get(1)
+        .and_then(|arg| batch::Subcommand::from_name(arg))
+    {
+        std::process::exit(batch::run(command, &args[2..]));
+    }
+
     let file_to_load = match args.len() {
         1 => None,
         2 => {
             if args[1] == "--help" {
-                println!("Usage: joyful_create [file]");
+                println!("{}", batch::USAGE);
                 return Ok(());
             }
 
@@ -61,7 +46,7 @@ fn main() -> eframe::Result {
         }
         _ => {
             println!("Too many arguments provided.");
-            println!("Usage: joyful_create [file]");
+            println!("{}", batch::USAGE);
 
             return Ok(());
         }
@@ -79,9 +64,8 @@ fn main() -> eframe::Result {
         viewport: egui::ViewportBuilder::default().with_inner_size([1024.0, 768.0]),
         renderer: eframe::Renderer::Wgpu,
         wgpu_options: WgpuConfiguration {
-            device_descriptor: Arc::new(|_| DeviceDescriptor {
-                required_features: Features::default()
-                    | Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES,
+            device_descriptor: Arc::new(|adapter| DeviceDescriptor {
+                required_features: GpuDevice::features(adapter),
                 ..Default::default()
             }),
             ..Default::default()
@@ -95,26 +79,28 @@ fn main() -> eframe::Result {
         options,
         Box::new(|cc| {
             let render_state = cc.wgpu_render_state.clone().unwrap();
-            let gpu = rt_arc.block_on(GpuDevice::new(render_state)).unwrap();
-
-            let workspace = match file_to_load {
+            let gpu = rt_arc
+                .block_on(GpuDevice::new(
+                    render_state.adapter.clone(),
+                    render_state.device.clone(),
+                    render_state.queue.clone(),
+                ))
+                .unwrap();
+
+            let workspace = match &file_to_load {
                 None => {
-                    let mut workspace = Workspace::default();
-                    workspace.create_layer(
-                        LayerCreationInfo {
-                            name: "Background".to_string(),
-                            init_rgba: Some([255, 255, 255, 255]),
-                            ..Default::default()
-                        },
+                    let mut workspace = Workspace::new_document(
+                        (512, 512),
+                        [255, 255, 255, 255],
+                        BitDepth::Eight,
                         &gpu,
-                        None,
-                    );
+                    )?;
 
-                    let tool: BrushTool = BrushTool::new(
+                    let tool: BrushToolNew = BrushToolNew::new(
                         BrushToolSettings {
                             size: 50.0,
                             color: Some([100, 255, 0, 255]),
-                            blend_mode: "normal".to_string(),
+                            blend_mode: BlendMode::Normal,
                             hardness: 0.0,
                             ..Default::default()
                         },
@@ -125,45 +111,32 @@ fn main() -> eframe::Result {
                     workspace
                 }
                 Some(path) => {
-                    let extension = path.extension().unwrap().to_str().unwrap();
-
-                    match extension {
-                        "jc" => {
-                            let workspace = Workspace::load(path.to_str().unwrap(), &gpu).unwrap();
-                            workspace
-                        }
-                        "png" => {
-                            let image = image::open(path).unwrap();
-                            let image = image.to_rgba8();
-                            let width = image.width();
-                            let height = image.height();
-
-                            let mut workspace = Workspace {
-                                size: (width, height),
-                                ..Default::default()
-                            };
-
-                            workspace.create_layer(
-                                LayerCreationInfo {
-                                    name: "Background".to_string(),
-                                    init_image: Some(image),
-                                    ..Default::default()
-                                },
-                                &gpu,
-                                None,
-                            );
+                    let opened =
+                        batch::unicode(path).and_then(|name| Ok(Workspace::open(name, &gpu)?));
+                    match opened {
+                        Ok((workspace, warnings)) => {
+                            for warning in warnings {
+                                eprintln!("{}: {}", path.display(), warning);
+                            }
                             workspace
                         }
-                        _ => {
-                            println!("Unsupported file type: {}", extension);
-                            Workspace::default()
+                        Err(e) => {
+                            eprintln!("Failed to load {}: {}", path.display(), e);
+                            return Err(Box::new(e));
                         }
                     }
                 }
             };
 
-            let output_tex = workspace.register_output_texture(cc);
-            let app = App::new(gpu, rt_arc.clone(), output_tex, workspace);
+            let output_tex = workspace.register_output_texture(&render_state);
+            let app = App::new(
+                gpu,
+                render_state,
+                rt_arc.clone(),
+                output_tex,
+                workspace,
+                file_to_load,
+            );
 
             Ok(Box::new(app))
         }),
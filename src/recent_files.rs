@@ -0,0 +1,81 @@
+//! The list behind File > Recent Files, kept in the user's config directory between sessions
+
+use std::path::{Path, PathBuf};
+
+/// How many files the list remembers
+const MAX_RECENT_FILES: usize = 10;
+
+#[derive(Default)]
+pub struct RecentFiles {
+    /// Most recent first
+    paths: Vec<PathBuf>,
+}
+
+/// `$XDG_CONFIG_HOME/joyful_create/recent_files`, falling back to `~/.config` and `%APPDATA%`
+fn storage_path() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+
+    Some(config_dir.join("joyful_create").join("recent_files"))
+}
+
+impl RecentFiles {
+    /// Reads the list saved by the last session, which is empty if there is none
+    pub fn load() -> Self {
+        let Some(contents) = storage_path().and_then(|path| std::fs::read_to_string(path).ok())
+        else {
+            return Self::default();
+        };
+
+        Self {
+            paths: contents
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(PathBuf::from)
+                .take(MAX_RECENT_FILES)
+                .collect(),
+        }
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Moves `path` to the top of the list and saves it
+    pub fn add(&mut self, path: &Path) {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        self.paths.retain(|recent| *recent != path);
+        self.paths.insert(0, path);
+        self.paths.truncate(MAX_RECENT_FILES);
+        self.save();
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        self.paths.retain(|recent| recent != path);
+        self.save();
+    }
+
+    pub fn clear(&mut self) {
+        self.paths.clear();
+        self.save();
+    }
+
+    fn save(&self) {
+        let Some(path) = storage_path() else {
+            return;
+        };
+
+        let contents: String = self
+            .paths
+            .iter()
+            .map(|recent| format!("{}\n", recent.display()))
+            .collect();
+        let result = std::fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| std::fs::write(&path, contents));
+        if let Err(e) = result {
+            eprintln!("Failed to save recent files to {}: {}", path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,385 @@
+//! Adjustment layers, which recolor the composite of the layers below them instead of
+//! holding pixels of their own.
+
+use serde::{Deserialize, Serialize};
+use util::DeviceExt;
+use wgpu::*;
+
+use super::{
+    bit_depth::{BitDepth, DEPTH_CHECKED},
+    copy_texture,
+    tools::response_curve::ResponseCurve,
+    LayerCreationInfo, LayerData, LayerInfo, LayerKind, Workspace,
+};
+use crate::GpuDevice;
+
+/// Number of entries in the lookup table passed to the adjustment shaders
+const LUT_SIZE: usize = 256;
+
+/// A color operation and its parameters. All values work on colors in `0..=1`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Adjustment {
+    Levels {
+        input_black: f32,
+        input_white: f32,
+        gamma: f32,
+        output_black: f32,
+        output_white: f32,
+    },
+    /// Maps every color channel through the same curve
+    Curves(ResponseCurve),
+    HueSaturation {
+        /// Rotation in degrees
+        hue: f32,
+        /// `-1` is grayscale and `1` doubles the saturation
+        saturation: f32,
+        /// `-1` is black and `1` is white
+        lightness: f32,
+    },
+    BrightnessContrast {
+        brightness: f32,
+        /// `-1` flattens everything to gray, `1` is close to a threshold
+        contrast: f32,
+    },
+    Invert,
+    /// Pixels with a luma of at least `level` become white, the rest black
+    Threshold {
+        level: f32,
+    },
+}
+
+impl Adjustment {
+    /// Every kind of adjustment, with parameters that leave the image unchanged where possible
+    pub fn presets() -> [Adjustment; 6] {
+        [
+            Adjustment::Levels {
+                input_black: 0.0,
+                input_white: 1.0,
+                gamma: 1.0,
+                output_black: 0.0,
+                output_white: 1.0,
+            },
+            Adjustment::Curves(ResponseCurve::linear()),
+            Adjustment::HueSaturation {
+                hue: 0.0,
+                saturation: 0.0,
+                lightness: 0.0,
+            },
+            Adjustment::BrightnessContrast {
+                brightness: 0.0,
+                contrast: 0.0,
+            },
+            Adjustment::Invert,
+            Adjustment::Threshold { level: 0.5 },
+        ]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Adjustment::Levels { .. } => "Levels",
+            Adjustment::Curves(_) => "Curves",
+            Adjustment::HueSaturation { .. } => "Hue/Saturation",
+            Adjustment::BrightnessContrast { .. } => "Brightness/Contrast",
+            Adjustment::Invert => "Invert",
+            Adjustment::Threshold { .. } => "Threshold",
+        }
+    }
+
+    fn shader(&self) -> &'static str {
+        match self {
+            Adjustment::Levels { .. } => "adjustments/levels",
+            Adjustment::Curves(_) => "adjustments/curves",
+            Adjustment::HueSaturation { .. } => "adjustments/hue_saturation",
+            Adjustment::BrightnessContrast { .. } => "adjustments/brightness_contrast",
+            Adjustment::Invert => "adjustments/invert",
+            Adjustment::Threshold { .. } => "adjustments/threshold",
+        }
+    }
+
+    /// Matches `params` in the adjustment shaders
+    fn params(&self) -> [f32; 8] {
+        match *self {
+            Adjustment::Levels {
+                input_black,
+                input_white,
+                gamma,
+                output_black,
+                output_white,
+            } => [
+                input_black,
+                input_white,
+                gamma,
+                output_black,
+                output_white,
+                0.0,
+                0.0,
+                0.0,
+            ],
+            Adjustment::HueSaturation {
+                hue,
+                saturation,
+                lightness,
+            } => [hue, saturation, lightness, 0.0, 0.0, 0.0, 0.0, 0.0],
+            Adjustment::BrightnessContrast {
+                brightness,
+                contrast,
+            } => [brightness, contrast, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            Adjustment::Threshold { level } => [level, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            Adjustment::Curves(_) | Adjustment::Invert => [0.0; 8],
+        }
+    }
+
+    /// The curve sampled at evenly spaced inputs, the identity for other adjustments
+    pub(crate) fn lut(&self) -> Vec<f32> {
+        (0..LUT_SIZE)
+            .map(|i| i as f32 / (LUT_SIZE - 1) as f32)
+            .map(|input| match self {
+                Adjustment::Curves(curve) => curve.evaluate(input).clamp(0.0, 1.0),
+                _ => input,
+            })
+            .collect()
+    }
+
+    /// Editor for the parameters
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        match self {
+            Adjustment::Levels {
+                input_black,
+                input_white,
+                gamma,
+                output_black,
+                output_white,
+            } => {
+                ui.add(egui::Slider::new(input_black, 0.0..=1.0).text("Input black"));
+                ui.add(egui::Slider::new(input_white, 0.0..=1.0).text("Input white"));
+                ui.add(
+                    egui::Slider::new(gamma, 0.1..=10.0)
+                        .logarithmic(true)
+                        .text("Gamma"),
+                );
+                ui.add(egui::Slider::new(output_black, 0.0..=1.0).text("Output black"));
+                ui.add(egui::Slider::new(output_white, 0.0..=1.0).text("Output white"));
+            }
+            Adjustment::Curves(curve) => curve.ui(ui),
+            Adjustment::HueSaturation {
+                hue,
+                saturation,
+                lightness,
+            } => {
+                ui.add(egui::Slider::new(hue, -180.0..=180.0).text("Hue"));
+                ui.add(egui::Slider::new(saturation, -1.0..=1.0).text("Saturation"));
+                ui.add(egui::Slider::new(lightness, -1.0..=1.0).text("Lightness"));
+            }
+            Adjustment::BrightnessContrast {
+                brightness,
+                contrast,
+            } => {
+                ui.add(egui::Slider::new(brightness, -1.0..=1.0).text("Brightness"));
+                ui.add(egui::Slider::new(contrast, -1.0..=0.99).text("Contrast"));
+            }
+            Adjustment::Invert => {
+                ui.label("No settings");
+            }
+            Adjustment::Threshold { level } => {
+                ui.add(egui::Slider::new(level, 0.0..=1.0).text("Level"));
+            }
+        }
+    }
+}
+
+impl Workspace {
+    /// Adds an adjustment layer at `index`, or on top of the stack when `None`
+    pub fn create_adjustment_layer(
+        &mut self,
+        adjustment: Adjustment,
+        index: Option<usize>,
+        gpu: &GpuDevice,
+    ) {
+        let info = LayerCreationInfo {
+            name: adjustment.name().to_string(),
+            kind: LayerKind::Adjustment(adjustment),
+            ..Default::default()
+        };
+        self.create_layer(info, gpu, index);
+    }
+
+    /// Replaces the parameters of the adjustment layer at `index`
+    pub fn set_adjustment(&mut self, index: usize, adjustment: Adjustment, gpu: &GpuDevice) {
+        assert!(
+            self.layers[index].kind.is_adjustment(),
+            "layer {} is not an adjustment layer",
+            index
+        );
+
+        let info = LayerInfo {
+            kind: LayerKind::Adjustment(adjustment),
+            ..self.layers[index].clone()
+        };
+        self.set_layer_info(index, info, gpu);
+    }
+
+    /// Shows `adjustment` on the layer at `index` without recording it, for live previews
+    /// while parameters are being dragged
+    pub fn preview_adjustment(&mut self, index: usize, adjustment: Adjustment, gpu: &GpuDevice) {
+        self.layers[index].kind = LayerKind::Adjustment(adjustment);
+        self.recalculate_output_texture(gpu, index);
+    }
+
+    /// Recolors `below` and blends the result back onto it with `mask` and the layer's opacity
+    /// and blend mode, writing into the layer's running total. The alpha of `below` is kept, an
+    /// adjustment never makes pixels more opaque.
+    pub(super) fn composite_adjustment(
+        gpu: &GpuDevice,
+        size: (u32, u32),
+        adjustment: &Adjustment,
+        layer_info: &LayerInfo,
+        layer: &LayerData,
+        mask: &Texture,
+        below: &Texture,
+    ) {
+        run_adjustment_shader(gpu, adjustment, below, &layer.texture);
+        Self::blend(
+            gpu,
+            size,
+            layer_info,
+            &layer.texture,
+            mask,
+            below,
+            &layer.running_total,
+        );
+        Self::run_blend_shader(
+            gpu,
+            gpu.layer_shader("adjustments/keep_alpha", BitDepth::of(below))
+                .expect(DEPTH_CHECKED),
+            1.0,
+            &layer.running_total,
+            &layer.mask,
+            below,
+            &layer.texture,
+        );
+        copy_texture(gpu, &layer.texture, &layer.running_total);
+    }
+}
+
+/// Runs the adjustment's shader over the whole of `input`, writing into `output`
+fn run_adjustment_shader(
+    gpu: &GpuDevice,
+    adjustment: &Adjustment,
+    input: &Texture,
+    output: &Texture,
+) {
+    let device = &gpu.device;
+    let depth = BitDepth::of(output);
+
+    let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: None,
+        entries: &[
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::StorageTexture {
+                    access: StorageTextureAccess::ReadOnly,
+                    format: depth.texture_format(),
+                    view_dimension: TextureViewDimension::D2,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::StorageTexture {
+                    access: StorageTextureAccess::WriteOnly,
+                    format: depth.texture_format(),
+                    view_dimension: TextureViewDimension::D2,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 3,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+    });
+
+    let params_buffer = device.create_buffer_init(&util::BufferInitDescriptor {
+        label: None,
+        contents: bytemuck::cast_slice(&adjustment.params()),
+        usage: BufferUsages::UNIFORM,
+    });
+    let lut_buffer = device.create_buffer_init(&util::BufferInitDescriptor {
+        label: None,
+        contents: bytemuck::cast_slice(&adjustment.lut()),
+        usage: BufferUsages::STORAGE,
+    });
+
+    let bind_group = device.create_bind_group(&BindGroupDescriptor {
+        label: None,
+        layout: &layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(
+                    &input.create_view(&TextureViewDescriptor::default()),
+                ),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::TextureView(
+                    &output.create_view(&TextureViewDescriptor::default()),
+                ),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: params_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: lut_buffer.as_entire_binding(),
+            },
+        ],
+    });
+
+    let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+        label: None,
+        bind_group_layouts: &[&layout],
+        push_constant_ranges: &[],
+    });
+    let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+        label: None,
+        layout: Some(&pipeline_layout),
+        module: gpu
+            .layer_shader(adjustment.shader(), depth)
+            .expect(DEPTH_CHECKED),
+        entry_point: "main",
+        compilation_options: Default::default(),
+        cache: None,
+    });
+
+    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+    {
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: None,
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(&pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.dispatch_workgroups(output.width().div_ceil(16), output.height().div_ceil(16), 1);
+    }
+    gpu.queue.submit(Some(encoder.finish()));
+}
@@ -0,0 +1,188 @@
+//! How precisely a document stores its layers, and converting pixels between the texture
+//! layout of each depth and plain samples.
+//!
+//! Layers and running totals are stored in the document's depth and every shader that reads
+//! or writes them is compiled once per depth, see [`BitDepth::shader`]. Masks and selections
+//! stay 8-bit, and so does the output texture, which is what gets shown and exported.
+
+use half::f16;
+use serde::{Deserialize, Serialize};
+use wgpu::*;
+
+use crate::GpuDevice;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum BitDepth {
+    #[default]
+    Eight,
+    Sixteen,
+    /// 16-bit floating point
+    Half,
+    /// 32-bit floating point
+    Float,
+}
+
+/// Every path that creates layers checks their depth first, so the shaders for the depth of
+/// existing layers are always loaded
+pub(crate) const DEPTH_CHECKED: &str = "layer depths are checked before layers are created";
+
+/// The device can't store or blend layers of this depth, so its shader variants aren't loaded
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct UnsupportedBitDepth(pub BitDepth);
+
+impl std::fmt::Display for UnsupportedBitDepth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} layers are not supported by this GPU", self.0.name())
+    }
+}
+
+impl std::error::Error for UnsupportedBitDepth {}
+
+impl BitDepth {
+    pub const ALL: [BitDepth; 4] = [
+        BitDepth::Eight,
+        BitDepth::Sixteen,
+        BitDepth::Half,
+        BitDepth::Float,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BitDepth::Eight => "8-bit",
+            BitDepth::Sixteen => "16-bit",
+            BitDepth::Half => "16-bit float",
+            BitDepth::Float => "32-bit float",
+        }
+    }
+
+    pub fn texture_format(self) -> TextureFormat {
+        match self {
+            BitDepth::Eight => TextureFormat::Rgba8Unorm,
+            BitDepth::Sixteen => TextureFormat::Rgba16Unorm,
+            BitDepth::Half => TextureFormat::Rgba16Float,
+            BitDepth::Float => TextureFormat::Rgba32Float,
+        }
+    }
+
+    /// How storage textures of this depth are declared in WGSL
+    pub fn storage_format(self) -> &'static str {
+        match self {
+            BitDepth::Eight => "rgba8unorm",
+            BitDepth::Sixteen => "rgba16unorm",
+            BitDepth::Half => "rgba16float",
+            BitDepth::Float => "rgba32float",
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> u32 {
+        match self {
+            BitDepth::Eight => 4,
+            BitDepth::Sixteen | BitDepth::Half => 8,
+            BitDepth::Float => 16,
+        }
+    }
+
+    pub fn is_float(self) -> bool {
+        matches!(self, BitDepth::Half | BitDepth::Float)
+    }
+
+    /// The depth of a layer or running total texture
+    pub fn of(texture: &Texture) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|depth| depth.texture_format() == texture.format())
+            .unwrap_or_else(|| panic!("{:?} is not a layer format", texture.format()))
+    }
+
+    /// Name of the variant of `shader` whose `rgba8unorm` storage textures are declared in
+    /// this depth instead. The build script compiles one for every such shader.
+    pub fn shader(self, shader: &str) -> String {
+        match self {
+            BitDepth::Eight => shader.to_string(),
+            _ => format!("{}.{}", shader, self.storage_format()),
+        }
+    }
+
+    /// Whether layers of this depth can be created and blended on the device. Reading storage
+    /// textures needs read-write support for their format in wgpu.
+    pub fn is_supported(self, gpu: &GpuDevice) -> bool {
+        // the shaders were written for 8-bit, any adapter that runs them at all will do
+        if self == BitDepth::Eight {
+            return true;
+        }
+
+        let format = self.texture_format();
+        let features = gpu.adapter.get_texture_format_features(format);
+        gpu.device.features().contains(format.required_features())
+            && features
+                .allowed_usages
+                .contains(TextureUsages::STORAGE_BINDING)
+            && features
+                .flags
+                .contains(TextureFormatFeatureFlags::STORAGE_READ_WRITE)
+    }
+
+    /// [`BitDepth::is_supported`] for paths that create layers of this depth
+    pub fn check(self, gpu: &GpuDevice) -> Result<(), UnsupportedBitDepth> {
+        match self.is_supported(gpu) {
+            true => Ok(()),
+            false => Err(UnsupportedBitDepth(self)),
+        }
+    }
+
+    /// Converts texture data of this depth to samples, 0 being black and 1 white
+    pub fn decode(self, data: &[u8]) -> Vec<f32> {
+        match self {
+            BitDepth::Eight => data.iter().map(|&value| value as f32 / 255.0).collect(),
+            BitDepth::Sixteen => data
+                .chunks_exact(2)
+                .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 65535.0)
+                .collect(),
+            BitDepth::Half => data
+                .chunks_exact(2)
+                .map(|bytes| f16::from_le_bytes([bytes[0], bytes[1]]).to_f32())
+                .collect(),
+            BitDepth::Float => data
+                .chunks_exact(4)
+                .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+                .collect(),
+        }
+    }
+
+    /// Converts samples to texture data of this depth, clamping them between 0 and 1
+    pub fn encode(self, samples: &[f32]) -> Vec<u8> {
+        let samples = samples.iter().map(|sample| sample.clamp(0.0, 1.0));
+        match self {
+            BitDepth::Eight => samples
+                .map(|sample| (sample * 255.0).round() as u8)
+                .collect(),
+            BitDepth::Sixteen => samples
+                .flat_map(|sample| ((sample * 65535.0).round() as u16).to_le_bytes())
+                .collect(),
+            BitDepth::Half => samples
+                .flat_map(|sample| f16::from_f32(sample).to_le_bytes())
+                .collect(),
+            BitDepth::Float => samples.flat_map(f32::to_le_bytes).collect(),
+        }
+    }
+
+    /// Converts 8-bit samples to texture data of this depth
+    pub fn encode_u8(self, samples: Vec<u8>) -> Vec<u8> {
+        match self {
+            BitDepth::Eight => samples,
+            BitDepth::Sixteen => samples
+                .into_iter()
+                .flat_map(|sample| (sample as u16 * 257).to_le_bytes())
+                .collect(),
+            _ => self.encode(&BitDepth::Eight.decode(&samples)),
+        }
+    }
+
+    /// Converts texture data of this depth to 8-bit samples, rounding to the nearest value
+    pub fn decode_u8(self, data: Vec<u8>) -> Vec<u8> {
+        match self {
+            BitDepth::Eight => data,
+            _ => BitDepth::Eight.encode(&self.decode(&data)),
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Operations on the document as a whole rather than on single layers
+
+use image::{
+    imageops::{self, FilterType},
+    ImageBuffer, Rgba,
+};
+
+use super::{
+    bit_depth::{BitDepth, UnsupportedBitDepth},
+    copy_texture,
+    history::Command,
+    LayerCreationInfo, Workspace,
+};
+use crate::GpuDevice;
+
+impl Workspace {
+    /// Replaces every layer with a single layer holding the composite
+    pub async fn flatten(&mut self, gpu: &GpuDevice) {
+        // copied from the top running total rather than the output texture to keep the depth
+        let mut info = LayerCreationInfo {
+            name: "Background".to_string(),
+            ..Default::default()
+        };
+        let data = self.new_layer_data(&mut info, gpu);
+        let top = match self.layer_data.last() {
+            Some(layer) => &layer.running_total,
+            None => self.eternal_blank.as_ref().unwrap(),
+        };
+        copy_texture(gpu, top, &data.texture);
+
+        // removed from the top down so undoing puts them back from the bottom up
+        let mut commands = Vec::with_capacity(self.layers.len() + 1);
+        for index in (0..self.layers.len()).rev() {
+            let (info, data) = self.remove_layer_data(index, gpu);
+            if !info.is_tool_layer {
+                commands.push(Command::LayerRemoved { index, info, data });
+            }
+        }
+
+        let index = self.insert_layer_data(info.into(), data, None, gpu);
+        commands.push(Command::LayerInserted { index });
+
+        self.selected_layer = Some(index);
+        self.record(Command::Batch(commands));
+    }
+
+    /// Scales every layer and mask to `size`. This can't be undone and clears the history.
+    /// The output texture is replaced, so anything showing it has to register it again.
+    pub async fn resize(&mut self, size: (u32, u32), filter: FilterType, gpu: &GpuDevice) {
+        let mut layers = Vec::with_capacity(self.layers.len());
+        for data in &self.layer_data {
+            // resampled as floats so deeper documents keep their precision
+            let pixels = gpu.texture_data(&data.texture, self.size.0).await;
+            let image: ImageBuffer<Rgba<f32>, Vec<f32>> =
+                ImageBuffer::from_raw(self.size.0, self.size.1, self.bit_depth.decode(&pixels))
+                    .unwrap();
+            let image = imageops::resize(&image, size.0, size.1, filter);
+            let mask = gpu.texture_to_luma_image(&data.mask, self.size.0).await;
+            layers.push((
+                self.bit_depth.encode(&image.into_raw()),
+                imageops::resize(&mask, size.0, size.1, filter),
+            ));
+        }
+
+        let scale = (
+            size.0 as f32 / self.size.0 as f32,
+            size.1 as f32 / self.size.1 as f32,
+        );
+        self.pixel_at_center = (
+            self.pixel_at_center.0 * scale.0,
+            self.pixel_at_center.1 * scale.1,
+        );
+        self.size = size;
+
+        self.layer_data = layers
+            .into_iter()
+            .map(|(pixels, mask)| {
+                let mut info = LayerCreationInfo {
+                    init_pixels: Some(pixels),
+                    init_mask_image: Some(mask),
+                    ..Default::default()
+                };
+                self.new_layer_data(&mut info, gpu)
+            })
+            .collect();
+
+        self.history.clear();
+        self.history.mark_changed();
+        self.build_output_texture(gpu);
+    }
+
+    /// Converts every layer to `depth`, rounding to the nearest value when there are fewer
+    /// bits and clamping float values outside 0 to 1. This can't be undone and clears the
+    /// history. The output texture is replaced, so anything showing it has to register it
+    /// again.
+    pub async fn set_bit_depth(
+        &mut self,
+        depth: BitDepth,
+        gpu: &GpuDevice,
+    ) -> Result<(), UnsupportedBitDepth> {
+        if depth == self.bit_depth {
+            return Ok(());
+        }
+        depth.check(gpu)?;
+
+        let mut layers = Vec::with_capacity(self.layers.len());
+        for data in &self.layer_data {
+            let pixels = gpu.texture_data(&data.texture, self.size.0).await;
+            layers.push(depth.encode(&self.bit_depth.decode(&pixels)));
+        }
+        self.bit_depth = depth;
+
+        let old_data = std::mem::take(&mut self.layer_data);
+        self.layer_data = old_data
+            .into_iter()
+            .zip(layers)
+            .map(|(data, pixels)| {
+                let mut info = LayerCreationInfo {
+                    init_pixels: Some(pixels),
+                    init_mask_texture: Some(data.mask),
+                    ..Default::default()
+                };
+                self.new_layer_data(&mut info, gpu)
+            })
+            .collect();
+
+        self.history.clear();
+        self.history.mark_changed();
+        self.build_output_texture(gpu);
+        Ok(())
+    }
+}
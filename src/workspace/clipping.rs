@@ -0,0 +1,114 @@
+//! Clipping masks, where a run of clipped layers only shows through the coverage of the
+//! unclipped layer right below the run.
+
+use wgpu::*;
+
+use super::{
+    bit_depth::{BitDepth, DEPTH_CHECKED},
+    LayerData, LayerInfo, Workspace,
+};
+use crate::GpuDevice;
+
+/// The layer that the layer at `index` is clipped to, if it is clipped. Only pixel and
+/// adjustment layers can be clipped, and only to a pixel layer, otherwise the flag is
+/// ignored.
+pub fn clip_base(layers: &[LayerInfo], index: usize) -> Option<usize> {
+    let clippable = |i: usize| layers[i].kind.is_pixel() || layers[i].kind.is_adjustment();
+    if !layers[index].clipped || !clippable(index) {
+        return None;
+    }
+
+    let base = (0..index)
+        .rev()
+        .find(|&i| !(layers[i].clipped && clippable(i)))?;
+    layers[base].kind.is_pixel().then_some(base)
+}
+
+impl Workspace {
+    pub fn clip_base(&self, index: usize) -> Option<usize> {
+        clip_base(&self.layers, index)
+    }
+
+    /// Writes the mask of a clipped layer multiplied by its base's alpha and mask into `out`
+    pub(super) fn build_clip_mask(
+        gpu: &GpuDevice,
+        base: &LayerData,
+        mask: &Texture,
+        out: &Texture,
+    ) {
+        let device = &gpu.device;
+
+        let storage_entry = |binding, access, format| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::StorageTexture {
+                access,
+                format,
+                view_dimension: TextureViewDimension::D2,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                storage_entry(0, StorageTextureAccess::ReadOnly, base.texture.format()),
+                storage_entry(1, StorageTextureAccess::ReadOnly, TextureFormat::R8Unorm),
+                storage_entry(2, StorageTextureAccess::ReadOnly, TextureFormat::R8Unorm),
+                storage_entry(3, StorageTextureAccess::WriteOnly, TextureFormat::R8Unorm),
+            ],
+        });
+
+        let views = [&base.texture, &base.mask, mask, out]
+            .map(|texture| texture.create_view(&TextureViewDescriptor::default()));
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&views[0]),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(&views[1]),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::TextureView(&views[2]),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: BindingResource::TextureView(&views[3]),
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: None,
+            layout: Some(&pipeline_layout),
+            module: gpu
+                .layer_shader("clipping/clip_mask", BitDepth::of(&base.texture))
+                .expect(DEPTH_CHECKED),
+            entry_point: "main",
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        {
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: None,
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(out.width().div_ceil(16), out.height().div_ceil(16), 1);
+        }
+        gpu.queue.submit(Some(encoder.finish()));
+    }
+}
@@ -0,0 +1,222 @@
+//! The on-disk layout of `.jc` files.
+//!
+//! A file starts with [`MAGIC`] and a little endian `u32` format version, followed by a
+//! `u32` length prefixed, bincode encoded chunk table and then the chunk payloads. Every
+//! chunk has a [`ChunkKind`] and a name, so readers can look chunks up without relying on
+//! their order, and unknown chunks can be skipped by older readers.
+//!
+//! Files written before the header existed (version 1) are a `u32` length, a bincode blob
+//! of the workspace and then length prefixed PNGs alternating between layer pixels and
+//! layer masks. [`Container::from_bytes`] converts those into the same chunk layout.
+
+use serde::{Deserialize, Serialize};
+
+use super::WorkspaceLoadError;
+
+pub const MAGIC: [u8; 8] = *b"JOYCRT\r\n";
+
+/// The version of the headerless files written before the container existed
+pub const LEGACY_VERSION: u32 = 1;
+/// Bumped whenever the metadata layout or the way layers render changes, see `migrations`
+pub const FORMAT_VERSION: u32 = 7;
+
+pub const METADATA_CHUNK: &str = "workspace";
+pub const THUMBNAIL_CHUNK: &str = "thumbnail";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChunkKind {
+    /// bincode encoded `Workspace`, in the layout of the file's format version
+    Metadata,
+    /// PNG encoded RGBA pixels of a layer, with 16 bits per channel in 16-bit documents
+    LayerPixels,
+    /// PNG encoded grayscale mask of a layer
+    LayerMask,
+    /// PNG encoded preview of the composited image
+    Thumbnail,
+    /// Pixels of a layer in a floating point document, stored the way its textures hold them:
+    /// rows without padding of little endian RGBA floats
+    LayerFloatPixels,
+}
+
+pub fn layer_pixels_chunk(index: usize) -> String {
+    format!("layers/{}/pixels", index)
+}
+
+pub fn layer_mask_chunk(index: usize) -> String {
+    format!("layers/{}/mask", index)
+}
+
+pub struct Chunk {
+    pub kind: ChunkKind,
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChunkEntry {
+    kind: ChunkKind,
+    name: String,
+    offset: u64,
+    len: u64,
+}
+
+pub struct Container {
+    pub version: u32,
+    pub chunks: Vec<Chunk>,
+}
+
+impl Default for Container {
+    fn default() -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            chunks: Vec::new(),
+        }
+    }
+}
+
+impl Container {
+    pub fn push(&mut self, kind: ChunkKind, name: impl Into<String>, data: Vec<u8>) {
+        self.chunks.push(Chunk {
+            kind,
+            name: name.into(),
+            data,
+        });
+    }
+
+    pub fn chunk(&self, kind: ChunkKind, name: &str) -> Option<&Chunk> {
+        self.chunks
+            .iter()
+            .find(|chunk| chunk.kind == kind && chunk.name == name)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut offset = 0;
+        let table = self
+            .chunks
+            .iter()
+            .map(|chunk| {
+                let entry = ChunkEntry {
+                    kind: chunk.kind,
+                    name: chunk.name.clone(),
+                    offset,
+                    len: chunk.data.len() as u64,
+                };
+                offset += chunk.data.len() as u64;
+                entry
+            })
+            .collect::<Vec<_>>();
+        let table = bincode::serialize(&table).unwrap();
+
+        let mut data = Vec::with_capacity(16 + table.len() + offset as usize);
+        data.extend_from_slice(&MAGIC);
+        data.extend_from_slice(&self.version.to_le_bytes());
+        data.extend_from_slice(&(table.len() as u32).to_le_bytes());
+        data.extend_from_slice(&table);
+        for chunk in self.chunks.iter() {
+            data.extend_from_slice(&chunk.data);
+        }
+
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, WorkspaceLoadError> {
+        if !data.starts_with(&MAGIC) {
+            return Self::from_legacy_bytes(data);
+        }
+
+        let mut reader = ByteReader(&data[MAGIC.len()..]);
+        let version = reader
+            .read_u32()
+            .ok_or(WorkspaceLoadError::TruncatedHeader)?;
+        if version > FORMAT_VERSION {
+            return Err(WorkspaceLoadError::UnsupportedVersion(version));
+        }
+
+        let table_len = reader
+            .read_u32()
+            .ok_or(WorkspaceLoadError::TruncatedHeader)? as usize;
+        let table = reader
+            .take(table_len)
+            .ok_or(WorkspaceLoadError::TruncatedHeader)?;
+        let table: Vec<ChunkEntry> = bincode::deserialize(table)?;
+        let payload = reader.0;
+
+        let chunks = table
+            .into_iter()
+            .map(|entry| {
+                let start = entry.offset as usize;
+                let end = start.checked_add(entry.len as usize);
+                let data = end
+                    .and_then(|end| payload.get(start..end))
+                    .ok_or_else(|| WorkspaceLoadError::TruncatedChunk(entry.name.clone()))?;
+
+                Ok(Chunk {
+                    kind: entry.kind,
+                    name: entry.name,
+                    data: data.to_vec(),
+                })
+            })
+            .collect::<Result<Vec<_>, WorkspaceLoadError>>()?;
+
+        Ok(Self { version, chunks })
+    }
+
+    fn from_legacy_bytes(data: &[u8]) -> Result<Self, WorkspaceLoadError> {
+        let mut reader = ByteReader(data);
+        let mut this = Self {
+            version: LEGACY_VERSION,
+            chunks: Vec::new(),
+        };
+
+        let metadata = reader
+            .read_chunk()
+            .ok_or(WorkspaceLoadError::TruncatedHeader)?;
+        this.push(ChunkKind::Metadata, METADATA_CHUNK, metadata);
+
+        let mut index = 0;
+        while !reader.0.is_empty() {
+            let name = layer_pixels_chunk(index);
+            let pixels = reader
+                .read_chunk()
+                .ok_or_else(|| WorkspaceLoadError::TruncatedChunk(name.clone()))?;
+            this.push(ChunkKind::LayerPixels, name, pixels);
+
+            if reader.0.is_empty() {
+                break;
+            }
+            let name = layer_mask_chunk(index);
+            let mask = reader
+                .read_chunk()
+                .ok_or_else(|| WorkspaceLoadError::TruncatedChunk(name.clone()))?;
+            this.push(ChunkKind::LayerMask, name, mask);
+
+            index += 1;
+        }
+
+        Ok(this)
+    }
+}
+
+struct ByteReader<'a>(&'a [u8]);
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn read_u32(&mut self) -> Option<u32> {
+        let bytes = self.take(4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Reads a `u32` length prefixed chunk, as used by legacy files
+    fn read_chunk(&mut self) -> Option<Vec<u8>> {
+        let len = self.read_u32()? as usize;
+        self.take(len).map(<[u8]>::to_vec)
+    }
+}
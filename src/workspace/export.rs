@@ -0,0 +1,156 @@
+//! Writing the flattened composite to common image formats
+
+use std::{fs::File, io::BufWriter, path::Path};
+
+use image::{
+    codecs::{
+        jpeg::JpegEncoder,
+        png::{CompressionType, FilterType, PngEncoder},
+    },
+    ExtendedColorType, ImageBuffer, ImageEncoder, ImageResult, Rgb, Rgba,
+};
+
+use super::Workspace;
+use crate::GpuDevice;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    #[default]
+    Png,
+    Jpeg,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 2] = [ExportFormat::Png, ExportFormat::Jpeg];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ExportFormat::Png => "PNG",
+            ExportFormat::Jpeg => "JPEG",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Png => "png",
+            ExportFormat::Jpeg => "jpg",
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_lowercase().as_str() {
+            "png" => Some(ExportFormat::Png),
+            "jpg" | "jpeg" => Some(ExportFormat::Jpeg),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format from the extension of `path`
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+        Self::from_extension(path.as_ref().extension()?.to_str()?)
+    }
+
+    pub fn supports_alpha(self) -> bool {
+        self == ExportFormat::Png
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum PngCompression {
+    Fast,
+    #[default]
+    Default,
+    Best,
+}
+
+impl PngCompression {
+    pub const ALL: [PngCompression; 3] = [
+        PngCompression::Fast,
+        PngCompression::Default,
+        PngCompression::Best,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PngCompression::Fast => "Fast",
+            PngCompression::Default => "Default",
+            PngCompression::Best => "Best",
+        }
+    }
+
+    fn compression_type(self) -> CompressionType {
+        match self {
+            PngCompression::Fast => CompressionType::Fast,
+            PngCompression::Default => CompressionType::Default,
+            PngCompression::Best => CompressionType::Best,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExportOptions {
+    /// From 1 to 100
+    pub jpeg_quality: u8,
+    pub png_compression: PngCompression,
+    /// When off, or for formats without alpha, the image is flattened onto white
+    pub keep_alpha: bool,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            jpeg_quality: 90,
+            png_compression: PngCompression::Default,
+            keep_alpha: true,
+        }
+    }
+}
+
+impl Workspace {
+    /// Writes the composite of all visible layers to `path`
+    pub async fn export(
+        &self,
+        path: &str,
+        format: ExportFormat,
+        options: &ExportOptions,
+        gpu: &GpuDevice,
+    ) -> ImageResult<()> {
+        #[cfg(debug_assertions)]
+        println!("Exporting workspace to {}...", path);
+
+        let output = self.output_texture.as_ref().unwrap();
+        let image = gpu.texture_to_image(output, self.size.0).await;
+        let (width, height) = image.dimensions();
+        let (data, color_type) = if options.keep_alpha && format.supports_alpha() {
+            (image.into_raw(), ExtendedColorType::Rgba8)
+        } else {
+            (
+                flatten_onto_white(&image).into_raw(),
+                ExtendedColorType::Rgb8,
+            )
+        };
+
+        let writer = BufWriter::new(File::create(path)?);
+        match format {
+            ExportFormat::Png => PngEncoder::new_with_quality(
+                writer,
+                options.png_compression.compression_type(),
+                FilterType::Adaptive,
+            )
+            .write_image(&data, width, height, color_type),
+            ExportFormat::Jpeg => {
+                JpegEncoder::new_with_quality(writer, options.jpeg_quality.clamp(1, 100))
+                    .write_image(&data, width, height, color_type)
+            }
+        }
+    }
+}
+
+fn flatten_onto_white(image: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+        let Rgba([r, g, b, a]) = *image.get_pixel(x, y);
+        let blend =
+            |channel: u8| ((channel as u32 * a as u32 + 255 * (255 - a as u32) + 127) / 255) as u8;
+        Rgb([blend(r), blend(g), blend(b)])
+    })
+}
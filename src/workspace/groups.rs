@@ -0,0 +1,172 @@
+//! Layer groups, stored inline in the layer stack between a `LayerKind::GroupEnd` and the
+//! `LayerKind::Group` above it.
+
+use super::{history::Command, GroupMode, LayerCreationInfo, LayerInfo, LayerKind, Workspace};
+use crate::GpuDevice;
+
+/// The index of the `GroupEnd` matching the group at `header`
+pub fn group_end(layers: &[LayerInfo], header: usize) -> usize {
+    let mut depth = 0;
+    for i in (0..header).rev() {
+        match layers[i].kind {
+            LayerKind::Group { .. } => depth += 1,
+            LayerKind::GroupEnd if depth == 0 => return i,
+            LayerKind::GroupEnd => depth -= 1,
+            LayerKind::Pixel | LayerKind::Adjustment(_) => (),
+        }
+    }
+    panic!("group at {} has no end", header);
+}
+
+/// The index of the group closed by the `GroupEnd` at `end`
+pub fn group_header(layers: &[LayerInfo], end: usize) -> usize {
+    let mut depth = 0;
+    for (i, info) in layers.iter().enumerate().skip(end + 1) {
+        match info.kind {
+            LayerKind::GroupEnd => depth += 1,
+            LayerKind::Group { .. } if depth == 0 => return i,
+            LayerKind::Group { .. } => depth -= 1,
+            LayerKind::Pixel | LayerKind::Adjustment(_) => (),
+        }
+    }
+    panic!("group end at {} has no group", end);
+}
+
+/// The first layer whose group marker has no partner, if any. Everything else assumes that
+/// every `Group` has a `GroupEnd` below it, so stacks read from files are checked with this.
+pub fn unbalanced_group(layers: &[LayerInfo]) -> Option<usize> {
+    let mut open_ends = Vec::new();
+    for (i, info) in layers.iter().enumerate() {
+        match info.kind {
+            LayerKind::GroupEnd => open_ends.push(i),
+            LayerKind::Group { .. } if open_ends.pop().is_none() => return Some(i),
+            LayerKind::Group { .. } | LayerKind::Pixel | LayerKind::Adjustment(_) => (),
+        }
+    }
+    open_ends.first().copied()
+}
+
+impl Workspace {
+    pub fn group_end(&self, header: usize) -> usize {
+        group_end(&self.layers, header)
+    }
+
+    pub fn group_header(&self, end: usize) -> usize {
+        group_header(&self.layers, end)
+    }
+
+    /// Creates a group around the layer or group at `around`, or an empty group on top of the
+    /// stack. Returns the index of the new group.
+    pub fn create_group(
+        &mut self,
+        name: String,
+        mode: GroupMode,
+        around: Option<usize>,
+        gpu: &GpuDevice,
+    ) -> usize {
+        // the header goes above the wrapped layers, which move up by one for the end marker
+        let (bottom, header_index) = match around {
+            Some(i) if self.layers[i].kind.is_group() => (self.group_end(i), i + 2),
+            Some(i) => (i, i + 2),
+            None => (self.layers.len(), self.layers.len() + 1),
+        };
+
+        let mut end = LayerCreationInfo {
+            name: format!("{} end", name),
+            kind: LayerKind::GroupEnd,
+            ..Default::default()
+        };
+        let end_data = self.new_layer_data(&mut end, gpu);
+        self.insert_layer_data(end.into(), end_data, Some(bottom), gpu);
+
+        let mut header = LayerCreationInfo {
+            name,
+            kind: LayerKind::Group {
+                mode,
+                collapsed: false,
+            },
+            init_mask_luma: Some(255),
+            ..Default::default()
+        };
+        let header_data = self.new_layer_data(&mut header, gpu);
+        self.insert_layer_data(header.into(), header_data, Some(header_index), gpu);
+
+        self.record(Command::Batch(vec![
+            Command::LayerInserted { index: bottom },
+            Command::LayerInserted {
+                index: header_index,
+            },
+        ]));
+        self.selected_layer = Some(header_index);
+
+        header_index
+    }
+
+    /// Deletes a group along with everything in it
+    pub fn delete_group(&mut self, header: usize, gpu: &GpuDevice) {
+        let end = self.group_end(header);
+
+        let mut commands = Vec::new();
+        for index in (end..=header).rev() {
+            let (info, data) = self.remove_layer_data(index, gpu);
+            commands.push(Command::LayerRemoved { index, info, data });
+        }
+        self.record(Command::Batch(commands));
+    }
+
+    /// Collapsing only affects the layers panel, so it is not recorded in the history
+    pub fn set_group_collapsed(&mut self, header: usize, collapsed: bool) {
+        if let LayerKind::Group { mode, .. } = self.layers[header].kind {
+            self.layers[header].kind = LayerKind::Group { mode, collapsed };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stack(kinds: &[LayerKind]) -> Vec<LayerInfo> {
+        kinds
+            .iter()
+            .map(|kind| {
+                LayerInfo::from(LayerCreationInfo {
+                    kind: kind.clone(),
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+
+    fn group() -> LayerKind {
+        LayerKind::Group {
+            mode: GroupMode::Isolated,
+            collapsed: false,
+        }
+    }
+
+    #[test]
+    fn nested_groups_are_balanced() {
+        let layers = stack(&[
+            LayerKind::Pixel,
+            LayerKind::GroupEnd,
+            LayerKind::GroupEnd,
+            LayerKind::Pixel,
+            group(),
+            group(),
+            LayerKind::Pixel,
+        ]);
+        assert_eq!(unbalanced_group(&layers), None);
+        assert_eq!(group_end(&layers, 4), 2);
+        assert_eq!(group_header(&layers, 1), 5);
+    }
+
+    #[test]
+    fn finds_markers_without_partner() {
+        let lone_header = stack(&[LayerKind::Pixel, group()]);
+        assert_eq!(unbalanced_group(&lone_header), Some(1));
+
+        let lone_end = stack(&[LayerKind::GroupEnd, LayerKind::GroupEnd, group()]);
+        assert_eq!(unbalanced_group(&lone_end), Some(0));
+    }
+}
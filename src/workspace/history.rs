@@ -0,0 +1,480 @@
+//! Undo/redo history for workspace mutations.
+//!
+//! Every [`Command`] describes a change that has already been applied to the workspace.
+//! Reverting a command undoes that change and returns the command describing the revert,
+//! which is what the opposite stack stores. Undoing pushes onto the redo stack and redoing
+//! pushes back onto the undo stack, so each change only needs one implementation.
+
+use std::collections::VecDeque;
+
+use wgpu::*;
+
+use super::{LayerData, LayerInfo, Workspace};
+use crate::GpuDevice;
+
+/// 512 MiB of snapshots and removed layers
+pub const DEFAULT_MEMORY_BUDGET: usize = 512 * 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    /// The square covering a circle, clamped to the workspace
+    pub fn around(center: (f32, f32), radius: f32, size: (u32, u32)) -> Option<Self> {
+        Self::covering(
+            (center.0 - radius, center.1 - radius),
+            (center.0 + radius, center.1 + radius),
+            size,
+        )
+    }
+
+    /// The pixels touched by the rectangle from `min` to `max`, clamped to the workspace
+    pub fn covering(min: (f32, f32), max: (f32, f32), size: (u32, u32)) -> Option<Self> {
+        let min_x = min.0.floor().max(0.0) as u32;
+        let min_y = min.1.floor().max(0.0) as u32;
+        let max_x = (max.0.ceil().max(0.0) as u32).min(size.0);
+        let max_y = (max.1.ceil().max(0.0) as u32).min(size.1);
+
+        if min_x >= max_x || min_y >= max_y {
+            return None;
+        }
+
+        Some(Self {
+            x: min_x,
+            y: min_y,
+            width: max_x - min_x,
+            height: max_y - min_y,
+        })
+    }
+
+    pub fn whole(size: (u32, u32)) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width: size.0,
+            height: size.1,
+        }
+    }
+
+    fn at_origin(region: Region) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            ..region
+        }
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Self {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+}
+
+/// A copy of part of a layer's texture and mask, kept on the GPU
+pub struct RegionSnapshot {
+    region: Region,
+    texture: Texture,
+    mask: Texture,
+}
+
+impl RegionSnapshot {
+    pub fn capture(layer: &LayerData, region: Region, gpu: &GpuDevice) -> Self {
+        let texture = create_snapshot_texture(gpu, region, layer.texture.format());
+        let mask = create_snapshot_texture(gpu, region, layer.mask.format());
+
+        let mut encoder = gpu
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor { label: None });
+        copy_region(
+            &mut encoder,
+            &layer.texture,
+            region,
+            &texture,
+            Region::at_origin(region),
+        );
+        copy_region(
+            &mut encoder,
+            &layer.mask,
+            region,
+            &mask,
+            Region::at_origin(region),
+        );
+        gpu.queue.submit(Some(encoder.finish()));
+
+        Self {
+            region,
+            texture,
+            mask,
+        }
+    }
+
+    /// Writes the snapshot back into the layer
+    pub fn restore(&self, layer: &LayerData, gpu: &GpuDevice) {
+        let mut encoder = gpu
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor { label: None });
+        let source = Region::at_origin(self.region);
+        copy_region(
+            &mut encoder,
+            &self.texture,
+            source,
+            &layer.texture,
+            self.region,
+        );
+        copy_region(&mut encoder, &self.mask, source, &layer.mask, self.region);
+        gpu.queue.submit(Some(encoder.finish()));
+    }
+
+    /// Copies the part of the snapshot inside `region`, which must lie within the snapshot
+    pub fn crop(&self, region: Region, gpu: &GpuDevice) -> Self {
+        let texture = create_snapshot_texture(gpu, region, self.texture.format());
+        let mask = create_snapshot_texture(gpu, region, self.mask.format());
+
+        let source = Region {
+            x: region.x - self.region.x,
+            y: region.y - self.region.y,
+            ..region
+        };
+        let mut encoder = gpu
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor { label: None });
+        copy_region(
+            &mut encoder,
+            &self.texture,
+            source,
+            &texture,
+            Region::at_origin(region),
+        );
+        copy_region(
+            &mut encoder,
+            &self.mask,
+            source,
+            &mask,
+            Region::at_origin(region),
+        );
+        gpu.queue.submit(Some(encoder.finish()));
+
+        Self {
+            region,
+            texture,
+            mask,
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn texture(&self) -> &Texture {
+        &self.texture
+    }
+
+    pub fn mask(&self) -> &Texture {
+        &self.mask
+    }
+
+    pub fn memory_size(&self) -> usize {
+        texture_memory_size(&self.texture) + texture_memory_size(&self.mask)
+    }
+}
+
+pub enum Command {
+    LayerInserted {
+        index: usize,
+    },
+    LayerRemoved {
+        index: usize,
+        info: LayerInfo,
+        data: Box<LayerData>,
+    },
+    LayerMoved {
+        from: usize,
+        to: usize,
+    },
+    /// A change to a layer's name, visibility, opacity or blend mode
+    LayerInfoChanged {
+        index: usize,
+        previous: LayerInfo,
+    },
+    /// A change to the pixels of a layer, holding what they were before the change
+    PixelsChanged {
+        index: usize,
+        previous: Box<RegionSnapshot>,
+    },
+    /// Several changes that are undone together, in the order they were made
+    Batch(Vec<Command>),
+}
+
+impl Command {
+    /// Undoes the command, returning the command that undoes the revert
+    fn revert(self, workspace: &mut Workspace, gpu: &GpuDevice) -> Self {
+        match self {
+            Command::LayerInserted { index } => {
+                let (info, data) = workspace.remove_layer_data(index, gpu);
+                Command::LayerRemoved { index, info, data }
+            }
+            Command::LayerRemoved { index, info, data } => {
+                workspace.insert_layer_data(info, data, Some(index), gpu);
+                Command::LayerInserted { index }
+            }
+            Command::LayerMoved { from, to } => {
+                workspace.move_layer_data(to, from, gpu);
+                Command::LayerMoved { from: to, to: from }
+            }
+            Command::LayerInfoChanged { index, previous } => {
+                let current = std::mem::replace(&mut workspace.layers[index], previous);
+                workspace.recalculate_output_texture(gpu, index);
+                Command::LayerInfoChanged {
+                    index,
+                    previous: current,
+                }
+            }
+            Command::PixelsChanged { index, previous } => {
+                let layer = &workspace.layer_data[index];
+                let current = Box::new(RegionSnapshot::capture(layer, previous.region, gpu));
+                previous.restore(layer, gpu);
+                workspace.recalculate_output_texture(gpu, index);
+                Command::PixelsChanged {
+                    index,
+                    previous: current,
+                }
+            }
+            Command::Batch(commands) => Command::Batch(
+                commands
+                    .into_iter()
+                    .rev()
+                    .map(|command| command.revert(workspace, gpu))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn memory_size(&self) -> usize {
+        match self {
+            Command::LayerRemoved { data, .. } => {
+                texture_memory_size(&data.texture)
+                    + texture_memory_size(&data.mask)
+                    + texture_memory_size(&data.running_total)
+            }
+            Command::PixelsChanged { previous, .. } => previous.memory_size(),
+            Command::Batch(commands) => commands.iter().map(Command::memory_size).sum(),
+            _ => std::mem::size_of::<Self>(),
+        }
+    }
+}
+
+/// Each command is stored with the state the workspace is in after reverting it
+pub struct History {
+    undo_stack: VecDeque<(u64, Command)>,
+    redo_stack: Vec<(u64, Command)>,
+    memory_budget: usize,
+    memory_used: usize,
+    /// Identifies the current contents of the workspace. Every new change gets a new state,
+    /// undoing and redoing return to earlier ones.
+    state: u64,
+    next_state: u64,
+    saved_state: Option<u64>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(DEFAULT_MEMORY_BUDGET)
+    }
+}
+
+impl History {
+    pub fn new(memory_budget: usize) -> Self {
+        Self {
+            undo_stack: VecDeque::new(),
+            redo_stack: Vec::new(),
+            memory_budget,
+            memory_used: 0,
+            state: 0,
+            next_state: 1,
+            saved_state: Some(0),
+        }
+    }
+
+    pub fn memory_budget(&self) -> usize {
+        self.memory_budget
+    }
+
+    pub fn set_memory_budget(&mut self, memory_budget: usize) {
+        self.memory_budget = memory_budget;
+        self.enforce_budget();
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.memory_used
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
+    /// Forgets every change. The workspace is still modified if it was before.
+    pub fn clear(&mut self) {
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.memory_used = 0;
+    }
+
+    /// Whether the workspace differs from when [`History::mark_saved`] was last called
+    pub fn is_modified(&self) -> bool {
+        self.saved_state != Some(self.state)
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.saved_state = Some(self.state);
+    }
+
+    /// Notes a change that can't be undone, so the workspace counts as modified
+    pub fn mark_changed(&mut self) {
+        self.state = self.next_state;
+        self.next_state += 1;
+    }
+
+    /// Records a change that has just been applied, discarding anything that could be redone
+    pub fn push(&mut self, command: Command) {
+        for (_, command) in self.redo_stack.drain(..) {
+            self.memory_used -= command.memory_size();
+        }
+
+        self.memory_used += command.memory_size();
+        self.undo_stack.push_back((self.state, command));
+        self.mark_changed();
+        self.enforce_budget();
+    }
+
+    /// Drops the oldest changes until the history fits in its budget. The most recent change
+    /// is always kept so that it can be undone.
+    fn enforce_budget(&mut self) {
+        while self.memory_used > self.memory_budget && self.undo_stack.len() > 1 {
+            let (_, command) = self.undo_stack.pop_front().unwrap();
+            self.memory_used -= command.memory_size();
+        }
+    }
+}
+
+impl Workspace {
+    pub fn record(&mut self, command: Command) {
+        self.history.push(command);
+    }
+
+    /// Returns whether there was anything to undo
+    pub fn undo(&mut self, gpu: &GpuDevice) -> bool {
+        let Some((state, command)) = self.history.undo_stack.pop_back() else {
+            return false;
+        };
+        self.history.memory_used -= command.memory_size();
+
+        let inverse = command.revert(self, gpu);
+        self.history.memory_used += inverse.memory_size();
+        self.history.redo_stack.push((self.history.state, inverse));
+        self.history.state = state;
+        true
+    }
+
+    /// Returns whether there was anything to redo
+    pub fn redo(&mut self, gpu: &GpuDevice) -> bool {
+        let Some((state, command)) = self.history.redo_stack.pop() else {
+            return false;
+        };
+        self.history.memory_used -= command.memory_size();
+
+        let inverse = command.revert(self, gpu);
+        self.history.memory_used += inverse.memory_size();
+        self.history
+            .undo_stack
+            .push_back((self.history.state, inverse));
+        self.history.state = state;
+        self.history.enforce_budget();
+        true
+    }
+
+    /// Captures a region of a layer before it is painted on. Record the returned command once
+    /// the change has been made.
+    pub fn snapshot_pixels(&self, index: usize, region: Region, gpu: &GpuDevice) -> Command {
+        Command::PixelsChanged {
+            index,
+            previous: Box::new(RegionSnapshot::capture(
+                &self.layer_data[index],
+                region,
+                gpu,
+            )),
+        }
+    }
+}
+
+fn create_snapshot_texture(gpu: &GpuDevice, region: Region, format: TextureFormat) -> Texture {
+    gpu.device.create_texture(&TextureDescriptor {
+        label: None,
+        size: Extent3d {
+            width: region.width,
+            height: region.height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format,
+        usage: TextureUsages::COPY_SRC | TextureUsages::COPY_DST | TextureUsages::TEXTURE_BINDING,
+        view_formats: &[format],
+    })
+}
+
+fn copy_region(
+    encoder: &mut CommandEncoder,
+    from: &Texture,
+    from_region: Region,
+    to: &Texture,
+    to_region: Region,
+) {
+    encoder.copy_texture_to_texture(
+        ImageCopyTexture {
+            texture: from,
+            mip_level: 0,
+            origin: Origin3d {
+                x: from_region.x,
+                y: from_region.y,
+                z: 0,
+            },
+            aspect: TextureAspect::All,
+        },
+        ImageCopyTexture {
+            texture: to,
+            mip_level: 0,
+            origin: Origin3d {
+                x: to_region.x,
+                y: to_region.y,
+                z: 0,
+            },
+            aspect: TextureAspect::All,
+        },
+        Extent3d {
+            width: from_region.width,
+            height: from_region.height,
+            depth_or_array_layers: 1,
+        },
+    );
+}
+
+fn texture_memory_size(texture: &Texture) -> usize {
+    let bytes_per_pixel = texture.format().block_copy_size(None).unwrap_or(4) as usize;
+    texture.width() as usize * texture.height() as usize * bytes_per_pixel
+}
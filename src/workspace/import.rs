@@ -0,0 +1,137 @@
+//! Bringing raster images into the workspace, either as new documents or as layers
+
+use std::path::Path;
+
+use image::{imageops, ImageBuffer, ImageReader, Rgba};
+
+use super::{
+    bit_depth::BitDepth, history::Command, LayerCreationInfo, Workspace, WorkspaceLoadError,
+};
+use crate::GpuDevice;
+
+/// Extensions of the image formats that can be imported
+pub const IMPORT_EXTENSIONS: [&str; 10] = [
+    "png", "jpg", "jpeg", "tif", "tiff", "webp", "bmp", "gif", "tga", "qoi",
+];
+
+pub fn is_importable(path: impl AsRef<Path>) -> bool {
+    path.as_ref()
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            IMPORT_EXTENSIONS
+                .iter()
+                .any(|candidate| candidate.eq_ignore_ascii_case(extension))
+        })
+}
+
+/// Decodes an image, going by its contents rather than its extension. Animated formats give
+/// their first frame. The size is read from the header first, so an image wider or taller
+/// than `max_size` is rejected without decoding it.
+pub fn read_image(
+    path: impl AsRef<Path>,
+    max_size: u32,
+) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, WorkspaceLoadError> {
+    let size = ImageReader::open(&path)?
+        .with_guessed_format()?
+        .into_dimensions()?;
+    check_size(size, max_size)?;
+
+    let reader = ImageReader::open(path)?.with_guessed_format()?;
+    Ok(reader.decode()?.into_rgba8())
+}
+
+fn check_size(size: (u32, u32), max_size: u32) -> Result<(), WorkspaceLoadError> {
+    if size.0 > max_size || size.1 > max_size {
+        return Err(WorkspaceLoadError::InvalidSize(size));
+    }
+    Ok(())
+}
+
+impl Workspace {
+    /// Opens an image as a document with a single layer
+    pub fn import(path: &str, gpu: &GpuDevice) -> Result<Self, WorkspaceLoadError> {
+        let image = read_image(path, gpu.device.limits().max_texture_dimension_2d)?;
+
+        BitDepth::Eight.check(gpu)?;
+        Ok(Self::from_image(image, gpu))
+    }
+
+    /// Reads an image and places it as a new layer, see [`Workspace::place_image`]
+    pub fn import_layer(
+        &mut self,
+        path: &str,
+        offset: (i32, i32),
+        index: Option<usize>,
+        gpu: &GpuDevice,
+    ) -> Result<usize, WorkspaceLoadError> {
+        let image = read_image(path, gpu.device.limits().max_texture_dimension_2d)?;
+        let name = Path::new(path)
+            .file_stem()
+            .map_or("Image".to_string(), |stem| {
+                stem.to_string_lossy().into_owned()
+            });
+
+        self.place_image(&image, name, offset, index, gpu)
+    }
+
+    /// Adds a layer holding `image` with its top left corner at `offset`, cropping whatever
+    /// falls outside the workspace. The layer goes at `index`, or on top of the stack when
+    /// `None`, and its index is returned. Images larger than a texture can be are rejected
+    /// like when importing them.
+    pub fn place_image(
+        &mut self,
+        image: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+        name: String,
+        offset: (i32, i32),
+        index: Option<usize>,
+        gpu: &GpuDevice,
+    ) -> Result<usize, WorkspaceLoadError> {
+        check_size(
+            image.dimensions(),
+            gpu.device.limits().max_texture_dimension_2d,
+        )?;
+
+        let mut layer = ImageBuffer::from_pixel(self.size.0, self.size.1, Rgba([255, 255, 255, 0]));
+        imageops::replace(&mut layer, image, offset.0 as i64, offset.1 as i64);
+
+        let mut info = LayerCreationInfo {
+            name,
+            init_image: Some(layer),
+            ..Default::default()
+        };
+        let data = self.new_layer_data(&mut info, gpu);
+        let index = self.insert_layer_data(info.into(), data, index, gpu);
+        self.record(Command::LayerInserted { index });
+        self.selected_layer = Some(index);
+
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversized_images_are_rejected_before_decoding() {
+        let path = std::env::temp_dir().join(format!(
+            "joyful_create_{}_oversized.png",
+            std::process::id()
+        ));
+        ImageBuffer::from_pixel(40, 2, Rgba([0u8, 0, 0, 255]))
+            .save(&path)
+            .unwrap();
+        // cut off where the pixels start, so only reading the size can succeed
+        let data = std::fs::read(&path).unwrap();
+        let pixels = data.windows(4).position(|chunk| chunk == b"IDAT").unwrap() + 4;
+        std::fs::write(&path, &data[..pixels]).unwrap();
+
+        let result = read_image(&path, 16);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            result,
+            Err(WorkspaceLoadError::InvalidSize((40, 2)))
+        ));
+    }
+}
@@ -1,14 +1,63 @@
 use image::{ImageBuffer, ImageFormat, ImageReader, Luma, Rgba};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use wgpu::Texture;
 
-#[derive(Serialize, Deserialize, Debug)]
+use super::adjustments::Adjustment;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct LayerInfo {
     pub name: String,
     pub visible: bool,
     pub opacity: f32,
     pub blend_mode: BlendMode,
     pub is_tool_layer: bool,
+    pub kind: LayerKind,
+    /// Clipped layers only show where the nearest unclipped layer below them, their base, is
+    /// opaque
+    pub clipped: bool,
+}
+
+/// Groups are stored inline in the layer stack, as a `GroupEnd` below their contents and a
+/// `Group` above them that holds the group's name, opacity, blend mode and mask.
+///
+/// New variants go at the end, so that files written before them still decode.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub enum LayerKind {
+    #[default]
+    Pixel,
+    Group {
+        mode: GroupMode,
+        collapsed: bool,
+    },
+    GroupEnd,
+    /// Holds no pixels of its own, instead recoloring everything below it. The layer's
+    /// texture is scratch space for the adjusted colors.
+    Adjustment(Adjustment),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum GroupMode {
+    /// The contents blend straight into the layers below, as if they weren't grouped. The
+    /// group's opacity and mask fade between the result and the layers below.
+    PassThrough,
+    /// The contents are composited on their own, then blended as a single layer
+    #[default]
+    Isolated,
+}
+
+impl LayerKind {
+    pub fn is_group(&self) -> bool {
+        matches!(self, LayerKind::Group { .. })
+    }
+
+    /// Whether the layer has pixels of its own that tools and filters can edit
+    pub fn is_pixel(&self) -> bool {
+        matches!(self, LayerKind::Pixel)
+    }
+
+    pub fn is_adjustment(&self) -> bool {
+        matches!(self, LayerKind::Adjustment(_))
+    }
 }
 
 pub struct LayerCreationInfo {
@@ -18,11 +67,16 @@ pub struct LayerCreationInfo {
     pub blend_mode: BlendMode,
     pub init_texture: Option<Texture>,
     pub init_image: Option<ImageBuffer<Rgba<u8>, Vec<u8>>>,
+    /// Pixels in the texture layout of the workspace's bit depth, see
+    /// [`BitDepth::decode`](super::bit_depth::BitDepth::decode)
+    pub init_pixels: Option<Vec<u8>>,
     pub init_rgba: Option<[u8; 4]>,
     pub init_mask_texture: Option<Texture>,
     pub init_mask_image: Option<ImageBuffer<Luma<u8>, Vec<u8>>>,
     pub init_mask_luma: Option<u8>,
     pub is_tool_layer: bool,
+    pub kind: LayerKind,
+    pub clipped: bool,
 }
 
 impl Default for LayerCreationInfo {
@@ -31,14 +85,17 @@ impl Default for LayerCreationInfo {
             name: "New Layer".to_string(),
             visible: true,
             opacity: 1.0,
-            blend_mode: "normal".to_string(),
+            blend_mode: BlendMode::Normal,
             init_texture: None,
             init_image: None,
+            init_pixels: None,
             init_rgba: None,
             init_mask_texture: None,
             init_mask_image: None,
             init_mask_luma: None,
             is_tool_layer: false,
+            kind: LayerKind::Pixel,
+            clipped: false,
         }
     }
 }
@@ -51,8 +108,176 @@ impl From<LayerCreationInfo> for LayerInfo {
             opacity: info.opacity,
             blend_mode: info.blend_mode,
             is_tool_layer: info.is_tool_layer,
+            kind: info.kind,
+            clipped: info.clipped,
+        }
+    }
+}
+
+/// How a layer combines with the layers below it. Every mode is a compute shader in
+/// `shaders/blend_modes/` named after [`BlendMode::name`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Dissolve,
+    DarkenOnly,
+    Multiply,
+    ColorBurn,
+    LinearBurn,
+    LightenOnly,
+    Screen,
+    ColorDodge,
+    LinearDodge,
+    Overlay,
+    SoftLight,
+    HardLight,
+    VividLight,
+    LinearLight,
+    PinLight,
+    HardMix,
+    Difference,
+    Exclusion,
+    Subtract,
+    Divide,
+    GrainExtract,
+    GrainMerge,
+    Hue,
+    Saturation,
+    Value,
+    Color,
+    Luminosity,
+}
+
+impl BlendMode {
+    pub const ALL: [BlendMode; 28] = [
+        BlendMode::Normal,
+        BlendMode::Dissolve,
+        BlendMode::DarkenOnly,
+        BlendMode::Multiply,
+        BlendMode::ColorBurn,
+        BlendMode::LinearBurn,
+        BlendMode::LightenOnly,
+        BlendMode::Screen,
+        BlendMode::ColorDodge,
+        BlendMode::LinearDodge,
+        BlendMode::Overlay,
+        BlendMode::SoftLight,
+        BlendMode::HardLight,
+        BlendMode::VividLight,
+        BlendMode::LinearLight,
+        BlendMode::PinLight,
+        BlendMode::HardMix,
+        BlendMode::Difference,
+        BlendMode::Exclusion,
+        BlendMode::Subtract,
+        BlendMode::Divide,
+        BlendMode::GrainExtract,
+        BlendMode::GrainMerge,
+        BlendMode::Hue,
+        BlendMode::Saturation,
+        BlendMode::Value,
+        BlendMode::Color,
+        BlendMode::Luminosity,
+    ];
+
+    /// The name written to files and used to find the shader. Never change these, files
+    /// store blend modes by name.
+    pub fn name(self) -> &'static str {
+        match self {
+            BlendMode::Normal => "normal",
+            BlendMode::Dissolve => "dissolve",
+            BlendMode::DarkenOnly => "darken_only",
+            BlendMode::Multiply => "multiply",
+            BlendMode::ColorBurn => "color_burn",
+            BlendMode::LinearBurn => "linear_burn",
+            BlendMode::LightenOnly => "lighten_only",
+            BlendMode::Screen => "screen",
+            BlendMode::ColorDodge => "color_dodge",
+            BlendMode::LinearDodge => "linear_dodge",
+            BlendMode::Overlay => "overlay",
+            BlendMode::SoftLight => "soft_light",
+            BlendMode::HardLight => "hard_light",
+            BlendMode::VividLight => "vivid_light",
+            BlendMode::LinearLight => "linear_light",
+            BlendMode::PinLight => "pin_light",
+            BlendMode::HardMix => "hard_mix",
+            BlendMode::Difference => "difference",
+            BlendMode::Exclusion => "exclusion",
+            BlendMode::Subtract => "subtract",
+            BlendMode::Divide => "divide",
+            BlendMode::GrainExtract => "grain_extract",
+            BlendMode::GrainMerge => "grain_merge",
+            BlendMode::Hue => "hue",
+            BlendMode::Saturation => "saturation",
+            BlendMode::Value => "value",
+            BlendMode::Color => "color",
+            BlendMode::Luminosity => "luminosity",
         }
     }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|mode| mode.name() == name)
+    }
+
+    /// The name shown in the UI
+    pub fn label(self) -> &'static str {
+        match self {
+            BlendMode::Normal => "Normal",
+            BlendMode::Dissolve => "Dissolve",
+            BlendMode::DarkenOnly => "Darken Only",
+            BlendMode::Multiply => "Multiply",
+            BlendMode::ColorBurn => "Color Burn",
+            BlendMode::LinearBurn => "Linear Burn",
+            BlendMode::LightenOnly => "Lighten Only",
+            BlendMode::Screen => "Screen",
+            BlendMode::ColorDodge => "Color Dodge",
+            BlendMode::LinearDodge => "Linear Dodge",
+            BlendMode::Overlay => "Overlay",
+            BlendMode::SoftLight => "Soft Light",
+            BlendMode::HardLight => "Hard Light",
+            BlendMode::VividLight => "Vivid Light",
+            BlendMode::LinearLight => "Linear Light",
+            BlendMode::PinLight => "Pin Light",
+            BlendMode::HardMix => "Hard Mix",
+            BlendMode::Difference => "Difference",
+            BlendMode::Exclusion => "Exclusion",
+            BlendMode::Subtract => "Subtract",
+            BlendMode::Divide => "Divide",
+            BlendMode::GrainExtract => "Grain Extract",
+            BlendMode::GrainMerge => "Grain Merge",
+            BlendMode::Hue => "Hue",
+            BlendMode::Saturation => "Saturation",
+            BlendMode::Value => "Value",
+            BlendMode::Color => "Color",
+            BlendMode::Luminosity => "Luminosity",
+        }
+    }
+
+    pub fn shader(self) -> String {
+        format!("blend_modes/{}", self.name())
+    }
 }
 
-pub type BlendMode = String;
+/// Unknown names, e.g. from a hand edited or newer file, fall back to normal
+impl From<&str> for BlendMode {
+    fn from(name: &str) -> Self {
+        Self::from_name(name).unwrap_or_else(|| {
+            eprintln!("Unknown blend mode {:?}, using normal", name);
+            BlendMode::Normal
+        })
+    }
+}
+
+// stored as the name, which also keeps the layout of files from when this was a `String`
+impl Serialize for BlendMode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for BlendMode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(String::deserialize(deserializer)?.as_str().into())
+    }
+}
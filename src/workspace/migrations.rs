@@ -0,0 +1,188 @@
+//! Upgrades the metadata chunk of older `.jc` files to the current `Workspace` layout.
+//!
+//! bincode is not self-describing, so every format version whose metadata layout differs
+//! from the current one keeps a frozen copy of its structs here. When `Workspace` or
+//! `LayerInfo` change shape, bump `container::FORMAT_VERSION`, copy the old structs into a
+//! new module and convert them in [`upgrade_metadata`].
+//!
+//! Changes to how layers render can't be migrated, since the pixels of a document are all
+//! that is stored. Documents saved before such a change still open, with warnings about the
+//! layers that look different, see [`rendering_warnings`].
+
+use super::container::{FORMAT_VERSION, LEGACY_VERSION};
+use super::layer_info::{BlendMode, GroupMode, LayerInfo, LayerKind};
+use super::{Workspace, WorkspaceLoadError};
+
+/// The first version blended like `compositor`: every mode fades by the layer's alpha like
+/// Normal, with the formulas of the W3C compositing spec, Photoshop and GIMP, and pass-through
+/// groups fade premultiplied. The metadata layout is the same as in version 6.
+pub const CORRECTED_BLENDING_VERSION: u32 = 7;
+
+pub fn upgrade_metadata(version: u32, metadata: &[u8]) -> Result<Workspace, WorkspaceLoadError> {
+    match version {
+        6 | FORMAT_VERSION => Ok(bincode::deserialize(metadata)?),
+        5 => Ok(bincode::deserialize::<v5::Workspace>(metadata)?.into()),
+        3 | 4 => Ok(bincode::deserialize::<v3::Workspace>(metadata)?.into()),
+        LEGACY_VERSION | 2 => Ok(bincode::deserialize::<v1::Workspace>(metadata)?.into()),
+        _ => Err(WorkspaceLoadError::UnsupportedVersion(version)),
+    }
+}
+
+/// Warnings about the layers of a document of `version` that render differently than when it
+/// was saved
+pub fn rendering_warnings(version: u32, layers: &[LayerInfo]) -> Vec<String> {
+    if version >= CORRECTED_BLENDING_VERSION {
+        return Vec::new();
+    }
+
+    let changed = layers
+        .iter()
+        .filter(|layer| match layer.kind {
+            LayerKind::Group {
+                mode: GroupMode::PassThrough,
+                ..
+            } => true,
+            LayerKind::GroupEnd => false,
+            _ => layer.blend_mode != BlendMode::Normal,
+        })
+        .map(|layer| format!("\"{}\"", layer.name))
+        .collect::<Vec<String>>();
+    match changed.is_empty() {
+        true => Vec::new(),
+        false => vec![format!(
+            "blend modes were corrected after this file was saved, {} may look different",
+            changed.join(", ")
+        )],
+    }
+}
+
+/// The layout of headerless files, written before the container format existed, and of
+/// version 2, which only added the container. Layers could not be grouped yet.
+mod v1 {
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct Workspace {
+        pub size: (u32, u32),
+        pub zoom: f32,
+        pub pixel_at_center: (f32, f32),
+        pub layers: Vec<LayerInfo>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct LayerInfo {
+        pub name: String,
+        pub visible: bool,
+        pub opacity: f32,
+        pub blend_mode: String,
+        pub is_tool_layer: bool,
+    }
+
+    impl From<Workspace> for super::Workspace {
+        fn from(old: Workspace) -> Self {
+            Self {
+                size: old.size,
+                zoom: old.zoom,
+                pixel_at_center: old.pixel_at_center,
+                layers: old.layers.into_iter().map(Into::into).collect(),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl From<LayerInfo> for crate::workspace::LayerInfo {
+        fn from(old: LayerInfo) -> Self {
+            Self {
+                name: old.name,
+                visible: old.visible,
+                opacity: old.opacity,
+                blend_mode: old.blend_mode.as_str().into(),
+                is_tool_layer: old.is_tool_layer,
+                kind: Default::default(),
+                clipped: false,
+            }
+        }
+    }
+}
+
+/// Versions 3 and 4, with groups and, from version 4 on, adjustment layers. Version 4 only
+/// appended `LayerKind::Adjustment`, so both decode the same way. Layers could not be
+/// clipped yet.
+mod v3 {
+    use serde::Deserialize;
+
+    use crate::workspace::layer_info::LayerKind;
+
+    #[derive(Deserialize)]
+    pub struct Workspace {
+        pub size: (u32, u32),
+        pub zoom: f32,
+        pub pixel_at_center: (f32, f32),
+        pub layers: Vec<LayerInfo>,
+    }
+
+    /// `LayerKind` has not changed shape since, copy it in here once it does
+    #[derive(Deserialize)]
+    pub struct LayerInfo {
+        pub name: String,
+        pub visible: bool,
+        pub opacity: f32,
+        pub blend_mode: String,
+        pub is_tool_layer: bool,
+        pub kind: LayerKind,
+    }
+
+    impl From<Workspace> for super::Workspace {
+        fn from(old: Workspace) -> Self {
+            Self {
+                size: old.size,
+                zoom: old.zoom,
+                pixel_at_center: old.pixel_at_center,
+                layers: old.layers.into_iter().map(Into::into).collect(),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl From<LayerInfo> for crate::workspace::LayerInfo {
+        fn from(old: LayerInfo) -> Self {
+            Self {
+                name: old.name,
+                visible: old.visible,
+                opacity: old.opacity,
+                blend_mode: old.blend_mode.as_str().into(),
+                is_tool_layer: old.is_tool_layer,
+                kind: old.kind,
+                clipped: false,
+            }
+        }
+    }
+}
+
+/// Version 5, where layers could be clipped. Documents were always 8-bit.
+mod v5 {
+    use serde::Deserialize;
+
+    use crate::workspace::LayerInfo;
+
+    /// `LayerInfo` has not changed shape since, copy it in here once it does
+    #[derive(Deserialize)]
+    pub struct Workspace {
+        pub size: (u32, u32),
+        pub zoom: f32,
+        pub pixel_at_center: (f32, f32),
+        pub layers: Vec<LayerInfo>,
+    }
+
+    impl From<Workspace> for super::Workspace {
+        fn from(old: Workspace) -> Self {
+            Self {
+                size: old.size,
+                zoom: old.zoom,
+                pixel_at_center: old.pixel_at_center,
+                layers: old.layers,
+                ..Default::default()
+            }
+        }
+    }
+}
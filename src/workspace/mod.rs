@@ -1,17 +1,32 @@
-use eframe::CreationContext;
 use egui::{Image, PaintCallbackInfo, TextureId};
-use egui_wgpu::CallbackTrait;
+use egui_wgpu::{CallbackTrait, RenderState};
 use image::{ImageBuffer, ImageFormat, ImageReader, Rgba};
 use serde::{Deserialize, Serialize};
 use std::{borrow::Borrow, default::Default, io::Cursor};
 use util::{DeviceExt, TextureDataOrder};
 use wgpu::*;
 
+pub mod adjustments;
+pub mod bit_depth;
+pub mod canvas;
+pub mod clipping;
+pub mod container;
+pub mod export;
+pub mod groups;
+pub mod history;
+pub mod import;
 pub mod layer_info;
+mod migrations;
+pub mod openraster;
+pub mod psd;
+pub mod selection;
 pub mod tools;
 pub mod workspace_serialization;
 
+use bit_depth::{BitDepth, DEPTH_CHECKED};
+use history::*;
 use layer_info::*;
+use selection::Selection;
 use tools::*;
 pub use workspace_serialization::*;
 
@@ -23,6 +38,7 @@ pub struct Workspace {
     pub zoom: f32,
     pub pixel_at_center: (f32, f32),
     pub layers: Vec<LayerInfo>,
+    pub bit_depth: BitDepth,
 
     #[serde(skip)]
     pub selected_layer: Option<usize>,
@@ -30,6 +46,7 @@ pub struct Workspace {
     #[serde(skip)]
     pub layer_data: Vec<Box<LayerData>>,
 
+    /// The composite at 8 bits per channel whatever the bit depth, as shown and exported
     #[serde(skip)]
     pub output_texture: Option<Texture>,
 
@@ -38,6 +55,16 @@ pub struct Workspace {
 
     #[serde(skip)]
     pub eternal_blank: Option<Texture>,
+
+    #[serde(skip)]
+    pub selection: Option<Selection>,
+
+    /// Scratch space for the combined masks of clipped layers
+    #[serde(skip)]
+    pub clip_mask: Option<Texture>,
+
+    #[serde(skip)]
+    pub history: History,
 }
 
 impl Default for Workspace {
@@ -47,11 +74,15 @@ impl Default for Workspace {
             zoom: 1.0,
             pixel_at_center: (256.0, 256.0),
             layers: Vec::new(),
+            bit_depth: BitDepth::default(),
             layer_data: Vec::new(),
             output_texture: None,
             selected_tool: None,
             eternal_blank: None,
+            selection: None,
+            clip_mask: None,
             selected_layer: None,
+            history: History::default(),
         }
     }
 }
@@ -62,20 +93,29 @@ pub struct LayerData {
     running_total: Texture,
 }
 
+impl LayerData {
+    pub fn texture(&self) -> &Texture {
+        &self.texture
+    }
+
+    pub fn mask(&self) -> &Texture {
+        &self.mask
+    }
+}
+
 impl Workspace {
     pub fn set_tool(&mut self, tool: Box<dyn Tool>) {
         self.selected_tool = Some(tool);
     }
     pub fn move_layer(&mut self, from: usize, to: usize, gpu: &GpuDevice) {
-        if let Some(selected_layer) = self.selected_layer {
-            match selected_layer {
-                from => self.selected_layer = Some(to),
-                to => self.selected_layer = Some(from),
-                i if i > from && i < to => self.selected_layer = Some(i - 1),
-                i if i < from && i > to => self.selected_layer = Some(i + 1),
-                _ => (),
-            }
-        }
+        self.move_layer_data(from, to, gpu);
+        self.record(Command::LayerMoved { from, to });
+    }
+
+    pub fn move_layer_data(&mut self, from: usize, to: usize, gpu: &GpuDevice) {
+        self.selected_layer = self
+            .selected_layer
+            .map(|selected_layer| index_after_move(selected_layer, from, to));
 
         let info = self.layers.remove(from);
         self.layers.insert(to, info);
@@ -86,12 +126,46 @@ impl Workspace {
         self.recalculate_output_texture(gpu, if from < to { from } else { to });
     }
 
-    pub fn create_layer(
+    pub fn delete_layer(&mut self, index: usize, gpu: &GpuDevice) {
+        match self.layers[index].kind {
+            LayerKind::Group { .. } => return self.delete_group(index, gpu),
+            LayerKind::GroupEnd => return self.delete_group(self.group_header(index), gpu),
+            LayerKind::Pixel | LayerKind::Adjustment(_) => (),
+        }
+
+        let (info, data) = self.remove_layer_data(index, gpu);
+        if !info.is_tool_layer {
+            self.record(Command::LayerRemoved { index, info, data });
+        }
+    }
+
+    pub fn remove_layer_data(
         &mut self,
-        mut info: LayerCreationInfo,
+        index: usize,
         gpu: &GpuDevice,
+    ) -> (LayerInfo, Box<LayerData>) {
+        self.selected_layer = match self.selected_layer {
+            Some(i) if i == index => index.checked_sub(1),
+            Some(i) if i > index => Some(i - 1),
+            selected_layer => selected_layer,
+        };
+
+        let info = self.layers.remove(index);
+        let data = self.layer_data.remove(index);
+
+        self.recalculate_output_texture(gpu, index);
+
+        (info, data)
+    }
+
+    /// Inserts a layer at `index`, or on top of the stack when `None`, returning where it went
+    pub fn insert_layer_data(
+        &mut self,
+        info: LayerInfo,
+        data: Box<LayerData>,
         index: Option<usize>,
-    ) {
+        gpu: &GpuDevice,
+    ) -> usize {
         match index {
             Some(index) if index > self.layers.len() => {
                 panic!("index out of bounds");
@@ -101,11 +175,120 @@ impl Workspace {
             }
             _ => {}
         }
+
+        let index = index.unwrap_or(self.layers.len());
+        self.layers.insert(index, info);
+        self.layer_data.insert(index, data);
+        self.recalculate_output_texture(gpu, index);
+
+        index
+    }
+
+    /// Blends layer `index` into the layer below it and removes it. The merged layer's mask
+    /// is applied, while the mask of the layer below is left untouched.
+    pub fn merge_layer_down(&mut self, index: usize, gpu: &GpuDevice) {
+        assert!(index > 0, "no layer below layer {} to merge into", index);
+        assert!(
+            self.layers[index - 1].kind.is_pixel(),
+            "layer {} is not a pixel layer",
+            index - 1
+        );
+
+        let merged = gpu.device.create_texture(&TextureDescriptor {
+            label: None,
+            size: Extent3d {
+                width: self.size.0,
+                height: self.size.1,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: self.bit_depth.texture_format(),
+            usage: TextureUsages::STORAGE_BINDING | TextureUsages::COPY_SRC,
+            view_formats: &[self.bit_depth.texture_format()],
+        });
+
+        let layer_info = &self.layers[index];
+        let layer = &self.layer_data[index];
+        let below = &self.layer_data[index - 1];
+        Self::blend(
+            gpu,
+            self.size,
+            layer_info,
+            &layer.texture,
+            &layer.mask,
+            &below.texture,
+            &merged,
+        );
+
+        let mut encoder = gpu
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor { label: None });
+        encoder.copy_texture_to_texture(
+            ImageCopyTexture {
+                texture: &merged,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+                aspect: TextureAspect::All,
+            },
+            ImageCopyTexture {
+                texture: &below.texture,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+                aspect: TextureAspect::All,
+            },
+            Extent3d {
+                width: self.size.0,
+                height: self.size.1,
+                depth_or_array_layers: 1,
+            },
+        );
+        gpu.queue.submit(Some(encoder.finish()));
+
+        self.remove_layer_data(index, gpu);
+        self.recalculate_output_texture(gpu, index - 1);
+    }
+
+    pub fn set_layer_info(&mut self, index: usize, info: LayerInfo, gpu: &GpuDevice) {
+        let previous = std::mem::replace(&mut self.layers[index], info);
+        self.recalculate_output_texture(gpu, index);
+        self.record(Command::LayerInfoChanged { index, previous });
+    }
+
+    pub fn set_layer_visibility(&mut self, index: usize, visible: bool, gpu: &GpuDevice) {
+        let info = LayerInfo {
+            visible,
+            ..self.layers[index].clone()
+        };
+        self.set_layer_info(index, info, gpu);
+    }
+
+    pub fn create_layer(
+        &mut self,
+        mut info: LayerCreationInfo,
+        gpu: &GpuDevice,
+        index: Option<usize>,
+    ) {
+        let layer_data = self.new_layer_data(&mut info, gpu);
+
+        let is_tool_layer = info.is_tool_layer;
+        let index = self.insert_layer_data(info.into(), layer_data, index, gpu);
+        if !is_tool_layer {
+            self.record(Command::LayerInserted { index });
+        }
+    }
+
+    /// Creates the textures of a layer, taking the initial contents out of `info`
+    fn new_layer_data(&self, info: &mut LayerCreationInfo, gpu: &GpuDevice) -> Box<LayerData> {
         let texture = if info.init_texture.is_some() {
             info.init_texture.take().unwrap()
         } else {
-            let texture_data = if info.init_image.is_some() {
-                info.init_image.take().unwrap().into_vec()
+            let texture_data = if info.init_pixels.is_some() {
+                info.init_pixels.take().unwrap()
+            } else if info.init_image.is_some() {
+                let image = info.init_image.take().unwrap().into_vec();
+                self.bit_depth.encode_u8(image)
             } else {
                 let init_rgba: [u8; 4] = info.init_rgba.take().unwrap_or([255, 255, 255, 0]);
                 let tex_data: Vec<[u8; 4]> = vec![init_rgba; (self.size.0 * self.size.1) as usize];
@@ -115,10 +298,12 @@ impl Workspace {
                 let new_len = len * 4;
                 let new_capacity = capacity * 4;
 
-                unsafe { Vec::from_raw_parts(ptr as *mut u8, new_len, new_capacity) }
+                let tex_data =
+                    unsafe { Vec::from_raw_parts(ptr as *mut u8, new_len, new_capacity) };
+                self.bit_depth.encode_u8(tex_data)
             };
 
-            let layer_texture = gpu.render_state.device.create_texture(&TextureDescriptor {
+            let layer_texture = gpu.device.create_texture(&TextureDescriptor {
                 label: None,
                 size: Extent3d {
                     width: self.size.0,
@@ -128,16 +313,16 @@ impl Workspace {
                 mip_level_count: 1,
                 sample_count: 1,
                 dimension: TextureDimension::D2,
-                format: TextureFormat::Rgba8Unorm,
+                format: self.bit_depth.texture_format(),
                 usage: TextureUsages::RENDER_ATTACHMENT
                     | TextureUsages::TEXTURE_BINDING
                     | TextureUsages::COPY_DST
                     | TextureUsages::STORAGE_BINDING
                     | TextureUsages::COPY_SRC,
-                view_formats: &[TextureFormat::Rgba8Unorm],
+                view_formats: &[self.bit_depth.texture_format()],
             });
 
-            gpu.render_state.queue.write_texture(
+            gpu.queue.write_texture(
                 ImageCopyTexture {
                     texture: &layer_texture,
                     mip_level: 0,
@@ -147,7 +332,7 @@ impl Workspace {
                 &texture_data,
                 ImageDataLayout {
                     offset: 0,
-                    bytes_per_row: Some(4 * self.size.0),
+                    bytes_per_row: Some(self.bit_depth.bytes_per_pixel() * self.size.0),
                     rows_per_image: Some(self.size.1),
                 },
                 Extent3d {
@@ -170,13 +355,18 @@ impl Workspace {
             None, info.init_rgba,
             "cannot have both init_texture and init_rgba"
         );
+        #[cfg(debug_assertions)]
+        assert!(
+            info.init_pixels.is_none(),
+            "cannot have both init_texture and init_pixels"
+        );
 
         let mask = if info.init_mask_texture.is_some() {
             info.init_mask_texture.take().unwrap()
         } else if info.init_mask_image.is_some() {
             let mask_data = info.init_mask_image.take().unwrap().into_vec();
 
-            let mask_texture = gpu.render_state.device.create_texture(&TextureDescriptor {
+            let mask_texture = gpu.device.create_texture(&TextureDescriptor {
                 label: None,
                 size: Extent3d {
                     width: self.size.0,
@@ -195,7 +385,7 @@ impl Workspace {
                 view_formats: &[TextureFormat::R8Unorm],
             });
 
-            gpu.render_state.queue.write_texture(
+            gpu.queue.write_texture(
                 ImageCopyTexture {
                     texture: &mask_texture,
                     mip_level: 0,
@@ -220,8 +410,8 @@ impl Workspace {
             let mask_luma = info.init_mask_luma.take().unwrap_or(255);
             // fill with 100% opacity
             let mask_data: Vec<u8> = vec![mask_luma; (self.size.0 * self.size.1) as usize];
-            gpu.render_state.device.create_texture_with_data(
-                &gpu.render_state.queue,
+            gpu.device.create_texture_with_data(
+                &gpu.queue,
                 &TextureDescriptor {
                     label: None,
                     size: Extent3d {
@@ -245,7 +435,7 @@ impl Workspace {
             )
         };
 
-        let running_total = gpu.render_state.device.create_texture(&TextureDescriptor {
+        let running_total = gpu.device.create_texture(&TextureDescriptor {
             label: None,
             size: Extent3d {
                 width: self.size.0,
@@ -255,13 +445,13 @@ impl Workspace {
             mip_level_count: 1,
             sample_count: 1,
             dimension: TextureDimension::D2,
-            format: TextureFormat::Rgba8Unorm,
+            format: self.bit_depth.texture_format(),
             usage: TextureUsages::RENDER_ATTACHMENT
                 | TextureUsages::TEXTURE_BINDING
                 | TextureUsages::COPY_DST
                 | TextureUsages::STORAGE_BINDING
                 | TextureUsages::COPY_SRC,
-            view_formats: &[TextureFormat::Rgba8Unorm],
+            view_formats: &[self.bit_depth.texture_format()],
         });
 
         let layer_data = LayerData {
@@ -269,26 +459,13 @@ impl Workspace {
             mask,
             running_total,
         };
-        let layer_data = Box::new(layer_data);
-
-        match index {
-            Some(index) => {
-                self.layers.insert(index, info.into());
-                self.layer_data.insert(index, layer_data);
-                self.recalculate_output_texture(gpu, index);
-            }
-            None => {
-                self.layers.push(info.into());
-                self.layer_data.push(layer_data);
-                self.recalculate_output_texture(gpu, self.layers.len() - 1);
-            }
-        }
+        Box::new(layer_data)
     }
 
     pub fn build_output_texture(&mut self, gpu: &GpuDevice) {
         #[cfg(debug_assertions)]
         println!("Creating eternal blank texture...");
-        self.eternal_blank = Some(gpu.render_state.device.create_texture(&TextureDescriptor {
+        self.eternal_blank = Some(gpu.device.create_texture(&TextureDescriptor {
             label: None,
             size: Extent3d {
                 width: self.size.0,
@@ -298,19 +475,18 @@ impl Workspace {
             mip_level_count: 1,
             sample_count: 1,
             dimension: TextureDimension::D2,
-            format: TextureFormat::Rgba8Unorm,
+            format: self.bit_depth.texture_format(),
             usage: TextureUsages::RENDER_ATTACHMENT
                 | TextureUsages::TEXTURE_BINDING
                 | TextureUsages::COPY_SRC
                 | TextureUsages::STORAGE_BINDING,
-            view_formats: &[TextureFormat::Rgba8Unorm],
+            view_formats: &[self.bit_depth.texture_format()],
         }));
 
         #[cfg(debug_assertions)]
         println!("Blanking eternal blank texture...");
         let clear_color = Rgba([0, 0, 0, 0]);
         let mut encoder = gpu
-            .render_state
             .device
             .create_command_encoder(&CommandEncoderDescriptor { label: None });
         {
@@ -339,11 +515,11 @@ impl Workspace {
             });
         }
 
-        gpu.render_state.queue.submit(Some(encoder.finish()));
+        gpu.queue.submit(Some(encoder.finish()));
 
         #[cfg(debug_assertions)]
         println!("Creating output texture...");
-        self.output_texture = Some(gpu.render_state.device.create_texture(&TextureDescriptor {
+        self.output_texture = Some(gpu.device.create_texture(&TextureDescriptor {
             label: None,
             size: Extent3d {
                 width: self.size.0,
@@ -357,10 +533,36 @@ impl Workspace {
             usage: TextureUsages::RENDER_ATTACHMENT
                 | TextureUsages::TEXTURE_BINDING
                 | TextureUsages::COPY_DST
+                | TextureUsages::COPY_SRC
                 | TextureUsages::STORAGE_BINDING,
             view_formats: &[TextureFormat::Rgba8Unorm],
         }));
 
+        self.clip_mask = Some(gpu.device.create_texture(&TextureDescriptor {
+            label: None,
+            size: Extent3d {
+                width: self.size.0,
+                height: self.size.1,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R8Unorm,
+            usage: TextureUsages::STORAGE_BINDING,
+            view_formats: &[TextureFormat::R8Unorm],
+        }));
+
+        let selection_size = self
+            .selection
+            .as_ref()
+            .map(|selection| (selection.texture().width(), selection.texture().height()));
+        if selection_size != Some(self.size) {
+            #[cfg(debug_assertions)]
+            println!("Creating selection texture...");
+            self.selection = Some(Selection::new(self.size, gpu));
+        }
+
         self.recalculate_output_texture(gpu, 0);
     }
 
@@ -369,251 +571,289 @@ impl Workspace {
             self.build_output_texture(gpu);
             return;
         }
+
+        // a group's mode decides how its contents start, so recomposite them as well
+        let start = match self.layers.get(start) {
+            Some(info) if info.kind.is_group() => self.group_end(start),
+            _ => start,
+        };
+
+        let blank = self.eternal_blank.as_ref().unwrap();
         for i in start..self.layers.len() {
             let layer_info = &self.layers[i];
-            if !layer_info.visible {
-                continue;
-            }
-
-            let layer_texture = &self.layer_data[i].texture;
-            let mask = &self.layer_data[i].mask;
+            let layer = &self.layer_data[i];
+            let below = match i {
+                0 => blank,
+                _ => &self.layer_data[i - 1].running_total,
+            };
 
             #[cfg(debug_assertions)]
             println!("Applying layer {:?}...", layer_info);
 
-            let shader = gpu
-                .shaders
-                .get(format!("blend_modes/{}", layer_info.blend_mode).as_str())
-                .unwrap();
-
-            let bind_group_layout =
-                gpu.render_state
-                    .device
-                    .create_bind_group_layout(&BindGroupLayoutDescriptor {
-                        entries: &[
-                            BindGroupLayoutEntry {
-                                binding: 0,
-                                visibility: ShaderStages::COMPUTE,
-                                ty: BindingType::StorageTexture {
-                                    access: StorageTextureAccess::ReadOnly,
-                                    format: TextureFormat::Rgba8Unorm,
-                                    view_dimension: TextureViewDimension::D2,
-                                },
-                                count: None,
-                            },
-                            BindGroupLayoutEntry {
-                                binding: 1,
-                                visibility: ShaderStages::COMPUTE,
-                                ty: BindingType::StorageTexture {
-                                    access: StorageTextureAccess::ReadOnly,
-                                    format: TextureFormat::Rgba8Unorm,
-                                    view_dimension: TextureViewDimension::D2,
-                                },
-                                count: None,
-                            },
-                            BindGroupLayoutEntry {
-                                binding: 2,
-                                visibility: ShaderStages::COMPUTE,
-                                ty: BindingType::StorageTexture {
-                                    access: StorageTextureAccess::WriteOnly,
-                                    format: TextureFormat::Rgba8Unorm,
-                                    view_dimension: TextureViewDimension::D2,
-                                },
-                                count: None,
-                            },
-                            BindGroupLayoutEntry {
-                                binding: 3,
-                                visibility: ShaderStages::COMPUTE,
-                                ty: BindingType::StorageTexture {
-                                    access: StorageTextureAccess::ReadOnly,
-                                    format: TextureFormat::R8Unorm,
-                                    view_dimension: TextureViewDimension::D2,
-                                },
-                                count: None,
-                            },
-                            BindGroupLayoutEntry {
-                                binding: 4,
-                                visibility: ShaderStages::COMPUTE,
-                                ty: BindingType::Buffer {
-                                    ty: BufferBindingType::Uniform,
-                                    has_dynamic_offset: false,
-                                    min_binding_size:
-                                        Some(
-                                            std::num::NonZeroU64::new(
-                                                std::mem::size_of::<f32>() as u64
-                                            )
-                                            .unwrap(),
-                                        ),
-                                },
-                                count: None,
-                            },
-                        ],
-                        label: None,
-                    });
-            let opacity = layer_info.opacity;
-            let data = vec![opacity];
-            let buffer = gpu.render_state.device.create_buffer(&BufferDescriptor {
-                label: None,
-                size: (data.len() * std::mem::size_of::<f32>()) as u64,
-                usage: BufferUsages::COPY_DST | BufferUsages::UNIFORM,
-                mapped_at_creation: false,
-            });
+            // clipped layers are hidden along with their base
+            let clip_base = self.clip_base(i);
+            let visible =
+                layer_info.visible && clip_base.is_none_or(|base| self.layers[base].visible);
+            let mask = match clip_base {
+                Some(base) if visible => {
+                    let clip_mask = self.clip_mask.as_ref().unwrap();
+                    Self::build_clip_mask(gpu, &self.layer_data[base], &layer.mask, clip_mask);
+                    clip_mask
+                }
+                _ => &layer.mask,
+            };
 
-            gpu.render_state
-                .queue
-                .write_buffer(&buffer, 0, bytemuck::cast_slice(&data));
-            let bind_group = gpu
-                .render_state
-                .device
-                .create_bind_group(&BindGroupDescriptor {
-                    layout: &bind_group_layout,
-                    entries: &[
-                        BindGroupEntry {
-                            binding: 0,
-                            resource: BindingResource::TextureView(
-                                &layer_texture.create_view(&TextureViewDescriptor::default()),
-                            ),
+            match layer_info.kind {
+                LayerKind::Pixel if visible => {
+                    Self::blend(
+                        gpu,
+                        self.size,
+                        layer_info,
+                        &layer.texture,
+                        mask,
+                        below,
+                        &layer.running_total,
+                    );
+                }
+                LayerKind::Adjustment(ref adjustment) if visible => {
+                    Self::composite_adjustment(
+                        gpu, self.size, adjustment, layer_info, layer, mask, below,
+                    );
+                }
+                LayerKind::Pixel | LayerKind::Adjustment(_) => {
+                    copy_texture(gpu, below, &layer.running_total)
+                }
+                LayerKind::GroupEnd => {
+                    let header = &self.layers[self.group_header(i)];
+                    match header.kind {
+                        LayerKind::Group {
+                            mode: GroupMode::PassThrough,
+                            ..
+                        } => copy_texture(gpu, below, &layer.running_total),
+                        _ => copy_texture(gpu, blank, &layer.running_total),
+                    }
+                }
+                LayerKind::Group { mode, .. } => {
+                    let end = self.group_end(i);
+                    let base = match end {
+                        0 => blank,
+                        _ => &self.layer_data[end - 1].running_total,
+                    };
+                    // i - 1 is at least `end`, the group's contents end up in its running total
+                    let contents = below;
+
+                    if !layer_info.visible {
+                        copy_texture(gpu, base, &layer.running_total);
+                        continue;
+                    }
+
+                    let shader = match mode {
+                        GroupMode::Isolated => layer_info.blend_mode.shader(),
+                        GroupMode::PassThrough => "groups/pass_through".to_string(),
+                    };
+                    Self::run_blend_shader(
+                        gpu,
+                        gpu.layer_shader(&shader, self.bit_depth)
+                            .expect(DEPTH_CHECKED),
+                        layer_info.opacity,
+                        contents,
+                        &layer.mask,
+                        base,
+                        &layer.running_total,
+                    );
+                }
+            }
+        }
+
+        let top = match self.layer_data.last() {
+            Some(layer) => &layer.running_total,
+            None => blank,
+        };
+        let output = self.output_texture.as_ref().unwrap();
+        match self.bit_depth {
+            BitDepth::Eight => copy_texture(gpu, top, output),
+            _ => convert_to_output(gpu, top, output),
+        }
+    }
+
+    /// Blends `layer` over `below` into `out`, all of which must be the size of the workspace
+    pub fn blend(
+        gpu: &GpuDevice,
+        size: (u32, u32),
+        layer_info: &LayerInfo,
+        layer: &Texture,
+        mask: &Texture,
+        below: &Texture,
+        out: &Texture,
+    ) {
+        let shader = gpu
+            .layer_shader(&layer_info.blend_mode.shader(), BitDepth::of(out))
+            .expect(DEPTH_CHECKED);
+
+        #[cfg(debug_assertions)]
+        assert_eq!(size, (out.width(), out.height()));
+
+        Self::run_blend_shader(gpu, shader, layer_info.opacity, layer, mask, below, out);
+    }
+
+    /// Runs a shader with the blend mode bindings over the whole of `out`. The layer textures
+    /// all have the bit depth of `out`.
+    fn run_blend_shader(
+        gpu: &GpuDevice,
+        shader: &ShaderModule,
+        opacity: f32,
+        layer: &Texture,
+        mask: &Texture,
+        below: &Texture,
+        out: &Texture,
+    ) {
+        let format = out.format();
+        let bind_group_layout = gpu
+            .device
+            .create_bind_group_layout(&BindGroupLayoutDescriptor {
+                entries: &[
+                    BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::StorageTexture {
+                            access: StorageTextureAccess::ReadOnly,
+                            format,
+                            view_dimension: TextureViewDimension::D2,
                         },
-                        BindGroupEntry {
-                            binding: 1,
-                            resource: BindingResource::TextureView(&if let Some(last_visible) =
-                                (0..i).rev().find(|&j| self.layers[j].visible)
-                            {
-                                let last_visible: usize =
-                                    (0..i).rev().find(|&j| self.layers[j].visible).unwrap();
-                                self.layer_data[i - 1]
-                                    .running_total
-                                    .create_view(&TextureViewDescriptor::default())
-                            } else {
-                                self.eternal_blank
-                                    .as_ref()
-                                    .unwrap()
-                                    .create_view(&TextureViewDescriptor::default())
-                            }),
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::StorageTexture {
+                            access: StorageTextureAccess::ReadOnly,
+                            format,
+                            view_dimension: TextureViewDimension::D2,
                         },
-                        BindGroupEntry {
-                            binding: 2,
-                            resource: BindingResource::TextureView(
-                                &self.layer_data[i]
-                                    .running_total
-                                    .create_view(&TextureViewDescriptor::default()),
-                            ),
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::StorageTexture {
+                            access: StorageTextureAccess::WriteOnly,
+                            format,
+                            view_dimension: TextureViewDimension::D2,
                         },
-                        BindGroupEntry {
-                            binding: 3,
-                            resource: BindingResource::TextureView(
-                                &mask.create_view(&TextureViewDescriptor::default()),
-                            ),
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::StorageTexture {
+                            access: StorageTextureAccess::ReadOnly,
+                            format: TextureFormat::R8Unorm,
+                            view_dimension: TextureViewDimension::D2,
                         },
-                        BindGroupEntry {
-                            binding: 4,
-                            resource: BindingResource::Buffer(BufferBinding {
-                                buffer: &buffer,
-                                offset: 0,
-                                size: None,
-                            }),
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: Some(
+                                std::num::NonZeroU64::new(std::mem::size_of::<f32>() as u64)
+                                    .unwrap(),
+                            ),
                         },
-                    ],
-                    label: None,
-                });
-
-            let pipeline_layout =
-                gpu.render_state
-                    .device
-                    .create_pipeline_layout(&PipelineLayoutDescriptor {
-                        label: None,
-                        bind_group_layouts: &[&bind_group_layout],
-                        push_constant_ranges: &[],
-                    });
-
-            let pipeline =
-                gpu.render_state
-                    .device
-                    .create_compute_pipeline(&ComputePipelineDescriptor {
-                        label: None,
-                        layout: Some(&pipeline_layout),
-                        module: shader,
-                        entry_point: "main",
-                        compilation_options: Default::default(),
-                        cache: None,
-                    });
-
-            let mut encoder = gpu
-                .render_state
-                .device
-                .create_command_encoder(&CommandEncoderDescriptor { label: None });
-            {
-                let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor {
-                    label: None,
-                    timestamp_writes: None,
-                });
-                pass.set_pipeline(&pipeline);
-                pass.set_bind_group(0, &bind_group, &[]);
-                pass.dispatch_workgroups((self.size.0 + 7) / 8, (self.size.1 + 7) / 8, 1);
-            }
-            gpu.render_state.queue.submit(Some(encoder.finish()));
-        }
+                        count: None,
+                    },
+                ],
+                label: None,
+            });
+        let data = vec![opacity];
+        let buffer = gpu.device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: (data.len() * std::mem::size_of::<f32>()) as u64,
+            usage: BufferUsages::COPY_DST | BufferUsages::UNIFORM,
+            mapped_at_creation: false,
+        });
 
-        let last_visible = (0..self.layers.len())
-            .rev()
-            .find(|&i| self.layers[i].visible);
-        if let Some(last_visible) = last_visible {
-            let last_visible: usize = last_visible;
-            let last_layer = &self.layer_data[last_visible];
-            let mut encoder = gpu
-                .render_state
-                .device
-                .create_command_encoder(&CommandEncoderDescriptor { label: None });
-            encoder.copy_texture_to_texture(
-                ImageCopyTexture {
-                    texture: &last_layer.running_total,
-                    mip_level: 0,
-                    origin: Origin3d::ZERO,
-                    aspect: TextureAspect::All,
+        gpu.queue
+            .write_buffer(&buffer, 0, bytemuck::cast_slice(&data));
+        let bind_group = gpu.device.create_bind_group(&BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(
+                        &layer.create_view(&TextureViewDescriptor::default()),
+                    ),
                 },
-                ImageCopyTexture {
-                    texture: self.output_texture.as_ref().unwrap(),
-                    mip_level: 0,
-                    origin: Origin3d::ZERO,
-                    aspect: TextureAspect::All,
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(
+                        &below.create_view(&TextureViewDescriptor::default()),
+                    ),
                 },
-                Extent3d {
-                    width: self.size.0,
-                    height: self.size.1,
-                    depth_or_array_layers: 1,
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::TextureView(
+                        &out.create_view(&TextureViewDescriptor::default()),
+                    ),
                 },
-            );
-            gpu.render_state.queue.submit(Some(encoder.finish()));
-        } else {
-            let mut encoder = gpu
-                .render_state
-                .device
-                .create_command_encoder(&CommandEncoderDescriptor { label: None });
-            encoder.copy_texture_to_texture(
-                ImageCopyTexture {
-                    texture: self.eternal_blank.as_ref().unwrap(),
-                    mip_level: 0,
-                    origin: Origin3d::ZERO,
-                    aspect: TextureAspect::All,
-                },
-                ImageCopyTexture {
-                    texture: self.output_texture.as_ref().unwrap(),
-                    mip_level: 0,
-                    origin: Origin3d::ZERO,
-                    aspect: TextureAspect::All,
+                BindGroupEntry {
+                    binding: 3,
+                    resource: BindingResource::TextureView(
+                        &mask.create_view(&TextureViewDescriptor::default()),
+                    ),
                 },
-                Extent3d {
-                    width: self.size.0,
-                    height: self.size.1,
-                    depth_or_array_layers: 1,
+                BindGroupEntry {
+                    binding: 4,
+                    resource: BindingResource::Buffer(BufferBinding {
+                        buffer: &buffer,
+                        offset: 0,
+                        size: None,
+                    }),
                 },
-            );
-            gpu.render_state.queue.submit(Some(encoder.finish()));
+            ],
+            label: None,
+        });
+
+        let pipeline_layout = gpu
+            .device
+            .create_pipeline_layout(&PipelineLayoutDescriptor {
+                label: None,
+                bind_group_layouts: &[&bind_group_layout],
+                push_constant_ranges: &[],
+            });
+
+        let pipeline = gpu
+            .device
+            .create_compute_pipeline(&ComputePipelineDescriptor {
+                label: None,
+                layout: Some(&pipeline_layout),
+                module: shader,
+                entry_point: "main",
+                compilation_options: Default::default(),
+                cache: None,
+            });
+
+        let mut encoder = gpu
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor { label: None });
+        {
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: None,
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(out.width().div_ceil(16), out.height().div_ceil(16), 1);
         }
+        gpu.queue.submit(Some(encoder.finish()));
     }
-    pub fn register_output_texture(&self, cc: &CreationContext) -> TextureId {
-        let renderer = cc.wgpu_render_state.as_ref().unwrap().renderer.clone();
-        let device = cc.wgpu_render_state.as_ref().unwrap().device.clone();
+
+    pub fn register_output_texture(&self, render_state: &RenderState) -> TextureId {
+        let renderer = render_state.renderer.clone();
+        let device = render_state.device.clone();
         let texture_view = self
             .output_texture
             .as_ref()
@@ -632,3 +872,137 @@ impl Workspace {
         }
     }
 }
+
+/// Where the layer at `index` ends up when the layer at `from` is taken out and inserted at `to`
+fn index_after_move(index: usize, from: usize, to: usize) -> usize {
+    match index {
+        i if i == from => to,
+        i if i > from && i <= to => i - 1,
+        i if i < from && i >= to => i + 1,
+        i => i,
+    }
+}
+
+fn copy_texture(gpu: &GpuDevice, from: &Texture, to: &Texture) {
+    let mut encoder = gpu
+        .device
+        .create_command_encoder(&CommandEncoderDescriptor { label: None });
+    encoder.copy_texture_to_texture(from.as_image_copy(), to.as_image_copy(), from.size());
+    gpu.queue.submit(Some(encoder.finish()));
+}
+
+/// Draws a running total of a document deeper than 8 bits into the output texture, see
+/// `shaders/output/convert.wgsl`
+fn convert_to_output(gpu: &GpuDevice, from: &Texture, to: &Texture) {
+    let device = &gpu.device;
+    let shader = gpu.shaders.get("output/convert").unwrap();
+
+    let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: None,
+        entries: &[BindGroupLayoutEntry {
+            binding: 0,
+            visibility: ShaderStages::FRAGMENT,
+            ty: BindingType::Texture {
+                // 32-bit float textures can't be filtered, they are only loaded from
+                sample_type: TextureSampleType::Float { filterable: false },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        }],
+    });
+    let bind_group = device.create_bind_group(&BindGroupDescriptor {
+        label: None,
+        layout: &layout,
+        entries: &[BindGroupEntry {
+            binding: 0,
+            resource: BindingResource::TextureView(
+                &from.create_view(&TextureViewDescriptor::default()),
+            ),
+        }],
+    });
+
+    let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+        label: None,
+        bind_group_layouts: &[&layout],
+        push_constant_ranges: &[],
+    });
+    let pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: None,
+        layout: Some(&pipeline_layout),
+        vertex: VertexState {
+            module: shader,
+            entry_point: "vs_main",
+            compilation_options: Default::default(),
+            buffers: &[],
+        },
+        primitive: PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: MultisampleState::default(),
+        fragment: Some(FragmentState {
+            module: shader,
+            entry_point: "fs_main",
+            compilation_options: Default::default(),
+            targets: &[Some(ColorTargetState {
+                format: to.format(),
+                blend: None,
+                write_mask: ColorWrites::ALL,
+            })],
+        }),
+        multiview: None,
+        cache: None,
+    });
+
+    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+    {
+        let mut pass = encoder.begin_render_pass(&RenderPassDescriptor {
+            label: None,
+            color_attachments: &[Some(RenderPassColorAttachment {
+                view: &to.create_view(&TextureViewDescriptor::default()),
+                resolve_target: None,
+                ops: Operations {
+                    load: LoadOp::Clear(Color::TRANSPARENT),
+                    store: StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        pass.set_pipeline(&pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+    gpu.queue.submit(Some(encoder.finish()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Moves the names like `move_layer_data` moves layers
+    fn moved(from: usize, to: usize) -> Vec<usize> {
+        let mut layers = (0..5).collect::<Vec<usize>>();
+        let layer = layers.remove(from);
+        layers.insert(to, layer);
+        layers
+    }
+
+    #[test]
+    fn selection_follows_moved_layers() {
+        for from in 0..5 {
+            for to in 0..5 {
+                let layers = moved(from, to);
+                for index in 0..5 {
+                    let after = index_after_move(index, from, to);
+                    assert_eq!(
+                        layers[after], index,
+                        "{} moved from {} to {}",
+                        index, from, to
+                    );
+                    assert_eq!(index_after_move(after, to, from), index);
+                }
+            }
+        }
+    }
+}
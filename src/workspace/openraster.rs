@@ -0,0 +1,657 @@
+//! Reading and writing OpenRaster (`.ora`) files, the layered format shared by GIMP, Krita and
+//! MyPaint.
+//!
+//! An OpenRaster file is a zip archive holding a `stack.xml` that describes the layer tree,
+//! a PNG per layer, and the flattened image. Groups become nested `<stack>` elements. Masks
+//! have no place in the format, so they are multiplied into the layer's alpha on the way out.
+//! Anything OpenRaster can't express on its own, like adjustment layers, clipping, and blend
+//! modes without an SVG equivalent, is kept in attributes under our own namespace so that it
+//! survives a round trip through this program.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Cursor, Read, Seek, Write},
+    path::Path,
+};
+
+use image::{imageops, ExtendedColorType, ImageBuffer, Rgba};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use super::{
+    adjustments::Adjustment,
+    layer_info::{BlendMode, GroupMode, LayerInfo, LayerKind},
+    workspace_serialization::{encode_png, thumbnail},
+    LayerCreationInfo, Workspace, WorkspaceLoadError,
+};
+use crate::GpuDevice;
+
+const MIMETYPE: &str = "image/openraster";
+const STACK_XML: &str = "stack.xml";
+const MERGED_IMAGE: &str = "mergedimage.png";
+const THUMBNAIL: &str = "Thumbnails/thumbnail.png";
+/// The version of the spec the writer follows
+const ORA_VERSION: &str = "0.0.5";
+
+/// Namespace of the attributes only this program understands
+const JC_NAMESPACE: &str = "urn:joyful-create:openraster";
+
+/// What empty layers are filled with, matching new layers in the workspace
+const TRANSPARENT: Rgba<u8> = Rgba([255, 255, 255, 0]);
+
+pub fn is_openraster(path: impl AsRef<Path>) -> bool {
+    path.as_ref()
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("ora"))
+}
+
+/// A layer as it is stored in an OpenRaster file
+pub struct OraLayer {
+    pub info: LayerInfo,
+    /// Workspace sized pixels with the mask already applied. Only pixel layers have any.
+    pub image: Option<ImageBuffer<Rgba<u8>, Vec<u8>>>,
+}
+
+pub struct OraDocument {
+    pub size: (u32, u32),
+    /// Bottom to top, with groups laid out inline like [`Workspace::layers`]
+    pub layers: Vec<OraLayer>,
+}
+
+/// The `svg:` composite op matching `mode`, if the spec has one
+fn svg_composite_op(mode: BlendMode) -> Option<&'static str> {
+    Some(match mode {
+        BlendMode::Normal => "svg:src-over",
+        BlendMode::Multiply => "svg:multiply",
+        BlendMode::Screen => "svg:screen",
+        BlendMode::Overlay => "svg:overlay",
+        BlendMode::DarkenOnly => "svg:darken",
+        BlendMode::LightenOnly => "svg:lighten",
+        BlendMode::ColorDodge => "svg:color-dodge",
+        BlendMode::ColorBurn => "svg:color-burn",
+        BlendMode::HardLight => "svg:hard-light",
+        BlendMode::SoftLight => "svg:soft-light",
+        BlendMode::Difference => "svg:difference",
+        BlendMode::Exclusion => "svg:exclusion",
+        BlendMode::Hue => "svg:hue",
+        BlendMode::Saturation => "svg:saturation",
+        BlendMode::Color => "svg:color",
+        BlendMode::Luminosity => "svg:luminosity",
+        BlendMode::LinearDodge => "svg:plus",
+        _ => return None,
+    })
+}
+
+/// Modes without an SVG equivalent are written as `jc:` followed by [`BlendMode::name`], the
+/// same way Krita uses `krita:` for its own
+fn composite_op(mode: BlendMode) -> String {
+    svg_composite_op(mode).map_or_else(|| format!("jc:{}", mode.name()), str::to_string)
+}
+
+fn blend_mode_from_composite_op(op: &str) -> BlendMode {
+    if let Some(mode) = op.strip_prefix("jc:").and_then(BlendMode::from_name) {
+        return mode;
+    }
+
+    BlendMode::ALL
+        .into_iter()
+        .find(|&mode| svg_composite_op(mode) == Some(op))
+        .unwrap_or_else(|| {
+            eprintln!("Unsupported composite op {}, using normal", op);
+            BlendMode::Normal
+        })
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The attributes shared by `<layer>` and `<stack>`
+fn common_attributes(info: &LayerInfo) -> String {
+    let mut attributes = format!(
+        "name=\"{}\" opacity=\"{}\" visibility=\"{}\" composite-op=\"{}\"",
+        escape_xml(&info.name),
+        info.opacity.clamp(0.0, 1.0),
+        if info.visible { "visible" } else { "hidden" },
+        composite_op(info.blend_mode),
+    );
+    if info.clipped {
+        attributes.push_str(" jc:clipped=\"true\"");
+    }
+    attributes
+}
+
+/// The smallest rectangle holding every pixel that isn't fully transparent, as
+/// `(x, y, width, height)`
+fn content_bounds(image: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> Option<(u32, u32, u32, u32)> {
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    for (x, y, pixel) in image.enumerate_pixels() {
+        if pixel[3] == 0 {
+            continue;
+        }
+        bounds = Some(match bounds {
+            None => (x, y, x, y),
+            Some((left, top, right, bottom)) => {
+                (left.min(x), top.min(y), right.max(x), bottom.max(y))
+            }
+        });
+    }
+    bounds.map(|(left, top, right, bottom)| (left, top, right - left + 1, bottom - top + 1))
+}
+
+/// Writes `layers` as an OpenRaster archive. Pixel layers are cropped to their contents, and
+/// `merged` is stored as the flattened image and the thumbnail.
+pub fn write_openraster<W: Write + Seek>(
+    writer: W,
+    size: (u32, u32),
+    layers: &[OraLayer],
+    merged: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+) -> std::io::Result<()> {
+    let mut stack = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <image version=\"{}\" w=\"{}\" h=\"{}\" xmlns:jc=\"{}\">\n  <stack>\n",
+        ORA_VERSION, size.0, size.1, JC_NAMESPACE
+    );
+    let mut files = Vec::new();
+    let mut depth = 2;
+
+    // stack.xml lists layers from the top down
+    for (i, layer) in layers.iter().enumerate().rev() {
+        let info = &layer.info;
+        match &info.kind {
+            LayerKind::GroupEnd => {
+                depth -= 1;
+                stack.push_str(&format!("{}</stack>\n", "  ".repeat(depth)));
+            }
+            LayerKind::Group { mode, collapsed } => {
+                let isolation = match mode {
+                    GroupMode::PassThrough => "auto",
+                    GroupMode::Isolated => "isolate",
+                };
+                stack.push_str(&format!(
+                    "{}<stack {} isolation=\"{}\"{}>\n",
+                    "  ".repeat(depth),
+                    common_attributes(info),
+                    isolation,
+                    if *collapsed {
+                        " jc:collapsed=\"true\""
+                    } else {
+                        ""
+                    },
+                ));
+                depth += 1;
+            }
+            LayerKind::Pixel | LayerKind::Adjustment(_) => {
+                let (image, x, y) = match (&info.kind, &layer.image) {
+                    (LayerKind::Pixel, Some(image)) => match content_bounds(image) {
+                        Some((x, y, width, height)) => (
+                            imageops::crop_imm(image, x, y, width, height).to_image(),
+                            x,
+                            y,
+                        ),
+                        None => (ImageBuffer::from_pixel(1, 1, TRANSPARENT), 0, 0),
+                    },
+                    // the spec wants every layer to have pixels
+                    _ => (ImageBuffer::from_pixel(1, 1, TRANSPARENT), 0, 0),
+                };
+
+                let src = format!("data/layer{}.png", i);
+                stack.push_str(&format!(
+                    "{}<layer {} src=\"{}\" x=\"{}\" y=\"{}\"",
+                    "  ".repeat(depth),
+                    common_attributes(info),
+                    src,
+                    x,
+                    y,
+                ));
+                if let LayerKind::Adjustment(adjustment) = &info.kind {
+                    let adjustment = serde_json::to_string(adjustment).unwrap();
+                    stack.push_str(&format!(" jc:adjustment=\"{}\"", escape_xml(&adjustment)));
+                }
+                stack.push_str("/>\n");
+
+                let png = encode_png(image.as_raw(), image.dimensions(), ExtendedColorType::Rgba8);
+                files.push((src, png));
+            }
+        }
+    }
+    stack.push_str("  </stack>\n</image>\n");
+
+    let mut zip = ZipWriter::new(writer);
+    // PNGs are already compressed, and the mimetype has to be stored as is so that it can be
+    // found at a fixed offset
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    zip.start_file("mimetype", stored)?;
+    zip.write_all(MIMETYPE.as_bytes())?;
+
+    zip.start_file(STACK_XML, deflated)?;
+    zip.write_all(stack.as_bytes())?;
+
+    for (src, png) in files {
+        zip.start_file(src, stored)?;
+        zip.write_all(&png)?;
+    }
+
+    zip.start_file(MERGED_IMAGE, stored)?;
+    zip.write_all(&encode_png(merged.as_raw(), size, ExtendedColorType::Rgba8))?;
+
+    let thumbnail = thumbnail(merged);
+    zip.start_file(THUMBNAIL, stored)?;
+    zip.write_all(&encode_png(
+        thumbnail.as_raw(),
+        thumbnail.dimensions(),
+        ExtendedColorType::Rgba8,
+    ))?;
+
+    zip.finish()?;
+    Ok(())
+}
+
+/// Reads the layer tree of an OpenRaster archive. Layers are placed at their offsets on a
+/// workspace sized image, and anything outside the image is cropped. Images wider or taller
+/// than `max_size` are rejected before any layer is read.
+pub fn read_openraster(data: &[u8], max_size: u32) -> Result<OraDocument, WorkspaceLoadError> {
+    let mut archive = ZipArchive::new(Cursor::new(data))?;
+
+    let stack = read_entry(&mut archive, STACK_XML)?;
+    let stack = String::from_utf8(stack)
+        .map_err(|_| WorkspaceLoadError::BadStackXml("not UTF-8".to_string()))?;
+    let document = roxmltree::Document::parse(&stack)
+        .map_err(|e| WorkspaceLoadError::BadStackXml(e.to_string()))?;
+
+    let image = document.root_element();
+    if !image.has_tag_name("image") {
+        return Err(WorkspaceLoadError::BadStackXml(format!(
+            "root element is <{}>, not <image>",
+            image.tag_name().name()
+        )));
+    }
+    let size = (
+        required_attribute(image, "w")?,
+        required_attribute(image, "h")?,
+    );
+    if size.0 == 0 || size.1 == 0 || size.0 > max_size || size.1 > max_size {
+        return Err(WorkspaceLoadError::InvalidSize(size));
+    }
+
+    let root = image
+        .children()
+        .find(|node| node.has_tag_name("stack"))
+        .ok_or_else(|| WorkspaceLoadError::BadStackXml("no root <stack>".to_string()))?;
+
+    let mut layers = Vec::new();
+    read_stack(root, &mut archive, size, &mut layers)?;
+    layers.reverse();
+
+    Ok(OraDocument { size, layers })
+}
+
+fn read_entry(
+    archive: &mut ZipArchive<Cursor<&[u8]>>,
+    name: &str,
+) -> Result<Vec<u8>, WorkspaceLoadError> {
+    let mut entry = match archive.by_name(name) {
+        Ok(entry) => entry,
+        Err(zip::result::ZipError::FileNotFound) => {
+            return Err(WorkspaceLoadError::MissingEntry(name.to_string()))
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut data = Vec::with_capacity(entry.size() as usize);
+    entry.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn required_attribute<T: std::str::FromStr>(
+    node: roxmltree::Node,
+    name: &str,
+) -> Result<T, WorkspaceLoadError> {
+    let value = node.attribute(name).ok_or_else(|| {
+        WorkspaceLoadError::BadStackXml(format!(
+            "<{}> has no {} attribute",
+            node.tag_name().name(),
+            name
+        ))
+    })?;
+    value.trim().parse().map_err(|_| {
+        WorkspaceLoadError::BadStackXml(format!("{}=\"{}\" is not a valid value", name, value))
+    })
+}
+
+/// Offsets are integers in the spec, but some writers give them as decimals
+fn offset_attribute(node: roxmltree::Node, name: &str) -> i64 {
+    node.attribute(name)
+        .and_then(|value| value.trim().parse::<f64>().ok())
+        .map_or(0, |value| value.round() as i64)
+}
+
+/// The attributes shared by `<layer>` and `<stack>`, with the spec's defaults for missing ones
+fn read_info(node: roxmltree::Node, kind: LayerKind) -> LayerInfo {
+    LayerInfo {
+        name: node.attribute("name").unwrap_or("Layer").to_string(),
+        visible: node.attribute("visibility") != Some("hidden"),
+        opacity: node
+            .attribute("opacity")
+            .and_then(|value| value.trim().parse::<f32>().ok())
+            .unwrap_or(1.0)
+            .clamp(0.0, 1.0),
+        blend_mode: node
+            .attribute("composite-op")
+            .map_or(BlendMode::Normal, blend_mode_from_composite_op),
+        is_tool_layer: false,
+        kind,
+        clipped: node.attribute((JC_NAMESPACE, "clipped")) == Some("true"),
+    }
+}
+
+/// Appends the children of `stack` to `layers` from the top down
+fn read_stack(
+    stack: roxmltree::Node,
+    archive: &mut ZipArchive<Cursor<&[u8]>>,
+    size: (u32, u32),
+    layers: &mut Vec<OraLayer>,
+) -> Result<(), WorkspaceLoadError> {
+    for node in stack.children().filter(|node| node.is_element()) {
+        match node.tag_name().name() {
+            "stack" => {
+                let mode = match node.attribute("isolation") {
+                    Some("auto") => GroupMode::PassThrough,
+                    _ => GroupMode::Isolated,
+                };
+                let collapsed = node.attribute((JC_NAMESPACE, "collapsed")) == Some("true");
+                let header = read_info(node, LayerKind::Group { mode, collapsed });
+                let end = LayerInfo {
+                    name: format!("{} end", header.name),
+                    visible: true,
+                    opacity: 1.0,
+                    blend_mode: BlendMode::Normal,
+                    is_tool_layer: false,
+                    kind: LayerKind::GroupEnd,
+                    clipped: false,
+                };
+
+                layers.push(OraLayer {
+                    info: header,
+                    image: None,
+                });
+                read_stack(node, archive, size, layers)?;
+                layers.push(OraLayer {
+                    info: end,
+                    image: None,
+                });
+            }
+            "layer" => {
+                if let Some(adjustment) = node.attribute((JC_NAMESPACE, "adjustment")) {
+                    match serde_json::from_str::<Adjustment>(adjustment) {
+                        Ok(adjustment) => {
+                            layers.push(OraLayer {
+                                info: read_info(node, LayerKind::Adjustment(adjustment)),
+                                image: None,
+                            });
+                            continue;
+                        }
+                        Err(e) => eprintln!("Ignoring unreadable adjustment: {}", e),
+                    }
+                }
+
+                let src: String = required_attribute(node, "src")?;
+                let pixels = image::load_from_memory(&read_entry(archive, &src)?)?.into_rgba8();
+                let mut image = ImageBuffer::from_pixel(size.0, size.1, TRANSPARENT);
+                imageops::replace(
+                    &mut image,
+                    &pixels,
+                    offset_attribute(node, "x"),
+                    offset_attribute(node, "y"),
+                );
+
+                layers.push(OraLayer {
+                    info: read_info(node, LayerKind::Pixel),
+                    image: Some(image),
+                });
+            }
+            other => eprintln!("Skipping unsupported OpenRaster element <{}>", other),
+        }
+    }
+
+    Ok(())
+}
+
+impl Workspace {
+    /// Saves the layers as an OpenRaster file. Tool layers are left out and masks are
+    /// applied to their layers.
+    pub async fn save_ora(&self, path: &str, gpu: &GpuDevice) -> std::io::Result<()> {
+        #[cfg(debug_assertions)]
+        println!("Saving workspace as OpenRaster at {}...", path);
+
+        let mut layers = Vec::with_capacity(self.layers.len());
+        for (info, data) in self.layers.iter().zip(&self.layer_data) {
+            if info.is_tool_layer {
+                continue;
+            }
+
+            let image = if info.kind.is_pixel() {
+                let mut image = gpu.texture_to_image(&data.texture, self.size.0).await;
+                let mask = gpu.texture_to_luma_image(&data.mask, self.size.0).await;
+                for (pixel, mask) in image.pixels_mut().zip(mask.pixels()) {
+                    pixel[3] = ((pixel[3] as u32 * mask[0] as u32 + 127) / 255) as u8;
+                }
+                Some(image)
+            } else {
+                None
+            };
+
+            layers.push(OraLayer {
+                info: info.clone(),
+                image,
+            });
+        }
+
+        let output = self.output_texture.as_ref().unwrap();
+        let merged = gpu.texture_to_image(output, self.size.0).await;
+
+        let writer = BufWriter::new(File::create(path)?);
+        write_openraster(writer, self.size, &layers, &merged)
+    }
+
+    pub fn load_ora(path: &str, gpu: &GpuDevice) -> Result<Self, WorkspaceLoadError> {
+        #[cfg(debug_assertions)]
+        println!("Loading OpenRaster file at {}...", path);
+
+        let data = std::fs::read(path)?;
+        let max_size = gpu.device.limits().max_texture_dimension_2d;
+        let document = read_openraster(&data, max_size)?;
+
+        let size = document.size;
+
+        let mut this = Workspace {
+            size,
+            pixel_at_center: (size.0 as f32 / 2.0, size.1 as f32 / 2.0),
+            ..Default::default()
+        };
+        this.bit_depth.check(gpu)?;
+
+        for layer in document.layers {
+            let info = layer.info;
+            let mut info = LayerCreationInfo {
+                name: info.name,
+                visible: info.visible,
+                opacity: info.opacity,
+                blend_mode: info.blend_mode,
+                kind: info.kind,
+                clipped: info.clipped,
+                init_image: layer.image,
+                ..Default::default()
+            };
+            let data = this.new_layer_data(&mut info, gpu);
+            this.layers.push(info.into());
+            this.layer_data.push(data);
+        }
+
+        this.build_output_texture(gpu);
+
+        Ok(this)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: (u32, u32) = (20, 12);
+
+    fn info(name: &str, kind: LayerKind) -> LayerInfo {
+        LayerInfo::from(LayerCreationInfo {
+            name: name.to_string(),
+            kind,
+            ..Default::default()
+        })
+    }
+
+    /// A block of varied pixels at a different place for every `seed`, transparent around it
+    /// so that the writer has something to crop
+    fn pixel_layer(info: LayerInfo, seed: u32) -> OraLayer {
+        let image = ImageBuffer::from_fn(SIZE.0, SIZE.1, |x, y| {
+            let inside = (seed % 8..seed % 8 + 10).contains(&x) && (2..9).contains(&y);
+            match inside {
+                true => Rgba([
+                    (x * 12 + seed * 7) as u8,
+                    (y * 20) as u8,
+                    (seed * 9) as u8,
+                    (100 + x * y) as u8,
+                ]),
+                false => TRANSPARENT,
+            }
+        });
+        OraLayer {
+            info,
+            image: Some(image),
+        }
+    }
+
+    fn marker(info: LayerInfo) -> OraLayer {
+        OraLayer { info, image: None }
+    }
+
+    fn group(name: &str, mode: GroupMode, collapsed: bool) -> (LayerInfo, LayerInfo) {
+        let header = info(name, LayerKind::Group { mode, collapsed });
+        let end = LayerInfo {
+            name: format!("{} end", name),
+            ..info("", LayerKind::GroupEnd)
+        };
+        (header, end)
+    }
+
+    fn round_trip(layers: &[OraLayer]) -> OraDocument {
+        let merged = ImageBuffer::from_pixel(SIZE.0, SIZE.1, Rgba([0, 0, 0, 255]));
+        let mut file = Cursor::new(Vec::new());
+        write_openraster(&mut file, SIZE, layers, &merged).unwrap();
+        read_openraster(file.get_ref(), 16384).unwrap()
+    }
+
+    fn assert_same_layers(written: &[OraLayer], read: &OraDocument) {
+        assert_eq!(read.size, SIZE);
+        assert_eq!(read.layers.len(), written.len());
+        for (i, (written, read)) in written.iter().zip(&read.layers).enumerate() {
+            assert_eq!(read.info, written.info, "layer {}", i);
+            assert_eq!(read.image, written.image, "pixels of layer {}", i);
+        }
+    }
+
+    #[test]
+    fn groups_and_hidden_layers_round_trip() {
+        let (outer, outer_end) = group("Outer", GroupMode::Isolated, false);
+        let (inner, inner_end) = group("Inner <&>", GroupMode::PassThrough, true);
+        let hidden_group = LayerInfo {
+            visible: false,
+            opacity: 0.25,
+            ..inner
+        };
+        let hidden_layer = LayerInfo {
+            visible: false,
+            ..info("Hidden", LayerKind::Pixel)
+        };
+
+        let layers = vec![
+            pixel_layer(info("Background", LayerKind::Pixel), 0),
+            marker(outer_end),
+            pixel_layer(hidden_layer, 1),
+            marker(inner_end),
+            pixel_layer(info("Nested", LayerKind::Pixel), 2),
+            marker(hidden_group),
+            marker(outer),
+            pixel_layer(info("Top", LayerKind::Pixel), 3),
+        ];
+        assert_same_layers(&layers, &round_trip(&layers));
+    }
+
+    #[test]
+    fn every_blend_mode_round_trips() {
+        let layers: Vec<OraLayer> = BlendMode::ALL
+            .into_iter()
+            .enumerate()
+            .map(|(i, mode)| {
+                let info = LayerInfo {
+                    blend_mode: mode,
+                    opacity: 0.5 + i as f32 / 100.0,
+                    ..info(mode.label(), LayerKind::Pixel)
+                };
+                pixel_layer(info, i as u32)
+            })
+            .collect();
+        assert_same_layers(&layers, &round_trip(&layers));
+    }
+
+    #[test]
+    fn clipping_and_adjustments_round_trip() {
+        let clipped = LayerInfo {
+            clipped: true,
+            blend_mode: BlendMode::Multiply,
+            ..info("Clipped", LayerKind::Pixel)
+        };
+        let adjustment = Adjustment::Levels {
+            input_black: 0.1,
+            input_white: 0.8,
+            gamma: 1.5,
+            output_black: 0.0,
+            output_white: 0.9,
+        };
+        let adjustment = LayerInfo {
+            clipped: true,
+            opacity: 0.6,
+            ..info("Levels", LayerKind::Adjustment(adjustment))
+        };
+
+        let layers = vec![
+            pixel_layer(info("Base", LayerKind::Pixel), 0),
+            pixel_layer(clipped, 1),
+            marker(adjustment),
+        ];
+        assert_same_layers(&layers, &round_trip(&layers));
+    }
+
+    #[test]
+    fn oversized_images_are_rejected() {
+        let size = (20000, 1);
+        let merged = ImageBuffer::from_pixel(size.0, size.1, TRANSPARENT);
+        let mut file = Cursor::new(Vec::new());
+        write_openraster(&mut file, size, &[], &merged).unwrap();
+
+        assert!(matches!(
+            read_openraster(file.get_ref(), 16384),
+            Err(WorkspaceLoadError::InvalidSize((20000, 1)))
+        ));
+    }
+}
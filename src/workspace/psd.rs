@@ -0,0 +1,1622 @@
+//! Reading and writing Photoshop documents (`.psd`, and reading the large document `.psb`
+//! variant).
+//!
+//! Layers are stored bottom to top in a PSD, and groups are laid out inline between a section
+//! divider below their contents and a folder record above them, which is the same layout as
+//! [`Workspace::layers`]. Only 8 and 16 bit RGB and grayscale documents are read, and 16 bit
+//! samples are reduced to 8 bits. Layers without pixels of their own, like adjustment and fill
+//! layers, are skipped with a warning, and text layers and smart objects are read as the
+//! pixels Photoshop rendered for them. Files are written as 8 bit RGB with PackBits
+//! compressed channels, the way Photoshop saves them by default.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::Path,
+};
+
+use image::{imageops, ImageBuffer, Luma, Pixel, Rgba};
+
+use super::{
+    layer_info::{BlendMode, GroupMode, LayerInfo, LayerKind},
+    LayerCreationInfo, Workspace, WorkspaceLoadError,
+};
+use crate::GpuDevice;
+
+/// What transparent layer pixels are filled with, matching new layers in the workspace
+const TRANSPARENT: Rgba<u8> = Rgba([255, 255, 255, 0]);
+
+/// Photoshop's four character blend mode keys. Darker and lighter color have no equivalent,
+/// so they are approximated in [`blend_mode_from_key`].
+const BLEND_MODE_KEYS: [(BlendMode, &[u8; 4]); 25] = [
+    (BlendMode::Normal, b"norm"),
+    (BlendMode::Dissolve, b"diss"),
+    (BlendMode::DarkenOnly, b"dark"),
+    (BlendMode::Multiply, b"mul "),
+    (BlendMode::ColorBurn, b"idiv"),
+    (BlendMode::LinearBurn, b"lbrn"),
+    (BlendMode::LightenOnly, b"lite"),
+    (BlendMode::Screen, b"scrn"),
+    (BlendMode::ColorDodge, b"div "),
+    (BlendMode::LinearDodge, b"lddg"),
+    (BlendMode::Overlay, b"over"),
+    (BlendMode::SoftLight, b"sLit"),
+    (BlendMode::HardLight, b"hLit"),
+    (BlendMode::VividLight, b"vLit"),
+    (BlendMode::LinearLight, b"lLit"),
+    (BlendMode::PinLight, b"pLit"),
+    (BlendMode::HardMix, b"hMix"),
+    (BlendMode::Difference, b"diff"),
+    (BlendMode::Exclusion, b"smud"),
+    (BlendMode::Subtract, b"fsub"),
+    (BlendMode::Divide, b"fdiv"),
+    (BlendMode::Hue, b"hue "),
+    (BlendMode::Saturation, b"sat "),
+    (BlendMode::Color, b"colr"),
+    (BlendMode::Luminosity, b"lum "),
+];
+
+/// The key Photoshop knows `mode` by. Grain extract, grain merge and value have no
+/// equivalent and are saved as normal.
+fn blend_mode_key(mode: BlendMode) -> &'static [u8; 4] {
+    match BLEND_MODE_KEYS
+        .iter()
+        .find(|(candidate, _)| *candidate == mode)
+    {
+        Some((_, key)) => key,
+        None => {
+            eprintln!(
+                "Photoshop has no {} blend mode, saving as normal",
+                mode.label()
+            );
+            b"norm"
+        }
+    }
+}
+
+/// The blend mode key of pass through groups
+const PASS_THROUGH_KEY: &[u8; 4] = b"pass";
+
+/// Additional layer information keys whose length is 64 bits in PSB files
+const LARGE_KEYS: [&[u8; 4]; 13] = [
+    b"LMsk", b"Lr16", b"Lr32", b"Layr", b"Mt16", b"Mt32", b"Mtrn", b"Alph", b"FMsk", b"lnk2",
+    b"FEid", b"FXid", b"PxSD",
+];
+
+const ADJUSTMENT_KEYS: [&[u8; 4]; 18] = [
+    b"levl", b"curv", b"brit", b"hue2", b"hue ", b"blnc", b"nvrt", b"post", b"thrs", b"grdm",
+    b"selc", b"mixr", b"phfl", b"vibA", b"expA", b"clrL", b"blwh", b"CgEd",
+];
+
+const FILL_KEYS: [&[u8; 4]; 3] = [b"SoCo", b"GdFl", b"PtFl"];
+
+const SMART_OBJECT_KEYS: [&[u8; 4]; 3] = [b"SoLd", b"PlLd", b"SoLE"];
+
+const TEXT_KEYS: [&[u8; 4]; 2] = [b"TySh", b"tySh"];
+
+pub fn is_psd(path: impl AsRef<Path>) -> bool {
+    path.as_ref()
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            extension.eq_ignore_ascii_case("psd") || extension.eq_ignore_ascii_case("psb")
+        })
+}
+
+/// Falls back to normal, with a warning, for keys without a matching mode
+fn blend_mode_from_key(key: &[u8; 4], warnings: &mut Vec<String>) -> BlendMode {
+    if let Some((mode, _)) = BLEND_MODE_KEYS
+        .iter()
+        .find(|(_, candidate)| *candidate == key)
+    {
+        return *mode;
+    }
+
+    let approximation = match key {
+        b"dkCl" => Some(BlendMode::DarkenOnly),
+        b"lgCl" => Some(BlendMode::LightenOnly),
+        _ => None,
+    };
+    match approximation {
+        Some(mode) => {
+            warnings.push(format!(
+                "Blend mode {} was approximated with {}",
+                String::from_utf8_lossy(key),
+                mode.label()
+            ));
+            mode
+        }
+        None => {
+            warnings.push(format!(
+                "Unsupported blend mode {}, using normal",
+                String::from_utf8_lossy(key)
+            ));
+            BlendMode::Normal
+        }
+    }
+}
+
+pub type MaskImage = ImageBuffer<Luma<u8>, Vec<u8>>;
+
+/// A layer as it is stored in a Photoshop document
+pub struct PsdLayer {
+    pub info: LayerInfo,
+    /// Workspace sized pixels, which groups don't have
+    pub image: Option<ImageBuffer<Rgba<u8>, Vec<u8>>>,
+    /// Workspace sized mask, if the layer has one
+    pub mask: Option<MaskImage>,
+}
+
+pub struct PsdDocument {
+    pub size: (u32, u32),
+    /// Bottom to top, with groups laid out inline like [`Workspace::layers`]
+    pub layers: Vec<PsdLayer>,
+    /// Everything that could not be read faithfully
+    pub warnings: Vec<String>,
+}
+
+fn bad_psd(reason: impl Into<String>) -> WorkspaceLoadError {
+    WorkspaceLoadError::BadPsd(reason.into())
+}
+
+/// Big endian reads over a byte slice that fail instead of panicking at the end
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+    /// PSB files use 64 bit lengths in a few places
+    large: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], WorkspaceLoadError> {
+        let end = self
+            .position
+            .checked_add(count)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| bad_psd("the file ends early"))?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn key(&mut self) -> Result<&'a [u8; 4], WorkspaceLoadError> {
+        Ok(self.bytes(4)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, WorkspaceLoadError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, WorkspaceLoadError> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn i16(&mut self) -> Result<i16, WorkspaceLoadError> {
+        Ok(i16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, WorkspaceLoadError> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, WorkspaceLoadError> {
+        Ok(i32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, WorkspaceLoadError> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// A length that is 64 bits in PSB files and 32 bits otherwise
+    fn length(&mut self) -> Result<usize, WorkspaceLoadError> {
+        if self.large {
+            Ok(self.u64()? as usize)
+        } else {
+            Ok(self.u32()? as usize)
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+
+    /// Splits off the next `length` bytes as their own reader
+    fn section(&mut self, length: usize) -> Result<Reader<'a>, WorkspaceLoadError> {
+        Ok(Reader {
+            data: self.bytes(length)?,
+            position: 0,
+            large: self.large,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Rect {
+    top: i32,
+    left: i32,
+    bottom: i32,
+    right: i32,
+}
+
+impl Rect {
+    fn read(reader: &mut Reader) -> Result<Self, WorkspaceLoadError> {
+        Ok(Self {
+            top: reader.i32()?,
+            left: reader.i32()?,
+            bottom: reader.i32()?,
+            right: reader.i32()?,
+        })
+    }
+
+    fn size(&self) -> Result<(u32, u32), WorkspaceLoadError> {
+        let width = self.right.checked_sub(self.left);
+        let height = self.bottom.checked_sub(self.top);
+        match (width, height) {
+            (Some(width), Some(height)) if width >= 0 && height >= 0 => {
+                Ok((width as u32, height as u32))
+            }
+            _ => Err(bad_psd("a layer has a negative size")),
+        }
+    }
+
+    /// The part of the rectangle that lies inside the document, relative to the rectangle's
+    /// top left corner. Fails for rectangles larger than a document of this kind can be.
+    fn inside(&self, header: &Header) -> Result<Crop, WorkspaceLoadError> {
+        let (width, height) = self.size()?;
+        if width > header.max_size() || height > header.max_size() {
+            return Err(bad_psd("a layer is larger than the document can be"));
+        }
+
+        // the start and length of the part of `start..start + length` within `0..document`
+        let overlap = |start: i32, length: u32, document: u32| {
+            let begin = (-(start as i64)).clamp(0, length as i64);
+            let end = (document as i64 - start as i64).clamp(begin, length as i64);
+            (begin as u32, (end - begin) as u32)
+        };
+        let (x, width) = overlap(self.left, width, header.size.0);
+        let (y, height) = overlap(self.top, height, header.size.1);
+        Ok(Crop {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
+
+/// The part of a plane that is kept while decoding it
+#[derive(Clone, Copy)]
+struct Crop {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Crop {
+    fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+}
+
+struct Header {
+    channels: u16,
+    size: (u32, u32),
+    /// Bits per sample, 8 or 16
+    depth: u16,
+    grayscale: bool,
+    large: bool,
+}
+
+impl Header {
+    /// The largest width and height the format allows
+    fn max_size(&self) -> u32 {
+        if self.large {
+            MAX_PSB_SIZE
+        } else {
+            MAX_PSD_SIZE
+        }
+    }
+}
+
+struct MaskRecord {
+    rect: Rect,
+    default_color: u8,
+    flags: u8,
+}
+
+impl MaskRecord {
+    const DISABLED: u8 = 0x02;
+    const INVERTED: u8 = 0x04;
+}
+
+/// Kinds of layers that can't be brought in as they are
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Unsupported {
+    Adjustment,
+    Fill,
+    SmartObject,
+    Text,
+}
+
+struct LayerRecord<'a> {
+    rect: Rect,
+    /// Channel ids with their compressed data
+    channels: Vec<(i16, &'a [u8])>,
+    blend_key: [u8; 4],
+    opacity: u8,
+    clipped: bool,
+    hidden: bool,
+    mask: Option<MaskRecord>,
+    /// Set when there are both a vector mask and a pixel mask, in which case the pixel mask is
+    /// in channel -3 and this is its record
+    real_mask: Option<MaskRecord>,
+    has_vector_mask: bool,
+    name: String,
+    /// The divider type and blend key of group records
+    section: Option<(u32, Option<[u8; 4]>)>,
+    unsupported: Option<Unsupported>,
+}
+
+impl LayerRecord<'_> {
+    const HIDDEN: u8 = 0x02;
+
+    const OPEN_FOLDER: u32 = 1;
+    const CLOSED_FOLDER: u32 = 2;
+    const SECTION_DIVIDER: u32 = 3;
+}
+
+/// Parses a Photoshop document. Anything that can't be represented is left out, with a
+/// warning saying so. Documents wider or taller than `max_size` or than the format allows are
+/// rejected before anything is decoded, and layers are cropped to the document.
+pub fn read_psd(data: &[u8], max_size: u32) -> Result<PsdDocument, WorkspaceLoadError> {
+    let mut reader = Reader {
+        data,
+        position: 0,
+        large: false,
+    };
+
+    if reader.bytes(4)? != b"8BPS" {
+        return Err(bad_psd("not a Photoshop file"));
+    }
+    let large = match reader.u16()? {
+        1 => false,
+        2 => true,
+        version => return Err(bad_psd(format!("version {} is not supported", version))),
+    };
+    reader.large = large;
+    reader.bytes(6)?;
+
+    let channels = reader.u16()?;
+    if !(1..=56).contains(&channels) {
+        return Err(bad_psd(format!("{} channels are not supported", channels)));
+    }
+    let height = reader.u32()?;
+    let width = reader.u32()?;
+    let depth = reader.u16()?;
+    let grayscale = match reader.u16()? {
+        1 => true,
+        3 => false,
+        _ => return Err(bad_psd("only RGB and grayscale documents are supported")),
+    };
+    if depth != 8 && depth != 16 {
+        return Err(bad_psd(format!(
+            "{} bit documents are not supported",
+            depth
+        )));
+    }
+    let header = Header {
+        channels,
+        size: (width, height),
+        depth,
+        grayscale,
+        large,
+    };
+    let max_size = max_size.min(header.max_size());
+    if width == 0 || height == 0 || width > max_size || height > max_size {
+        return Err(WorkspaceLoadError::InvalidSize((width, height)));
+    }
+
+    // color mode data and image resources
+    let length = reader.u32()? as usize;
+    reader.bytes(length)?;
+    let length = reader.u32()? as usize;
+    reader.bytes(length)?;
+
+    let mut warnings = Vec::new();
+    let mut records = Vec::new();
+
+    let length = reader.length()?;
+    let mut section = reader.section(length)?;
+    if section.remaining() > 0 {
+        let length = section.length()?;
+        let mut layer_info = section.section(length)?;
+        if layer_info.remaining() > 0 {
+            records = read_layer_info(&mut layer_info, &mut warnings)?;
+        }
+
+        // global layer mask info
+        let length = section.u32()? as usize;
+        section.bytes(length)?;
+
+        // 16 bit documents keep their layers in an additional information block instead
+        while records.is_empty() && section.remaining() >= 12 {
+            let signature = section.key()?;
+            if signature != b"8BIM" && signature != b"8B64" {
+                break;
+            }
+            let key = section.key()?;
+            let length = if LARGE_KEYS.contains(&key) {
+                section.length()?
+            } else {
+                section.u32()? as usize
+            };
+            let mut block = section.section(length)?;
+            if key == b"Lr16" {
+                records = read_layer_info(&mut block, &mut warnings)?;
+            }
+            // blocks are padded to four bytes
+            let padding = (4 - length % 4) % 4;
+            section.bytes(padding.min(section.remaining()))?;
+        }
+    }
+
+    let mut layers = build_layers(&records, &header, &mut warnings)?;
+    if layers.is_empty() {
+        // documents without layers only have the merged image
+        let image = read_merged_image(&mut reader, &header)?;
+        layers.push(PsdLayer {
+            info: LayerInfo {
+                name: "Background".to_string(),
+                ..layer_info(LayerKind::Pixel)
+            },
+            image: Some(image),
+            mask: None,
+        });
+    }
+
+    Ok(PsdDocument {
+        size: header.size,
+        layers,
+        warnings,
+    })
+}
+
+fn layer_info(kind: LayerKind) -> LayerInfo {
+    LayerInfo {
+        name: String::new(),
+        visible: true,
+        opacity: 1.0,
+        blend_mode: BlendMode::Normal,
+        is_tool_layer: false,
+        kind,
+        clipped: false,
+    }
+}
+
+/// Reads the layer records followed by their channel data
+fn read_layer_info<'a>(
+    reader: &mut Reader<'a>,
+    warnings: &mut Vec<String>,
+) -> Result<Vec<LayerRecord<'a>>, WorkspaceLoadError> {
+    // a negative count means the merged image has transparency, which doesn't matter here
+    let count = reader.i16()?.unsigned_abs() as usize;
+
+    let mut records = Vec::with_capacity(count);
+    let mut lengths = Vec::with_capacity(count);
+    for _ in 0..count {
+        let (record, channel_lengths) = read_layer_record(reader, warnings)?;
+        records.push(record);
+        lengths.push(channel_lengths);
+    }
+
+    for (record, channel_lengths) in records.iter_mut().zip(lengths) {
+        for ((_, data), length) in record.channels.iter_mut().zip(channel_lengths) {
+            *data = reader.bytes(length)?;
+        }
+    }
+
+    Ok(records)
+}
+
+fn read_layer_record<'a>(
+    reader: &mut Reader<'a>,
+    warnings: &mut Vec<String>,
+) -> Result<(LayerRecord<'a>, Vec<usize>), WorkspaceLoadError> {
+    let rect = Rect::read(reader)?;
+
+    let channel_count = reader.u16()? as usize;
+    let mut channels = Vec::with_capacity(channel_count);
+    let mut lengths = Vec::with_capacity(channel_count);
+    for _ in 0..channel_count {
+        channels.push((reader.i16()?, &[][..]));
+        lengths.push(reader.length()?);
+    }
+
+    if reader.bytes(4)? != b"8BIM" {
+        return Err(bad_psd("a layer record has a bad blend mode signature"));
+    }
+    let blend_key = *reader.key()?;
+    let opacity = reader.u8()?;
+    let clipped = reader.u8()? != 0;
+    let flags = reader.u8()?;
+    reader.u8()?;
+
+    let length = reader.u32()? as usize;
+    let mut extra = reader.section(length)?;
+
+    let length = extra.u32()? as usize;
+    let mut mask_data = extra.section(length)?;
+    let (mask, real_mask) = if length >= 20 {
+        let rect = Rect::read(&mut mask_data)?;
+        let default_color = mask_data.u8()?;
+        let flags = mask_data.u8()?;
+        let mask = MaskRecord {
+            rect,
+            default_color,
+            flags,
+        };
+
+        let real_mask = if length >= 36 {
+            let flags = mask_data.u8()?;
+            let default_color = mask_data.u8()?;
+            let rect = Rect::read(&mut mask_data)?;
+            Some(MaskRecord {
+                rect,
+                default_color,
+                flags,
+            })
+        } else {
+            None
+        };
+        (Some(mask), real_mask)
+    } else {
+        (None, None)
+    };
+
+    // blending ranges
+    let length = extra.u32()? as usize;
+    extra.bytes(length)?;
+
+    // a Pascal string padded to four bytes, in Mac Roman that is close enough to Latin-1 for
+    // the names people use
+    let length = extra.u8()? as usize;
+    let mut name: String = extra.bytes(length)?.iter().map(|&c| c as char).collect();
+    let padding = (4 - (length + 1) % 4) % 4;
+    extra.bytes(padding.min(extra.remaining()))?;
+
+    let mut section = None;
+    let mut unsupported = None;
+    let mut has_vector_mask = false;
+    while extra.remaining() >= 12 {
+        let signature = extra.key()?;
+        if signature != b"8BIM" && signature != b"8B64" {
+            warnings.push(format!("Layer \"{}\" has unreadable extra data", name));
+            break;
+        }
+        let key = extra.key()?;
+        let length = if LARGE_KEYS.contains(&key) {
+            extra.length()?
+        } else {
+            extra.u32()? as usize
+        };
+        let mut block = extra.section(length)?;
+
+        match key {
+            b"luni" => {
+                let count = block.u32()? as usize;
+                let units = (0..count)
+                    .map(|_| block.u16())
+                    .collect::<Result<Vec<_>, _>>()?;
+                name = String::from_utf16_lossy(&units)
+                    .trim_end_matches('\0')
+                    .to_string();
+            }
+            b"lsct" | b"lsdk" => {
+                let kind = block.u32()?;
+                let key = if block.remaining() >= 8 {
+                    block.key()?;
+                    Some(*block.key()?)
+                } else {
+                    None
+                };
+                section = Some((kind, key));
+            }
+            b"vmsk" | b"vsms" => has_vector_mask = true,
+            key if ADJUSTMENT_KEYS.contains(&key) => unsupported = Some(Unsupported::Adjustment),
+            key if FILL_KEYS.contains(&key) => unsupported = Some(Unsupported::Fill),
+            key if SMART_OBJECT_KEYS.contains(&key) => unsupported = Some(Unsupported::SmartObject),
+            key if TEXT_KEYS.contains(&key) => unsupported = Some(Unsupported::Text),
+            _ => (),
+        }
+    }
+
+    let record = LayerRecord {
+        rect,
+        channels,
+        blend_key,
+        opacity,
+        clipped,
+        hidden: flags & LayerRecord::HIDDEN != 0,
+        mask,
+        real_mask,
+        has_vector_mask,
+        name,
+        section,
+        unsupported,
+    };
+    Ok((record, lengths))
+}
+
+/// Turns layer records into workspace layers, bottom to top
+fn build_layers(
+    records: &[LayerRecord],
+    header: &Header,
+    warnings: &mut Vec<String>,
+) -> Result<Vec<PsdLayer>, WorkspaceLoadError> {
+    let mut layers = Vec::with_capacity(records.len());
+    let mut depth = 0;
+
+    for record in records {
+        let kind = match record.section {
+            Some((LayerRecord::SECTION_DIVIDER, _)) => {
+                depth += 1;
+                LayerKind::GroupEnd
+            }
+            Some((kind @ (LayerRecord::OPEN_FOLDER | LayerRecord::CLOSED_FOLDER), key)) => {
+                if depth == 0 {
+                    return Err(bad_psd("a group is missing its end"));
+                }
+                depth -= 1;
+
+                let key = key.unwrap_or(record.blend_key);
+                LayerKind::Group {
+                    mode: if &key == PASS_THROUGH_KEY {
+                        GroupMode::PassThrough
+                    } else {
+                        GroupMode::Isolated
+                    },
+                    collapsed: kind == LayerRecord::CLOSED_FOLDER,
+                }
+            }
+            _ => LayerKind::Pixel,
+        };
+
+        match record.unsupported {
+            Some(Unsupported::Adjustment) => {
+                warnings.push(format!("Skipped adjustment layer \"{}\"", record.name));
+                continue;
+            }
+            Some(Unsupported::Fill) => {
+                warnings.push(format!("Skipped fill layer \"{}\"", record.name));
+                continue;
+            }
+            Some(Unsupported::SmartObject) => warnings.push(format!(
+                "Smart object \"{}\" was read as pixels",
+                record.name
+            )),
+            Some(Unsupported::Text) => {
+                warnings.push(format!("Text layer \"{}\" was read as pixels", record.name))
+            }
+            None => (),
+        }
+
+        let blend_mode = if &record.blend_key == PASS_THROUGH_KEY {
+            BlendMode::Normal
+        } else {
+            blend_mode_from_key(&record.blend_key, warnings)
+        };
+        let info = LayerInfo {
+            name: record.name.clone(),
+            visible: !record.hidden,
+            opacity: record.opacity as f32 / 255.0,
+            blend_mode,
+            is_tool_layer: false,
+            clipped: record.clipped && kind.is_pixel(),
+            kind,
+        };
+
+        let image = if info.kind.is_pixel() {
+            Some(read_layer_pixels(record, header)?)
+        } else {
+            None
+        };
+        let mask = if info.kind == LayerKind::GroupEnd {
+            None
+        } else {
+            read_layer_mask(record, header, warnings)?
+        };
+
+        layers.push(PsdLayer { info, image, mask });
+    }
+
+    if depth != 0 {
+        return Err(bad_psd("a group is missing its header"));
+    }
+
+    Ok(layers)
+}
+
+/// The layer's pixels placed on a workspace sized image
+fn read_layer_pixels(
+    record: &LayerRecord,
+    header: &Header,
+) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, WorkspaceLoadError> {
+    let mut image = ImageBuffer::from_pixel(header.size.0, header.size.1, TRANSPARENT);
+    let crop = record.rect.inside(header)?;
+    if crop.is_empty() {
+        return Ok(image);
+    }
+
+    let (width, height) = record.rect.size()?;
+    let channel = |id: i16| -> Result<Option<Vec<u8>>, WorkspaceLoadError> {
+        match record.channels.iter().find(|(channel, _)| *channel == id) {
+            Some((_, data)) => Ok(Some(
+                decode_planes(data, width, height, 1, crop, header)?.remove(0),
+            )),
+            None => Ok(None),
+        }
+    };
+
+    let red = channel(0)?;
+    let (green, blue) = if header.grayscale {
+        (None, None)
+    } else {
+        (channel(1)?, channel(2)?)
+    };
+    let alpha = channel(-1)?;
+
+    let sample = |plane: &Option<Vec<u8>>, i: usize, default: u8| {
+        plane.as_ref().map_or(default, |plane| plane[i])
+    };
+    let layer = ImageBuffer::from_fn(crop.width, crop.height, |x, y| {
+        let i = (y * crop.width + x) as usize;
+        let red = sample(&red, i, 0);
+        let (green, blue) = if header.grayscale {
+            (red, red)
+        } else {
+            (sample(&green, i, 0), sample(&blue, i, 0))
+        };
+        Rgba([red, green, blue, sample(&alpha, i, 255)])
+    });
+
+    imageops::replace(
+        &mut image,
+        &layer,
+        record.rect.left as i64 + crop.x as i64,
+        record.rect.top as i64 + crop.y as i64,
+    );
+    Ok(image)
+}
+
+/// The layer's pixel mask placed on a workspace sized image, with the mask's default color
+/// everywhere outside its bounds
+fn read_layer_mask(
+    record: &LayerRecord,
+    header: &Header,
+    warnings: &mut Vec<String>,
+) -> Result<Option<MaskImage>, WorkspaceLoadError> {
+    if record.has_vector_mask {
+        warnings.push(format!(
+            "Vector mask of \"{}\" is not supported and was left out",
+            record.name
+        ));
+    }
+
+    let channel = |id: i16| record.channels.iter().find(|(channel, _)| *channel == id);
+    let pixel_mask = (record.real_mask.as_ref().zip(channel(-3)))
+        .or_else(|| record.mask.as_ref().zip(channel(-2)));
+    let Some((mask, (_, data))) = pixel_mask else {
+        return Ok(None);
+    };
+    if mask.flags & MaskRecord::DISABLED != 0 {
+        warnings.push(format!("Disabled mask of \"{}\" was left out", record.name));
+        return Ok(None);
+    }
+
+    let mut image =
+        ImageBuffer::from_pixel(header.size.0, header.size.1, Luma([mask.default_color]));
+    let crop = mask.rect.inside(header)?;
+    if !crop.is_empty() {
+        let (width, height) = mask.rect.size()?;
+        let plane = decode_planes(data, width, height, 1, crop, header)?.remove(0);
+        if let Some(pixels) = ImageBuffer::<Luma<u8>, _>::from_raw(crop.width, crop.height, plane) {
+            imageops::replace(
+                &mut image,
+                &pixels,
+                mask.rect.left as i64 + crop.x as i64,
+                mask.rect.top as i64 + crop.y as i64,
+            );
+        }
+    }
+
+    if mask.flags & MaskRecord::INVERTED != 0 {
+        imageops::invert(&mut image);
+    }
+    Ok(Some(image))
+}
+
+/// The flattened image at the end of the file
+fn read_merged_image(
+    reader: &mut Reader,
+    header: &Header,
+) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, WorkspaceLoadError> {
+    let (width, height) = header.size;
+    let color_channels = if header.grayscale { 1 } else { 3 };
+    if header.channels < color_channels {
+        return Err(bad_psd("the merged image is missing color channels"));
+    }
+
+    // every channel shares one table of row lengths, so all of them have to be decoded
+    let data = &reader.data[reader.position..];
+    let whole = Crop {
+        x: 0,
+        y: 0,
+        width,
+        height,
+    };
+    let planes = decode_planes(data, width, height, header.channels as usize, whole, header)?;
+
+    // further channels are saved selections rather than transparency
+    Ok(ImageBuffer::from_fn(width, height, |x, y| {
+        let i = (y * width + x) as usize;
+        if header.grayscale {
+            Rgba([planes[0][i], planes[0][i], planes[0][i], 255])
+        } else {
+            Rgba([planes[0][i], planes[1][i], planes[2][i], 255])
+        }
+    }))
+}
+
+/// Decodes `count` planes that share one compression header, keeping the `crop` of each
+/// reduced to 8 bits per sample. Only the kept part is ever held in memory.
+fn decode_planes(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    count: usize,
+    crop: Crop,
+    header: &Header,
+) -> Result<Vec<Vec<u8>>, WorkspaceLoadError> {
+    let sample_size = header.depth as usize / 8;
+    let row_size = width as usize * sample_size;
+    let rows = height as usize * count;
+    if row_size == 0 || rows == 0 || crop.is_empty() {
+        return Ok(vec![Vec::new(); count]);
+    }
+
+    let mut reader = Reader {
+        data,
+        position: 0,
+        large: header.large,
+    };
+    let compression = reader.u16()?;
+    let body = &data[reader.position..];
+
+    let kept_rows = crop.y as usize..(crop.y + crop.height) as usize;
+    let kept_bytes = crop.x as usize * sample_size..(crop.x + crop.width) as usize * sample_size;
+    // grows with the data that is actually there rather than with what the header claims
+    let mut samples = Vec::new();
+    // rows run through every plane in turn
+    let mut keep = |row: usize, bytes: &[u8]| {
+        if kept_rows.contains(&(row % height as usize)) {
+            samples.extend_from_slice(&bytes[kept_bytes.clone()]);
+        }
+    };
+
+    match compression {
+        0 => {
+            let body = body
+                .get(..row_size * rows)
+                .ok_or_else(|| bad_psd("channel data ends early"))?;
+            for (row, bytes) in body.chunks_exact(row_size).enumerate() {
+                keep(row, bytes);
+            }
+        }
+        1 => {
+            let length_size = if header.large { 4 } else { 2 };
+            if reader.remaining() < rows * length_size {
+                return Err(bad_psd("channel data ends early"));
+            }
+            let mut row_lengths = Vec::with_capacity(rows);
+            for _ in 0..rows {
+                row_lengths.push(if header.large {
+                    reader.u32()? as usize
+                } else {
+                    reader.u16()? as usize
+                });
+            }
+
+            for (row, length) in row_lengths.into_iter().enumerate() {
+                keep(row, &unpack_bits(reader.bytes(length)?, row_size)?);
+            }
+        }
+        2 | 3 => {
+            let mut decoder = flate2::read::ZlibDecoder::new(body);
+            let mut bytes = vec![0; row_size];
+            for row in 0..rows {
+                decoder.read_exact(&mut bytes).map_err(|e| match e.kind() {
+                    std::io::ErrorKind::UnexpectedEof => bad_psd("channel data ends early"),
+                    _ => bad_psd(format!("channel data can't be inflated: {}", e)),
+                })?;
+                if compression == 3 {
+                    undo_prediction(&mut bytes, row_size, sample_size);
+                }
+                keep(row, &bytes);
+            }
+        }
+        compression => {
+            return Err(bad_psd(format!(
+                "compression method {} is not supported",
+                compression
+            )))
+        }
+    }
+
+    if sample_size == 2 {
+        samples = samples
+            .chunks_exact(2)
+            .map(|sample| {
+                let sample = u16::from_be_bytes([sample[0], sample[1]]) as u32;
+                ((sample * 255 + 32767) / 65535) as u8
+            })
+            .collect();
+    }
+
+    let plane_size = samples.len() / count;
+    Ok(samples
+        .chunks_exact(plane_size)
+        .map(<[u8]>::to_vec)
+        .collect())
+}
+
+/// Decodes a PackBits row, which has to come out at `size` bytes
+fn unpack_bits(data: &[u8], size: usize) -> Result<Vec<u8>, WorkspaceLoadError> {
+    let mut row = Vec::with_capacity(size);
+    let mut i = 0;
+    while i < data.len() && row.len() < size {
+        let header = data[i] as i8;
+        i += 1;
+        match header {
+            0.. => {
+                let count = header as usize + 1;
+                let literal = data
+                    .get(i..i + count)
+                    .ok_or_else(|| bad_psd("compressed row ends early"))?;
+                row.extend_from_slice(literal);
+                i += count;
+            }
+            -128 => (),
+            _ => {
+                let count = (1 - header as isize) as usize;
+                let value = *data
+                    .get(i)
+                    .ok_or_else(|| bad_psd("compressed row ends early"))?;
+                row.extend(std::iter::repeat_n(value, count));
+                i += 1;
+            }
+        }
+    }
+
+    if row.len() != size {
+        return Err(bad_psd("compressed row has the wrong length"));
+    }
+    Ok(row)
+}
+
+/// Zip with prediction stores every sample as the difference from the one to its left
+fn undo_prediction(samples: &mut [u8], row_size: usize, sample_size: usize) {
+    for row in samples.chunks_exact_mut(row_size) {
+        if sample_size == 2 {
+            for i in (2..row.len()).step_by(2) {
+                let previous = u16::from_be_bytes([row[i - 2], row[i - 1]]);
+                let delta = u16::from_be_bytes([row[i], row[i + 1]]);
+                row[i..i + 2].copy_from_slice(&previous.wrapping_add(delta).to_be_bytes());
+            }
+        } else {
+            for i in 1..row.len() {
+                row[i] = row[i].wrapping_add(row[i - 1]);
+            }
+        }
+    }
+}
+
+/// The largest width and height a PSD can have, larger documents need PSB
+const MAX_PSD_SIZE: u32 = 30000;
+/// The largest width and height a PSB can have
+const MAX_PSB_SIZE: u32 = 300000;
+
+const GROUP_END_NAME: &str = "</Layer group>";
+
+/// The smallest rectangle holding every pixel for which `include` is true
+fn bounds<P: Pixel>(
+    image: &ImageBuffer<P, Vec<P::Subpixel>>,
+    include: impl Fn(&P) -> bool,
+) -> Option<Rect> {
+    let mut bounds: Option<Rect> = None;
+    for (x, y, pixel) in image.enumerate_pixels() {
+        if !include(pixel) {
+            continue;
+        }
+        let (x, y) = (x as i32, y as i32);
+        bounds = Some(match bounds {
+            None => Rect {
+                top: y,
+                left: x,
+                bottom: y + 1,
+                right: x + 1,
+            },
+            Some(rect) => Rect {
+                top: rect.top.min(y),
+                left: rect.left.min(x),
+                bottom: rect.bottom.max(y + 1),
+                right: rect.right.max(x + 1),
+            },
+        });
+    }
+    bounds
+}
+
+impl Rect {
+    fn write(&self, out: &mut Vec<u8>) {
+        for side in [self.top, self.left, self.bottom, self.right] {
+            out.extend_from_slice(&side.to_be_bytes());
+        }
+    }
+}
+
+/// PackBits, which encodes runs of three or more equal bytes as a count and the byte
+fn pack_bits(row: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < row.len() {
+        let run = row[i..]
+            .iter()
+            .take(128)
+            .take_while(|&&value| value == row[i])
+            .count();
+        if run >= 3 {
+            out.push((1 - run as i16) as u8);
+            out.push(row[i]);
+            i += run;
+            continue;
+        }
+
+        let start = i;
+        while i < row.len() && i - start < 128 {
+            if i + 2 < row.len() && row[i] == row[i + 1] && row[i] == row[i + 2] {
+                break;
+            }
+            i += 1;
+        }
+        out.push((i - start - 1) as u8);
+        out.extend_from_slice(&row[start..i]);
+    }
+}
+
+/// Compresses planes that share one compression header, as the merged image does
+fn encode_planes(planes: &[Vec<u8>], width: u32) -> Vec<u8> {
+    let mut out = 1u16.to_be_bytes().to_vec();
+    if width == 0 {
+        return out;
+    }
+
+    let mut rows = Vec::new();
+    let mut lengths = Vec::new();
+    for row in planes.iter().flat_map(|plane| plane.chunks(width as usize)) {
+        let start = rows.len();
+        pack_bits(row, &mut rows);
+        lengths.push((rows.len() - start) as u16);
+    }
+
+    for length in lengths {
+        out.extend_from_slice(&length.to_be_bytes());
+    }
+    out.extend_from_slice(&rows);
+    out
+}
+
+/// A Pascal string padded to four bytes. Characters outside ASCII are replaced, the full name
+/// is in the `luni` block.
+fn pascal_name(name: &str) -> Vec<u8> {
+    let bytes: Vec<u8> = name
+        .chars()
+        .map(|c| if c.is_ascii() { c as u8 } else { b'?' })
+        .take(255)
+        .collect();
+    let mut out = vec![bytes.len() as u8];
+    out.extend_from_slice(&bytes);
+    out.resize(out.len().div_ceil(4) * 4, 0);
+    out
+}
+
+fn additional_info(key: &[u8; 4], data: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(b"8BIM");
+    out.extend_from_slice(key);
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+fn unicode_name(name: &str) -> Vec<u8> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let mut data = (units.len() as u32).to_be_bytes().to_vec();
+    for unit in units {
+        data.extend_from_slice(&unit.to_be_bytes());
+    }
+    data.resize(data.len().div_ceil(4) * 4, 0);
+    data
+}
+
+/// Appends the layer's record to `records` and its channels to `channels`
+fn write_layer(layer: &PsdLayer, records: &mut Vec<u8>, channels: &mut Vec<u8>) {
+    let info = &layer.info;
+
+    // group records have no pixels, and pixel layers only keep the part that isn't transparent
+    let rect = match &layer.image {
+        Some(image) if info.kind.is_pixel() => {
+            bounds(image, |pixel| pixel[3] != 0).unwrap_or_default()
+        }
+        _ => Rect::default(),
+    };
+    let (width, height) = rect.size().unwrap();
+    let cropped = layer
+        .image
+        .as_ref()
+        .filter(|_| width > 0 && height > 0)
+        .map(|image| {
+            imageops::crop_imm(image, rect.left as u32, rect.top as u32, width, height).to_image()
+        });
+
+    // channel id, width and samples
+    let mut planes: Vec<(i16, u32, Vec<u8>)> = [(-1, 3), (0, 0), (1, 1), (2, 2)]
+        .into_iter()
+        .map(|(id, channel)| {
+            let plane = cropped.as_ref().map_or(Vec::new(), |image| {
+                image.pixels().map(|pixel| pixel[channel]).collect()
+            });
+            (id, width, plane)
+        })
+        .collect();
+
+    // masks are white wherever they leave the layer alone, which is also their default color
+    let mask_rect = layer
+        .mask
+        .as_ref()
+        .and_then(|mask| Some((mask, bounds(mask, |pixel| pixel[0] != 255)?)));
+    if let Some((mask, mask_rect)) = mask_rect {
+        let (mask_width, mask_height) = mask_rect.size().unwrap();
+        let cropped = imageops::crop_imm(
+            mask,
+            mask_rect.left as u32,
+            mask_rect.top as u32,
+            mask_width,
+            mask_height,
+        )
+        .to_image();
+        planes.push((-2, mask_width, cropped.into_raw()));
+    }
+
+    rect.write(records);
+    records.extend_from_slice(&(planes.len() as u16).to_be_bytes());
+    for (id, width, plane) in &planes {
+        let data = encode_planes(std::slice::from_ref(plane), *width);
+        records.extend_from_slice(&id.to_be_bytes());
+        records.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        channels.extend_from_slice(&data);
+    }
+
+    let (blend_key, section) = match &info.kind {
+        LayerKind::Group { mode, collapsed } => {
+            let kind = if *collapsed {
+                LayerRecord::CLOSED_FOLDER
+            } else {
+                LayerRecord::OPEN_FOLDER
+            };
+            let key = match mode {
+                GroupMode::PassThrough => PASS_THROUGH_KEY,
+                GroupMode::Isolated => blend_mode_key(info.blend_mode),
+            };
+            (key, Some((kind, key)))
+        }
+        LayerKind::GroupEnd => (b"norm", Some((LayerRecord::SECTION_DIVIDER, b"norm"))),
+        _ => (blend_mode_key(info.blend_mode), None),
+    };
+
+    records.extend_from_slice(b"8BIM");
+    records.extend_from_slice(blend_key);
+    records.push((info.opacity.clamp(0.0, 1.0) * 255.0).round() as u8);
+    records.push(info.clipped as u8);
+    records.push(if info.visible { 0 } else { LayerRecord::HIDDEN });
+    records.push(0);
+
+    let mut extra = Vec::new();
+    match mask_rect {
+        Some((_, mask_rect)) => {
+            extra.extend_from_slice(&20u32.to_be_bytes());
+            mask_rect.write(&mut extra);
+            // default color, flags and padding
+            extra.extend_from_slice(&[255, 0, 0, 0]);
+        }
+        None => extra.extend_from_slice(&0u32.to_be_bytes()),
+    }
+    // blending ranges
+    extra.extend_from_slice(&0u32.to_be_bytes());
+
+    let name = match info.kind {
+        LayerKind::GroupEnd => GROUP_END_NAME,
+        _ => &info.name,
+    };
+    extra.extend_from_slice(&pascal_name(name));
+    additional_info(b"luni", &unicode_name(name), &mut extra);
+    if let Some((kind, key)) = section {
+        let mut data = kind.to_be_bytes().to_vec();
+        data.extend_from_slice(b"8BIM");
+        data.extend_from_slice(key);
+        additional_info(b"lsct", &data, &mut extra);
+    }
+
+    records.extend_from_slice(&(extra.len() as u32).to_be_bytes());
+    records.extend_from_slice(&extra);
+}
+
+/// Writes `layers` as an 8 bit RGB Photoshop document, with `merged` as the flattened image
+/// other programs show
+pub fn write_psd<W: Write>(
+    mut writer: W,
+    size: (u32, u32),
+    layers: &[PsdLayer],
+    merged: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+) -> std::io::Result<()> {
+    if size.0 > MAX_PSD_SIZE || size.1 > MAX_PSD_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Photoshop documents can be at most {0}x{0} pixels",
+                MAX_PSD_SIZE
+            ),
+        ));
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"8BPS");
+    out.extend_from_slice(&1u16.to_be_bytes());
+    out.extend_from_slice(&[0; 6]);
+    // the merged image is RGBA
+    out.extend_from_slice(&4u16.to_be_bytes());
+    out.extend_from_slice(&size.1.to_be_bytes());
+    out.extend_from_slice(&size.0.to_be_bytes());
+    out.extend_from_slice(&8u16.to_be_bytes());
+    out.extend_from_slice(&3u16.to_be_bytes());
+
+    // color mode data and image resources
+    out.extend_from_slice(&0u32.to_be_bytes());
+    out.extend_from_slice(&0u32.to_be_bytes());
+
+    // a negative count says the merged image's alpha channel is its transparency
+    let mut layer_info = (-(layers.len() as i16)).to_be_bytes().to_vec();
+    let mut channels = Vec::new();
+    for layer in layers {
+        write_layer(layer, &mut layer_info, &mut channels);
+    }
+    layer_info.extend_from_slice(&channels);
+    layer_info.resize(layer_info.len().next_multiple_of(2), 0);
+
+    let section_length = 4 + layer_info.len() + 4;
+    out.extend_from_slice(&(section_length as u32).to_be_bytes());
+    out.extend_from_slice(&(layer_info.len() as u32).to_be_bytes());
+    out.extend_from_slice(&layer_info);
+    // global layer mask info
+    out.extend_from_slice(&0u32.to_be_bytes());
+
+    let planes: Vec<Vec<u8>> = (0..4)
+        .map(|channel| merged.pixels().map(|pixel| pixel[channel]).collect())
+        .collect();
+    out.extend_from_slice(&encode_planes(&planes, size.0));
+
+    writer.write_all(&out)?;
+    writer.flush()
+}
+
+impl Workspace {
+    /// Saves the layers as a Photoshop document. Tool layers are left out, and so are
+    /// adjustment layers, which Photoshop stores in a way this program can't write.
+    pub async fn save_psd(&self, path: &str, gpu: &GpuDevice) -> std::io::Result<()> {
+        #[cfg(debug_assertions)]
+        println!("Saving workspace as Photoshop document at {}...", path);
+
+        let mut layers = Vec::with_capacity(self.layers.len());
+        for (info, data) in self.layers.iter().zip(&self.layer_data) {
+            if info.is_tool_layer {
+                continue;
+            }
+            if info.kind.is_adjustment() {
+                eprintln!(
+                    "Adjustment layer \"{}\" can't be saved in a Photoshop document and was left out",
+                    info.name
+                );
+                continue;
+            }
+
+            let image = if info.kind.is_pixel() {
+                Some(gpu.texture_to_image(&data.texture, self.size.0).await)
+            } else {
+                None
+            };
+            let mask = if info.kind == LayerKind::GroupEnd {
+                None
+            } else {
+                Some(gpu.texture_to_luma_image(&data.mask, self.size.0).await)
+            };
+
+            layers.push(PsdLayer {
+                info: info.clone(),
+                image,
+                mask,
+            });
+        }
+
+        let output = self.output_texture.as_ref().unwrap();
+        let merged = gpu.texture_to_image(output, self.size.0).await;
+
+        let writer = BufWriter::new(File::create(path)?);
+        write_psd(writer, self.size, &layers, &merged)
+    }
+
+    /// Loads a Photoshop document, returning the warnings about anything that was left out
+    pub fn load_psd(
+        path: &str,
+        gpu: &GpuDevice,
+    ) -> Result<(Self, Vec<String>), WorkspaceLoadError> {
+        #[cfg(debug_assertions)]
+        println!("Loading Photoshop document at {}...", path);
+
+        let data = std::fs::read(path)?;
+        let max_size = gpu.device.limits().max_texture_dimension_2d;
+        let document = read_psd(&data, max_size)?;
+
+        let size = document.size;
+
+        let mut this = Workspace {
+            size,
+            pixel_at_center: (size.0 as f32 / 2.0, size.1 as f32 / 2.0),
+            ..Default::default()
+        };
+        this.bit_depth.check(gpu)?;
+
+        for layer in document.layers {
+            let info = layer.info;
+            let mut info = LayerCreationInfo {
+                name: info.name,
+                visible: info.visible,
+                opacity: info.opacity,
+                blend_mode: info.blend_mode,
+                kind: info.kind,
+                clipped: info.clipped,
+                init_image: layer.image,
+                init_mask_image: layer.mask,
+                ..Default::default()
+            };
+            let data = this.new_layer_data(&mut info, gpu);
+            this.layers.push(info.into());
+            this.layer_data.push(data);
+        }
+
+        this.build_output_texture(gpu);
+
+        Ok((this, document.warnings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: (u32, u32) = (16, 8);
+    /// Where the rectangle of the first layer record starts in files from `write_psd`: the
+    /// header, the lengths of the color mode data, the image resources, the layer and mask
+    /// section and the layer info, and the layer count
+    const FIRST_RECT: usize = 26 + 4 + 4 + 4 + 4 + 2;
+
+    fn header(version: u16, size: (u32, u32)) -> Vec<u8> {
+        let mut data = b"8BPS".to_vec();
+        data.extend_from_slice(&version.to_be_bytes());
+        data.extend_from_slice(&[0; 6]);
+        data.extend_from_slice(&3u16.to_be_bytes());
+        data.extend_from_slice(&size.1.to_be_bytes());
+        data.extend_from_slice(&size.0.to_be_bytes());
+        data.extend_from_slice(&8u16.to_be_bytes());
+        data.extend_from_slice(&3u16.to_be_bytes());
+        data
+    }
+
+    /// Opaque varied pixels in `2..10, 1..6`, transparent around them
+    fn block() -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        ImageBuffer::from_fn(SIZE.0, SIZE.1, |x, y| {
+            match (2..10).contains(&x) && (1..6).contains(&y) {
+                true => Rgba([(x * 20) as u8, (y * 40) as u8, 90, 255]),
+                false => TRANSPARENT,
+            }
+        })
+    }
+
+    fn write(layers: &[PsdLayer]) -> Vec<u8> {
+        let merged = ImageBuffer::from_pixel(SIZE.0, SIZE.1, Rgba([0, 0, 0, 255]));
+        let mut data = Vec::new();
+        write_psd(&mut data, SIZE, layers, &merged).unwrap();
+        data
+    }
+
+    fn single_layer() -> Vec<u8> {
+        write(&[PsdLayer {
+            info: layer_info(LayerKind::Pixel),
+            image: Some(block()),
+            mask: None,
+        }])
+    }
+
+    /// Moves the rectangle of the first layer record by `(x, y)`
+    fn move_first_rect(data: &mut [u8], (x, y): (i32, i32)) {
+        for (i, offset) in [y, x, y, x].into_iter().enumerate() {
+            let at = FIRST_RECT + i * 4;
+            let side = i32::from_be_bytes(data[at..at + 4].try_into().unwrap());
+            data[at..at + 4].copy_from_slice(&(side + offset).to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn oversized_documents_are_rejected_before_reading_on() {
+        // the files end after the header, so any other error means more was read
+        let psd = header(1, (10, 40000));
+        assert!(matches!(
+            read_psd(&psd, u32::MAX),
+            Err(WorkspaceLoadError::InvalidSize((10, 40000)))
+        ));
+
+        let psb = header(2, (10, 40000));
+        assert!(matches!(
+            read_psd(&psb, 16384),
+            Err(WorkspaceLoadError::InvalidSize((10, 40000)))
+        ));
+        assert!(matches!(
+            read_psd(&psb, u32::MAX),
+            Err(WorkspaceLoadError::BadPsd(_))
+        ));
+        let psb = header(2, (10, 400000));
+        assert!(matches!(
+            read_psd(&psb, u32::MAX),
+            Err(WorkspaceLoadError::InvalidSize((10, 400000)))
+        ));
+    }
+
+    #[test]
+    fn oversized_layers_are_rejected() {
+        let mut data = single_layer();
+        let bottom = FIRST_RECT + 8;
+        data[bottom..bottom + 4].copy_from_slice(&40000i32.to_be_bytes());
+
+        assert!(matches!(
+            read_psd(&data, u32::MAX),
+            Err(WorkspaceLoadError::BadPsd(_))
+        ));
+    }
+
+    #[test]
+    fn layers_reaching_outside_the_document_are_cropped() {
+        let mut data = single_layer();
+        move_first_rect(&mut data, (-3, -2));
+        let document = read_psd(&data, u32::MAX).unwrap();
+
+        let original = block();
+        let expected = ImageBuffer::from_fn(SIZE.0, SIZE.1, |x, y| {
+            *original
+                .get_pixel_checked(x + 3, y + 2)
+                .unwrap_or(&TRANSPARENT)
+        });
+        assert_eq!(document.layers[0].image.as_ref(), Some(&expected));
+    }
+
+    #[test]
+    fn layers_outside_the_document_are_empty() {
+        let mut data = single_layer();
+        move_first_rect(&mut data, (1000, -1000));
+        let document = read_psd(&data, u32::MAX).unwrap();
+
+        let empty = ImageBuffer::from_pixel(SIZE.0, SIZE.1, TRANSPARENT);
+        assert_eq!(document.layers[0].image.as_ref(), Some(&empty));
+    }
+
+    fn pixel_layer(name: &str, image: ImageBuffer<Rgba<u8>, Vec<u8>>) -> PsdLayer {
+        PsdLayer {
+            info: LayerInfo {
+                name: name.to_string(),
+                ..layer_info(LayerKind::Pixel)
+            },
+            image: Some(image),
+            mask: None,
+        }
+    }
+
+    fn marker(info: LayerInfo) -> PsdLayer {
+        PsdLayer {
+            info,
+            image: None,
+            mask: None,
+        }
+    }
+
+    /// Fades out to the right, and leaves the first columns alone so the writer crops it
+    fn gradient_mask() -> MaskImage {
+        ImageBuffer::from_fn(SIZE.0, SIZE.1, |x, y| match x < 3 {
+            true => Luma([255]),
+            false => Luma([(255 - x * 14 - y * 3) as u8]),
+        })
+    }
+
+    #[test]
+    fn groups_masks_and_clipping_round_trip() {
+        let group_end = LayerInfo {
+            name: GROUP_END_NAME.to_string(),
+            ..layer_info(LayerKind::GroupEnd)
+        };
+        // opacities are stored as bytes
+        let opacity = |byte: u8| byte as f32 / 255.0;
+
+        let mut masked = pixel_layer("Masked", block());
+        masked.info.blend_mode = BlendMode::Screen;
+        masked.info.opacity = opacity(128);
+        masked.mask = Some(gradient_mask());
+
+        let mut clipped = pixel_layer("Clipped", block());
+        clipped.info.blend_mode = BlendMode::Multiply;
+        clipped.info.clipped = true;
+        clipped.info.visible = false;
+
+        let mut nested = pixel_layer("Nested ünïcode", block());
+        nested.info.blend_mode = BlendMode::SoftLight;
+        nested.mask = Some(gradient_mask());
+
+        let mut inner = marker(LayerInfo {
+            name: "Inner".to_string(),
+            ..layer_info(LayerKind::Group {
+                mode: GroupMode::PassThrough,
+                collapsed: true,
+            })
+        });
+        inner.mask = Some(gradient_mask());
+
+        let outer = marker(LayerInfo {
+            name: "Outer".to_string(),
+            blend_mode: BlendMode::Overlay,
+            opacity: opacity(200),
+            ..layer_info(LayerKind::Group {
+                mode: GroupMode::Isolated,
+                collapsed: false,
+            })
+        });
+
+        let mut top = pixel_layer("Top", block());
+        top.info.blend_mode = BlendMode::HardLight;
+        top.info.opacity = opacity(77);
+
+        let layers = vec![
+            pixel_layer("Background", block()),
+            marker(group_end.clone()),
+            masked,
+            clipped,
+            marker(group_end),
+            nested,
+            inner,
+            outer,
+            top,
+        ];
+        let document = read_psd(&write(&layers), u32::MAX).unwrap();
+
+        assert_eq!(document.size, SIZE);
+        assert!(document.warnings.is_empty(), "{:?}", document.warnings);
+        assert_eq!(document.layers.len(), layers.len());
+        for (i, (written, read)) in layers.iter().zip(&document.layers).enumerate() {
+            assert_eq!(read.info, written.info, "layer {}", i);
+            assert_eq!(read.image, written.image, "pixels of layer {}", i);
+            assert_eq!(read.mask, written.mask, "mask of layer {}", i);
+        }
+    }
+}
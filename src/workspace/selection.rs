@@ -0,0 +1,694 @@
+//! The workspace selection, an R8 coverage texture that painting tools and filters clip to.
+
+use bytemuck::{Pod, Zeroable};
+use image::{GrayImage, Luma, Rgba, RgbaImage};
+use std::collections::VecDeque;
+use util::DeviceExt;
+use wgpu::*;
+
+use super::{bit_depth::DEPTH_CHECKED, history::Region, Workspace};
+use crate::GpuDevice;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SelectionOp {
+    #[default]
+    Replace,
+    Add,
+    Subtract,
+    Intersect,
+}
+
+impl SelectionOp {
+    pub const ALL: [SelectionOp; 4] = [
+        SelectionOp::Replace,
+        SelectionOp::Add,
+        SelectionOp::Subtract,
+        SelectionOp::Intersect,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SelectionOp::Replace => "Replace",
+            SelectionOp::Add => "Add",
+            SelectionOp::Subtract => "Subtract",
+            SelectionOp::Intersect => "Intersect",
+        }
+    }
+}
+
+/// Shapes in workspace pixels
+#[derive(Clone, Debug, PartialEq)]
+pub enum SelectionShape {
+    Rectangle {
+        min: (f32, f32),
+        max: (f32, f32),
+    },
+    Ellipse {
+        min: (f32, f32),
+        max: (f32, f32),
+    },
+    /// A freehand polygon, closed between the last and first points
+    Lasso(Vec<(f32, f32)>),
+    Everything,
+    /// Coverage of every pixel of the workspace, computed on the CPU
+    Mask(GrayImage),
+}
+
+/// Settings of the "Select Color Range" command
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ColorRange {
+    pub color: [u8; 4],
+    /// Distance from `color`, in 8 bit channel steps, over which pixels fade out of the selection
+    pub fuzziness: f32,
+    pub antialias: bool,
+    pub source: SampleSource,
+}
+
+impl Default for ColorRange {
+    fn default() -> Self {
+        Self {
+            color: [255, 255, 255, 255],
+            fuzziness: 40.0,
+            antialias: true,
+            source: SampleSource::Composite,
+        }
+    }
+}
+
+/// Where color based selections read their pixels from
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SampleSource {
+    /// The selected layer, or the top layer when none is selected
+    #[default]
+    Layer,
+    /// The composited image, as shown on the canvas
+    Composite,
+}
+
+// matches `Params` in shaders/selection/shape.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct ShapeParams {
+    kind: u32,
+    op: u32,
+    feather: f32,
+    point_count: u32,
+    min: [f32; 2],
+    max: [f32; 2],
+}
+
+const INVERT_OP: u32 = 4;
+
+pub struct Selection {
+    texture: Texture,
+    /// An inactive selection covers the whole workspace, so clipping to it changes nothing
+    active: bool,
+    /// Bumped every time the coverage changes
+    generation: u64,
+    pipeline: ComputePipeline,
+    layout: BindGroupLayout,
+    /// Bound in place of a mask for shapes that don't use one
+    empty_mask: Texture,
+}
+
+impl Selection {
+    pub fn new(size: (u32, u32), gpu: &GpuDevice) -> Self {
+        let device = &gpu.device;
+
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("Selection"),
+            size: Extent3d {
+                width: size.0,
+                height: size.1,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R8Unorm,
+            usage: TextureUsages::STORAGE_BINDING
+                | TextureUsages::TEXTURE_BINDING
+                | TextureUsages::COPY_SRC
+                | TextureUsages::COPY_DST,
+            view_formats: &[TextureFormat::R8Unorm],
+        });
+
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::StorageTexture {
+                        access: StorageTextureAccess::ReadWrite,
+                        format: TextureFormat::R8Unorm,
+                        view_dimension: TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: false },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: None,
+            layout: Some(&pipeline_layout),
+            module: gpu.shaders.get("selection/shape").unwrap(),
+            entry_point: "main",
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+        let empty_mask = create_mask_texture(gpu, &GrayImage::new(1, 1));
+
+        let mut this = Self {
+            texture,
+            active: false,
+            generation: 0,
+            pipeline,
+            layout,
+            empty_mask,
+        };
+        this.run(&SelectionShape::Everything, 0, 0.0, gpu);
+
+        this
+    }
+
+    pub fn texture(&self) -> &Texture {
+        &self.texture
+    }
+
+    pub fn view(&self) -> TextureView {
+        self.texture.create_view(&TextureViewDescriptor::default())
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn run(&mut self, shape: &SelectionShape, op: u32, feather: f32, gpu: &GpuDevice) {
+        let device = &gpu.device;
+
+        let normalize = |min: (f32, f32), max: (f32, f32)| {
+            (
+                [min.0.min(max.0), min.1.min(max.1)],
+                [min.0.max(max.0), min.1.max(max.1)],
+            )
+        };
+        let (kind, (min, max), points) = match shape {
+            SelectionShape::Rectangle { min, max } => (0, normalize(*min, *max), vec![[0.0; 2]]),
+            SelectionShape::Ellipse { min, max } => (1, normalize(*min, *max), vec![[0.0; 2]]),
+            SelectionShape::Lasso(points) => (
+                2,
+                ([0.0; 2], [0.0; 2]),
+                points.iter().map(|&(x, y)| [x, y]).collect(),
+            ),
+            SelectionShape::Everything => (3, ([0.0; 2], [0.0; 2]), vec![[0.0; 2]]),
+            SelectionShape::Mask(_) => (4, ([0.0; 2], [0.0; 2]), vec![[0.0; 2]]),
+        };
+        let mask = match shape {
+            SelectionShape::Mask(coverage) => Some(create_mask_texture(gpu, coverage)),
+            _ => None,
+        };
+
+        let params = ShapeParams {
+            kind,
+            op,
+            feather,
+            point_count: points.len() as u32,
+            min,
+            max,
+        };
+
+        let params_buffer = device.create_buffer_init(&util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::bytes_of(&params),
+            usage: BufferUsages::UNIFORM,
+        });
+        let points_buffer = device.create_buffer_init(&util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&points),
+            usage: BufferUsages::STORAGE,
+        });
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &self.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&self.view()),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: params_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: points_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: BindingResource::TextureView(
+                        &mask
+                            .as_ref()
+                            .unwrap_or(&self.empty_mask)
+                            .create_view(&TextureViewDescriptor::default()),
+                    ),
+                },
+            ],
+        });
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        {
+            let mut cpass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: None,
+                timestamp_writes: None,
+            });
+            cpass.set_pipeline(&self.pipeline);
+            cpass.set_bind_group(0, &bind_group, &[]);
+            cpass.dispatch_workgroups(
+                self.texture.width().div_ceil(16),
+                self.texture.height().div_ceil(16),
+                1,
+            );
+        }
+        gpu.queue.submit(Some(encoder.finish()));
+
+        self.generation += 1;
+    }
+}
+
+fn create_mask_texture(gpu: &GpuDevice, coverage: &GrayImage) -> Texture {
+    gpu.device.create_texture_with_data(
+        &gpu.queue,
+        &TextureDescriptor {
+            label: None,
+            size: Extent3d {
+                width: coverage.width(),
+                height: coverage.height(),
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R8Unorm,
+            usage: TextureUsages::TEXTURE_BINDING,
+            view_formats: &[TextureFormat::R8Unorm],
+        },
+        util::TextureDataOrder::LayerMajor,
+        coverage.as_raw(),
+    )
+}
+
+/// Pixels of a layer being filtered. Write the filtered image into `output` and hand the
+/// target back to [`Workspace::finish_filter`], which keeps it only inside the selection.
+pub struct FilterTarget {
+    pub index: usize,
+    pub input: Texture,
+    pub output: Texture,
+}
+
+impl Workspace {
+    pub fn selection(&self) -> &Selection {
+        self.selection
+            .as_ref()
+            .expect("the selection is created with the output texture")
+    }
+
+    /// Combines `shape` with the current selection. `feather` is the width of the soft edge
+    /// in pixels.
+    pub fn select(
+        &mut self,
+        shape: &SelectionShape,
+        op: SelectionOp,
+        feather: f32,
+        gpu: &GpuDevice,
+    ) {
+        let selection = self.selection.as_mut().unwrap();
+
+        // nothing is selected, so adding and intersecting act on an empty selection
+        let op = match (selection.active, op) {
+            (false, SelectionOp::Subtract) => return,
+            (false, _) => SelectionOp::Replace,
+            (true, op) => op,
+        };
+
+        selection.run(shape, op as u32, feather, gpu);
+        selection.active = true;
+    }
+
+    pub fn select_all(&mut self, gpu: &GpuDevice) {
+        let selection = self.selection.as_mut().unwrap();
+        selection.run(&SelectionShape::Everything, 0, 0.0, gpu);
+        selection.active = true;
+    }
+
+    pub fn select_none(&mut self, gpu: &GpuDevice) {
+        let selection = self.selection.as_mut().unwrap();
+        selection.run(&SelectionShape::Everything, 0, 0.0, gpu);
+        selection.active = false;
+    }
+
+    pub fn invert_selection(&mut self, gpu: &GpuDevice) {
+        let selection = self.selection.as_mut().unwrap();
+        if selection.active {
+            selection.run(&SelectionShape::Everything, INVERT_OP, 0.0, gpu);
+        } else {
+            self.select_all(gpu);
+        }
+    }
+
+    /// Reads back the pixels that color based selections are made from
+    pub async fn sample_image(&self, source: SampleSource, gpu: &GpuDevice) -> Option<RgbaImage> {
+        let texture = match source {
+            SampleSource::Layer => {
+                let index = self.selected_layer.or(self.layers.len().checked_sub(1))?;
+                if !self.layers[index].kind.is_pixel() {
+                    return None;
+                }
+                &self.layer_data[index].texture
+            }
+            SampleSource::Composite => self.output_texture.as_ref()?,
+        };
+
+        Some(gpu.texture_to_image(texture, self.size.0).await)
+    }
+
+    pub async fn select_color_range(
+        &mut self,
+        range: &ColorRange,
+        op: SelectionOp,
+        gpu: &GpuDevice,
+    ) {
+        let Some(image) = self.sample_image(range.source, gpu).await else {
+            return;
+        };
+
+        let mut coverage = color_range_coverage(&image, Rgba(range.color), range.fuzziness);
+        if range.antialias {
+            coverage = antialias_coverage(&coverage);
+        }
+
+        self.select(&SelectionShape::Mask(coverage), op, 0.0, gpu);
+    }
+
+    /// Copies a layer into textures a filter can read from and write to
+    pub fn begin_filter(&self, index: usize, gpu: &GpuDevice) -> FilterTarget {
+        let device = &gpu.device;
+        let create = || {
+            device.create_texture(&TextureDescriptor {
+                label: None,
+                size: Extent3d {
+                    width: self.size.0,
+                    height: self.size.1,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: self.bit_depth.texture_format(),
+                usage: TextureUsages::STORAGE_BINDING
+                    | TextureUsages::TEXTURE_BINDING
+                    | TextureUsages::COPY_SRC
+                    | TextureUsages::COPY_DST,
+                view_formats: &[self.bit_depth.texture_format()],
+            })
+        };
+        let input = create();
+        let output = create();
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        encoder.copy_texture_to_texture(
+            self.layer_data[index].texture.as_image_copy(),
+            input.as_image_copy(),
+            input.size(),
+        );
+        gpu.queue.submit(Some(encoder.finish()));
+
+        FilterTarget {
+            index,
+            input,
+            output,
+        }
+    }
+
+    /// Writes a filtered layer back, keeping the original pixels outside the selection
+    pub fn finish_filter(&mut self, target: FilterTarget, gpu: &GpuDevice) {
+        let command = self.snapshot_pixels(target.index, Region::whole(self.size), gpu);
+
+        let device = &gpu.device;
+        let layer_format = self.bit_depth.texture_format();
+        let storage_entry = |binding, access, format| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::StorageTexture {
+                access,
+                format,
+                view_dimension: TextureViewDimension::D2,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                storage_entry(0, StorageTextureAccess::ReadOnly, layer_format),
+                storage_entry(1, StorageTextureAccess::ReadOnly, layer_format),
+                storage_entry(2, StorageTextureAccess::ReadOnly, TextureFormat::R8Unorm),
+                storage_entry(3, StorageTextureAccess::WriteOnly, layer_format),
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: None,
+            layout: Some(&pipeline_layout),
+            module: gpu
+                .layer_shader("selection/clip", self.bit_depth)
+                .expect(DEPTH_CHECKED),
+            entry_point: "main",
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+        let view = |texture: &Texture| texture.create_view(&TextureViewDescriptor::default());
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&view(&target.input)),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(&view(&target.output)),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::TextureView(&self.selection().view()),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: BindingResource::TextureView(&view(
+                        &self.layer_data[target.index].texture,
+                    )),
+                },
+            ],
+        });
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        {
+            let mut cpass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: None,
+                timestamp_writes: None,
+            });
+            cpass.set_pipeline(&pipeline);
+            cpass.set_bind_group(0, &bind_group, &[]);
+            cpass.dispatch_workgroups(self.size.0.div_ceil(16), self.size.1.div_ceil(16), 1);
+        }
+        gpu.queue.submit(Some(encoder.finish()));
+
+        self.record(command);
+        self.recalculate_output_texture(gpu, target.index);
+    }
+}
+
+/// A line segment in workspace pixels
+pub type Segment = ((f32, f32), (f32, f32));
+
+/// The edges between selected and unselected pixels of a coverage image, merged into
+/// horizontal and vertical runs. Used to draw marching ants.
+pub fn selection_outline(coverage: &GrayImage) -> Vec<Segment> {
+    let (width, height) = coverage.dimensions();
+    let selected = |x: i64, y: i64| {
+        x >= 0
+            && y >= 0
+            && x < width as i64
+            && y < height as i64
+            && coverage.get_pixel(x as u32, y as u32).0[0] >= 128
+    };
+
+    let mut segments = Vec::new();
+
+    for y in 0..=height as i64 {
+        let mut run_start = None;
+        for x in 0..=width as i64 {
+            let edge = x < width as i64 && selected(x, y - 1) != selected(x, y);
+            match (edge, run_start) {
+                (true, None) => run_start = Some(x),
+                (false, Some(start)) => {
+                    segments.push(((start as f32, y as f32), (x as f32, y as f32)));
+                    run_start = None;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    for x in 0..=width as i64 {
+        let mut run_start = None;
+        for y in 0..=height as i64 {
+            let edge = y < height as i64 && selected(x - 1, y) != selected(x, y);
+            match (edge, run_start) {
+                (true, None) => run_start = Some(y),
+                (false, Some(start)) => {
+                    segments.push(((x as f32, start as f32), (x as f32, y as f32)));
+                    run_start = None;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    segments
+}
+
+/// The largest difference between any two channels of the colors
+fn color_distance(a: Rgba<u8>, b: Rgba<u8>) -> f32 {
+    a.0.iter()
+        .zip(b.0.iter())
+        .map(|(a, b)| a.abs_diff(*b))
+        .max()
+        .unwrap_or(0) as f32
+}
+
+/// Selects the pixels within `tolerance` of the color at `seed`, either only those connected
+/// to it or all of them. Tolerance is in 8 bit channel steps.
+pub fn magic_wand_coverage(
+    image: &RgbaImage,
+    seed: (u32, u32),
+    tolerance: f32,
+    contiguous: bool,
+) -> GrayImage {
+    let (width, height) = image.dimensions();
+    let mut coverage = GrayImage::new(width, height);
+    if seed.0 >= width || seed.1 >= height {
+        return coverage;
+    }
+
+    let target = *image.get_pixel(seed.0, seed.1);
+    let matches = |x: u32, y: u32| color_distance(*image.get_pixel(x, y), target) <= tolerance;
+
+    if !contiguous {
+        for (x, y, pixel) in coverage.enumerate_pixels_mut() {
+            if matches(x, y) {
+                *pixel = Luma([255]);
+            }
+        }
+        return coverage;
+    }
+
+    let mut queue = VecDeque::from([seed]);
+    coverage.put_pixel(seed.0, seed.1, Luma([255]));
+    while let Some((x, y)) = queue.pop_front() {
+        let neighbours = [
+            (x.wrapping_sub(1), y),
+            (x + 1, y),
+            (x, y.wrapping_sub(1)),
+            (x, y + 1),
+        ];
+        for (x, y) in neighbours {
+            if x < width && y < height && coverage.get_pixel(x, y).0[0] == 0 && matches(x, y) {
+                coverage.put_pixel(x, y, Luma([255]));
+                queue.push_back((x, y));
+            }
+        }
+    }
+
+    coverage
+}
+
+/// Selects every pixel by how close it is to `color`. Pixels within `fuzziness` are
+/// partially selected, falling off linearly with their distance.
+pub fn color_range_coverage(image: &RgbaImage, color: Rgba<u8>, fuzziness: f32) -> GrayImage {
+    GrayImage::from_fn(image.width(), image.height(), |x, y| {
+        let distance = color_distance(*image.get_pixel(x, y), color);
+        let coverage = if fuzziness > 0.0 {
+            (1.0 - distance / fuzziness).clamp(0.0, 1.0)
+        } else if distance == 0.0 {
+            1.0
+        } else {
+            0.0
+        };
+        Luma([(coverage * 255.0).round() as u8])
+    })
+}
+
+/// Softens hard edges with a 3x3 box filter
+pub fn antialias_coverage(coverage: &GrayImage) -> GrayImage {
+    let (width, height) = coverage.dimensions();
+    GrayImage::from_fn(width, height, |x, y| {
+        let mut sum = 0u32;
+        let mut count = 0u32;
+        for ny in y.saturating_sub(1)..=(y + 1).min(height - 1) {
+            for nx in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+                sum += coverage.get_pixel(nx, ny).0[0] as u32;
+                count += 1;
+            }
+        }
+        Luma([(sum / count) as u8])
+    })
+}
@@ -1,11 +1,14 @@
+use serde::{Deserialize, Serialize};
 use wgpu::{BindGroup, BindingResource, BufferBinding, Texture};
 
 use crate::{
-    workspace::{BlendMode, LayerCreationInfo},
+    workspace::{history::Region, BlendMode, LayerCreationInfo},
     GpuDevice,
 };
 
-use super::{ActionOrigin, Tool, Workspace};
+use super::{
+    commit_tool_layer, response_curve::ResponseCurve, ActionOrigin, PointerInput, Tool, Workspace,
+};
 use wgpu::util::DeviceExt;
 
 pub struct BrushTool {
@@ -16,9 +19,15 @@ pub struct BrushTool {
     pub hardness: f32,
     pub rotation: f32, // in radians
     pub opacity: f32,
+    pub pressure: PressureResponse,
     pub group_one_binding: Option<BindGroup>,
+    pub opacity_buffer: Option<wgpu::Buffer>,
+    pub size_buffer: Option<wgpu::Buffer>,
+    pub hardness_buffer: Option<wgpu::Buffer>,
     pub group_zero_binding: Option<BindGroup>,
     pub pipeline: Option<wgpu::ComputePipeline>,
+    pub tool_layer: Option<usize>,
+    pub stroke_bounds: Option<Region>,
 }
 
 pub struct BrushToolSettings {
@@ -29,6 +38,7 @@ pub struct BrushToolSettings {
     pub hardness: f32,
     pub rotation: f32, // in radians
     pub opacity: f32,
+    pub pressure: PressureResponse,
 }
 
 impl Default for BrushToolSettings {
@@ -37,12 +47,56 @@ impl Default for BrushToolSettings {
             size: 10.0,
             color: None,
             texture: None,
-            blend_mode: "normal".into(),
+            blend_mode: BlendMode::Normal,
             hardness: 1.0,
             rotation: 0.0,
             opacity: 1.0,
+            pressure: PressureResponse::default(),
+        }
+    }
+}
+
+/// How pen pressure affects a brush. Each curve maps pressure to a multiplier of the
+/// brush's own setting.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PressureResponse {
+    pub size: ResponseCurve,
+    pub opacity: ResponseCurve,
+    pub hardness: ResponseCurve,
+}
+
+impl Default for PressureResponse {
+    fn default() -> Self {
+        Self {
+            size: ResponseCurve::linear(),
+            opacity: ResponseCurve::constant(1.0),
+            hardness: ResponseCurve::constant(1.0),
+        }
+    }
+}
+
+impl PressureResponse {
+    /// Returns the size, opacity and hardness of a dab painted with `input`
+    pub fn apply(&self, input: &PointerInput, size: f32, opacity: f32, hardness: f32) -> Dab {
+        Dab {
+            size: size * self.size.evaluate(input.pressure),
+            opacity: (opacity * self.opacity.evaluate(input.pressure)).clamp(0.0, 1.0),
+            hardness: (hardness * self.hardness.evaluate(input.pressure)).clamp(0.0, 1.0),
         }
     }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.collapsing("Pressure to size", |ui| self.size.ui(ui));
+        ui.collapsing("Pressure to opacity", |ui| self.opacity.ui(ui));
+        ui.collapsing("Pressure to hardness", |ui| self.hardness.ui(ui));
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Dab {
+    pub size: f32,
+    pub opacity: f32,
+    pub hardness: f32,
 }
 
 impl BrushTool {
@@ -55,9 +109,15 @@ impl BrushTool {
             hardness: settings.hardness,
             rotation: settings.rotation,
             opacity: settings.opacity,
+            pressure: settings.pressure,
             group_one_binding: None,
+            opacity_buffer: None,
+            size_buffer: None,
+            hardness_buffer: None,
             group_zero_binding: None,
             pipeline: None,
+            tool_layer: None,
+            stroke_bounds: None,
         };
 
         this.gen_group_one_binding(gpu);
@@ -67,15 +127,15 @@ impl BrushTool {
     }
 
     fn create_pipeline(&mut self, gpu: &GpuDevice) {
-        let device = &gpu.render_state.device;
+        let device = &gpu.device;
         let shader = gpu.shaders.get("tools/brush").unwrap();
 
-        let zero_layout =
-            gpu.render_state
-                .device
-                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                    label: None,
-                    entries: &[wgpu::BindGroupLayoutEntry {
+        let zero_layout = gpu
+            .device
+            .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: None,
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
                         binding: 0,
                         visibility: wgpu::ShaderStages::COMPUTE,
                         ty: wgpu::BindingType::StorageTexture {
@@ -84,85 +144,77 @@ impl BrushTool {
                             view_dimension: wgpu::TextureViewDimension::D2,
                         },
                         count: None,
-                    }],
-                });
-
-        let one_layout =
-            gpu.render_state
-                .device
-                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                    label: None,
-                    entries: &[
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 0,
-                            visibility: wgpu::ShaderStages::COMPUTE,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Uniform,
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
                         },
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 1,
-                            visibility: wgpu::ShaderStages::COMPUTE,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Uniform,
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
+                        count: None,
+                    },
+                ],
+            });
+
+        let one_layout = gpu
+            .device
+            .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: None,
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
                         },
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 2,
-                            visibility: wgpu::ShaderStages::COMPUTE,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Uniform,
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
                         },
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 3,
-                            visibility: wgpu::ShaderStages::COMPUTE,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Uniform,
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
                         },
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 4,
-                            visibility: wgpu::ShaderStages::COMPUTE,
-                            ty: wgpu::BindingType::Texture {
-                                multisampled: false,
-                                view_dimension: wgpu::TextureViewDimension::D2,
-                                sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                            },
-                            count: None,
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
                         },
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 5,
-                            visibility: wgpu::ShaderStages::COMPUTE,
-                            ty: wgpu::BindingType::Buffer {
-                                ty: wgpu::BufferBindingType::Uniform,
-                                has_dynamic_offset: false,
-                                min_binding_size: None,
-                            },
-                            count: None,
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
                         },
-                    ],
-                });
-
-        let two_layout =
-            gpu.render_state
-                .device
-                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                    label: None,
-                    entries: &[wgpu::BindGroupLayoutEntry {
-                        binding: 0,
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 5,
                         visibility: wgpu::ShaderStages::COMPUTE,
                         ty: wgpu::BindingType::Buffer {
                             ty: wgpu::BufferBindingType::Uniform,
@@ -170,8 +222,25 @@ impl BrushTool {
                             min_binding_size: None,
                         },
                         count: None,
-                    }],
-                });
+                    },
+                ],
+            });
+
+        let two_layout = gpu
+            .device
+            .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: None,
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
 
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: None,
@@ -194,28 +263,24 @@ impl BrushTool {
     }
 
     fn gen_group_one_binding(&mut self, gpu: &GpuDevice) {
-        let device = &gpu.render_state.device;
+        let device = &gpu.device;
         let brush_texture_view = self
             .texture
             .as_ref()
-            .unwrap_or(
-                &gpu.render_state
-                    .device
-                    .create_texture(&wgpu::TextureDescriptor {
-                        label: None,
-                        size: wgpu::Extent3d {
-                            width: 1,
-                            height: 1,
-                            depth_or_array_layers: 1,
-                        },
-                        mip_level_count: 1,
-                        sample_count: 1,
-                        dimension: wgpu::TextureDimension::D2,
-                        format: wgpu::TextureFormat::R8Unorm,
-                        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-                        view_formats: &[wgpu::TextureFormat::R8Unorm],
-                    }),
-            )
+            .unwrap_or(&gpu.device.create_texture(&wgpu::TextureDescriptor {
+                label: None,
+                size: wgpu::Extent3d {
+                    width: 1,
+                    height: 1,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::R8Unorm,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                view_formats: &[wgpu::TextureFormat::R8Unorm],
+            }))
             .create_view(&wgpu::TextureViewDescriptor::default());
 
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -289,21 +354,18 @@ impl BrushTool {
             contents: bytemuck::cast_slice(&[self.opacity]),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
-        let opacity_buffer: BindingResource<'_> = opacity_buffer.as_entire_binding();
 
         let brush_size_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: None,
             contents: bytemuck::cast_slice(&[self.size]),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
-        let brush_size_buffer: BindingResource<'_> = brush_size_buffer.as_entire_binding();
 
         let brush_hardness_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: None,
             contents: bytemuck::cast_slice(&[self.hardness]),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
-        let brush_hardness_buffer: BindingResource<'_> = brush_hardness_buffer.as_entire_binding();
 
         let brush_rotation_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: None,
@@ -324,15 +386,15 @@ impl BrushTool {
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: opacity_buffer,
+                    resource: opacity_buffer.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: brush_size_buffer,
+                    resource: brush_size_buffer.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: 2,
-                    resource: brush_hardness_buffer,
+                    resource: brush_hardness_buffer.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: 3,
@@ -351,6 +413,9 @@ impl BrushTool {
         });
 
         self.group_one_binding = Some(bind_group);
+        self.opacity_buffer = Some(opacity_buffer);
+        self.size_buffer = Some(brush_size_buffer);
+        self.hardness_buffer = Some(brush_hardness_buffer);
     }
 
     fn create_brush_layer(&mut self, workspace: &mut Workspace, gpu: &GpuDevice) {
@@ -358,10 +423,14 @@ impl BrushTool {
         workspace.create_layer(
             LayerCreationInfo {
                 name: "Brush Layer".to_string(),
-                blend_mode: self.blend_mode.clone(),
+                blend_mode: self.blend_mode,
                 init_mask_luma: Some(0),
                 init_rgba: self.color,
                 is_tool_layer: true,
+                // clip the preview the same way as the layer it will be merged into
+                clipped: workspace
+                    .selected_layer
+                    .is_some_and(|i| workspace.layers[i].clipped),
                 ..Default::default()
             },
             gpu,
@@ -369,43 +438,88 @@ impl BrushTool {
         );
 
         let index = index.unwrap_or(workspace.layers.len() - 1);
+        self.tool_layer = Some(index);
+        self.stroke_bounds = None;
+
         let layer_mask = &workspace.layer_data[index].mask;
         let mask_view = layer_mask.create_view(&wgpu::TextureViewDescriptor::default());
 
         let bind_group_layout =
-            gpu.render_state
-                .device
+            gpu.device
                 .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                     label: None,
-                    entries: &[wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::StorageTexture {
-                            access: wgpu::StorageTextureAccess::ReadWrite,
-                            format: wgpu::TextureFormat::R8Unorm,
-                            view_dimension: wgpu::TextureViewDimension::D2,
+                    entries: &[
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 0,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::StorageTexture {
+                                access: wgpu::StorageTextureAccess::ReadWrite,
+                                format: wgpu::TextureFormat::R8Unorm,
+                                view_dimension: wgpu::TextureViewDimension::D2,
+                            },
+                            count: None,
                         },
-                        count: None,
-                    }],
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 1,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Texture {
+                                multisampled: false,
+                                view_dimension: wgpu::TextureViewDimension::D2,
+                                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            },
+                            count: None,
+                        },
+                    ],
                 });
 
-        let bind_group = gpu
-            .render_state
-            .device
-            .create_bind_group(&wgpu::BindGroupDescriptor {
-                layout: &bind_group_layout,
-                entries: &[wgpu::BindGroupEntry {
+        let bind_group = gpu.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
                     binding: 0,
                     resource: wgpu::BindingResource::TextureView(&mask_view),
-                }],
-                label: None,
-            });
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&workspace.selection().view()),
+                },
+            ],
+            label: None,
+        });
 
         self.group_zero_binding = Some(bind_group);
     }
-    fn brush(&self, mouse_loc: (f32, f32), gpu: &GpuDevice) {
-        let device = &gpu.render_state.device;
-        let queue = &gpu.render_state.queue;
+    fn brush(&mut self, input: PointerInput, workspace: &Workspace, gpu: &GpuDevice) {
+        let mouse_loc = input.pos;
+        let dab = self
+            .pressure
+            .apply(&input, self.size, self.opacity, self.hardness);
+
+        if let Some(dab) = Region::around(mouse_loc, dab.size + 1.0, workspace.size) {
+            self.stroke_bounds = Some(match self.stroke_bounds {
+                Some(bounds) => bounds.union(dab),
+                None => dab,
+            });
+        }
+
+        let device = &gpu.device;
+        let queue = &gpu.queue;
+
+        queue.write_buffer(
+            self.opacity_buffer.as_ref().unwrap(),
+            0,
+            bytemuck::cast_slice(&[dab.opacity]),
+        );
+        queue.write_buffer(
+            self.size_buffer.as_ref().unwrap(),
+            0,
+            bytemuck::cast_slice(&[dab.size]),
+        );
+        queue.write_buffer(
+            self.hardness_buffer.as_ref().unwrap(),
+            0,
+            bytemuck::cast_slice(&[dab.hardness]),
+        );
 
         let brush_center_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: None,
@@ -449,7 +563,7 @@ impl BrushTool {
             cpass.set_bind_group(1, self.group_one_binding.as_ref().unwrap(), &[]);
             cpass.set_bind_group(2, &bind_group, &[]);
 
-            let work_groups = (self.size + 1.0) / 4.0;
+            let work_groups = (dab.size + 1.0) / 4.0;
             let work_groups = work_groups.ceil() as u32;
             println!("Work groups: {}", work_groups);
             cpass.dispatch_workgroups(work_groups, work_groups, 1);
@@ -457,9 +571,11 @@ impl BrushTool {
 
         queue.submit(std::iter::once(cpass.finish()));
     }
-    fn apply(&self, workspace: &mut Workspace, gpu: &GpuDevice) {
-        // merge the tool layer with the layer below it
-        todo!()
+    fn apply(&mut self, workspace: &mut Workspace, gpu: &GpuDevice) {
+        self.group_zero_binding = None;
+        if let Some(tool_layer) = self.tool_layer.take() {
+            commit_tool_layer(workspace, gpu, tool_layer, self.stroke_bounds.take());
+        }
     }
 }
 
@@ -469,19 +585,24 @@ impl Tool for BrushTool {
     }
     fn perform_action(&mut self, workspace: &mut Workspace, gpu: &GpuDevice, origin: ActionOrigin) {
         match origin {
-            ActionOrigin::MouseDown(mouse_loc) => {
+            ActionOrigin::MouseDown(input) => {
                 self.create_brush_layer(workspace, gpu);
-                self.brush(mouse_loc, gpu);
+                self.brush(input, workspace, gpu);
                 workspace.recalculate_output_texture(gpu, workspace.selected_layer.unwrap_or(0));
             }
-            ActionOrigin::MouseMove(mouse_loc) => {
-                self.brush(mouse_loc, gpu);
+            ActionOrigin::MouseMove(input) => {
+                self.brush(input, workspace, gpu);
                 workspace.recalculate_output_texture(gpu, workspace.selected_layer.unwrap_or(0));
             }
-            ActionOrigin::MouseUp(_mouse_loc) => {
+            ActionOrigin::MouseUp(_input) => {
                 self.apply(workspace, gpu);
             }
-            _ => (),
         }
     }
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.add(egui::Slider::new(&mut self.size, 1.0..=500.0).text("Size"));
+        ui.add(egui::Slider::new(&mut self.opacity, 0.0..=1.0).text("Opacity"));
+        ui.add(egui::Slider::new(&mut self.hardness, 0.0..=1.0).text("Hardness"));
+        self.pressure.ui(ui);
+    }
 }
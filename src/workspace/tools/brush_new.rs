@@ -1,45 +1,134 @@
+use wgpu::util::DeviceExt;
 use wgpu::*;
 
 use crate::{
-    workspace::{BlendMode, LayerCreationInfo},
+    workspace::{history::Region, BlendMode, LayerCreationInfo},
     GpuDevice,
 };
 
-use super::{ActionOrigin, Tool, Workspace};
+use super::{
+    brush::{BrushToolSettings, PressureResponse},
+    commit_tool_layer, ActionOrigin, PointerInput, Tool, Workspace,
+};
 
 use bytemuck::{Pod, Zeroable};
 
-struct BrushToolNew {
+/// A round brush that renders the stroke as capsules between the recorded mouse positions,
+/// so fast strokes stay continuous no matter how far apart the mouse events are.
+pub struct BrushToolNew {
     pub size: f32,
     pub color: Option<[u8; 4]>,
-    pub texture: Option<TextureView>,
     pub blend_mode: BlendMode,
     pub hardness: f32,
     pub rotation: f32, // in radians
     pub opacity: f32,
+    /// Minimum distance between recorded path points, as a fraction of the brush size
+    pub spacing: f32,
+    pub pressure: PressureResponse,
 
-    pub path: Vec<Vec2>,
+    pub path: Vec<PathPoint>,
+    /// Index of the first path point that has not been rendered yet
+    pub rendered: usize,
+    pub mask: Option<TextureView>,
+    pub tool_layer: Option<usize>,
+    pub stroke_bounds: Option<Region>,
     pub pipeline: Option<ComputePipeline>,
+    pub group_zero_layout: Option<BindGroupLayout>,
+    pub group_one_layout: Option<BindGroupLayout>,
     pub group_zero_bind_group: Option<BindGroup>,
     pub path_buffer: Option<Buffer>,
     pub path_len_buffer: Option<Buffer>,
+    pub origin_buffer: Option<Buffer>,
     pub cur_path_buffer_len: usize,
 }
 
 #[repr(C)]
 #[derive(Copy, Clone, Pod, Zeroable)]
-struct Vec2(f32, f32);
+pub struct Vec2(pub f32, pub f32);
+
+/// A point on the stroke, with the pressure curves already applied. `size`, `opacity` and
+/// `hardness` multiply the brush's own settings.
+#[repr(C)]
+#[derive(Copy, Clone, Pod, Zeroable)]
+pub struct PathPoint {
+    pub position: Vec2,
+    pub size: f32,
+    pub opacity: f32,
+    pub hardness: f32,
+    // WGSL rounds the struct up to the alignment of its vec2
+    _padding: f32,
+}
+
+impl PathPoint {
+    pub fn new(input: &PointerInput, pressure: &PressureResponse) -> Self {
+        let dab = pressure.apply(input, 1.0, 1.0, 1.0);
+        Self {
+            position: Vec2(input.pos.0, input.pos.1),
+            size: dab.size,
+            opacity: dab.opacity,
+            hardness: dab.hardness,
+            _padding: 0.0,
+        }
+    }
+}
+
+/// The pixels covered by capsules of `size` between `points`, clamped to the workspace
+pub fn path_bounds(points: &[PathPoint], size: f32, workspace_size: (u32, u32)) -> Option<Region> {
+    let radius = size * points.iter().map(|p| p.size).fold(0.0, f32::max) + 1.0;
+    let (min, max) = points.iter().fold(
+        (
+            (f32::INFINITY, f32::INFINITY),
+            (f32::NEG_INFINITY, f32::NEG_INFINITY),
+        ),
+        |(min, max), point| {
+            let Vec2(x, y) = point.position;
+            ((min.0.min(x), min.1.min(y)), (max.0.max(x), max.1.max(y)))
+        },
+    );
+    Region::covering(
+        (min.0 - radius, min.1 - radius),
+        (max.0 + radius, max.1 + radius),
+        workspace_size,
+    )
+}
 
 impl BrushToolNew {
+    pub fn new(settings: BrushToolSettings, gpu: &GpuDevice) -> Self {
+        #[cfg(debug_assertions)]
+        assert!(
+            settings.texture.is_none(),
+            "textured brushes are not supported by the path brush"
+        );
+
+        let mut this = Self {
+            size: settings.size,
+            color: settings.color,
+            blend_mode: settings.blend_mode,
+            hardness: settings.hardness,
+            rotation: settings.rotation,
+            opacity: settings.opacity,
+            pressure: settings.pressure,
+            ..Default::default()
+        };
+
+        this.build_pipeline(gpu);
+
+        this
+    }
+
     fn acquire_mask(&mut self, workspace: &mut Workspace, gpu: &GpuDevice) {
         let index = workspace.selected_layer.map(|x| x + 1);
         workspace.create_layer(
             LayerCreationInfo {
                 name: "Brush Layer".to_string(),
-                blend_mode: self.blend_mode.clone(),
+                blend_mode: self.blend_mode,
                 init_mask_luma: Some(0),
                 init_rgba: self.color,
                 is_tool_layer: true,
+                // clip the preview the same way as the layer it will be merged into
+                clipped: workspace
+                    .selected_layer
+                    .is_some_and(|i| workspace.layers[i].clipped),
                 ..Default::default()
             },
             gpu,
@@ -47,26 +136,198 @@ impl BrushToolNew {
         );
 
         let index = index.unwrap_or(workspace.layers.len() - 1);
+        self.tool_layer = Some(index);
+        self.stroke_bounds = None;
+
         let layer_mask = &workspace.layer_data[index].mask;
         let mask_view = layer_mask.create_view(&wgpu::TextureViewDescriptor::default());
 
-        self.texture = Some(mask_view);
+        self.mask = Some(mask_view);
     }
 
-    fn build_pipeline(&mut self, workspace: &mut Workspace, gpu: &GpuDevice) {
+    fn build_pipeline(&mut self, gpu: &GpuDevice) {
+        let device = &gpu.device;
         let shader = gpu.shaders.get("tools/brush_new").unwrap();
 
-        todo!();
+        let uniform_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+
+        let group_zero_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::StorageTexture {
+                        access: StorageTextureAccess::ReadWrite,
+                        format: TextureFormat::R8Unorm,
+                        view_dimension: TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                uniform_entry(1),
+                uniform_entry(2),
+                uniform_entry(3),
+                uniform_entry(4),
+                BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: false },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let group_one_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                uniform_entry(1),
+                uniform_entry(2),
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&group_zero_layout, &group_one_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: None,
+            layout: Some(&pipeline_layout),
+            module: shader,
+            entry_point: "main",
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+        self.path_len_buffer = Some(device.create_buffer(&BufferDescriptor {
+            label: Some("Brush Path Length Buffer"),
+            size: std::mem::size_of::<u32>() as u64,
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        }));
+        self.origin_buffer = Some(device.create_buffer(&BufferDescriptor {
+            label: Some("Brush Origin Buffer"),
+            size: 2 * std::mem::size_of::<u32>() as u64,
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        }));
+
+        self.pipeline = Some(pipeline);
+        self.group_zero_layout = Some(group_zero_layout);
+        self.group_one_layout = Some(group_one_layout);
     }
 
+    fn build_group_zero(&mut self, workspace: &Workspace, gpu: &GpuDevice) {
+        let device = &gpu.device;
+        let uniform = |value: f32| {
+            device.create_buffer_init(&util::BufferInitDescriptor {
+                label: None,
+                contents: bytemuck::cast_slice(&[value]),
+                usage: BufferUsages::UNIFORM,
+            })
+        };
+
+        let opacity_buffer = uniform(self.opacity);
+        let size_buffer = uniform(self.size);
+        let hardness_buffer = uniform(self.hardness);
+        let rotation_buffer = uniform(self.rotation);
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: self.group_zero_layout.as_ref().unwrap(),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(self.mask.as_ref().unwrap()),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: opacity_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: size_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: hardness_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: rotation_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: BindingResource::TextureView(&workspace.selection().view()),
+                },
+            ],
+        });
+
+        self.group_zero_bind_group = Some(bind_group);
+    }
+
+    /// Adds a point to the path unless it is closer than `spacing` to the last one
+    fn push_point(&mut self, input: &PointerInput) -> bool {
+        let point = PathPoint::new(input, &self.pressure);
+        if let Some(last) = self.path.last() {
+            let Vec2(x, y) = last.position;
+            let distance = ((input.pos.0 - x).powi(2) + (input.pos.1 - y).powi(2)).sqrt();
+            if distance < self.spacing * self.size * last.size.min(point.size) {
+                return false;
+            }
+        }
+
+        self.path.push(point);
+        true
+    }
+
+    /// Renders the segments of the path that have not been rendered yet
     fn render_path(&mut self, workspace: &mut Workspace, gpu: &GpuDevice) {
-        let pipeline = self.pipeline.as_ref().unwrap();
-        if self.path.len() > self.cur_path_buffer_len {
-            let path_buffer = gpu.render_state.device.create_buffer(&BufferDescriptor {
+        if self.rendered >= self.path.len() {
+            return;
+        }
+        // the segment leading into the first new point starts at the last rendered point
+        let pending = &self.path[self.rendered.saturating_sub(1)..];
+
+        let bounds = path_bounds(pending, self.size, workspace.size);
+        let Some(bounds) = bounds else {
+            self.rendered = self.path.len();
+            return;
+        };
+        self.stroke_bounds = Some(match self.stroke_bounds {
+            Some(stroke_bounds) => stroke_bounds.union(bounds),
+            None => bounds,
+        });
+
+        if pending.len() > self.cur_path_buffer_len {
+            self.cur_path_buffer_len = round_up_power_two(pending.len());
+            let path_buffer = gpu.device.create_buffer(&BufferDescriptor {
                 label: Some("Brush Path Buffer"),
-                size: (round_up_power_two(
-                    self.path.len() * 2 * std::mem::size_of::<f32>() as usize,
-                )) as u64,
+                size: (self.cur_path_buffer_len * std::mem::size_of::<PathPoint>()) as u64,
                 usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
                 mapped_at_creation: false,
             });
@@ -74,75 +335,42 @@ impl BrushToolNew {
             self.path_buffer = Some(path_buffer);
         }
 
-        let mut path_buffer = self.path_buffer.take().unwrap();
-        {
-            let mut mapped_range = path_buffer
-                .slice(..(self.path.len() * 2 * std::mem::size_of::<f32>()) as u64)
-                .get_mapped_range_mut();
-
-            mapped_range.copy_from_slice(bytemuck::cast_slice(self.path.as_slice()));
-        }
-        path_buffer.unmap();
-        self.path_buffer = Some(path_buffer);
-
-        let mut path_len_buffer = self.path_buffer.take().unwrap();
-        {
-            let mut mapped_range = path_len_buffer
-                .slice(..std::mem::size_of::<u32>() as u64)
-                .get_mapped_range_mut();
+        let queue = &gpu.queue;
+        let path_buffer = self.path_buffer.as_ref().unwrap();
+        let path_len_buffer = self.path_len_buffer.as_ref().unwrap();
+        let origin_buffer = self.origin_buffer.as_ref().unwrap();
+        queue.write_buffer(path_buffer, 0, bytemuck::cast_slice(pending));
+        queue.write_buffer(
+            path_len_buffer,
+            0,
+            bytemuck::cast_slice(&[pending.len() as u32]),
+        );
+        queue.write_buffer(
+            origin_buffer,
+            0,
+            bytemuck::cast_slice(&[bounds.x, bounds.y]),
+        );
 
-            mapped_range.copy_from_slice(bytemuck::cast_slice(&[self.path.len() as u32]));
-        }
-        path_len_buffer.unmap();
-        self.path_len_buffer = Some(path_len_buffer);
-
-        let group_one_layout =
-            gpu.render_state
-                .device
-                .create_bind_group_layout(&BindGroupLayoutDescriptor {
-                    entries: &[
-                        BindGroupLayoutEntry {
-                            binding: 0,
-                            visibility: ShaderStages::COMPUTE,
-                            ty: BindingType::Buffer {
-                                min_binding_size: None,
-                                has_dynamic_offset: false,
-                                ty: BufferBindingType::Storage { read_only: true },
-                            },
-                            count: None,
-                        },
-                        BindGroupLayoutEntry {
-                            binding: 1,
-                            visibility: ShaderStages::COMPUTE,
-                            ty: BindingType::Buffer {
-                                min_binding_size: None,
-                                has_dynamic_offset: false,
-                                ty: BufferBindingType::Uniform,
-                            },
-                            count: None,
-                        },
-                    ],
-                    label: None,
-                });
-
-        let group_one_bind_group =
-            gpu.render_state
-                .device
-                .create_bind_group(&BindGroupDescriptor {
-                    layout: &group_one_layout,
-                    entries: &[BindGroupEntry {
-                        binding: 0,
-                        resource: BindingResource::Buffer(BufferBinding {
-                            buffer: self.path_buffer.as_ref().unwrap(),
-                            offset: 0,
-                            size: None,
-                        }),
-                    }],
-                    label: None,
-                });
+        let group_one_bind_group = gpu.device.create_bind_group(&BindGroupDescriptor {
+            layout: self.group_one_layout.as_ref().unwrap(),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: path_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: path_len_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: origin_buffer.as_entire_binding(),
+                },
+            ],
+            label: None,
+        });
 
         let mut cpass = gpu
-            .render_state
             .device
             .create_command_encoder(&CommandEncoderDescriptor { label: None });
         {
@@ -151,26 +379,31 @@ impl BrushToolNew {
                 timestamp_writes: None,
             });
 
-            cpass.set_pipeline(pipeline);
+            cpass.set_pipeline(self.pipeline.as_ref().unwrap());
             cpass.set_bind_group(0, self.group_zero_bind_group.as_ref().unwrap(), &[]);
             cpass.set_bind_group(1, &group_one_bind_group, &[]);
 
-            let work_groups = (
-                (workspace.size.0 as f32 / 8.0).ceil() as u32,
-                (workspace.size.1 as f32 / 8.0).ceil() as u32,
-                1,
-            );
-            cpass.dispatch_workgroups(work_groups.0, work_groups.1, work_groups.2);
+            cpass.dispatch_workgroups(bounds.width.div_ceil(8), bounds.height.div_ceil(8), 1);
         }
 
-        gpu.render_state
-            .queue
-            .submit(std::iter::once(cpass.finish()));
+        gpu.queue.submit(std::iter::once(cpass.finish()));
+
+        self.rendered = self.path.len();
+    }
+
+    fn apply(&mut self, workspace: &mut Workspace, gpu: &GpuDevice) {
+        self.group_zero_bind_group = None;
+        self.mask = None;
+        self.path.clear();
+        self.rendered = 0;
+        if let Some(tool_layer) = self.tool_layer.take() {
+            commit_tool_layer(workspace, gpu, tool_layer, self.stroke_bounds.take());
+        }
     }
 }
 
 #[inline]
-fn round_up_power_two(x: usize) -> usize {
+pub(super) fn round_up_power_two(x: usize) -> usize {
     let mut x = x;
     x -= 1;
     x |= x >> 1;
@@ -187,17 +420,25 @@ impl Default for BrushToolNew {
         Self {
             size: 10.0,
             color: Some([255, 255, 255, 255]),
-            texture: None,
-            blend_mode: "normal".to_string(),
+            blend_mode: BlendMode::Normal,
             hardness: 0.5,
             rotation: 0.0,
             opacity: 1.0,
+            spacing: 0.1,
+            pressure: PressureResponse::default(),
             path: Vec::new(),
+            rendered: 0,
+            mask: None,
+            tool_layer: None,
+            stroke_bounds: None,
             pipeline: None,
+            group_zero_layout: None,
+            group_one_layout: None,
             group_zero_bind_group: None,
             path_buffer: None,
-            cur_path_buffer_len: 0,
             path_len_buffer: None,
+            origin_buffer: None,
+            cur_path_buffer_len: 0,
         }
     }
 }
@@ -209,21 +450,49 @@ impl Tool for BrushToolNew {
 
     fn perform_action(&mut self, workspace: &mut Workspace, gpu: &GpuDevice, origin: ActionOrigin) {
         match origin {
-            ActionOrigin::MouseDown(mouse_loc) => {
+            ActionOrigin::MouseDown(input) => {
+                if self.pipeline.is_none() {
+                    self.build_pipeline(gpu);
+                }
+
                 self.path.clear();
-                self.path.push(Vec2(mouse_loc.0, mouse_loc.1));
+                self.rendered = 0;
+                self.path.push(PathPoint::new(&input, &self.pressure));
 
                 self.acquire_mask(workspace, gpu);
-                self.build_pipeline(workspace, gpu);
+                self.build_group_zero(workspace, gpu);
                 self.render_path(workspace, gpu);
+                workspace.recalculate_output_texture(gpu, self.tool_layer.unwrap());
             }
-            ActionOrigin::MouseMove(mouse_loc) => {
-                self.path.push(Vec2(mouse_loc.0, mouse_loc.1));
+            ActionOrigin::MouseMove(input) => {
+                if self.tool_layer.is_none() || !self.push_point(&input) {
+                    return;
+                }
 
                 self.render_path(workspace, gpu);
+                workspace.recalculate_output_texture(gpu, self.tool_layer.unwrap());
+            }
+            ActionOrigin::MouseUp(input) => {
+                if self.tool_layer.is_none() {
+                    return;
+                }
+
+                // always end the stroke exactly where the mouse was released
+                self.path.push(PathPoint::new(&input, &self.pressure));
+                self.render_path(workspace, gpu);
+                self.apply(workspace, gpu);
             }
-            ActionOrigin::MouseUp(mouse_loc) => {}
-            _ => {}
         }
     }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        // the uniforms are only rebuilt at the start of a stroke
+        ui.add_enabled_ui(self.tool_layer.is_none(), |ui| {
+            ui.add(egui::Slider::new(&mut self.size, 1.0..=500.0).text("Size"));
+            ui.add(egui::Slider::new(&mut self.opacity, 0.0..=1.0).text("Opacity"));
+            ui.add(egui::Slider::new(&mut self.hardness, 0.0..=1.0).text("Hardness"));
+            ui.add(egui::Slider::new(&mut self.spacing, 0.01..=1.0).text("Spacing"));
+            self.pressure.ui(ui);
+        });
+    }
 }
@@ -0,0 +1,529 @@
+use wgpu::util::DeviceExt;
+use wgpu::*;
+
+use crate::{
+    workspace::{
+        bit_depth::{BitDepth, DEPTH_CHECKED},
+        history::{Command, Region, RegionSnapshot},
+    },
+    GpuDevice,
+};
+
+use super::{
+    brush::{BrushToolSettings, PressureResponse},
+    brush_new::{path_bounds, round_up_power_two, PathPoint, Vec2},
+    ActionOrigin, Tool, Workspace,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum EraserMode {
+    /// Lowers the alpha of the layer's pixels
+    #[default]
+    Alpha,
+    /// Paints black into the layer's mask, leaving its pixels untouched
+    Mask,
+}
+
+impl EraserMode {
+    fn shader(self) -> &'static str {
+        match self {
+            EraserMode::Alpha => "tools/eraser_alpha",
+            EraserMode::Mask => "tools/eraser_mask",
+        }
+    }
+
+    fn target_format(self, depth: BitDepth) -> TextureFormat {
+        match self {
+            EraserMode::Alpha => depth.texture_format(),
+            EraserMode::Mask => TextureFormat::R8Unorm,
+        }
+    }
+}
+
+/// Erases along the same capsule path as [`BrushToolNew`](super::brush_new::BrushToolNew),
+/// directly in the selected layer. The layer is copied at the start of each stroke and the
+/// stroke is redrawn from that copy, so overlapping segments never erase more than once.
+pub struct EraserTool {
+    pub size: f32,
+    pub hardness: f32,
+    pub rotation: f32, // in radians
+    pub opacity: f32,
+    /// Minimum distance between recorded path points, as a fraction of the brush size
+    pub spacing: f32,
+    pub pressure: PressureResponse,
+    pub mode: EraserMode,
+
+    pub path: Vec<PathPoint>,
+    /// Index of the first path point that has not been rendered yet
+    pub rendered: usize,
+    pub layer: Option<usize>,
+    /// The layer as it was when the stroke started
+    pub original: Option<RegionSnapshot>,
+    pub coverage: Option<Texture>,
+    pub stroke_bounds: Option<Region>,
+    pub pipeline: Option<ComputePipeline>,
+    /// What the pipeline was built for, it has to be rebuilt when either changes
+    pub pipeline_mode: Option<(EraserMode, BitDepth)>,
+    pub group_zero_layout: Option<BindGroupLayout>,
+    pub group_one_layout: Option<BindGroupLayout>,
+    pub group_zero_bind_group: Option<BindGroup>,
+    pub path_buffer: Option<Buffer>,
+    pub path_len_buffer: Option<Buffer>,
+    pub origin_buffer: Option<Buffer>,
+    pub cur_path_buffer_len: usize,
+}
+
+impl EraserTool {
+    pub fn new(settings: BrushToolSettings, mode: EraserMode, gpu: &GpuDevice) -> Self {
+        let mut this = Self {
+            size: settings.size,
+            hardness: settings.hardness,
+            rotation: settings.rotation,
+            opacity: settings.opacity,
+            pressure: settings.pressure,
+            mode,
+            ..Default::default()
+        };
+
+        this.build_pipeline(BitDepth::default(), gpu);
+
+        this
+    }
+
+    fn build_pipeline(&mut self, depth: BitDepth, gpu: &GpuDevice) {
+        let device = &gpu.device;
+        // masks are 8-bit at every depth
+        let shader = match self.mode {
+            EraserMode::Alpha => gpu
+                .layer_shader(self.mode.shader(), depth)
+                .expect(DEPTH_CHECKED),
+            EraserMode::Mask => gpu.shaders.get(self.mode.shader()).unwrap(),
+        };
+
+        let uniform_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+
+        let group_zero_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::StorageTexture {
+                        access: StorageTextureAccess::ReadWrite,
+                        format: TextureFormat::R8Unorm,
+                        view_dimension: TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                uniform_entry(1),
+                uniform_entry(2),
+                uniform_entry(3),
+                uniform_entry(4),
+                BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: false },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::StorageTexture {
+                        access: StorageTextureAccess::WriteOnly,
+                        format: self.mode.target_format(depth),
+                        view_dimension: TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: false },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let group_one_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                uniform_entry(1),
+                uniform_entry(2),
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&group_zero_layout, &group_one_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: None,
+            layout: Some(&pipeline_layout),
+            module: shader,
+            entry_point: "main",
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+        if self.path_len_buffer.is_none() {
+            self.path_len_buffer = Some(device.create_buffer(&BufferDescriptor {
+                label: Some("Eraser Path Length Buffer"),
+                size: std::mem::size_of::<u32>() as u64,
+                usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }));
+            self.origin_buffer = Some(device.create_buffer(&BufferDescriptor {
+                label: Some("Eraser Origin Buffer"),
+                size: 2 * std::mem::size_of::<u32>() as u64,
+                usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }));
+        }
+
+        self.pipeline = Some(pipeline);
+        self.pipeline_mode = Some((self.mode, depth));
+        self.group_zero_layout = Some(group_zero_layout);
+        self.group_one_layout = Some(group_one_layout);
+    }
+
+    /// Copies the layer being erased and creates the texture that tracks the stroke's coverage
+    fn begin_stroke(&mut self, workspace: &Workspace, gpu: &GpuDevice) -> bool {
+        let Some(index) = workspace
+            .selected_layer
+            .or(workspace.layers.len().checked_sub(1))
+        else {
+            return false;
+        };
+        let info = &workspace.layers[index];
+        if info.is_tool_layer || !info.kind.is_pixel() {
+            return false;
+        }
+
+        let device = &gpu.device;
+        let layer = &workspace.layer_data[index];
+        let original = RegionSnapshot::capture(layer, Region::whole(workspace.size), gpu);
+
+        let coverage = device.create_texture(&TextureDescriptor {
+            label: Some("Eraser Coverage"),
+            size: Extent3d {
+                width: workspace.size.0,
+                height: workspace.size.1,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R8Unorm,
+            usage: TextureUsages::STORAGE_BINDING,
+            view_formats: &[TextureFormat::R8Unorm],
+        });
+
+        let uniform = |value: f32| {
+            device.create_buffer_init(&util::BufferInitDescriptor {
+                label: None,
+                contents: bytemuck::cast_slice(&[value]),
+                usage: BufferUsages::UNIFORM,
+            })
+        };
+
+        let opacity_buffer = uniform(self.opacity);
+        let size_buffer = uniform(self.size);
+        let hardness_buffer = uniform(self.hardness);
+        let rotation_buffer = uniform(self.rotation);
+
+        let (source, target) = match self.mode {
+            EraserMode::Alpha => (original.texture(), &layer.texture),
+            EraserMode::Mask => (original.mask(), &layer.mask),
+        };
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: self.group_zero_layout.as_ref().unwrap(),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(
+                        &coverage.create_view(&TextureViewDescriptor::default()),
+                    ),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: opacity_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: size_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: hardness_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: rotation_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: BindingResource::TextureView(
+                        &source.create_view(&TextureViewDescriptor::default()),
+                    ),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: BindingResource::TextureView(
+                        &target.create_view(&TextureViewDescriptor::default()),
+                    ),
+                },
+                BindGroupEntry {
+                    binding: 7,
+                    resource: BindingResource::TextureView(&workspace.selection().view()),
+                },
+            ],
+        });
+
+        self.layer = Some(index);
+        self.original = Some(original);
+        self.coverage = Some(coverage);
+        self.stroke_bounds = None;
+        self.group_zero_bind_group = Some(bind_group);
+        true
+    }
+
+    /// Adds a point to the path unless it is closer than `spacing` to the last one
+    fn push_point(&mut self, point: PathPoint) -> bool {
+        if let Some(last) = self.path.last() {
+            let Vec2(x, y) = last.position;
+            let Vec2(new_x, new_y) = point.position;
+            let distance = ((new_x - x).powi(2) + (new_y - y).powi(2)).sqrt();
+            if distance < self.spacing * self.size * last.size.min(point.size) {
+                return false;
+            }
+        }
+
+        self.path.push(point);
+        true
+    }
+
+    /// Erases along the segments of the path that have not been rendered yet
+    fn render_path(&mut self, workspace: &Workspace, gpu: &GpuDevice) {
+        if self.rendered >= self.path.len() {
+            return;
+        }
+        // the segment leading into the first new point starts at the last rendered point
+        let pending = &self.path[self.rendered.saturating_sub(1)..];
+
+        let Some(bounds) = path_bounds(pending, self.size, workspace.size) else {
+            self.rendered = self.path.len();
+            return;
+        };
+        self.stroke_bounds = Some(match self.stroke_bounds {
+            Some(stroke_bounds) => stroke_bounds.union(bounds),
+            None => bounds,
+        });
+
+        if pending.len() > self.cur_path_buffer_len {
+            self.cur_path_buffer_len = round_up_power_two(pending.len());
+            let path_buffer = gpu.device.create_buffer(&BufferDescriptor {
+                label: Some("Eraser Path Buffer"),
+                size: (self.cur_path_buffer_len * std::mem::size_of::<PathPoint>()) as u64,
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+
+            self.path_buffer = Some(path_buffer);
+        }
+
+        let queue = &gpu.queue;
+        let path_buffer = self.path_buffer.as_ref().unwrap();
+        let path_len_buffer = self.path_len_buffer.as_ref().unwrap();
+        let origin_buffer = self.origin_buffer.as_ref().unwrap();
+        queue.write_buffer(path_buffer, 0, bytemuck::cast_slice(pending));
+        queue.write_buffer(
+            path_len_buffer,
+            0,
+            bytemuck::cast_slice(&[pending.len() as u32]),
+        );
+        queue.write_buffer(
+            origin_buffer,
+            0,
+            bytemuck::cast_slice(&[bounds.x, bounds.y]),
+        );
+
+        let group_one_bind_group = gpu.device.create_bind_group(&BindGroupDescriptor {
+            layout: self.group_one_layout.as_ref().unwrap(),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: path_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: path_len_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: origin_buffer.as_entire_binding(),
+                },
+            ],
+            label: None,
+        });
+
+        let mut encoder = gpu
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor { label: None });
+        {
+            let mut cpass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: None,
+                timestamp_writes: None,
+            });
+
+            cpass.set_pipeline(self.pipeline.as_ref().unwrap());
+            cpass.set_bind_group(0, self.group_zero_bind_group.as_ref().unwrap(), &[]);
+            cpass.set_bind_group(1, &group_one_bind_group, &[]);
+
+            cpass.dispatch_workgroups(bounds.width.div_ceil(8), bounds.height.div_ceil(8), 1);
+        }
+
+        queue.submit(std::iter::once(encoder.finish()));
+
+        self.rendered = self.path.len();
+    }
+
+    /// Records the erased pixels so that the stroke can be undone
+    fn apply(&mut self, workspace: &mut Workspace, gpu: &GpuDevice) {
+        self.group_zero_bind_group = None;
+        self.coverage = None;
+        self.path.clear();
+        self.rendered = 0;
+
+        let original = self.original.take();
+        let (Some(index), Some(original)) = (self.layer.take(), original) else {
+            return;
+        };
+        if let Some(bounds) = self.stroke_bounds.take() {
+            workspace.record(Command::PixelsChanged {
+                index,
+                previous: Box::new(original.crop(bounds, gpu)),
+            });
+        }
+    }
+}
+
+impl Default for EraserTool {
+    fn default() -> Self {
+        Self {
+            size: 10.0,
+            hardness: 0.5,
+            rotation: 0.0,
+            opacity: 1.0,
+            spacing: 0.1,
+            pressure: PressureResponse::default(),
+            mode: EraserMode::default(),
+            path: Vec::new(),
+            rendered: 0,
+            layer: None,
+            original: None,
+            coverage: None,
+            stroke_bounds: None,
+            pipeline: None,
+            pipeline_mode: None,
+            group_zero_layout: None,
+            group_one_layout: None,
+            group_zero_bind_group: None,
+            path_buffer: None,
+            path_len_buffer: None,
+            origin_buffer: None,
+            cur_path_buffer_len: 0,
+        }
+    }
+}
+
+impl Tool for EraserTool {
+    fn name(&self) -> &str {
+        "Eraser"
+    }
+
+    fn perform_action(&mut self, workspace: &mut Workspace, gpu: &GpuDevice, origin: ActionOrigin) {
+        match origin {
+            ActionOrigin::MouseDown(input) => {
+                if self.pipeline_mode != Some((self.mode, workspace.bit_depth)) {
+                    self.build_pipeline(workspace.bit_depth, gpu);
+                }
+
+                self.path.clear();
+                self.rendered = 0;
+                if !self.begin_stroke(workspace, gpu) {
+                    return;
+                }
+
+                self.path.push(PathPoint::new(&input, &self.pressure));
+                self.render_path(workspace, gpu);
+                workspace.recalculate_output_texture(gpu, self.layer.unwrap());
+            }
+            ActionOrigin::MouseMove(input) => {
+                if self.layer.is_none() || !self.push_point(PathPoint::new(&input, &self.pressure))
+                {
+                    return;
+                }
+
+                self.render_path(workspace, gpu);
+                workspace.recalculate_output_texture(gpu, self.layer.unwrap());
+            }
+            ActionOrigin::MouseUp(input) => {
+                let Some(layer) = self.layer else {
+                    return;
+                };
+
+                // always end the stroke exactly where the mouse was released
+                self.path.push(PathPoint::new(&input, &self.pressure));
+                self.render_path(workspace, gpu);
+                workspace.recalculate_output_texture(gpu, layer);
+                self.apply(workspace, gpu);
+            }
+        }
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        // the uniforms are only rebuilt at the start of a stroke
+        ui.add_enabled_ui(self.layer.is_none(), |ui| {
+            ui.horizontal(|ui| {
+                ui.selectable_value(&mut self.mode, EraserMode::Alpha, "Alpha");
+                ui.selectable_value(&mut self.mode, EraserMode::Mask, "Mask");
+            });
+            ui.add(egui::Slider::new(&mut self.size, 1.0..=500.0).text("Size"));
+            ui.add(egui::Slider::new(&mut self.opacity, 0.0..=1.0).text("Strength"));
+            ui.add(egui::Slider::new(&mut self.hardness, 0.0..=1.0).text("Hardness"));
+            ui.add(egui::Slider::new(&mut self.spacing, 0.01..=1.0).text("Spacing"));
+            self.pressure.ui(ui);
+        });
+    }
+}
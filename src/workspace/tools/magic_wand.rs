@@ -0,0 +1,88 @@
+use crate::GpuDevice;
+
+use super::{
+    super::{
+        selection::{
+            antialias_coverage, magic_wand_coverage, SampleSource, SelectionOp, SelectionShape,
+        },
+        Workspace,
+    },
+    select::modifier_op,
+    ActionOrigin, Tool,
+};
+
+/// Selects pixels of a similar color to the one clicked
+pub struct MagicWandTool {
+    /// Largest difference in any channel, in 8 bit steps, for a pixel to be selected
+    pub tolerance: f32,
+    /// Only select pixels connected to the clicked one
+    pub contiguous: bool,
+    pub antialias: bool,
+    pub source: SampleSource,
+    pub op: SelectionOp,
+}
+
+impl Default for MagicWandTool {
+    fn default() -> Self {
+        Self {
+            tolerance: 32.0,
+            contiguous: true,
+            antialias: true,
+            source: SampleSource::Layer,
+            op: SelectionOp::Replace,
+        }
+    }
+}
+
+impl Tool for MagicWandTool {
+    fn name(&self) -> &str {
+        "Magic Wand"
+    }
+
+    fn perform_action(&mut self, workspace: &mut Workspace, gpu: &GpuDevice, origin: ActionOrigin) {
+        let ActionOrigin::MouseDown(input) = origin else {
+            return;
+        };
+        let (x, y) = input.pos;
+        if x < 0.0 || y < 0.0 {
+            return;
+        }
+
+        let Some(image) = futures::executor::block_on(workspace.sample_image(self.source, gpu))
+        else {
+            return;
+        };
+
+        let mut coverage = magic_wand_coverage(
+            &image,
+            (x as u32, y as u32),
+            self.tolerance,
+            self.contiguous,
+        );
+        if self.antialias {
+            coverage = antialias_coverage(&coverage);
+        }
+
+        workspace.select(
+            &SelectionShape::Mask(coverage),
+            modifier_op(&input, self.op),
+            0.0,
+            gpu,
+        );
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.add(egui::Slider::new(&mut self.tolerance, 0.0..=255.0).text("Tolerance"));
+        ui.checkbox(&mut self.contiguous, "Contiguous");
+        ui.checkbox(&mut self.antialias, "Anti-alias");
+        ui.horizontal(|ui| {
+            ui.selectable_value(&mut self.source, SampleSource::Layer, "Layer");
+            ui.selectable_value(&mut self.source, SampleSource::Composite, "Composite");
+        });
+        ui.horizontal(|ui| {
+            for op in SelectionOp::ALL {
+                ui.selectable_value(&mut self.op, op, op.name());
+            }
+        });
+    }
+}
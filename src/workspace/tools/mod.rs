@@ -1,31 +1,97 @@
 pub mod brush;
 pub mod brush_new;
+pub mod eraser;
+pub mod magic_wand;
+pub mod response_curve;
 pub mod select;
 
 use crate::GpuDevice;
 
+use super::history::{Command, Region};
+use super::LayerInfo;
 pub use super::Workspace;
 pub use select::SelectTool;
 
 pub trait Tool {
     fn name(&self) -> &str;
     fn perform_action(&mut self, workspace: &mut Workspace, gpu: &GpuDevice, origin: ActionOrigin);
+    /// Settings shown in the side panel while the tool is selected
+    fn ui(&mut self, _ui: &mut egui::Ui) {}
+    /// Draws over the canvas, e.g. the outline of a shape being dragged out. `to_screen`
+    /// converts workspace pixels to screen coordinates.
+    fn paint(&self, _painter: &egui::Painter, _to_screen: &dyn Fn((f32, f32)) -> egui::Pos2) {}
 }
 
 impl Default for Box<dyn Tool> {
     fn default() -> Self {
-        Box::new(SelectTool)
+        Box::new(SelectTool::new())
     }
 }
 
+static DEFAULT_TOOL: SelectTool = SelectTool::new();
+
 impl Default for &dyn Tool {
     fn default() -> Self {
-        &SelectTool
+        &DEFAULT_TOOL
     }
 }
 
 pub enum ActionOrigin {
-    MouseMove((f32, f32)),
-    MouseDown((f32, f32)),
-    MouseUp((f32, f32)),
+    MouseMove(PointerInput),
+    MouseDown(PointerInput),
+    MouseUp(PointerInput),
+}
+
+/// The state of the mouse or pen at the time of an action
+#[derive(Clone, Copy, Debug)]
+pub struct PointerInput {
+    /// Position in workspace pixels
+    pub pos: (f32, f32),
+    /// Pen pressure in `0..=1`, always 1 for devices that don't report pressure
+    pub pressure: f32,
+    /// Pen tilt from vertical along the x and y axes in radians, 0 when not reported
+    pub tilt: (f32, f32),
+    pub modifiers: egui::Modifiers,
+    /// Seconds since the application started
+    pub timestamp: f64,
+}
+
+impl PointerInput {
+    pub fn at(pos: (f32, f32)) -> Self {
+        Self {
+            pos,
+            pressure: 1.0,
+            tilt: (0.0, 0.0),
+            modifiers: egui::Modifiers::NONE,
+            timestamp: 0.0,
+        }
+    }
+}
+
+/// Merges a tool layer into the layer below it, recording the change to the pixels in
+/// `bounds` so that it can be undone. When there is no pixel layer right below, the tool
+/// layer is kept as a regular layer instead.
+pub fn commit_tool_layer(
+    workspace: &mut Workspace,
+    gpu: &GpuDevice,
+    tool_layer: usize,
+    bounds: Option<Region>,
+) {
+    if tool_layer == 0 || !workspace.layers[tool_layer - 1].kind.is_pixel() {
+        let info = LayerInfo {
+            is_tool_layer: false,
+            ..workspace.layers[tool_layer].clone()
+        };
+        workspace.layers[tool_layer] = info;
+        workspace.record(Command::LayerInserted { index: tool_layer });
+        return;
+    }
+
+    let snapshot = bounds.map(|bounds| workspace.snapshot_pixels(tool_layer - 1, bounds, gpu));
+
+    workspace.merge_layer_down(tool_layer, gpu);
+
+    if let Some(snapshot) = snapshot {
+        workspace.record(snapshot);
+    }
 }
@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+
+/// A piecewise linear mapping from an input in `0..=1`, such as pen pressure, to a
+/// multiplier. Inputs outside of the control points use the value of the nearest one.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ResponseCurve {
+    /// Control points as `(input, output)`, kept sorted by input
+    points: Vec<(f32, f32)>,
+}
+
+impl Default for ResponseCurve {
+    fn default() -> Self {
+        Self::constant(1.0)
+    }
+}
+
+impl ResponseCurve {
+    pub fn new(points: Vec<(f32, f32)>) -> Self {
+        let mut this = Self { points };
+        this.sort();
+        this
+    }
+
+    pub fn linear() -> Self {
+        Self::new(vec![(0.0, 0.0), (1.0, 1.0)])
+    }
+
+    pub fn constant(value: f32) -> Self {
+        Self::new(vec![(0.0, value), (1.0, value)])
+    }
+
+    pub fn points(&self) -> &[(f32, f32)] {
+        &self.points
+    }
+
+    pub fn set_point(&mut self, index: usize, point: (f32, f32)) {
+        self.points[index] = point;
+        self.sort();
+    }
+
+    pub fn add_point(&mut self, point: (f32, f32)) {
+        self.points.push(point);
+        self.sort();
+    }
+
+    /// Removes a control point, keeping at least one
+    pub fn remove_point(&mut self, index: usize) {
+        if self.points.len() > 1 {
+            self.points.remove(index);
+        }
+    }
+
+    pub fn evaluate(&self, input: f32) -> f32 {
+        let Some(&(first_x, first_y)) = self.points.first() else {
+            return 1.0;
+        };
+        if input <= first_x {
+            return first_y;
+        }
+
+        for pair in self.points.windows(2) {
+            let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+            if input <= x1 {
+                if x1 - x0 <= f32::EPSILON {
+                    return y1;
+                }
+                let t = (input - x0) / (x1 - x0);
+                return y0 + (y1 - y0) * t;
+            }
+        }
+
+        self.points.last().unwrap().1
+    }
+
+    fn sort(&mut self) {
+        self.points
+            .sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+    }
+
+    /// Editor for the control points
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        let mut changed = None;
+        let mut removed = None;
+        for (i, point) in self.points.iter().enumerate() {
+            let mut point = *point;
+            ui.horizontal(|ui| {
+                let input = ui.add(
+                    egui::DragValue::new(&mut point.0)
+                        .range(0.0..=1.0)
+                        .speed(0.01)
+                        .prefix("in "),
+                );
+                let output = ui.add(
+                    egui::DragValue::new(&mut point.1)
+                        .range(0.0..=1.0)
+                        .speed(0.01)
+                        .prefix("out "),
+                );
+                if input.changed() || output.changed() {
+                    changed = Some((i, point));
+                }
+                if ui.small_button("x").clicked() {
+                    removed = Some(i);
+                }
+            });
+        }
+
+        if let Some((i, point)) = changed {
+            self.set_point(i, point);
+        }
+        if let Some(i) = removed {
+            self.remove_point(i);
+        }
+        if ui.small_button("Add point").clicked() {
+            self.add_point((0.5, self.evaluate(0.5)));
+        }
+    }
+}
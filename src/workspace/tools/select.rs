@@ -1,19 +1,187 @@
+use egui::{Color32, Pos2, Shape, Stroke};
+
 use crate::GpuDevice;
 
-use super::{super::Workspace, ActionOrigin, Tool};
+use super::{
+    super::{
+        selection::{SelectionOp, SelectionShape},
+        Workspace,
+    },
+    ActionOrigin, PointerInput, Tool,
+};
 
-pub struct SelectTool;
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SelectionMode {
+    #[default]
+    Rectangle,
+    Ellipse,
+    Lasso,
+}
+
+/// Shift adds, alt subtracts and both intersect, like most editors
+pub fn modifier_op(input: &PointerInput, default: SelectionOp) -> SelectionOp {
+    match (input.modifiers.shift, input.modifiers.alt) {
+        (true, true) => SelectionOp::Intersect,
+        (true, false) => SelectionOp::Add,
+        (false, true) => SelectionOp::Subtract,
+        (false, false) => default,
+    }
+}
+
+pub struct SelectTool {
+    pub mode: SelectionMode,
+    pub op: SelectionOp,
+    /// Width of the soft edge in pixels
+    pub feather: f32,
+
+    /// Points of the shape being dragged out, the corners for rectangles and ellipses
+    drag: Vec<(f32, f32)>,
+    drag_op: SelectionOp,
+}
+
+impl SelectTool {
+    pub const fn new() -> Self {
+        Self {
+            mode: SelectionMode::Rectangle,
+            op: SelectionOp::Replace,
+            feather: 0.0,
+            drag: Vec::new(),
+            drag_op: SelectionOp::Replace,
+        }
+    }
+
+    fn shape(&self) -> Option<SelectionShape> {
+        match self.mode {
+            SelectionMode::Rectangle | SelectionMode::Ellipse => {
+                let (&min, &max) = (self.drag.first()?, self.drag.last()?);
+                if min.0 == max.0 || min.1 == max.1 {
+                    return None;
+                }
+
+                Some(if self.mode == SelectionMode::Rectangle {
+                    SelectionShape::Rectangle { min, max }
+                } else {
+                    SelectionShape::Ellipse { min, max }
+                })
+            }
+            SelectionMode::Lasso => {
+                (self.drag.len() >= 3).then(|| SelectionShape::Lasso(self.drag.clone()))
+            }
+        }
+    }
+}
+
+impl Default for SelectTool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Tool for SelectTool {
     fn name(&self) -> &str {
         "Select"
     }
-    fn perform_action(
-        &mut self,
-        workspace: &mut Workspace,
-        _gpu: &GpuDevice,
-        origin: ActionOrigin,
-    ) {
-        println!("Selecting");
+    fn perform_action(&mut self, workspace: &mut Workspace, gpu: &GpuDevice, origin: ActionOrigin) {
+        match origin {
+            ActionOrigin::MouseDown(input) => {
+                self.drag = vec![input.pos];
+                self.drag_op = modifier_op(&input, self.op);
+            }
+            ActionOrigin::MouseMove(input) => {
+                let Some(&last) = self.drag.last() else {
+                    return;
+                };
+
+                if self.mode == SelectionMode::Lasso {
+                    let distance =
+                        ((input.pos.0 - last.0).powi(2) + (input.pos.1 - last.1).powi(2)).sqrt();
+                    if distance >= 1.0 {
+                        self.drag.push(input.pos);
+                    }
+                } else {
+                    self.drag.truncate(1);
+                    self.drag.push(input.pos);
+                }
+            }
+            ActionOrigin::MouseUp(input) => {
+                if self.drag.is_empty() {
+                    return;
+                }
+                if self.mode != SelectionMode::Lasso {
+                    self.drag.truncate(1);
+                }
+                self.drag.push(input.pos);
+
+                match self.shape() {
+                    Some(shape) => workspace.select(&shape, self.drag_op, self.feather, gpu),
+                    // clicking without dragging out a shape deselects
+                    None if self.drag_op == SelectionOp::Replace => workspace.select_none(gpu),
+                    None => (),
+                }
+                self.drag.clear();
+            }
+        }
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.selectable_value(&mut self.mode, SelectionMode::Rectangle, "Rectangle");
+            ui.selectable_value(&mut self.mode, SelectionMode::Ellipse, "Ellipse");
+            ui.selectable_value(&mut self.mode, SelectionMode::Lasso, "Lasso");
+        });
+        ui.horizontal(|ui| {
+            for op in SelectionOp::ALL {
+                ui.selectable_value(&mut self.op, op, op.name());
+            }
+        });
+        ui.add(egui::Slider::new(&mut self.feather, 0.0..=100.0).text("Feather"));
+    }
+
+    fn paint(&self, painter: &egui::Painter, to_screen: &dyn Fn((f32, f32)) -> Pos2) {
+        let points: Vec<Pos2> = match self.mode {
+            SelectionMode::Rectangle | SelectionMode::Ellipse if self.drag.len() >= 2 => {
+                let (start, end) = (self.drag[0], self.drag[self.drag.len() - 1]);
+                let center = ((start.0 + end.0) / 2.0, (start.1 + end.1) / 2.0);
+                let radius = ((end.0 - start.0) / 2.0, (end.1 - start.1) / 2.0);
+
+                if self.mode == SelectionMode::Rectangle {
+                    vec![start, (end.0, start.1), end, (start.0, end.1), start]
+                } else {
+                    (0..=64)
+                        .map(|i| {
+                            let angle = i as f32 / 64.0 * std::f32::consts::TAU;
+                            (
+                                center.0 + radius.0 * angle.cos(),
+                                center.1 + radius.1 * angle.sin(),
+                            )
+                        })
+                        .collect()
+                }
+                .into_iter()
+                .map(to_screen)
+                .collect()
+            }
+            SelectionMode::Lasso if self.drag.len() >= 2 => {
+                self.drag.iter().copied().map(to_screen).collect()
+            }
+            _ => return,
+        };
+
+        painter.add(Shape::line(
+            points.clone(),
+            Stroke {
+                width: 1.0,
+                color: Color32::WHITE,
+            },
+        ));
+        painter.extend(Shape::dashed_line(
+            &points,
+            Stroke {
+                width: 1.0,
+                color: Color32::BLACK,
+            },
+            4.0,
+            4.0,
+        ));
     }
 }
@@ -1,107 +1,235 @@
-use std::io::Cursor;
+use std::{io::Cursor, path::Path};
 
-use image::{ImageEncoder, ImageFormat, ImageReader};
+use image::{
+    ExtendedColorType, GrayImage, ImageBuffer, ImageEncoder, ImageFormat, ImageReader, Rgba,
+    RgbaImage,
+};
 use wgpu::*;
 
+use super::bit_depth::{BitDepth, UnsupportedBitDepth};
+use super::container::*;
+use super::groups::unbalanced_group;
+use super::migrations::{rendering_warnings, upgrade_metadata};
+use super::openraster::is_openraster;
+use super::psd::is_psd;
 use super::Workspace;
-use crate::device::{pad_to_multiple_of_256, GpuDevice};
-use crate::workspace::LayerData;
+use crate::device::GpuDevice;
+use crate::workspace::{LayerCreationInfo, LayerData};
+
+#[derive(Debug)]
+pub enum WorkspaceLoadError {
+    Io(std::io::Error),
+    /// The file ends before its header or chunk table does
+    TruncatedHeader,
+    /// A chunk extends past the end of the file
+    TruncatedChunk(String),
+    UnsupportedVersion(u32),
+    BadBincode(bincode::Error),
+    MissingMetadata,
+    MissingPixels(usize),
+    MissingMask(usize),
+    PngDecode(image::ImageError),
+    /// The workspace size is zero or larger than the GPU can hold
+    InvalidSize((u32, u32)),
+    LayerSizeMismatch {
+        layer: usize,
+        expected: (u32, u32),
+        found: (u32, u32),
+    },
+    /// A `Group` without a `GroupEnd` below it or the reverse, at this layer
+    UnbalancedGroup(usize),
+    /// Raw pixels of a floating point layer don't add up to the size of the workspace
+    PixelDataSizeMismatch {
+        layer: usize,
+        expected: usize,
+        found: usize,
+    },
+    Zip(zip::result::ZipError),
+    /// An OpenRaster `stack.xml` that can't be parsed or lacks required attributes
+    BadStackXml(String),
+    /// A file an OpenRaster stack refers to isn't in the archive
+    MissingEntry(String),
+    /// A Photoshop document that is malformed or uses features that can't be read
+    BadPsd(String),
+    /// The document's layers are in a depth the GPU can't store or blend
+    UnsupportedBitDepth(BitDepth),
+}
 
-impl Workspace {
-    pub fn load(path: &str, gpu: &GpuDevice) -> Result<Self, Box<dyn std::error::Error>> {
-        #[cfg(debug_assertions)]
-        println!("Loading workspace at {}...", path);
+impl std::fmt::Display for WorkspaceLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "could not read file: {}", e),
+            Self::TruncatedHeader => write!(f, "file header is truncated"),
+            Self::TruncatedChunk(name) => write!(f, "chunk {} is truncated", name),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "file format version {} is not supported (newest supported is {})",
+                version, FORMAT_VERSION
+            ),
+            Self::BadBincode(e) => write!(f, "workspace metadata is corrupt: {}", e),
+            Self::MissingMetadata => write!(f, "workspace metadata is missing"),
+            Self::MissingPixels(layer) => write!(f, "layer {} is missing its pixels", layer),
+            Self::MissingMask(layer) => write!(f, "layer {} is missing its mask", layer),
+            Self::PngDecode(e) => write!(f, "could not decode image: {}", e),
+            Self::InvalidSize(size) => {
+                write!(f, "workspace size {}x{} is not supported", size.0, size.1)
+            }
+            Self::LayerSizeMismatch {
+                layer,
+                expected,
+                found,
+            } => write!(
+                f,
+                "layer {} is {}x{} but the workspace is {}x{}",
+                layer, found.0, found.1, expected.0, expected.1
+            ),
+            Self::UnbalancedGroup(layer) => {
+                write!(f, "the group marker at layer {} has no partner", layer)
+            }
+            Self::PixelDataSizeMismatch {
+                layer,
+                expected,
+                found,
+            } => write!(
+                f,
+                "layer {} has {} bytes of pixels but should have {}",
+                layer, found, expected
+            ),
+            Self::Zip(e) => write!(f, "could not read archive: {}", e),
+            Self::BadStackXml(reason) => write!(f, "stack.xml is invalid: {}", reason),
+            Self::MissingEntry(name) => write!(f, "{} is missing from the archive", name),
+            Self::BadPsd(reason) => write!(f, "could not read Photoshop document: {}", reason),
+            Self::UnsupportedBitDepth(depth) => {
+                write!(f, "{} layers are not supported by this GPU", depth.name())
+            }
+        }
+    }
+}
 
-        let data = std::fs::read(path)?;
-        let bincode_end = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize + 4;
-        let mut this: Self = bincode::deserialize(&data[4..bincode_end])?;
+impl std::error::Error for WorkspaceLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::BadBincode(e) => Some(e),
+            Self::PngDecode(e) => Some(e),
+            Self::Zip(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
-        let data = &data[bincode_end..];
-        let mut data = data.into_iter().map(|a| *a);
+impl From<std::io::Error> for WorkspaceLoadError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
 
-        loop {
-            let len: Result<[u8; 4], _> = data.next_chunk::<4>();
-            if len.is_err() {
-                break;
-            }
+impl From<UnsupportedBitDepth> for WorkspaceLoadError {
+    fn from(e: UnsupportedBitDepth) -> Self {
+        Self::UnsupportedBitDepth(e.0)
+    }
+}
+
+impl From<bincode::Error> for WorkspaceLoadError {
+    fn from(e: bincode::Error) -> Self {
+        Self::BadBincode(e)
+    }
+}
 
-            let len: [u8; 4] = len.unwrap();
-            let len = u32::from_le_bytes(len) as usize;
+impl From<image::ImageError> for WorkspaceLoadError {
+    fn from(e: image::ImageError) -> Self {
+        Self::PngDecode(e)
+    }
+}
 
-            let mdata = (&mut data).take(len).collect::<Vec<u8>>();
+impl From<zip::result::ZipError> for WorkspaceLoadError {
+    fn from(e: zip::result::ZipError) -> Self {
+        Self::Zip(e)
+    }
+}
 
-            let reader = Cursor::new(mdata);
-            let reader = ImageReader::with_format(reader, ImageFormat::Png);
+/// A `.jc` file read into memory, before anything is uploaded to the GPU
+pub struct DecodedDocument {
+    /// The document without any layer data
+    pub workspace: Workspace,
+    /// Pixels of every layer in the texture layout of the document's bit depth, see
+    /// [`BitDepth::decode`]
+    pub pixels: Vec<Vec<u8>>,
+    pub masks: Vec<GrayImage>,
+    /// Layers that render differently than when the file was saved, see
+    /// [`rendering_warnings`]
+    pub warnings: Vec<String>,
+}
 
-            let image = reader.decode()?.into_rgba8();
-            println!("c");
-            let width = image.width();
-            let height = image.height();
+impl Workspace {
+    /// Loads a `.jc` file. Also returns warnings about layers that render differently than
+    /// when it was saved.
+    pub fn load(path: &str, gpu: &GpuDevice) -> Result<(Self, Vec<String>), WorkspaceLoadError> {
+        #[cfg(debug_assertions)]
+        println!("Loading workspace at {}...", path);
 
+        let data = std::fs::read(path)?;
+        let max_size = gpu.device.limits().max_texture_dimension_2d;
+        let DecodedDocument {
+            workspace: mut this,
+            pixels,
+            masks,
+            warnings,
+        } = Self::decode(&data, max_size)?;
+
+        let (width, height) = this.size;
+        let depth = this.bit_depth;
+        depth.check(gpu)?;
+        for (data, mask_image) in pixels.into_iter().zip(masks) {
             #[cfg(debug_assertions)]
             print!("Creating layer texture...\n");
-            let input_texture = gpu.render_state.device.create_texture(&TextureDescriptor {
+            let input_texture = gpu.device.create_texture(&TextureDescriptor {
                 label: None,
                 size: Extent3d {
-                    width: width,
-                    height: height,
+                    width,
+                    height,
                     depth_or_array_layers: 1,
                 },
                 mip_level_count: 1,
                 sample_count: 1,
                 dimension: TextureDimension::D2,
-                format: TextureFormat::Rgba8Unorm,
+                format: depth.texture_format(),
                 usage: TextureUsages::TEXTURE_BINDING
                     | TextureUsages::COPY_SRC
                     | TextureUsages::STORAGE_BINDING
                     | TextureUsages::COPY_DST,
-                view_formats: &[TextureFormat::Rgba8Unorm],
+                view_formats: &[depth.texture_format()],
             });
 
             #[cfg(debug_assertions)]
             print!("Writing layer texture...\n");
-            gpu.render_state.queue.write_texture(
+            gpu.queue.write_texture(
                 ImageCopyTexture {
                     texture: &input_texture,
                     mip_level: 0,
                     origin: Origin3d::ZERO,
                     aspect: TextureAspect::All,
                 },
-                image.into_vec().as_slice(),
+                data.as_slice(),
                 ImageDataLayout {
                     offset: 0,
-                    bytes_per_row: Some(4 * width as u32),
-                    rows_per_image: Some(height as u32),
+                    bytes_per_row: Some(depth.bytes_per_pixel() * width),
+                    rows_per_image: Some(height),
                 },
                 Extent3d {
-                    width: width,
-                    height: height,
+                    width,
+                    height,
                     depth_or_array_layers: 1,
                 },
             );
 
-            let mask_len: [u8; 4] = (&mut data)
-                .next_chunk::<4>()
-                .expect("layer not provided with mask");
-            let mask_len = u32::from_le_bytes(mask_len) as usize;
-
-            let mask_data = (&mut data).take(mask_len).collect::<Vec<u8>>();
-
-            let reader = Cursor::new(mask_data);
-            let reader = ImageReader::with_format(reader, ImageFormat::Png);
-
-            let mask_image = reader.decode()?.into_luma8();
-
-            let mask_width = mask_image.width();
-            let mask_height = mask_image.height();
-
             #[cfg(debug_assertions)]
             print!("Creating mask texture...\n");
-            let mask_texture = gpu.render_state.device.create_texture(&TextureDescriptor {
+            let mask_texture = gpu.device.create_texture(&TextureDescriptor {
                 label: None,
                 size: Extent3d {
-                    width: mask_width,
-                    height: mask_height,
+                    width,
+                    height,
                     depth_or_array_layers: 1,
                 },
                 mip_level_count: 1,
@@ -117,7 +245,7 @@ impl Workspace {
 
             #[cfg(debug_assertions)]
             print!("Writing mask texture...\n");
-            gpu.render_state.queue.write_texture(
+            gpu.queue.write_texture(
                 ImageCopyTexture {
                     texture: &mask_texture,
                     mip_level: 0,
@@ -127,40 +255,40 @@ impl Workspace {
                 mask_image.into_vec().as_slice(),
                 ImageDataLayout {
                     offset: 0,
-                    bytes_per_row: Some(mask_width as u32),
-                    rows_per_image: Some(mask_height as u32),
+                    bytes_per_row: Some(width),
+                    rows_per_image: Some(height),
                 },
                 Extent3d {
-                    width: mask_width,
-                    height: mask_height,
+                    width,
+                    height,
                     depth_or_array_layers: 1,
                 },
             );
 
             #[cfg(debug_assertions)]
             print!("Creating layer running total texture...\n");
-            let running_total = gpu.render_state.device.create_texture(&TextureDescriptor {
+            let running_total = gpu.device.create_texture(&TextureDescriptor {
                 label: None,
                 size: Extent3d {
-                    width: width,
-                    height: height,
+                    width,
+                    height,
                     depth_or_array_layers: 1,
                 },
                 mip_level_count: 1,
                 sample_count: 1,
                 dimension: TextureDimension::D2,
-                format: TextureFormat::Rgba8Unorm,
+                format: depth.texture_format(),
                 usage: TextureUsages::TEXTURE_BINDING
                     | TextureUsages::COPY_SRC
                     | TextureUsages::STORAGE_BINDING
                     | TextureUsages::COPY_DST,
-                view_formats: &[TextureFormat::Rgba8Unorm],
+                view_formats: &[depth.texture_format()],
             });
 
             let layer_data = LayerData {
                 texture: input_texture,
                 mask: mask_texture,
-                running_total: running_total,
+                running_total,
             };
             let layer_data = Box::new(layer_data);
 
@@ -169,57 +297,461 @@ impl Workspace {
 
         this.build_output_texture(gpu);
 
-        Ok(this)
+        Ok((this, warnings))
+    }
+
+    /// Reads the contents of a `.jc` file without touching the GPU. Documents wider or taller
+    /// than `max_size` are rejected.
+    pub fn decode(data: &[u8], max_size: u32) -> Result<DecodedDocument, WorkspaceLoadError> {
+        let container = Container::from_bytes(data)?;
+
+        let metadata = container
+            .chunk(ChunkKind::Metadata, METADATA_CHUNK)
+            .ok_or(WorkspaceLoadError::MissingMetadata)?;
+        let this = upgrade_metadata(container.version, &metadata.data)?;
+
+        if this.size.0 == 0 || this.size.1 == 0 || this.size.0 > max_size || this.size.1 > max_size
+        {
+            return Err(WorkspaceLoadError::InvalidSize(this.size));
+        }
+        if let Some(layer) = unbalanced_group(&this.layers) {
+            return Err(WorkspaceLoadError::UnbalancedGroup(layer));
+        }
+
+        let depth = this.bit_depth;
+        let pixels_kind = if depth.is_float() {
+            ChunkKind::LayerFloatPixels
+        } else {
+            ChunkKind::LayerPixels
+        };
+        let mut layer_pixels = Vec::with_capacity(this.layers.len());
+        let mut masks = Vec::with_capacity(this.layers.len());
+        for i in 0..this.layers.len() {
+            let pixels = container
+                .chunk(pixels_kind, &layer_pixels_chunk(i))
+                .ok_or(WorkspaceLoadError::MissingPixels(i))?;
+
+            let (width, height) = this.size;
+            let data = if depth.is_float() {
+                let expected = width as usize * height as usize * depth.bytes_per_pixel() as usize;
+                if pixels.data.len() != expected {
+                    return Err(WorkspaceLoadError::PixelDataSizeMismatch {
+                        layer: i,
+                        expected,
+                        found: pixels.data.len(),
+                    });
+                }
+                pixels.data.clone()
+            } else {
+                let reader = Cursor::new(&pixels.data);
+                let reader = ImageReader::with_format(reader, ImageFormat::Png);
+
+                let image = reader.decode()?;
+                if (image.width(), image.height()) != this.size {
+                    return Err(WorkspaceLoadError::LayerSizeMismatch {
+                        layer: i,
+                        expected: this.size,
+                        found: (image.width(), image.height()),
+                    });
+                }
+
+                match depth {
+                    BitDepth::Sixteen => image
+                        .into_rgba16()
+                        .into_vec()
+                        .into_iter()
+                        .flat_map(u16::to_le_bytes)
+                        .collect(),
+                    _ => image.into_rgba8().into_vec(),
+                }
+            };
+
+            let mask = container
+                .chunk(ChunkKind::LayerMask, &layer_mask_chunk(i))
+                .ok_or(WorkspaceLoadError::MissingMask(i))?;
+
+            let reader = Cursor::new(&mask.data);
+            let reader = ImageReader::with_format(reader, ImageFormat::Png);
+
+            let mask_image = reader.decode()?.into_luma8();
+            if mask_image.dimensions() != this.size {
+                return Err(WorkspaceLoadError::LayerSizeMismatch {
+                    layer: i,
+                    expected: this.size,
+                    found: mask_image.dimensions(),
+                });
+            }
+
+            layer_pixels.push(data);
+            masks.push(mask_image);
+        }
+
+        let warnings = rendering_warnings(container.version, &this.layers);
+        Ok(DecodedDocument {
+            workspace: this,
+            pixels: layer_pixels,
+            masks,
+            warnings,
+        })
     }
 
-    pub async fn save(&self, path: &str, gpu: &GpuDevice) {
+    /// Creates a workspace of `size` with a single layer filled with `background`
+    pub fn new_document(
+        size: (u32, u32),
+        background: [u8; 4],
+        bit_depth: BitDepth,
+        gpu: &GpuDevice,
+    ) -> Result<Self, UnsupportedBitDepth> {
+        bit_depth.check(gpu)?;
+        Ok(Self::with_background(
+            size,
+            bit_depth,
+            LayerCreationInfo {
+                name: "Background".to_string(),
+                init_rgba: Some(background),
+                ..Default::default()
+            },
+            gpu,
+        ))
+    }
+
+    /// Creates a workspace the size of `image` with the image as its only layer
+    pub fn from_image(image: ImageBuffer<Rgba<u8>, Vec<u8>>, gpu: &GpuDevice) -> Self {
+        Self::with_background(
+            image.dimensions(),
+            BitDepth::Eight,
+            LayerCreationInfo {
+                name: "Background".to_string(),
+                init_image: Some(image),
+                ..Default::default()
+            },
+            gpu,
+        )
+    }
+
+    fn with_background(
+        size: (u32, u32),
+        bit_depth: BitDepth,
+        background: LayerCreationInfo,
+        gpu: &GpuDevice,
+    ) -> Self {
+        let mut workspace = Workspace {
+            size,
+            bit_depth,
+            pixel_at_center: (size.0 as f32 / 2.0, size.1 as f32 / 2.0),
+            ..Default::default()
+        };
+
+        workspace.create_layer(background, gpu, None);
+        // nothing to lose yet, so a fresh document doesn't count as modified
+        workspace.history.clear();
+        workspace.history.mark_saved();
+        workspace
+    }
+
+    /// Loads a `.jc`, OpenRaster or Photoshop file, or imports any other image as a single
+    /// layer document. Also returns warnings about anything that could not be read faithfully.
+    pub fn open(path: &str, gpu: &GpuDevice) -> Result<(Self, Vec<String>), WorkspaceLoadError> {
+        let is_workspace = Path::new(path)
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("jc"));
+        if is_workspace {
+            Self::load(path, gpu)
+        } else if is_openraster(path) {
+            Ok((Self::load_ora(path, gpu)?, Vec::new()))
+        } else if is_psd(path) {
+            Self::load_psd(path, gpu)
+        } else {
+            Ok((Self::import(path, gpu)?, Vec::new()))
+        }
+    }
+
+    pub async fn save(&self, path: &str, gpu: &GpuDevice) -> std::io::Result<()> {
         #[cfg(debug_assertions)]
         println!("Saving workspace at {}...", path);
 
-        let mut data = bincode::serialize(&self).unwrap();
-        let len = data.len() as u32;
-        let len_bytes = len.to_le_bytes();
-        let mut data = len_bytes
-            .iter()
-            .copied()
-            .chain(data.into_iter())
-            .collect::<Vec<u8>>();
-        let mut images = Vec::new();
-
+        let mut pixels = Vec::with_capacity(self.layer_data.len());
+        let mut masks = Vec::with_capacity(self.layer_data.len());
         for layer in self.layer_data.iter() {
-            {
-                let image = gpu.texture_to_image(&layer.texture, self.size.0).await;
-
-                let mut data = Vec::new();
-                let encoder = image::codecs::png::PngEncoder::new_with_quality(
-                    &mut data,
-                    image::codecs::png::CompressionType::Best,
-                    image::codecs::png::FilterType::NoFilter,
-                );
-
-                encoder
-                    .write_image(
-                        &image.into_vec(),
-                        self.size.0,
-                        self.size.1,
-                        image::ExtendedColorType::Rgba8,
-                    )
-                    .unwrap();
-
-                images.push(data.to_vec());
-            }
-            {
-                let mask_image = todo!();
+            pixels.push(gpu.texture_data(&layer.texture, self.size.0).await);
+            masks.push(gpu.texture_to_luma_image(&layer.mask, self.size.0).await);
+        }
+
+        let output = match self.output_texture.as_ref() {
+            Some(output_texture) => Some(gpu.texture_to_image(output_texture, self.size.0).await),
+            None => None,
+        };
+
+        std::fs::write(path, self.encode(&pixels, &masks, output.as_ref()))
+    }
+
+    /// Writes the document in the `.jc` format. `pixels` and `masks` are parallel to
+    /// `layers`, see [`DecodedDocument`], and `output` is shrunk into the thumbnail.
+    pub fn encode(
+        &self,
+        pixels: &[Vec<u8>],
+        masks: &[GrayImage],
+        output: Option<&ImageBuffer<Rgba<u8>, Vec<u8>>>,
+    ) -> Vec<u8> {
+        let mut container = Container::default();
+        container.push(
+            ChunkKind::Metadata,
+            METADATA_CHUNK,
+            bincode::serialize(&self).unwrap(),
+        );
+
+        for (i, (pixels, mask)) in pixels.iter().zip(masks).enumerate() {
+            match self.bit_depth {
+                BitDepth::Eight => {
+                    let data = encode_png(pixels, self.size, ExtendedColorType::Rgba8);
+                    container.push(ChunkKind::LayerPixels, layer_pixels_chunk(i), data);
+                }
+                BitDepth::Sixteen => {
+                    // the encoder takes samples in native byte order
+                    let samples = pixels
+                        .chunks_exact(2)
+                        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+                        .collect::<Vec<u16>>();
+                    let data = encode_png(
+                        bytemuck::cast_slice(&samples),
+                        self.size,
+                        ExtendedColorType::Rgba16,
+                    );
+                    container.push(ChunkKind::LayerPixels, layer_pixels_chunk(i), data);
+                }
+                BitDepth::Half | BitDepth::Float => {
+                    container.push(
+                        ChunkKind::LayerFloatPixels,
+                        layer_pixels_chunk(i),
+                        pixels.clone(),
+                    );
+                }
             }
+
+            let data = encode_png(mask.as_raw(), self.size, ExtendedColorType::L8);
+            container.push(ChunkKind::LayerMask, layer_mask_chunk(i), data);
+        }
+
+        if let Some(output) = output {
+            let thumbnail = thumbnail(output);
+            let data = encode_png(
+                thumbnail.as_raw(),
+                thumbnail.dimensions(),
+                ExtendedColorType::Rgba8,
+            );
+            container.push(ChunkKind::Thumbnail, THUMBNAIL_CHUNK, data);
+        }
+
+        container.to_bytes()
+    }
+
+    /// Reads only the preview image of a `.jc` file, without touching the GPU
+    pub fn load_thumbnail(path: &str) -> Result<Option<RgbaImage>, WorkspaceLoadError> {
+        let data = std::fs::read(path)?;
+        let container = Container::from_bytes(&data)?;
+
+        let Some(thumbnail) = container.chunk(ChunkKind::Thumbnail, THUMBNAIL_CHUNK) else {
+            return Ok(None);
+        };
+
+        let reader = ImageReader::with_format(Cursor::new(&thumbnail.data), ImageFormat::Png);
+        Ok(Some(reader.decode()?.into_rgba8()))
+    }
+}
+
+const THUMBNAIL_SIZE: u32 = 256;
+
+/// Scales `image` down to fit in [`THUMBNAIL_SIZE`], leaving smaller images as they are
+pub(super) fn thumbnail(image: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let (width, height) = image.dimensions();
+    let scale = (THUMBNAIL_SIZE as f32 / width.max(height) as f32).min(1.0);
+    image::imageops::thumbnail(
+        image,
+        ((width as f32 * scale) as u32).max(1),
+        ((height as f32 * scale) as u32).max(1),
+    )
+}
+
+pub(super) fn encode_png(data: &[u8], size: (u32, u32), color_type: ExtendedColorType) -> Vec<u8> {
+    let mut png = Vec::new();
+    let encoder = image::codecs::png::PngEncoder::new_with_quality(
+        &mut png,
+        image::codecs::png::CompressionType::Best,
+        image::codecs::png::FilterType::NoFilter,
+    );
+
+    encoder
+        .write_image(data, size.0, size.1, color_type)
+        .unwrap();
+
+    png
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Luma;
+
+    use super::*;
+    use crate::workspace::layer_info::{BlendMode, GroupMode, LayerInfo, LayerKind};
+
+    /// A document with two layers whose pixels and masks are different gradients
+    fn gradient_document(depth: BitDepth) -> (Workspace, Vec<Vec<u8>>, Vec<GrayImage>) {
+        let size = (24, 10);
+        let workspace = Workspace {
+            size,
+            bit_depth: depth,
+            layers: vec![
+                LayerInfo::from(LayerCreationInfo {
+                    name: "Background".to_string(),
+                    ..Default::default()
+                }),
+                LayerInfo::from(LayerCreationInfo {
+                    name: "Masked".to_string(),
+                    opacity: 0.5,
+                    ..Default::default()
+                }),
+            ],
+            ..Default::default()
+        };
+
+        let pixels = (0..2)
+            .map(|layer| {
+                let samples = (0..size.0 * size.1 * 4)
+                    .map(|i| ((i * (layer + 3)) % 1000) as f32 / 999.0)
+                    .collect::<Vec<f32>>();
+                depth.encode(&samples)
+            })
+            .collect();
+        let masks = vec![
+            GrayImage::from_fn(size.0, size.1, |x, _| {
+                Luma([(x * 255 / (size.0 - 1)) as u8])
+            }),
+            GrayImage::from_fn(size.0, size.1, |x, y| Luma([(x * 7 + y * 31) as u8])),
+        ];
+
+        (workspace, pixels, masks)
+    }
+
+    #[test]
+    fn layers_and_masks_round_trip() {
+        for depth in BitDepth::ALL {
+            let (workspace, pixels, masks) = gradient_document(depth);
+            let data = workspace.encode(&pixels, &masks, None);
+            let decoded = Workspace::decode(&data, 8192).unwrap();
+
+            assert_eq!(decoded.workspace.size, workspace.size);
+            assert_eq!(decoded.workspace.bit_depth, depth);
+            let names = decoded.workspace.layers.iter().map(|layer| &layer.name);
+            assert!(names.eq(["Background", "Masked"].iter()));
+            assert_eq!(decoded.pixels, pixels, "{} pixels differ", depth.name());
+            assert_eq!(decoded.masks, masks, "{} masks differ", depth.name());
+        }
+    }
+
+    #[test]
+    fn documents_blended_the_old_way_warn() {
+        let (mut workspace, pixels, masks) = gradient_document(BitDepth::Eight);
+        workspace.layers[1].blend_mode = BlendMode::Overlay;
+        let mut data = workspace.encode(&pixels, &masks, None);
+        assert!(Workspace::decode(&data, 8192).unwrap().warnings.is_empty());
+
+        data[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&6u32.to_le_bytes());
+        let decoded = Workspace::decode(&data, 8192).unwrap();
+        assert_eq!(decoded.pixels, pixels);
+        assert_eq!(decoded.warnings.len(), 1);
+        assert!(decoded.warnings[0].contains("\"Masked\""));
+        assert!(!decoded.warnings[0].contains("Background"));
+    }
+
+    fn saved_document() -> Vec<u8> {
+        let (workspace, pixels, masks) = gradient_document(BitDepth::Eight);
+        let output = RgbaImage::new(workspace.size.0, workspace.size.1);
+        workspace.encode(&pixels, &masks, Some(&output))
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        let data = saved_document();
+        assert!(Workspace::decode(&data, 8192).is_ok());
+
+        for len in 0..data.len() {
+            assert!(
+                Workspace::decode(&data[..len], 8192).is_err(),
+                "file cut to {} of {} bytes was accepted",
+                len,
+                data.len()
+            );
+        }
+    }
+
+    #[test]
+    fn flipped_bits_are_errors_or_harmless() {
+        let data = saved_document();
+        let original = Workspace::decode(&data, 8192).unwrap();
+        let flipped = |i: usize| {
+            let mut corrupt = data.clone();
+            corrupt[i / 8] ^= 1 << (i % 8);
+            Workspace::decode(&corrupt, 8192)
+        };
+
+        // flips in values like the zoom or in the unread thumbnail still make a valid
+        // document, all that matters is that they don't panic
+        for i in 0..data.len() * 8 {
+            let _ = flipped(i);
         }
 
-        for image in images {
-            let len = image.len() as u32;
-            let len = len.to_le_bytes();
-            data.extend_from_slice(&len);
-            data.extend_from_slice(&image);
+        // damaged pixels and masks are caught by the checksums of the PNGs, anything that
+        // gets past them has to leave the image as it was. The payloads follow the chunk
+        // table in order.
+        let container = Container::from_bytes(&data).unwrap();
+        let payload_len = container
+            .chunks
+            .iter()
+            .map(|chunk| chunk.data.len())
+            .sum::<usize>();
+        let mut start = data.len() - payload_len;
+        for chunk in &container.chunks {
+            let end = start + chunk.data.len();
+            if matches!(chunk.kind, ChunkKind::LayerPixels | ChunkKind::LayerMask) {
+                for i in start * 8..end * 8 {
+                    if let Ok(decoded) = flipped(i) {
+                        assert!(
+                            decoded.pixels == original.pixels && decoded.masks == original.masks,
+                            "flipping bit {} of {} changed the image unnoticed",
+                            i,
+                            chunk.name
+                        );
+                    }
+                }
+            }
+            start = end;
         }
+    }
 
-        std::fs::write(path, data).unwrap();
+    #[test]
+    fn group_without_end_is_an_error() {
+        let (mut workspace, pixels, masks) = gradient_document(BitDepth::Eight);
+        workspace.layers[1].kind = LayerKind::Group {
+            mode: GroupMode::PassThrough,
+            collapsed: false,
+        };
+        let data = workspace.encode(&pixels, &masks, None);
+
+        assert!(matches!(
+            Workspace::decode(&data, 8192),
+            Err(WorkspaceLoadError::UnbalancedGroup(1))
+        ));
+    }
+
+    #[test]
+    fn garbage_is_an_error() {
+        for data in [
+            &b""[..],
+            b"JOYCRT\r\n",
+            b"JOYCRT\r\n\x06\0\0\0\xff\xff\xff\xff",
+            &[0xff; 64],
+        ] {
+            assert!(Workspace::decode(data, 8192).is_err());
+        }
     }
 }
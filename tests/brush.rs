@@ -0,0 +1,77 @@
+//! Strokes of the brush tool and how they are committed into the layer below
+
+use futures::executor::block_on;
+use joyful_create::{
+    workspace::{
+        bit_depth::BitDepth,
+        tools::{
+            brush::{BrushTool, BrushToolSettings},
+            ActionOrigin, PointerInput, Tool,
+        },
+        Workspace,
+    },
+    GpuDevice,
+};
+
+/// Dabs the brush onto the middle of a 32x32 document's only layer and returns that layer's
+/// pixel at the middle
+fn stroke(background: [u8; 4], color: [u8; 4], opacity: f32, gpu: &GpuDevice) -> [u8; 4] {
+    let mut workspace =
+        Workspace::new_document((32, 32), background, BitDepth::Eight, gpu).unwrap();
+    workspace.selected_layer = Some(0);
+
+    let mut brush = BrushTool::new(
+        BrushToolSettings {
+            size: 6.0,
+            color: Some(color),
+            hardness: 0.9,
+            opacity,
+            ..Default::default()
+        },
+        gpu,
+    );
+    for origin in [
+        ActionOrigin::MouseDown(PointerInput::at((16.0, 16.0))),
+        ActionOrigin::MouseUp(PointerInput::at((16.0, 16.0))),
+    ] {
+        brush.perform_action(&mut workspace, gpu, origin);
+    }
+
+    assert_eq!(workspace.layers.len(), 1, "the tool layer was not merged");
+    let layer = block_on(gpu.texture_to_image(workspace.layer_data[0].texture(), 32));
+    layer.get_pixel(16, 16).0
+}
+
+fn assert_close(actual: [u8; 4], expected: [u8; 4]) {
+    let close = (0..4).all(|i| actual[i].abs_diff(expected[i]) <= 1);
+    assert!(close, "expected {:?}, got {:?}", expected, actual);
+}
+
+#[test]
+#[ignore = "needs a GPU"]
+fn half_opacity_stroke_on_transparent_layer_keeps_its_color() {
+    let gpu = GpuDevice::for_tests();
+
+    // straight alpha, so the color stays red rather than darkening and the coverage isn't
+    // squared
+    let pixel = stroke([0, 0, 0, 0], [255, 0, 0, 255], 0.5, &gpu);
+    assert_close(pixel, [255, 0, 0, 128]);
+}
+
+#[test]
+#[ignore = "needs a GPU"]
+fn half_opacity_stroke_on_opaque_layer_mixes_colors() {
+    let gpu = GpuDevice::for_tests();
+
+    let pixel = stroke([255, 255, 255, 255], [0, 0, 255, 255], 0.5, &gpu);
+    assert_close(pixel, [127, 127, 255, 255]);
+}
+
+#[test]
+#[ignore = "needs a GPU"]
+fn opaque_stroke_replaces_the_layer() {
+    let gpu = GpuDevice::for_tests();
+
+    let pixel = stroke([255, 255, 255, 255], [10, 200, 30, 255], 1.0, &gpu);
+    assert_close(pixel, [10, 200, 30, 255]);
+}
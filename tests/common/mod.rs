@@ -0,0 +1,10 @@
+//! Helpers shared by the integration tests
+
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+
+/// A path in the temp directory that no other test uses
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("joyful_create_{}_{}", std::process::id(), name))
+}
@@ -0,0 +1,35 @@
+//! Using the workspace, filters and serialization without a window
+
+mod common;
+
+use futures::executor::block_on;
+use joyful_create::{
+    filters::kernel::Kernel,
+    workspace::{bit_depth::BitDepth, Workspace},
+    GpuDevice,
+};
+
+#[test]
+#[ignore = "needs a GPU"]
+fn filter_save_and_load_without_egui() {
+    let gpu = GpuDevice::for_tests();
+
+    let mut workspace =
+        Workspace::new_document((48, 32), [40, 120, 200, 255], BitDepth::Eight, &gpu).unwrap();
+    let output = workspace.output_texture.as_ref().unwrap();
+    let image = block_on(gpu.texture_to_image(output, 48));
+    assert!(image.pixels().all(|pixel| pixel.0 == [40, 120, 200, 255]));
+
+    let kernel = Kernel::big_gaussian_kernel(&gpu, 5, 5);
+    block_on(kernel.apply_to_layer(&mut workspace, 0, &gpu));
+    let filtered = block_on(gpu.texture_to_image(workspace.output_texture.as_ref().unwrap(), 48));
+
+    let path = common::temp_path("headless.jc");
+    block_on(workspace.save(path.to_str().unwrap(), &gpu)).unwrap();
+    let (loaded, _) = Workspace::load(path.to_str().unwrap(), &gpu).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(loaded.size, workspace.size);
+    let reloaded = block_on(gpu.texture_to_image(loaded.output_texture.as_ref().unwrap(), 48));
+    assert_eq!(filtered, reloaded);
+}
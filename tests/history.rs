@@ -0,0 +1,50 @@
+//! Undoing and redoing layer edits on the GPU
+
+use joyful_create::{
+    workspace::{bit_depth::BitDepth, layer_info::LayerCreationInfo, Workspace},
+    GpuDevice,
+};
+
+fn names(workspace: &Workspace) -> Vec<&str> {
+    workspace
+        .layers
+        .iter()
+        .map(|layer| layer.name.as_str())
+        .collect()
+}
+
+#[test]
+#[ignore = "needs a GPU"]
+fn undoing_a_move_restores_order_and_selection() {
+    let gpu = GpuDevice::for_tests();
+
+    let mut workspace =
+        Workspace::new_document((8, 8), [255, 255, 255, 255], BitDepth::Eight, &gpu).unwrap();
+    for name in ["A", "B", "C"] {
+        workspace.create_layer(
+            LayerCreationInfo {
+                name: name.to_string(),
+                ..Default::default()
+            },
+            &gpu,
+            None,
+        );
+    }
+    let original = ["Background", "A", "B", "C"];
+    assert_eq!(names(&workspace), original);
+
+    for (selected, after) in [(1, 3), (2, 1), (3, 2), (0, 0)] {
+        workspace.selected_layer = Some(selected);
+        workspace.move_layer(1, 3, &gpu);
+        assert_eq!(names(&workspace), ["Background", "B", "C", "A"]);
+        assert_eq!(workspace.selected_layer, Some(after));
+
+        assert!(workspace.undo(&gpu));
+        assert_eq!(names(&workspace), original);
+        assert_eq!(workspace.selected_layer, Some(selected));
+
+        assert!(workspace.redo(&gpu));
+        assert_eq!(workspace.selected_layer, Some(after));
+        assert!(workspace.undo(&gpu));
+    }
+}
@@ -0,0 +1,98 @@
+//! Saving documents to `.jc` files and loading them back on the GPU
+
+mod common;
+
+use futures::executor::block_on;
+use image::{GrayImage, Luma, Rgba, RgbaImage};
+use joyful_create::{
+    workspace::{
+        bit_depth::{BitDepth, UnsupportedBitDepth},
+        layer_info::LayerCreationInfo,
+        Workspace, WorkspaceLoadError,
+    },
+    GpuDevice,
+};
+
+#[test]
+#[ignore = "needs a GPU"]
+fn gradient_mask_survives_save_and_load() {
+    let gpu = GpuDevice::for_tests();
+
+    let size = (40, 24);
+    let mut workspace =
+        Workspace::new_document(size, [255, 255, 255, 255], BitDepth::Eight, &gpu).unwrap();
+    let mask = GrayImage::from_fn(size.0, size.1, |x, y| Luma([(x * 6 + y) as u8]));
+    workspace.create_layer(
+        LayerCreationInfo {
+            name: "Masked".to_string(),
+            init_image: Some(RgbaImage::from_fn(size.0, size.1, |x, y| {
+                Rgba([x as u8 * 5, y as u8 * 9, 128, 255])
+            })),
+            init_mask_image: Some(mask.clone()),
+            ..Default::default()
+        },
+        &gpu,
+        None,
+    );
+    let pixels = block_on(gpu.texture_to_image(workspace.layer_data[1].texture(), size.0));
+
+    let path = common::temp_path("gradient_mask.jc");
+    block_on(workspace.save(path.to_str().unwrap(), &gpu)).unwrap();
+    let (loaded, _) = Workspace::load(path.to_str().unwrap(), &gpu).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(loaded.layers.len(), 2);
+    assert_eq!(loaded.layers[1].name, "Masked");
+    let loaded_mask = block_on(gpu.texture_to_luma_image(loaded.layer_data[1].mask(), size.0));
+    assert_eq!(loaded_mask.as_raw(), mask.as_raw());
+    let loaded_pixels = block_on(gpu.texture_to_image(loaded.layer_data[1].texture(), size.0));
+    assert_eq!(loaded_pixels, pixels);
+}
+
+#[test]
+#[ignore = "needs a GPU"]
+fn truncated_file_fails_to_load() {
+    let gpu = GpuDevice::for_tests();
+
+    let workspace =
+        Workspace::new_document((16, 16), [0, 0, 0, 255], BitDepth::Eight, &gpu).unwrap();
+    let path = common::temp_path("truncated.jc");
+    block_on(workspace.save(path.to_str().unwrap(), &gpu)).unwrap();
+    let data = std::fs::read(&path).unwrap();
+
+    for len in [0, 8, 16, data.len() / 2, data.len() - 1] {
+        std::fs::write(&path, &data[..len]).unwrap();
+        assert!(Workspace::load(path.to_str().unwrap(), &gpu).is_err());
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+#[ignore = "needs a GPU"]
+fn unsupported_depths_are_errors() {
+    let gpu = GpuDevice::for_tests();
+
+    let path = common::temp_path("depth.jc");
+    for depth in BitDepth::ALL {
+        let created = Workspace::new_document((8, 8), [0, 0, 0, 255], depth, &gpu);
+        let document = Workspace {
+            size: (8, 8),
+            bit_depth: depth,
+            ..Default::default()
+        };
+        std::fs::write(&path, document.encode(&[], &[], None)).unwrap();
+        let loaded = Workspace::load(path.to_str().unwrap(), &gpu);
+
+        if depth.is_supported(&gpu) {
+            assert_eq!(created.unwrap().bit_depth, depth);
+            assert_eq!(loaded.unwrap().0.bit_depth, depth);
+        } else {
+            assert_eq!(created.err(), Some(UnsupportedBitDepth(depth)));
+            assert!(matches!(
+                loaded,
+                Err(WorkspaceLoadError::UnsupportedBitDepth(found)) if found == depth
+            ));
+        }
+    }
+    std::fs::remove_file(&path).unwrap();
+}